@@ -1,9 +1,13 @@
+use std::fs;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus};
 use std::time::Duration;
 
 const RUN_ARGS: &[&str] = &["-no-reboot", "-no-shutdown"];
+// Tests write to their disks, boot disk included, so the writes are thrown away when QEMU exits.
 const TEST_ARGS: &[&str] = &[
+    "-snapshot",
     "-device",
     "isa-debug-exit,iobase=0xF4,iosize=0x04",
     "-serial",
@@ -14,6 +18,20 @@ const TEST_ARGS: &[&str] = &[
 ];
 const TEST_TIMEOUT_SECONDS: u64 = 10;
 
+// Where the kernel looks for the initramfs, and the header it expects in front of it. These have
+// to match the kernel's copies in `fs::initramfs`. The boot loader can't tell the kernel where a
+// file like this is, so the archive goes at a fixed address instead, and QEMU puts it there
+// before the boot loader runs. That has to stay clear of everything the boot loader allocates,
+// the kernel image, page tables and boot info, which it takes from the bottom of usable memory
+// up. The kernel ignores an archive the boot loader has used any of, and warns about it.
+const INITRAMFS_ADDRESS: u64 = 0x400_0000;
+const INITRAMFS_MAGIC: &[u8; 8] = b"HALOINIT";
+const DEFAULT_MEMORY_MIB: u64 = 128;
+// Tests that aren't given a disk get a blank one, so that the disk drivers have something to use.
+const SCRATCH_DISK_SIZE: u64 = 4 * 1024 * 1024;
+// Tests named like this boot a q35 machine instead, which has PCI Express and an MCFG table.
+const PCIE_TEST_PREFIX: &str = "pcie-";
+
 fn main() {
     let mut args = std::env::args().skip(1); // Skip executable name
 
@@ -21,28 +39,34 @@ fn main() {
         let path = PathBuf::from(args.next().unwrap());
         path.canonicalize().unwrap()
     };
-    // This is a bit of a hack to allow kimage to use `cargo run` without running QEMU.
-    let no_boot = if let Some(arg) = args.next() {
-        match arg.as_str() {
-            "--no-run" => true,
-            other => panic!("Unexpected argument {}!", other),
-        }
-    } else {
-        false
-    };
-    let uefi = if let Some(arg) = args.next() {
+    // `--no-run` is a bit of a hack to allow kimage to use `cargo run` without running QEMU.
+    let mut no_boot = false;
+    let mut uefi = false;
+    // Tests can't easily be given arguments, so the initramfs can come from the environment too.
+    let mut initramfs_source = std::env::var_os("HALOGEN_INITRAMFS").map(PathBuf::from);
+    let mut disks: Vec<PathBuf> = std::env::var_os("HALOGEN_DISK").map(PathBuf::from).into_iter().collect();
+    while let Some(arg) = args.next() {
         match arg.as_str() {
-            "--uefi" => true,
+            "--no-run" => no_boot = true,
+            "--uefi" => uefi = true,
+            "--initramfs" => initramfs_source = Some(PathBuf::from(args.next().expect("Missing initramfs path!"))),
+            "--disk" => disks.push(PathBuf::from(args.next().expect("Missing disk image path!"))),
             other => panic!("Unexpected argument {}!", other)
         }
-    } else {
-        false
-    };
+    }
+    // Firmware is free to use any memory it likes before handing over, and OVMF does.
+    if uefi && initramfs_source.is_some() {
+        panic!("An initramfs can only be loaded when booting through the BIOS!");
+    }
+    let initramfs = initramfs_source.map(|source| create_initramfs(&source, &kernel_binary_path));
 
     let image = create_disk_images(&kernel_binary_path, uefi);
 
     if no_boot {
         println!("Created disk image at `{}`", image.display());
+        if let Some((initramfs, _)) = &initramfs {
+            println!("Created initramfs at `{}`", initramfs.display());
+        }
         return;
     }
 
@@ -50,8 +74,33 @@ fn main() {
     run_command
         .arg("-drive")
         .arg(format!("format=raw,file={}", image.display()));
+    if let Some((initramfs, size)) = &initramfs {
+        // Leave plenty of memory above the archive for the kernel to use.
+        let memory = DEFAULT_MEMORY_MIB.max((INITRAMFS_ADDRESS + size) / (1024 * 1024) + 64);
+        run_command
+            .arg("-m")
+            .arg(format!("{}M", memory))
+            .arg("-device")
+            .arg(format!("loader,file={},addr={:#x},force-raw=on", initramfs.display(), INITRAMFS_ADDRESS));
+    }
 
     let binary_kind = runner_utils::binary_kind(&kernel_binary_path);
+    let binary_name = kernel_binary_path.file_name().unwrap().to_string_lossy();
+    if binary_kind.is_test() && binary_name.starts_with(PCIE_TEST_PREFIX) {
+        run_command.arg("-machine").arg("q35");
+    }
+    if binary_kind.is_test() && disks.is_empty() {
+        disks.push(create_scratch_disk(&kernel_binary_path));
+    }
+    // Raw images attached as virtio block devices, which the kernel names vda, vdb and so on.
+    for (index, disk) in disks.iter().enumerate() {
+        run_command
+            .arg("-drive")
+            .arg(format!("file={},format=raw,if=none,id=disk{}", disk.display(), index))
+            .arg("-device")
+            .arg(format!("virtio-blk-pci,drive=disk{}", index));
+    }
+
     if binary_kind.is_test() {
         run_command.args(TEST_ARGS);
         let exit_status = run_test_command(run_command);
@@ -83,7 +132,7 @@ pub fn create_disk_images(kernel_binary_path: &Path, uefi: bool) -> PathBuf {
         .arg("--kernel-manifest")
         .arg(&kernel_manifest_path)
         .arg("--kernel-binary")
-        .arg(&kernel_binary_path)
+        .arg(kernel_binary_path)
         .arg("--target-dir")
         .arg(kernel_manifest_path.parent().unwrap().join("target"))
         .arg("--out-dir")
@@ -108,3 +157,106 @@ pub fn create_disk_images(kernel_binary_path: &Path, uefi: bool) -> PathBuf {
     }
     disk_image
 }
+
+// Starts every test run with an empty disk, rather than whatever the last run left on it.
+fn create_scratch_disk(kernel_binary_path: &Path) -> PathBuf {
+    let kernel_binary_name = kernel_binary_path.file_name().unwrap().to_str().unwrap();
+    let path = kernel_binary_path.parent().unwrap().join(format!("disk-{}.img", kernel_binary_name));
+    let file = fs::File::create(&path).unwrap();
+    file.set_len(SCRATCH_DISK_SIZE).unwrap();
+    path
+}
+
+// Wraps an archive in the header the kernel looks for, packing it into a cpio archive first if
+// it's a directory. Returns where it was written and how big it is, header included.
+fn create_initramfs(source: &Path, kernel_binary_path: &Path) -> (PathBuf, u64) {
+    let archive = if source.is_dir() {
+        let mut writer = CpioWriter::default();
+        writer.add_directory(source, "").unwrap();
+        writer.finish()
+    } else {
+        fs::read(source).unwrap()
+    };
+
+    let kernel_binary_name = kernel_binary_path.file_name().unwrap().to_str().unwrap();
+    let path = kernel_binary_path.parent().unwrap().join(format!("initramfs-{}.img", kernel_binary_name));
+    let mut file = fs::File::create(&path).unwrap();
+    file.write_all(INITRAMFS_MAGIC).unwrap();
+    file.write_all(&(archive.len() as u64).to_le_bytes()).unwrap();
+    file.write_all(&archive).unwrap();
+    (path, INITRAMFS_MAGIC.len() as u64 + 8 + archive.len() as u64)
+}
+
+// Writes cpio archives in the "newc" format, which the kernel can unpack.
+#[derive(Default)]
+struct CpioWriter {
+    data: Vec<u8>,
+    next_inode: u32
+}
+
+impl CpioWriter {
+    // Adds everything in a directory, in sorted order so that the archive always comes out the
+    // same. Anything that isn't a file, directory or symlink is left out.
+    fn add_directory(&mut self, directory: &Path, prefix: &str) -> io::Result<()> {
+        let mut entries = fs::read_dir(directory)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name().into_string().expect("Initramfs file names must be UTF-8!");
+            let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+            let metadata = fs::symlink_metadata(entry.path())?;
+            let permissions = permissions(&metadata);
+            if metadata.is_dir() {
+                self.add_entry(&path, 0o040000 | permissions, &[]);
+                self.add_directory(&entry.path(), &path)?;
+            } else if metadata.is_file() {
+                self.add_entry(&path, 0o100000 | permissions, &fs::read(entry.path())?);
+            } else if metadata.file_type().is_symlink() {
+                let target = fs::read_link(entry.path())?;
+                let target = target.to_str().expect("Initramfs symlink targets must be UTF-8!");
+                self.add_entry(&path, 0o120000 | 0o777, target.as_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn add_entry(&mut self, name: &str, mode: u32, contents: &[u8]) {
+        self.next_inode += 1;
+        let fields = [
+            self.next_inode, mode, 0, 0, 1, 0, contents.len() as u32,
+            0, 0, 0, 0, name.len() as u32 + 1, 0
+        ];
+        self.data.extend_from_slice(b"070701");
+        for field in fields {
+            self.data.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        self.pad();
+        self.data.extend_from_slice(contents);
+        self.pad();
+    }
+
+    fn pad(&mut self) {
+        self.data.resize(self.data.len().next_multiple_of(4), 0);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.add_entry("TRAILER!!!", 0, &[]);
+        self.data
+    }
+}
+
+#[cfg(unix)]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644
+    }
+}
@@ -0,0 +1,156 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use super::heap::GrowableHeap;
+
+// The block sizes double as the block alignments, so they must all be powers of two.
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+const CLASS_COUNT: usize = BLOCK_SIZES.len();
+
+struct ListNode {
+    next: Option<&'static mut ListNode>
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClassStats {
+    pub block_size: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    pub in_use: usize,
+    pub free: usize
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FallbackStats {
+    pub allocations: usize,
+    pub deallocations: usize,
+    pub in_use: usize
+}
+
+struct SizeClasses {
+    heads: [Option<&'static mut ListNode>; CLASS_COUNT],
+    stats: [ClassStats; CLASS_COUNT],
+    fallback: FallbackStats
+}
+
+// Serves small allocations from per size class free lists, and hands anything larger than
+// the biggest block size to the growable linked list heap. Freed blocks are kept on their
+// free list rather than being returned to the fallback heap.
+pub struct FixedSizeBlockAllocator {
+    classes: Mutex<SizeClasses>,
+    fallback: GrowableHeap
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new(fallback: GrowableHeap) -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        const EMPTY_STATS: ClassStats = ClassStats {
+            block_size: 0,
+            allocations: 0,
+            deallocations: 0,
+            in_use: 0,
+            free: 0
+        };
+        let classes = SizeClasses {
+            heads: [EMPTY; CLASS_COUNT],
+            stats: [EMPTY_STATS; CLASS_COUNT],
+            fallback: FallbackStats { allocations: 0, deallocations: 0, in_use: 0 }
+        };
+        Self { classes: Mutex::new(classes), fallback }
+    }
+
+    pub fn fallback(&self) -> &GrowableHeap {
+        &self.fallback
+    }
+
+    pub fn class_stats(&self) -> [ClassStats; CLASS_COUNT] {
+        let mut stats = interrupts::without_interrupts(|| self.classes.lock().stats);
+        for (class, size) in stats.iter_mut().zip(BLOCK_SIZES) {
+            class.block_size = *size;
+        }
+        stats
+    }
+
+    pub fn fallback_stats(&self) -> FallbackStats {
+        interrupts::without_interrupts(|| self.classes.lock().fallback)
+    }
+}
+
+// Interrupts stay disabled while the allocator is in use, so that neither an interrupt handler
+// nor another thread can end up waiting on the allocator's locks while they are held.
+unsafe impl GlobalAlloc for FixedSizeBlockAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| self.allocate(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.deallocate(ptr, layout))
+    }
+}
+
+impl FixedSizeBlockAllocator {
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
+        let mut classes = self.classes.lock();
+        match class_index(&layout) {
+            Some(index) => {
+                if let Some(node) = classes.heads[index].take() {
+                    classes.heads[index] = node.next.take();
+                    let stats = &mut classes.stats[index];
+                    stats.allocations += 1;
+                    stats.in_use += 1;
+                    stats.free -= 1;
+                    return node as *mut ListNode as *mut u8;
+                }
+                // No free blocks of this size left, so carve a new one out of the fallback heap.
+                let block_size = BLOCK_SIZES[index];
+                let block_layout = Layout::from_size_align(block_size, block_size).unwrap();
+                let block = self.fallback.alloc(block_layout);
+                if !block.is_null() {
+                    let stats = &mut classes.stats[index];
+                    stats.allocations += 1;
+                    stats.in_use += 1;
+                }
+                block
+            }
+            None => {
+                let allocation = self.fallback.alloc(layout);
+                if !allocation.is_null() {
+                    classes.fallback.allocations += 1;
+                    classes.fallback.in_use += 1;
+                }
+                allocation
+            }
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        let mut classes = self.classes.lock();
+        match class_index(&layout) {
+            Some(index) => {
+                // Blocks are always at least as large and aligned as a list node.
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let node = ListNode { next: classes.heads[index].take() };
+                let node_ptr = ptr as *mut ListNode;
+                ptr::write(node_ptr, node);
+                classes.heads[index] = Some(&mut *node_ptr);
+                let stats = &mut classes.stats[index];
+                stats.deallocations += 1;
+                stats.in_use -= 1;
+                stats.free += 1;
+            }
+            None => {
+                self.fallback.dealloc(ptr, layout);
+                classes.fallback.deallocations += 1;
+                classes.fallback.in_use -= 1;
+            }
+        }
+    }
+}
+
+fn class_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required_block_size)
+}
@@ -0,0 +1,118 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB
+    },
+    VirtAddr
+};
+use crate::memory;
+
+const HEAP_GROWTH_STEP: usize = 64 * 1024;
+
+// A linked list heap that maps more pages at the top of the heap whenever an allocation
+// doesn't fit, up to the configured limit.
+//
+// Growing takes the mapper and then the frame allocator while the heap is locked, so the lock
+// order is heap, mapper, frame allocator. Anything holding either of the other two must not
+// allocate. Freeing is still fine, since every allocator lock is only ever held with interrupts
+// off and so can't be waited on by anything else on this CPU.
+pub struct GrowableHeap {
+    heap: Mutex<Heap>,
+    limit: AtomicUsize
+}
+
+impl GrowableHeap {
+    pub const fn new(limit: usize) -> Self {
+        Self { heap: Mutex::new(Heap::empty()), limit: AtomicUsize::new(limit) }
+    }
+
+    pub fn init(&self, start: usize, size: usize) -> Result<(), MapToError<Size4KiB>> {
+        map_heap_pages(start, size)?;
+        interrupts::without_interrupts(|| unsafe { self.heap.lock().init(start, size) });
+        Ok(())
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn size(&self) -> usize {
+        interrupts::without_interrupts(|| self.heap.lock().size())
+    }
+
+    pub fn used(&self) -> usize {
+        interrupts::without_interrupts(|| self.heap.lock().used())
+    }
+
+    fn grow(&self, heap: &mut Heap, layout: Layout) -> Result<(), MapToError<Size4KiB>> {
+        let required = (layout.size() + layout.align()).next_multiple_of(HEAP_GROWTH_STEP);
+        let available = self.limit.load(Ordering::Relaxed).saturating_sub(heap.size());
+        if required > available {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        map_heap_pages(heap.top(), required)?;
+        unsafe { heap.extend(required) };
+        Ok(())
+    }
+}
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+        if let Ok(allocation) = heap.allocate_first_fit(layout) {
+            return allocation.as_ptr();
+        }
+        if self.grow(&mut heap, layout).is_err() {
+            return ptr::null_mut();
+        }
+        heap.allocate_first_fit(layout).map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+// Backs a range of the heap with fresh frames. If that fails partway through, whatever was
+// mapped is unmapped and freed again, so that a failed allocation doesn't leak memory.
+fn map_heap_pages(start: usize, size: usize) -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+    let mut mapper = memory::mapper();
+    let mut frame_allocator = memory::frame_allocator();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for (index, page) in page_range.enumerate() {
+        let result = match frame_allocator.allocate_frame() {
+            Some(frame) => match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    Ok(())
+                }
+                Err(error) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    Err(error)
+                }
+            },
+            None => Err(MapToError::FrameAllocationFailed)
+        };
+        if let Err(error) = result {
+            for mapped in page_range.take(index) {
+                let (frame, flush) = mapper.unmap(mapped).expect("Failed to unmap a heap page that was just mapped!");
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            return Err(error);
+        }
+    }
+    Ok(())
+}
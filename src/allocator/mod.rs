@@ -0,0 +1,56 @@
+mod fixed_size_block;
+mod heap;
+
+pub use fixed_size_block::{ClassStats, FallbackStats, BLOCK_SIZES};
+
+use fixed_size_block::FixedSizeBlockAllocator;
+use heap::GrowableHeap;
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use crate::println;
+
+pub const HEAP_START: usize = 0x444444440000;
+pub const HEAP_SIZE: usize = 100 * 1024;
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: FixedSizeBlockAllocator = FixedSizeBlockAllocator::new(GrowableHeap::new(HEAP_MAX_SIZE));
+
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    ALLOCATOR.fallback().init(HEAP_START, HEAP_SIZE)
+}
+
+// Sets the ceiling the heap is allowed to grow to. This can't shrink the heap below its current size.
+pub fn set_heap_limit(limit: usize) {
+    ALLOCATOR.fallback().set_limit(limit);
+}
+
+pub fn heap_size() -> usize {
+    ALLOCATOR.fallback().size()
+}
+
+pub fn heap_used() -> usize {
+    ALLOCATOR.fallback().used()
+}
+
+pub fn class_stats() -> [ClassStats; BLOCK_SIZES.len()] {
+    ALLOCATOR.class_stats()
+}
+
+pub fn fallback_stats() -> FallbackStats {
+    ALLOCATOR.fallback_stats()
+}
+
+pub fn print_stats() {
+    println!("Heap: {} of {} bytes used", heap_used(), heap_size());
+    for class in class_stats().iter() {
+        println!(
+            "  {:>5} bytes: {} in use, {} free ({} allocations, {} deallocations)",
+            class.block_size, class.in_use, class.free, class.allocations, class.deallocations
+        );
+    }
+    let fallback = fallback_stats();
+    println!(
+        "  large: {} in use ({} allocations, {} deallocations)",
+        fallback.in_use, fallback.allocations, fallback.deallocations
+    );
+}
@@ -0,0 +1,208 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::sync::SleepMutex;
+use crate::syscall::Errno;
+use super::{check_request, BlockDevice, BlockResult, SECTOR_SIZE};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub write_backs: u64
+}
+
+struct CachedBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64
+}
+
+#[derive(Default)]
+struct State {
+    blocks: BTreeMap<u64, CachedBlock>,
+    // Blocks by when they were last used, so the least recently used one comes first.
+    recency: BTreeMap<u64, u64>,
+    clock: u64,
+    stats: CacheStats
+}
+
+// Keeps recently used blocks of a device in memory, and holds on to writes until the block is
+// evicted or the cache is synced. Blocks are a whole number of sectors, and the cache holds at
+// most `capacity` of them.
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    capacity: usize,
+    state: SleepMutex<State>
+}
+
+impl BufferCache {
+    pub fn new(device: Arc<dyn BlockDevice>, block_size: usize, capacity: usize) -> Arc<Self> {
+        assert!(block_size != 0 && block_size.is_multiple_of(SECTOR_SIZE), "Cache blocks must be made of whole sectors!");
+        assert!(capacity > 0, "A cache has to be able to hold something!");
+        Arc::new(Self { device, block_size, capacity, state: SleepMutex::new(State::default()) })
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.device.sector_count() / self.sectors_per_block()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().stats
+    }
+
+    pub fn dirty_blocks(&self) -> usize {
+        self.state.lock().blocks.values().filter(|block| block.dirty).count()
+    }
+
+    fn sectors_per_block(&self) -> u64 {
+        (self.block_size / SECTOR_SIZE) as u64
+    }
+
+    fn write_back(&self, state: &mut State, index: u64) -> BlockResult<()> {
+        let block = state.blocks.get_mut(&index).unwrap();
+        if block.dirty {
+            self.device.write_sectors(index * self.sectors_per_block(), &block.data)?;
+            block.dirty = false;
+            state.stats.write_backs += 1;
+        }
+        Ok(())
+    }
+
+    // Makes sure the block is cached and marks it as just used. Something else has to go to make
+    // room for it if the cache is full, and dirty blocks are written back first.
+    fn load<'a>(&self, state: &'a mut State, index: u64) -> BlockResult<&'a mut CachedBlock> {
+        if index >= self.block_count() {
+            return Err(Errno::EINVAL);
+        }
+        state.clock += 1;
+        let now = state.clock;
+        if let Some(block) = state.blocks.get_mut(&index) {
+            state.recency.remove(&block.last_used);
+            state.recency.insert(now, index);
+            block.last_used = now;
+            state.stats.hits += 1;
+            return Ok(state.blocks.get_mut(&index).unwrap());
+        }
+
+        state.stats.misses += 1;
+        while state.blocks.len() >= self.capacity {
+            let (&last_used, &victim) = state.recency.iter().next().unwrap();
+            self.write_back(state, victim)?;
+            state.recency.remove(&last_used);
+            state.blocks.remove(&victim);
+            state.stats.evictions += 1;
+        }
+        let mut data = vec![0; self.block_size];
+        self.device.read_sectors(index * self.sectors_per_block(), &mut data)?;
+        state.recency.insert(now, index);
+        Ok(state.blocks.entry(index).or_insert(CachedBlock { data, dirty: false, last_used: now }))
+    }
+
+    // The closure runs with the cache locked, so it mustn't use the cache itself.
+    pub fn with_block<R>(&self, index: u64, f: impl FnOnce(&[u8]) -> R) -> BlockResult<R> {
+        let mut state = self.state.lock();
+        let block = self.load(&mut state, index)?;
+        Ok(f(&block.data))
+    }
+
+    // The block is only written out once it's evicted or the cache is synced. Like `with_block`,
+    // the closure mustn't use the cache.
+    pub fn with_block_mut<R>(&self, index: u64, f: impl FnOnce(&mut [u8]) -> R) -> BlockResult<R> {
+        let mut state = self.state.lock();
+        let block = self.load(&mut state, index)?;
+        block.dirty = true;
+        Ok(f(&mut block.data))
+    }
+
+    // Byte addressed access, for anything that doesn't line up with blocks.
+    pub fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let index = position / self.block_size as u64;
+            let start = (position % self.block_size as u64) as usize;
+            let length = (self.block_size - start).min(buffer.len() - done);
+            self.with_block(index, |data| buffer[done..done + length].copy_from_slice(&data[start..start + length]))?;
+            done += length;
+        }
+        Ok(())
+    }
+
+    pub fn write_bytes(&self, offset: u64, data: &[u8]) -> BlockResult<()> {
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let index = position / self.block_size as u64;
+            let start = (position % self.block_size as u64) as usize;
+            let length = (self.block_size - start).min(data.len() - done);
+            self.with_block_mut(index, |block| block[start..start + length].copy_from_slice(&data[done..done + length]))?;
+            done += length;
+        }
+        Ok(())
+    }
+
+    // Writes back every dirty block, in order, then flushes the device.
+    pub fn sync(&self) -> BlockResult<()> {
+        let mut state = self.state.lock();
+        let dirty: Vec<u64> = state.blocks.iter().filter(|(_, block)| block.dirty).map(|(&index, _)| index).collect();
+        for index in dirty {
+            self.write_back(&mut state, index)?;
+        }
+        self.device.flush()
+    }
+
+    // Forgets everything cached without writing it back.
+    pub fn invalidate(&self) {
+        let mut state = self.state.lock();
+        state.blocks.clear();
+        state.recency.clear();
+    }
+}
+
+// Writing through the cache as a block device only reaches the disk once it's synced, just like
+// writing through `write_bytes`.
+impl BlockDevice for BufferCache {
+    fn sector_count(&self) -> u64 {
+        self.block_count() * self.sectors_per_block()
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        check_request(self, sector, buffer.len())?;
+        self.read_bytes(sector * SECTOR_SIZE as u64, buffer)
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        if self.read_only() {
+            return Err(Errno::EROFS);
+        }
+        check_request(self, sector, data.len())?;
+        self.write_bytes(sector * SECTOR_SIZE as u64, data)
+    }
+
+    fn flush(&self) -> BlockResult<()> {
+        self.sync()
+    }
+
+    fn read_only(&self) -> bool {
+        self.device.read_only()
+    }
+}
+
+// Anything still dirty is written back on a best effort basis, since there's nobody to tell.
+impl Drop for BufferCache {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
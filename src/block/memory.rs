@@ -0,0 +1,83 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use super::{check_request, BlockDevice, BlockResult, SECTOR_SIZE};
+
+// A disk that only exists in memory, for trying out filesystems and partition tables without a
+// real one. Only sectors that have been written take up any memory, the rest read as zeroes, so
+// even a large volume fits on the heap. It counts how often it's read and written, to tell what
+// made it to the disk and what was cached.
+pub struct MemoryDisk {
+    sectors: u64,
+    data: Mutex<BTreeMap<u64, [u8; SECTOR_SIZE]>>,
+    reads: AtomicUsize,
+    writes: AtomicUsize
+}
+
+impl MemoryDisk {
+    pub fn new(sectors: u64) -> Arc<Self> {
+        Arc::new(Self { sectors, data: Mutex::new(BTreeMap::new()), reads: AtomicUsize::new(0), writes: AtomicUsize::new(0) })
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    // Byte addressed access from outside, which doesn't count as reading or writing the disk.
+    pub fn patch(&self, offset: u64, bytes: &[u8]) {
+        let mut data = self.data.lock();
+        for (index, &byte) in bytes.iter().enumerate() {
+            let position = offset + index as u64;
+            let sector = data.entry(position / SECTOR_SIZE as u64).or_insert([0; SECTOR_SIZE]);
+            sector[(position % SECTOR_SIZE as u64) as usize] = byte;
+        }
+    }
+
+    pub fn bytes(&self, offset: u64, length: usize) -> Vec<u8> {
+        let mut buffer = vec![0; length];
+        let data = self.data.lock();
+        for (index, byte) in buffer.iter_mut().enumerate() {
+            let position = offset + index as u64;
+            if let Some(sector) = data.get(&(position / SECTOR_SIZE as u64)) {
+                *byte = sector[(position % SECTOR_SIZE as u64) as usize];
+            }
+        }
+        buffer
+    }
+}
+
+impl BlockDevice for MemoryDisk {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        check_request(self, sector, buffer.len())?;
+        let data = self.data.lock();
+        for (index, chunk) in buffer.chunks_mut(SECTOR_SIZE).enumerate() {
+            match data.get(&(sector + index as u64)) {
+                Some(stored) => chunk.copy_from_slice(stored),
+                None => chunk.fill(0)
+            }
+        }
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        check_request(self, sector, data.len())?;
+        let mut stored = self.data.lock();
+        for (index, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+            stored.insert(sector + index as u64, chunk.try_into().unwrap());
+        }
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+// Block devices, which are read and written in whole sectors rather than bytes. Drivers register
+// their disks here under a name, and filesystems find them by it.
+
+mod cache;
+mod memory;
+mod partition;
+
+pub use cache::{BufferCache, CacheStats};
+pub use memory::MemoryDisk;
+pub use partition::{crc32, read_partitions, Guid, Partition, PartitionInfo, PartitionKind};
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use log::{info, warn};
+use spin::RwLock;
+use crate::syscall::Errno;
+
+pub const SECTOR_SIZE: usize = 512;
+
+pub type BlockResult<T> = Result<T, Errno>;
+
+pub trait BlockDevice: Send + Sync {
+    fn sector_count(&self) -> u64;
+
+    // Buffers have to be a whole number of sectors long, and stay within the device.
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> BlockResult<()>;
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> BlockResult<()>;
+
+    // Returns once everything written so far has made it to the disk itself.
+    fn flush(&self) -> BlockResult<()> {
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+// Checks that a request lines up with sectors and fits on the device, and returns how many
+// sectors it covers.
+pub fn check_request(device: &dyn BlockDevice, sector: u64, length: usize) -> BlockResult<u64> {
+    if !length.is_multiple_of(SECTOR_SIZE) {
+        return Err(Errno::EINVAL);
+    }
+    let count = (length / SECTOR_SIZE) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(Errno::EINVAL)
+    }
+}
+
+static DEVICES: RwLock<BTreeMap<String, Arc<dyn BlockDevice>>> = RwLock::new(BTreeMap::new());
+
+// Names disks the way Linux does, with the driver's prefix followed by a letter: vda, vdb and so on.
+pub fn register(prefix: &str, device: Arc<dyn BlockDevice>) -> String {
+    let mut devices = DEVICES.write();
+    let name = (0..)
+        .map(|index| format!("{}{}", prefix, disk_letters(index)))
+        .find(|name| !devices.contains_key(name))
+        .unwrap();
+    info!("Registered block device {} with {} sectors.", name, device.sector_count());
+    devices.insert(name.clone(), device);
+    name
+}
+
+// Registers a disk along with each of its partitions, which are named after it: vda1, vda2 and
+// so on. A partition table that can't be read leaves the disk without partitions, but still
+// usable as a whole.
+pub fn add_disk(prefix: &str, device: Arc<dyn BlockDevice>) -> String {
+    let name = register(prefix, device.clone());
+    let partitions = match read_partitions(device.as_ref()) {
+        Ok(partitions) => partitions,
+        Err(error) => {
+            warn!("Failed to read the partition table of {}: {:?}", name, error);
+            return name;
+        }
+    };
+    for info in partitions {
+        let partition_name = format!("{}{}", name, info.number);
+        register_named(&partition_name, Arc::new(Partition::new(device.clone(), info)));
+    }
+    name
+}
+
+// Registers a device under exactly the given name, unless it's already taken.
+pub fn register_named(name: &str, device: Arc<dyn BlockDevice>) -> bool {
+    let mut devices = DEVICES.write();
+    if devices.contains_key(name) {
+        return false;
+    }
+    info!("Registered block device {} with {} sectors.", name, device.sector_count());
+    devices.insert(String::from(name), device);
+    true
+}
+
+pub fn unregister(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.write().remove(name)
+}
+
+pub fn device(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.read().get(name).cloned()
+}
+
+pub fn devices() -> Vec<String> {
+    DEVICES.read().keys().cloned().collect()
+}
+
+// a to z, then aa, ab and so on.
+fn disk_letters(mut index: usize) -> String {
+    let mut letters = Vec::new();
+    loop {
+        letters.push(b'a' + (index % 26) as u8);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    letters.reverse();
+    String::from_utf8(letters).unwrap()
+}
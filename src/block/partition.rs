@@ -0,0 +1,284 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::syscall::Errno;
+use super::{check_request, BlockDevice, BlockResult, SECTOR_SIZE};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_EMPTY: u8 = 0x00;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+const MBR_PROTECTIVE: u8 = 0xEE;
+// Logical partitions are numbered after the four primary ones, however many of those are used.
+const FIRST_LOGICAL: usize = 5;
+// Extended boot records form a linked list, which is cut short if it's suspiciously long.
+const MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_SECTOR: u64 = 1;
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+const GPT_MAX_ENTRIES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&byte| byte == 0)
+    }
+}
+
+// The first three groups are stored little endian, the rest as they're written.
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+            u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
+            bytes[8], bytes[9], bytes[10], bytes[11], bytes[12], bytes[13], bytes[14], bytes[15]
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    Mbr { partition_type: u8, bootable: bool },
+    Gpt { type_guid: Guid, unique_guid: Guid, name: String }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    // Numbered the way Linux numbers them, starting from 1.
+    pub number: usize,
+    pub start: u64,
+    pub sectors: u64,
+    pub kind: PartitionKind
+}
+
+// The IEEE CRC-32 that GPT headers and entry arrays are checked with.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn read_sector(device: &dyn BlockDevice, sector: u64) -> BlockResult<Vec<u8>> {
+    let mut buffer = vec![0; SECTOR_SIZE];
+    device.read_sectors(sector, &mut buffer)?;
+    Ok(buffer)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+struct MbrEntry {
+    bootable: bool,
+    partition_type: u8,
+    start: u64,
+    sectors: u64
+}
+
+fn mbr_entries(sector: &[u8]) -> impl Iterator<Item = MbrEntry> + '_ {
+    (0..4).map(move |index| {
+        let entry = &sector[MBR_ENTRIES + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        MbrEntry {
+            bootable: entry[0] & 0x80 != 0,
+            partition_type: entry[4],
+            start: u32_at(entry, 8) as u64,
+            sectors: u32_at(entry, 12) as u64
+        }
+    })
+}
+
+// Reads whatever partition table the device has. Devices without one have no partitions, which
+// isn't an error, but a table that's there and broken is.
+pub fn read_partitions(device: &dyn BlockDevice) -> BlockResult<Vec<PartitionInfo>> {
+    if device.sector_count() == 0 {
+        return Ok(Vec::new());
+    }
+    let mbr = read_sector(device, 0)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    if mbr_entries(&mbr).any(|entry| entry.partition_type == MBR_PROTECTIVE) {
+        return read_gpt(device);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in mbr_entries(&mbr).enumerate() {
+        if entry.partition_type == MBR_EMPTY || entry.sectors == 0 {
+            continue;
+        }
+        // The extended partition only holds the logical ones, and isn't worth exposing itself.
+        if MBR_EXTENDED.contains(&entry.partition_type) {
+            read_logical(device, entry.start, &mut partitions)?;
+            continue;
+        }
+        partitions.push(PartitionInfo {
+            number: index + 1,
+            start: entry.start,
+            sectors: entry.sectors,
+            kind: PartitionKind::Mbr { partition_type: entry.partition_type, bootable: entry.bootable }
+        });
+    }
+    partitions.sort_by_key(|partition| partition.number);
+    check_bounds(device, &partitions)?;
+    Ok(partitions)
+}
+
+// Each extended boot record describes one logical partition, relative to itself, and where the
+// next record is, relative to the start of the extended partition.
+fn read_logical(device: &dyn BlockDevice, extended_start: u64, partitions: &mut Vec<PartitionInfo>) -> BlockResult<()> {
+    let mut record = extended_start;
+    for number in FIRST_LOGICAL..FIRST_LOGICAL + MAX_LOGICAL {
+        let sector = read_sector(device, record)?;
+        if sector[510..512] != MBR_SIGNATURE {
+            return Err(Errno::EINVAL);
+        }
+        let mut entries = mbr_entries(&sector);
+        let (logical, next) = (entries.next().unwrap(), entries.next().unwrap());
+        if logical.partition_type != MBR_EMPTY && logical.sectors != 0 {
+            partitions.push(PartitionInfo {
+                number,
+                start: record + logical.start,
+                sectors: logical.sectors,
+                kind: PartitionKind::Mbr { partition_type: logical.partition_type, bootable: logical.bootable }
+            });
+        }
+        if next.partition_type == MBR_EMPTY || next.start == 0 {
+            return Ok(());
+        }
+        record = extended_start + next.start;
+    }
+    Ok(())
+}
+
+// Uses the primary header, or the backup at the end of the disk if the primary is damaged.
+fn read_gpt(device: &dyn BlockDevice) -> BlockResult<Vec<PartitionInfo>> {
+    let last_sector = device.sector_count() - 1;
+    let header = match gpt_header(device, GPT_HEADER_SECTOR)? {
+        Some(header) => header,
+        None => gpt_header(device, last_sector)?.ok_or(Errno::EINVAL)?
+    };
+    let entries_start = u64_at(&header, 72);
+    let entry_count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    if entry_count > GPT_MAX_ENTRIES || entry_size < GPT_MIN_ENTRY_SIZE || !entry_size.is_multiple_of(8) {
+        return Err(Errno::EINVAL);
+    }
+    let length = entry_count * entry_size;
+    let mut entries = vec![0; length.next_multiple_of(SECTOR_SIZE)];
+    check_request(device, entries_start, entries.len())?;
+    device.read_sectors(entries_start, &mut entries)?;
+    if crc32(&entries[..length]) != u32_at(&header, 88) {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries[..length].chunks(entry_size).enumerate() {
+        let type_guid = Guid(entry[0..16].try_into().unwrap());
+        if type_guid.is_zero() {
+            continue;
+        }
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        if last < first {
+            return Err(Errno::EINVAL);
+        }
+        let name: Vec<u16> = entry[56..128].chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        partitions.push(PartitionInfo {
+            number: index + 1,
+            start: first,
+            sectors: last - first + 1,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: Guid(entry[16..32].try_into().unwrap()),
+                name: String::from_utf16_lossy(&name)
+            }
+        });
+    }
+    check_bounds(device, &partitions)?;
+    Ok(partitions)
+}
+
+// Returns the header if it's there and its checksum is right.
+fn gpt_header(device: &dyn BlockDevice, sector: u64) -> BlockResult<Option<Vec<u8>>> {
+    let mut header = read_sector(device, sector)?;
+    let size = u32_at(&header, 12) as usize;
+    if &header[0..8] != GPT_SIGNATURE || !(GPT_MIN_HEADER_SIZE..=SECTOR_SIZE).contains(&size) {
+        return Ok(None);
+    }
+    // The checksum covers the header with the checksum itself zeroed.
+    let expected = u32_at(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..size]) != expected {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+fn check_bounds(device: &dyn BlockDevice, partitions: &[PartitionInfo]) -> BlockResult<()> {
+    let fits = |partition: &PartitionInfo| {
+        partition.start.checked_add(partition.sectors).is_some_and(|end| end <= device.sector_count())
+    };
+    if partitions.iter().all(fits) { Ok(()) } else { Err(Errno::EINVAL) }
+}
+
+// One partition of a device, as a device of its own.
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    info: PartitionInfo
+}
+
+impl Partition {
+    pub fn new(device: Arc<dyn BlockDevice>, info: PartitionInfo) -> Self {
+        Self { device, info }
+    }
+
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+}
+
+impl BlockDevice for Partition {
+    fn sector_count(&self) -> u64 {
+        self.info.sectors
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        check_request(self, sector, buffer.len())?;
+        self.device.read_sectors(self.info.start + sector, buffer)
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        check_request(self, sector, data.len())?;
+        self.device.write_sectors(self.info.start + sector, data)
+    }
+
+    fn flush(&self) -> BlockResult<()> {
+        self.device.flush()
+    }
+
+    fn read_only(&self) -> bool {
+        self.device.read_only()
+    }
+}
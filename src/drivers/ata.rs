@@ -0,0 +1,475 @@
+// ATA disks on IDE controllers, read and written a word at a time through programmed I/O. It's
+// slow, but it's there on almost every PC and emulator, which makes it the fallback for when
+// there's no virtio. Channels in compatibility mode sit at the ports and interrupt lines the
+// PC/AT gave them, and ones switched to native mode get their ports from BARs and are polled.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use log::info;
+use x86_64::instructions::port::Port;
+use crate::{block, interrupt, thread, time};
+use crate::block::{BlockDevice, BlockResult, SECTOR_SIZE};
+use crate::interrupt::InterruptIndex;
+use crate::pci::{DeviceMatch, Driver, PciDevice, COMMAND_IO};
+use crate::sync::{IrqMutex, SleepMutex};
+use crate::syscall::Errno;
+use crate::thread::ThreadId;
+
+const MASS_STORAGE_CLASS: u8 = 0x01;
+const IDE_SUBCLASS: u8 = 0x01;
+// Set in the programming interface when a channel has been switched over to native mode.
+const PRIMARY_NATIVE: u8 = 1 << 0;
+const SECONDARY_NATIVE: u8 = 1 << 2;
+
+// Registers in the command block.
+const DATA: u16 = 0;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+// The control block has the alternate status, which can be read without acknowledging an
+// interrupt, in the same place as the device control register.
+const CONTROL_NO_INTERRUPTS: u8 = 1 << 1;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+// Picks the drive by LBA rather than by cylinder, head and sector. The top bits are obsolete
+// but still expected to be set.
+const DRIVE_LBA: u8 = 0xE0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const COMMAND_READ: u8 = 0x20;
+const COMMAND_READ_EXT: u8 = 0x24;
+const COMMAND_WRITE: u8 = 0x30;
+const COMMAND_WRITE_EXT: u8 = 0x34;
+const COMMAND_FLUSH: u8 = 0xE7;
+const COMMAND_FLUSH_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+// Words of the IDENTIFY data.
+const IDENTIFY_CONFIG: usize = 0;
+const IDENTIFY_MODEL: usize = 27;
+const IDENTIFY_MODEL_WORDS: usize = 20;
+const IDENTIFY_CAPABILITIES: usize = 49;
+const IDENTIFY_SECTORS: usize = 60;
+const IDENTIFY_COMMANDS: usize = 83;
+const IDENTIFY_SECTORS_EXT: usize = 100;
+const CONFIG_NOT_ATA: u16 = 1 << 15;
+const CAPABILITY_LBA: u16 = 1 << 9;
+// Word 83 only means anything when its top two bits are 01.
+const COMMANDS_VALID_MASK: u16 = 0xC000;
+const COMMANDS_VALID: u16 = 0x4000;
+const COMMAND_SET_LBA48: u16 = 1 << 10;
+const COMMAND_SET_FLUSH: u16 = 1 << 12;
+const COMMAND_SET_FLUSH_EXT: u16 = 1 << 13;
+
+// Sectors that can be reached with 28 bit addresses, and moved by one command using them.
+const LBA28_LIMIT: u64 = 1 << 28;
+const LBA28_MAX_SECTORS: usize = 256;
+const LBA48_MAX_SECTORS: usize = 65536;
+
+// How long a drive gets to stop being busy before it's given up on.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// What the handler for a channel's interrupt line needs to wake whoever is waiting on it.
+struct LineState {
+    pending: AtomicBool,
+    waiter: IrqMutex<Option<ThreadId>>
+}
+
+struct LegacyChannel {
+    command: u16,
+    control: u16,
+    index: InterruptIndex,
+    line: LineState
+}
+
+static LEGACY_CHANNELS: [LegacyChannel; 2] = [
+    LegacyChannel {
+        command: 0x1F0,
+        control: 0x3F6,
+        index: InterruptIndex::PrimaryAta,
+        line: LineState { pending: AtomicBool::new(false), waiter: IrqMutex::new(None) }
+    },
+    LegacyChannel {
+        command: 0x170,
+        control: 0x376,
+        index: InterruptIndex::SecondaryAta,
+        line: LineState { pending: AtomicBool::new(false), waiter: IrqMutex::new(None) }
+    }
+];
+
+// Called from IRQ 14 and 15, for the primary and secondary channel. Reading the status register
+// is what lets the drive know its interrupt was seen.
+pub fn handle_interrupt(channel: usize) {
+    let legacy = &LEGACY_CHANNELS[channel];
+    unsafe { Port::<u8>::new(legacy.command + STATUS).read() };
+    legacy.line.pending.store(true, Ordering::SeqCst);
+    if let Some(thread) = *legacy.line.waiter.lock() {
+        thread::unpark(thread);
+    }
+}
+
+// The master and slave on a channel share its registers, so only one command can be going on
+// it at once.
+struct Channel {
+    command: u16,
+    control: u16,
+    // Only channels in compatibility mode have a line of their own, the rest are polled.
+    line: Option<&'static LineState>,
+    lock: SleepMutex<()>
+}
+
+impl Channel {
+    fn new(command: u16, control: u16, line: Option<&'static LineState>) -> Arc<Self> {
+        let channel = Self { command, control, line, lock: SleepMutex::new(()) };
+        channel.write_control(if line.is_some() { 0 } else { CONTROL_NO_INTERRUPTS });
+        Arc::new(channel)
+    }
+
+    fn compatibility(index: usize) -> Arc<Self> {
+        let legacy = &LEGACY_CHANNELS[index];
+        interrupt::enable_legacy_irq(legacy.index);
+        Self::new(legacy.command, legacy.control, Some(&legacy.line))
+    }
+
+    // Native channels have their command block in one BAR and their control block two bytes
+    // into the next.
+    fn native(device: &PciDevice, first_bar: usize) -> Option<Arc<Self>> {
+        let command = device.bars[first_bar]?.io_port()?;
+        let control = device.bars[first_bar + 1]?.io_port()? + 2;
+        Some(Self::new(command, control, None))
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.command + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.command + register).write(value) };
+    }
+
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control).read() }
+    }
+
+    fn write_control(&self, value: u8) {
+        unsafe { Port::<u8>::new(self.control).write(value) };
+    }
+
+    fn read_data(&self, buffer: &mut [u8]) {
+        let mut port = Port::<u16>::new(self.command + DATA);
+        for word in buffer.chunks_mut(2) {
+            word.copy_from_slice(&unsafe { port.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_data(&self, data: &[u8]) {
+        let mut port = Port::<u16>::new(self.command + DATA);
+        for word in data.chunks(2) {
+            unsafe { port.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    // Runs commands on one of the drives, with the channel to itself.
+    fn exclusive<T>(&self, slave: bool, commands: impl FnOnce() -> BlockResult<T>) -> BlockResult<T> {
+        let _guard = self.lock.lock();
+        self.write(DRIVE, DRIVE_LBA | if slave { DRIVE_SLAVE } else { 0 });
+        // The drive takes 400ns to put its status up after being selected, which is about four
+        // reads of the status register.
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+        self.poll()?;
+        let result = commands();
+        if let Some(line) = self.line {
+            *line.waiter.lock() = None;
+        }
+        result
+    }
+
+    // Has the interrupt the command raises wake the current thread.
+    fn send(&self, command: u8) {
+        if let Some(line) = self.line {
+            line.pending.store(false, Ordering::SeqCst);
+            *line.waiter.lock() = Some(thread::current());
+        }
+        self.write(COMMAND, command);
+    }
+
+    // Waits for the drive to stop being busy, and returns what it left in the status register.
+    fn poll(&self) -> BlockResult<u8> {
+        let deadline = time::ticks() + time::duration_to_ticks(TIMEOUT);
+        while self.alternate_status() & STATUS_BUSY != 0 {
+            if time::ticks() >= deadline {
+                return Err(Errno::EIO);
+            }
+            core::hint::spin_loop();
+        }
+        Ok(self.read(STATUS))
+    }
+
+    // Waits for the drive to raise its interrupt, on channels that have one, and then to be done.
+    // Drives don't interrupt before taking the first sector of a write, so that gets polled for.
+    // A drive that never interrupts gets as long as one that never stops being busy.
+    fn wait(&self, interrupt: bool) -> BlockResult<u8> {
+        if let (true, Some(line)) = (interrupt, self.line) {
+            let deadline = time::ticks() + time::duration_to_ticks(TIMEOUT);
+            while !line.pending.swap(false, Ordering::SeqCst) {
+                if time::ticks() >= deadline {
+                    return Err(Errno::EIO);
+                }
+                thread::park_until(deadline);
+            }
+        }
+        let status = self.poll()?;
+        if status & (STATUS_ERROR | STATUS_FAULT) != 0 {
+            return Err(Errno::EIO);
+        }
+        Ok(status)
+    }
+
+    // Waits for the drive to be ready to move the next sector.
+    fn wait_for_data(&self, interrupt: bool) -> BlockResult<()> {
+        if self.wait(interrupt)? & STATUS_DATA_REQUEST == 0 {
+            return Err(Errno::EIO);
+        }
+        Ok(())
+    }
+}
+
+// What a drive says about itself in answer to IDENTIFY.
+struct Identity {
+    sectors: u64,
+    lba48: bool,
+    flush: Option<u8>,
+    model: String
+}
+
+impl Identity {
+    // Drives that can only be addressed by cylinder, head and sector are older than anything
+    // worth supporting.
+    fn parse(data: &[u8]) -> Option<Self> {
+        let word = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+        if word(IDENTIFY_CONFIG) & CONFIG_NOT_ATA != 0 || word(IDENTIFY_CAPABILITIES) & CAPABILITY_LBA == 0 {
+            return None;
+        }
+        let commands = match word(IDENTIFY_COMMANDS) {
+            commands if commands & COMMANDS_VALID_MASK == COMMANDS_VALID => commands,
+            _ => 0
+        };
+        let lba48 = commands & COMMAND_SET_LBA48 != 0;
+        let sectors = if lba48 {
+            (0..4).fold(0, |sectors, index| sectors | ((word(IDENTIFY_SECTORS_EXT + index) as u64) << (index * 16)))
+        } else {
+            word(IDENTIFY_SECTORS) as u64 | ((word(IDENTIFY_SECTORS + 1) as u64) << 16)
+        };
+        let flush = if lba48 && commands & COMMAND_SET_FLUSH_EXT != 0 {
+            Some(COMMAND_FLUSH_EXT)
+        } else if commands & COMMAND_SET_FLUSH != 0 {
+            Some(COMMAND_FLUSH)
+        } else {
+            None
+        };
+        // The model is padded with spaces, and each word has its two characters swapped.
+        let model: Vec<u8> = (IDENTIFY_MODEL..IDENTIFY_MODEL + IDENTIFY_MODEL_WORDS)
+            .flat_map(|index| word(index).to_be_bytes())
+            .collect();
+        let model = String::from_utf8_lossy(&model).trim().into();
+        Some(Self { sectors, lba48, flush, model })
+    }
+}
+
+pub struct AtaDisk {
+    channel: Arc<Channel>,
+    slave: bool,
+    identity: Identity
+}
+
+impl AtaDisk {
+    // Asks a drive what it is. Nothing answering, or a drive that isn't a plain ATA disk, such
+    // as a CD drive, leaves nothing to use.
+    fn identify(channel: &Arc<Channel>, slave: bool) -> Option<Self> {
+        // A channel with nothing on it floats, and reads as all ones.
+        if channel.alternate_status() == 0xFF {
+            return None;
+        }
+        let identity = channel.exclusive(slave, || {
+            channel.write(SECTOR_COUNT, 0);
+            channel.write(LBA_LOW, 0);
+            channel.write(LBA_MID, 0);
+            channel.write(LBA_HIGH, 0);
+            channel.send(COMMAND_IDENTIFY);
+            if channel.alternate_status() == 0 {
+                return Err(Errno::ENODEV);
+            }
+            channel.poll()?;
+            // ATAPI and SATA devices abort IDENTIFY, and leave their signature behind.
+            if channel.read(LBA_MID) != 0 || channel.read(LBA_HIGH) != 0 {
+                return Err(Errno::ENODEV);
+            }
+            channel.wait_for_data(false)?;
+            let mut data = vec![0; SECTOR_SIZE];
+            channel.read_data(&mut data);
+            Identity::parse(&data).ok_or(Errno::ENODEV)
+        });
+        Some(Self { channel: channel.clone(), slave, identity: identity.ok()? })
+    }
+
+    pub fn model(&self) -> &str {
+        &self.identity.model
+    }
+
+    pub fn supports_lba48(&self) -> bool {
+        self.identity.lba48
+    }
+
+    pub fn uses_interrupts(&self) -> bool {
+        self.channel.line.is_some()
+    }
+
+    fn max_sectors(&self) -> usize {
+        if self.identity.lba48 { LBA48_MAX_SECTORS } else { LBA28_MAX_SECTORS }
+    }
+
+    // Starts a transfer, using 48 bit addressing only when it's needed, the way Linux does.
+    fn start(&self, sector: u64, count: usize, write: bool) {
+        let channel = &self.channel;
+        let drive = DRIVE_LBA | if self.slave { DRIVE_SLAVE } else { 0 };
+        let extended = sector + count as u64 > LBA28_LIMIT || count > LBA28_MAX_SECTORS;
+        if extended {
+            // The high bytes go first, and get pushed back by the low ones.
+            channel.write(DRIVE, drive);
+            channel.write(SECTOR_COUNT, (count >> 8) as u8);
+            channel.write(LBA_LOW, (sector >> 24) as u8);
+            channel.write(LBA_MID, (sector >> 32) as u8);
+            channel.write(LBA_HIGH, (sector >> 40) as u8);
+        } else {
+            channel.write(DRIVE, drive | ((sector >> 24) as u8 & 0x0F));
+        }
+        // A count of zero means as many as the command can take.
+        channel.write(SECTOR_COUNT, count as u8);
+        channel.write(LBA_LOW, sector as u8);
+        channel.write(LBA_MID, (sector >> 8) as u8);
+        channel.write(LBA_HIGH, (sector >> 16) as u8);
+        channel.send(match (extended, write) {
+            (false, false) => COMMAND_READ,
+            (false, true) => COMMAND_WRITE,
+            (true, false) => COMMAND_READ_EXT,
+            (true, true) => COMMAND_WRITE_EXT
+        });
+    }
+
+    // Drives interrupt once each sector of a read is ready.
+    fn read_command(&self, sector: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        self.start(sector, buffer.len() / SECTOR_SIZE, false);
+        for chunk in buffer.chunks_mut(SECTOR_SIZE) {
+            self.channel.wait_for_data(true)?;
+            self.channel.read_data(chunk);
+        }
+        Ok(())
+    }
+
+    // Drives interrupt once they've taken each sector of a write, including the last.
+    fn write_command(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        self.start(sector, data.len() / SECTOR_SIZE, true);
+        for (index, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+            self.channel.wait_for_data(index > 0)?;
+            self.channel.write_data(chunk);
+        }
+        self.channel.wait(true).map(|_| ())
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn sector_count(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        block::check_request(self, sector, buffer.len())?;
+        self.channel.exclusive(self.slave, || {
+            for (index, chunk) in buffer.chunks_mut(self.max_sectors() * SECTOR_SIZE).enumerate() {
+                self.read_command(sector + (index * self.max_sectors()) as u64, chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        block::check_request(self, sector, data.len())?;
+        self.channel.exclusive(self.slave, || {
+            for (index, chunk) in data.chunks(self.max_sectors() * SECTOR_SIZE).enumerate() {
+                self.write_command(sector + (index * self.max_sectors()) as u64, chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    // Drives too old to know about flushing write everything through anyway.
+    fn flush(&self) -> BlockResult<()> {
+        let command = match self.identity.flush {
+            Some(command) => command,
+            None => return Ok(())
+        };
+        self.channel.exclusive(self.slave, || {
+            self.channel.send(command);
+            self.channel.wait(true).map(|_| ())
+        })
+    }
+}
+
+pub struct AtaDriver;
+
+impl Driver for AtaDriver {
+    fn name(&self) -> &'static str {
+        "ata"
+    }
+
+    fn matches(&self) -> &[DeviceMatch] {
+        const MATCHES: &[DeviceMatch] = &[DeviceMatch::class(MASS_STORAGE_CLASS, IDE_SUBCLASS)];
+        MATCHES
+    }
+
+    // Disks are named the way Linux names them, hda, hdb and so on, in the order they're found.
+    fn probe(&self, device: &PciDevice) -> Result<(), Errno> {
+        device.enable(COMMAND_IO);
+        let channels = [(0, PRIMARY_NATIVE), (1, SECONDARY_NATIVE)].into_iter().filter_map(|(index, native)| {
+            if device.prog_if & native != 0 {
+                Channel::native(device, index * 2)
+            } else {
+                Some(Channel::compatibility(index))
+            }
+        });
+        let mut found = 0;
+        for channel in channels {
+            for slave in [false, true] {
+                let disk = match AtaDisk::identify(&channel, slave) {
+                    Some(disk) => Arc::new(disk),
+                    None => continue
+                };
+                info!(
+                    "ATA disk {} on {} has {} sectors, {}.",
+                    disk.model(),
+                    device.address,
+                    disk.sector_count(),
+                    if disk.uses_interrupts() { "with interrupts" } else { "polled" }
+                );
+                block::add_disk("hd", disk);
+                found += 1;
+            }
+        }
+        if found == 0 { Err(Errno::ENODEV) } else { Ok(()) }
+    }
+}
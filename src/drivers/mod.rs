@@ -0,0 +1,13 @@
+// Drivers for devices found on the PCI bus, which get offered every device they match once
+// they're registered.
+
+pub mod ata;
+pub mod virtio;
+
+use alloc::sync::Arc;
+use crate::pci;
+
+pub fn init() {
+    pci::register_driver(Arc::new(virtio::VirtioBlockDriver));
+    pci::register_driver(Arc::new(ata::AtaDriver));
+}
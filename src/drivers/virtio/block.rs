@@ -0,0 +1,292 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::ptr;
+use log::{info, warn};
+use crate::{block, interrupt, thread};
+use crate::block::{BlockDevice, BlockResult, SECTOR_SIZE};
+use crate::memory::DmaBuffer;
+use crate::pci::{DeviceMatch, Driver, MsiX, PciDevice};
+use crate::sync::{IrqMutex, SleepMutex};
+use crate::syscall::Errno;
+use crate::thread::ThreadId;
+use super::{
+    Buffer, Transport, Virtqueue, FEATURE_VERSION_1, NO_VECTOR, STATUS_ACKNOWLEDGE, STATUS_DRIVER,
+    STATUS_DRIVER_OK, STATUS_FAILED, STATUS_FEATURES_OK, VENDOR_ID
+};
+
+const LEGACY_DEVICE_ID: u16 = 0x1001;
+const MODERN_DEVICE_ID: u16 = 0x1042;
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+// The configuration starts with the capacity, always counted in 512 byte sectors.
+const CONFIG_CAPACITY: usize = 0;
+
+const REQUEST_QUEUE: u16 = 0;
+const HEADER_SIZE: usize = 16;
+// A request takes a descriptor for the header, one for the data and one for the status.
+const MIN_QUEUE_SIZE: u16 = 3;
+// Requests go through a bounce buffer, since the caller's buffer isn't physically contiguous.
+// Bigger requests are split up.
+const BOUNCE_SECTORS: usize = 128;
+
+// Requests are made one at a time, so all of this belongs to whichever is in progress.
+struct Requests {
+    queue: Virtqueue,
+    // The request header, then the status byte the device writes back.
+    header: DmaBuffer,
+    bounce: DmaBuffer
+}
+
+pub struct VirtioBlock {
+    transport: Box<dyn Transport>,
+    device: PciDevice,
+    capacity: u64,
+    features: u64,
+    requests: SleepMutex<Requests>,
+    // Whoever is waiting for the device to finish a request, for the interrupt handler to wake.
+    waiter: Arc<IrqMutex<Option<ThreadId>>>,
+    // Without an interrupt, requests are polled for instead.
+    interrupt: Option<(u8, MsiX)>
+}
+
+impl VirtioBlock {
+    pub fn new(device: &PciDevice, transport: Box<dyn Transport>) -> Result<Arc<Self>, Errno> {
+        transport.reset();
+        transport.set_status(STATUS_ACKNOWLEDGE);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let result = Self::initialize(device, transport);
+        if let Err(error) = &result {
+            warn!("Failed to set up virtio block device {}: {:?}", device.address, error);
+        }
+        result.map(Arc::new)
+    }
+
+    // Follows the initialization sequence from the specification, once the device knows there's
+    // a driver for it.
+    fn initialize(device: &PciDevice, transport: Box<dyn Transport>) -> Result<Self, Errno> {
+        let offered = transport.device_features();
+        let mut features = offered & (FEATURE_READ_ONLY | FEATURE_FLUSH);
+        if transport.is_modern() {
+            if offered & FEATURE_VERSION_1 == 0 {
+                transport.set_status(STATUS_FAILED);
+                return Err(Errno::ENODEV);
+            }
+            features |= FEATURE_VERSION_1;
+        }
+        transport.set_driver_features(features);
+        let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        if transport.is_modern() {
+            status |= STATUS_FEATURES_OK;
+            transport.set_status(status);
+            if transport.status() & STATUS_FEATURES_OK == 0 {
+                transport.set_status(STATUS_FAILED);
+                return Err(Errno::ENODEV);
+            }
+        }
+
+        let queue_size = transport.max_queue_size(REQUEST_QUEUE);
+        if queue_size < MIN_QUEUE_SIZE {
+            warn!("Virtio block device {} only has room for {} descriptor(s).", device.address, queue_size);
+            transport.set_status(STATUS_FAILED);
+            return Err(Errno::ENODEV);
+        }
+        let queue = Virtqueue::new(queue_size).ok_or(Errno::ENOMEM)?;
+        let header = DmaBuffer::new(HEADER_SIZE + 1).ok_or(Errno::ENOMEM)?;
+        let bounce = DmaBuffer::new(BOUNCE_SECTORS * SECTOR_SIZE).ok_or(Errno::ENOMEM)?;
+
+        let waiter = Arc::new(IrqMutex::new(None));
+        let interrupt = Self::enable_interrupt(device, transport.as_ref(), waiter.clone());
+        if let Err(error) = transport.enable_queue(REQUEST_QUEUE, &queue) {
+            Self::disable_interrupt(device, interrupt);
+            transport.set_status(STATUS_FAILED);
+            return Err(error);
+        }
+        let capacity = transport.read_config_u64(CONFIG_CAPACITY);
+        transport.set_status(status | STATUS_DRIVER_OK);
+
+        info!(
+            "Virtio block device {} has {} sectors through the {} interface, {}.",
+            device.address,
+            capacity,
+            if transport.is_modern() { "modern" } else { "legacy" },
+            if interrupt.is_some() { "with MSI-X" } else { "polled" }
+        );
+        Ok(Self {
+            transport,
+            device: device.clone(),
+            capacity,
+            features,
+            requests: SleepMutex::new(Requests { queue, header, bounce }),
+            waiter,
+            interrupt
+        })
+    }
+
+    // Has the device signal finished requests through MSI-X, which needs the APIC. Failing that,
+    // requests get polled for.
+    fn enable_interrupt(
+        device: &PciDevice,
+        transport: &dyn Transport,
+        waiter: Arc<IrqMutex<Option<ThreadId>>>
+    ) -> Option<(u8, MsiX)> {
+        let msix = device.msix()?;
+        let vector = interrupt::allocate_vector(Arc::new(move || {
+            if let Some(thread) = *waiter.lock() {
+                thread::unpark(thread);
+            }
+        }))?;
+        let interrupt = Some((vector, msix));
+        if msix.enable(device, &[vector]).is_err() {
+            Self::disable_interrupt(device, interrupt);
+            return None;
+        }
+        // Configuration changes aren't interesting, only finished requests are.
+        if !transport.set_config_vector(NO_VECTOR) || !transport.set_queue_vector(REQUEST_QUEUE, 0) {
+            Self::disable_interrupt(device, interrupt);
+            return None;
+        }
+        interrupt
+    }
+
+    fn disable_interrupt(device: &PciDevice, interrupt: Option<(u8, MsiX)>) {
+        if let Some((vector, msix)) = interrupt {
+            msix.disable(device.address);
+            interrupt::free_vector(vector);
+        }
+    }
+
+    pub fn pci_device(&self) -> &PciDevice {
+        &self.device
+    }
+
+    pub fn uses_interrupts(&self) -> bool {
+        self.interrupt.is_some()
+    }
+
+    // Sends one request and waits for the device to finish it. `length` bytes of the bounce
+    // buffer are sent along with it, to be read or written depending on the kind of request.
+    fn request(&self, requests: &mut Requests, kind: u32, sector: u64, length: usize) -> BlockResult<()> {
+        let header = requests.header.as_mut_slice();
+        header[0..4].copy_from_slice(&kind.to_le_bytes());
+        header[4..8].fill(0);
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+        header[HEADER_SIZE] = 0xFF;
+
+        let header_address = requests.header.physical_address();
+        let mut buffers = [Buffer { address: header_address, length: HEADER_SIZE as u32, writable: false }; 3];
+        let mut count = 1;
+        if length > 0 {
+            buffers[count] = Buffer {
+                address: requests.bounce.physical_address(),
+                length: length as u32,
+                writable: kind == REQUEST_IN
+            };
+            count += 1;
+        }
+        buffers[count] = Buffer { address: header_address + HEADER_SIZE, length: 1, writable: true };
+        count += 1;
+
+        let head = requests.queue.push(&buffers[..count]).ok_or(Errno::EBUSY)?;
+        *self.waiter.lock() = Some(thread::current());
+        self.transport.notify(REQUEST_QUEUE);
+        // Only one request is ever in flight, so whatever comes back is this one.
+        while requests.queue.pop_used().map(|(id, _)| id) != Some(head) {
+            if self.interrupt.is_some() {
+                thread::park();
+            } else {
+                thread::yield_now();
+            }
+        }
+        *self.waiter.lock() = None;
+
+        match unsafe { ptr::read_volatile(requests.header.as_ptr().add(HEADER_SIZE)) } {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(Errno::EOPNOTSUPP),
+            _ => Err(Errno::EIO)
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        block::check_request(self, sector, buffer.len())?;
+        let mut requests = self.requests.lock();
+        for (index, chunk) in buffer.chunks_mut(BOUNCE_SECTORS * SECTOR_SIZE).enumerate() {
+            let start = sector + (index * BOUNCE_SECTORS) as u64;
+            self.request(&mut requests, REQUEST_IN, start, chunk.len())?;
+            chunk.copy_from_slice(&requests.bounce.as_slice()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        if self.read_only() {
+            return Err(Errno::EROFS);
+        }
+        block::check_request(self, sector, data.len())?;
+        let mut requests = self.requests.lock();
+        for (index, chunk) in data.chunks(BOUNCE_SECTORS * SECTOR_SIZE).enumerate() {
+            let start = sector + (index * BOUNCE_SECTORS) as u64;
+            requests.bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.request(&mut requests, REQUEST_OUT, start, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    // Devices without a write cache don't offer flushing, and have nothing to flush.
+    fn flush(&self) -> BlockResult<()> {
+        if self.features & FEATURE_FLUSH == 0 {
+            return Ok(());
+        }
+        let mut requests = self.requests.lock();
+        self.request(&mut requests, REQUEST_FLUSH, 0, 0)
+    }
+
+    fn read_only(&self) -> bool {
+        self.features & FEATURE_READ_ONLY != 0
+    }
+}
+
+// The device is reset before its queue goes away, so it can't write into freed memory.
+impl Drop for VirtioBlock {
+    fn drop(&mut self) {
+        self.transport.reset();
+        Self::disable_interrupt(&self.device, self.interrupt);
+    }
+}
+
+pub struct VirtioBlockDriver;
+
+impl Driver for VirtioBlockDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn matches(&self) -> &[DeviceMatch] {
+        const MATCHES: &[DeviceMatch] = &[
+            DeviceMatch::device(VENDOR_ID, LEGACY_DEVICE_ID),
+            DeviceMatch::device(VENDOR_ID, MODERN_DEVICE_ID)
+        ];
+        MATCHES
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<(), Errno> {
+        let transport = super::transport(device).ok_or(Errno::ENODEV)?;
+        let disk = VirtioBlock::new(device, transport)?;
+        block::add_disk("vd", disk);
+        Ok(())
+    }
+}
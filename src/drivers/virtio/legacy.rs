@@ -0,0 +1,113 @@
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+use crate::pci::{MsiX, PciAddress, PciDevice, COMMAND_BUS_MASTER, COMMAND_IO};
+use crate::syscall::Errno;
+use super::{Transport, Virtqueue};
+
+const DEVICE_FEATURES: u16 = 0x00;
+const DRIVER_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0C;
+const QUEUE_SELECT: u16 = 0x0E;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const CONFIG_VECTOR: u16 = 0x14;
+const QUEUE_VECTOR: u16 = 0x16;
+// The device's own configuration comes straight after, which is further along with MSI-X on.
+const DEVICE_CONFIG: u16 = 0x14;
+const DEVICE_CONFIG_MSIX: u16 = 0x18;
+
+// Queues are given to the device as a frame number, and always have the size it asks for.
+const QUEUE_ADDRESS_SHIFT: u32 = 12;
+
+pub struct LegacyTransport {
+    address: PciAddress,
+    port: u16,
+    msix: Option<MsiX>
+}
+
+impl LegacyTransport {
+    pub fn new(device: &PciDevice) -> Option<Self> {
+        let port = device.bars[0]?.io_port()?;
+        device.enable(COMMAND_IO | COMMAND_BUS_MASTER);
+        Some(Self { address: device.address, port, msix: device.msix() })
+    }
+
+    fn read<T: PortValue>(&self, register: u16) -> T {
+        unsafe { Port::<T>::new(self.port + register).read() }
+    }
+
+    fn write<T: PortValue>(&self, register: u16, value: T) {
+        unsafe { Port::<T>::new(self.port + register).write(value) };
+    }
+
+    fn device_config(&self) -> u16 {
+        match self.msix {
+            Some(msix) if msix.enabled(self.address) => DEVICE_CONFIG_MSIX,
+            _ => DEVICE_CONFIG
+        }
+    }
+}
+
+trait PortValue: PortRead + PortWrite {}
+
+impl PortValue for u8 {}
+impl PortValue for u16 {}
+impl PortValue for u32 {}
+
+impl Transport for LegacyTransport {
+    fn is_modern(&self) -> bool {
+        false
+    }
+
+    fn device_features(&self) -> u64 {
+        self.read::<u32>(DEVICE_FEATURES) as u64
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.write(DRIVER_FEATURES, features as u32);
+    }
+
+    fn status(&self) -> u8 {
+        self.read(DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        self.write(DEVICE_STATUS, status);
+    }
+
+    fn max_queue_size(&self, queue: u16) -> u16 {
+        self.write(QUEUE_SELECT, queue);
+        self.read(QUEUE_SIZE)
+    }
+
+    fn enable_queue(&self, queue: u16, virtqueue: &Virtqueue) -> Result<(), Errno> {
+        if virtqueue.size() != self.max_queue_size(queue) {
+            return Err(Errno::EINVAL);
+        }
+        let frame = virtqueue.descriptor_address().as_u64() >> QUEUE_ADDRESS_SHIFT;
+        self.write(QUEUE_ADDRESS, frame as u32);
+        Ok(())
+    }
+
+    fn notify(&self, queue: u16) {
+        self.write(QUEUE_NOTIFY, queue);
+    }
+
+    fn set_config_vector(&self, entry: u16) -> bool {
+        self.write(CONFIG_VECTOR, entry);
+        self.read::<u16>(CONFIG_VECTOR) == entry
+    }
+
+    fn set_queue_vector(&self, queue: u16, entry: u16) -> bool {
+        self.write(QUEUE_SELECT, queue);
+        self.write(QUEUE_VECTOR, entry);
+        self.read::<u16>(QUEUE_VECTOR) == entry
+    }
+
+    fn read_config(&self, offset: usize, buffer: &mut [u8]) {
+        let start = self.device_config() + offset as u16;
+        for (index, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.read(start + index as u16);
+        }
+    }
+}
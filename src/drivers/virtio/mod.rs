@@ -0,0 +1,81 @@
+// Virtio devices on PCI. Transitional devices can be driven through either the legacy interface,
+// a block of I/O ports in BAR 0, or the modern one, which is spread over memory BARs described
+// by vendor specific capabilities. Modern is used whenever it's there.
+
+mod block;
+mod legacy;
+mod modern;
+mod queue;
+
+pub use block::{VirtioBlock, VirtioBlockDriver};
+pub use legacy::LegacyTransport;
+pub use modern::ModernTransport;
+pub use queue::{Buffer, Virtqueue};
+
+use alloc::boxed::Box;
+use crate::pci::PciDevice;
+use crate::syscall::Errno;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+// Legacy devices only have 32 feature bits, so can never offer this one.
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// Leaves an interrupt source without an MSI-X entry.
+pub const NO_VECTOR: u16 = 0xFFFF;
+
+pub trait Transport: Send + Sync {
+    fn is_modern(&self) -> bool;
+
+    fn device_features(&self) -> u64;
+
+    fn set_driver_features(&self, features: u64);
+
+    fn status(&self) -> u8;
+
+    fn set_status(&self, status: u8);
+
+    // Puts the device back the way it was before the driver found it, which stops it touching
+    // any memory it was given.
+    fn reset(&self) {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    // 0 if the queue doesn't exist.
+    fn max_queue_size(&self, queue: u16) -> u16;
+
+    fn enable_queue(&self, queue: u16, virtqueue: &Virtqueue) -> Result<(), Errno>;
+
+    fn notify(&self, queue: u16);
+
+    // Which MSI-X entry configuration changes and the queue are signalled through. Returns
+    // whether the device accepted it, which it can refuse if it ran out of resources.
+    fn set_config_vector(&self, entry: u16) -> bool;
+
+    fn set_queue_vector(&self, queue: u16, entry: u16) -> bool;
+
+    fn read_config(&self, offset: usize, buffer: &mut [u8]);
+
+    fn read_config_u64(&self, offset: usize) -> u64 {
+        let mut bytes = [0; 8];
+        self.read_config(offset, &mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+// Prefers the modern interface, falling back to the legacy one for older devices.
+pub fn transport(device: &PciDevice) -> Option<Box<dyn Transport>> {
+    match ModernTransport::new(device) {
+        Some(transport) => Some(Box::new(transport)),
+        None => Some(Box::new(LegacyTransport::new(device)?))
+    }
+}
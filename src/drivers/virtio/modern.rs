@@ -0,0 +1,192 @@
+use core::ptr;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory;
+use crate::pci::{self, Capability, PciDevice, CAPABILITY_VENDOR_SPECIFIC, COMMAND_BUS_MASTER, COMMAND_MEMORY};
+use crate::syscall::Errno;
+use super::{Transport, Virtqueue};
+
+// What each of the vendor specific capabilities points at. The ISR status is left out, since it's
+// only needed for legacy interrupts, which aren't used.
+const COMMON_CONFIG: u8 = 1;
+const NOTIFY_CONFIG: u8 = 2;
+const DEVICE_CONFIG: u8 = 4;
+
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0C;
+const CONFIG_VECTOR: usize = 0x10;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_VECTOR: usize = 0x1A;
+const QUEUE_ENABLE: usize = 0x1C;
+const QUEUE_NOTIFY_OFFSET: usize = 0x1E;
+const QUEUE_DESCRIPTORS: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+// Devices can offer queues of up to 32768 entries, which would mostly go unused, since requests
+// are made one at a time.
+const MAX_QUEUE_SIZE: u16 = 256;
+
+// A region of a memory BAR, which gets unmapped again along with the transport.
+struct Region {
+    address: VirtAddr,
+    length: usize
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        memory::unmap_mmio(self.address, self.length);
+    }
+}
+
+pub struct ModernTransport {
+    common: Region,
+    notify: Region,
+    notify_multiplier: u32,
+    device: Region,
+    // Selecting a queue and then using it has to happen without anyone selecting another.
+    queue_lock: Mutex<()>
+}
+
+// Maps the region a capability points at, which has to be in a memory BAR.
+fn map_region(device: &PciDevice, capability: Capability) -> Option<Region> {
+    let base = capability.offset as u16;
+    let bar = pci::read_u8(device.address, base + 4) as usize;
+    let offset = pci::read_u32(device.address, base + 8) as u64;
+    let length = pci::read_u32(device.address, base + 12) as usize;
+    let address = (*device.bars.get(bar)?)?.memory_address()?;
+    let address = memory::map_mmio(PhysAddr::new(address + offset), length).ok()?;
+    Some(Region { address, length })
+}
+
+impl ModernTransport {
+    pub fn new(device: &PciDevice) -> Option<Self> {
+        let (mut common, mut notify, mut device_config) = (None, None, None);
+        let mut notify_multiplier = 0;
+        for &capability in device.capabilities.iter().filter(|capability| capability.id == CAPABILITY_VENDOR_SPECIFIC) {
+            let kind = pci::read_u8(device.address, capability.offset as u16 + 3);
+            // The first capability of each kind is the one to use.
+            match kind {
+                COMMON_CONFIG if common.is_none() => common = Some(capability),
+                NOTIFY_CONFIG if notify.is_none() => {
+                    notify = Some(capability);
+                    notify_multiplier = pci::read_u32(device.address, capability.offset as u16 + 16);
+                }
+                DEVICE_CONFIG if device_config.is_none() => device_config = Some(capability),
+                _ => {}
+            }
+        }
+        device.enable(COMMAND_MEMORY | COMMAND_BUS_MASTER);
+        Some(Self {
+            common: map_region(device, common?)?,
+            notify: map_region(device, notify?)?,
+            notify_multiplier,
+            device: map_region(device, device_config?)?,
+            queue_lock: Mutex::new(())
+        })
+    }
+
+    fn read<T>(&self, register: usize) -> T {
+        unsafe { ptr::read_volatile((self.common.address + register).as_ptr()) }
+    }
+
+    fn write<T>(&self, register: usize, value: T) {
+        unsafe { ptr::write_volatile((self.common.address + register).as_mut_ptr(), value) };
+    }
+
+    // 64 bit registers are written in halves, which every device has to accept.
+    fn write_u64(&self, register: usize, value: u64) {
+        self.write(register, value as u32);
+        self.write(register + 4, (value >> 32) as u32);
+    }
+}
+
+impl Transport for ModernTransport {
+    fn is_modern(&self) -> bool {
+        true
+    }
+
+    fn device_features(&self) -> u64 {
+        self.write::<u32>(DEVICE_FEATURE_SELECT, 0);
+        let low = self.read::<u32>(DEVICE_FEATURE);
+        self.write::<u32>(DEVICE_FEATURE_SELECT, 1);
+        let high = self.read::<u32>(DEVICE_FEATURE);
+        (high as u64) << 32 | low as u64
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.write::<u32>(DRIVER_FEATURE_SELECT, 0);
+        self.write(DRIVER_FEATURE, features as u32);
+        self.write::<u32>(DRIVER_FEATURE_SELECT, 1);
+        self.write(DRIVER_FEATURE, (features >> 32) as u32);
+    }
+
+    fn status(&self) -> u8 {
+        self.read(DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        self.write(DEVICE_STATUS, status);
+    }
+
+    fn max_queue_size(&self, queue: u16) -> u16 {
+        let _lock = self.queue_lock.lock();
+        self.write(QUEUE_SELECT, queue);
+        self.read::<u16>(QUEUE_SIZE).min(MAX_QUEUE_SIZE)
+    }
+
+    fn enable_queue(&self, queue: u16, virtqueue: &Virtqueue) -> Result<(), Errno> {
+        let _lock = self.queue_lock.lock();
+        self.write(QUEUE_SELECT, queue);
+        if virtqueue.size() > self.read::<u16>(QUEUE_SIZE) {
+            return Err(Errno::EINVAL);
+        }
+        self.write(QUEUE_SIZE, virtqueue.size());
+        self.write_u64(QUEUE_DESCRIPTORS, virtqueue.descriptor_address().as_u64());
+        self.write_u64(QUEUE_DRIVER, virtqueue.available_address().as_u64());
+        self.write_u64(QUEUE_DEVICE, virtqueue.used_address().as_u64());
+        self.write::<u16>(QUEUE_ENABLE, 1);
+        Ok(())
+    }
+
+    fn notify(&self, queue: u16) {
+        let offset = {
+            let _lock = self.queue_lock.lock();
+            self.write(QUEUE_SELECT, queue);
+            self.read::<u16>(QUEUE_NOTIFY_OFFSET)
+        };
+        let address = self.notify.address + offset as u64 * self.notify_multiplier as u64;
+        unsafe { ptr::write_volatile(address.as_mut_ptr(), queue) };
+    }
+
+    fn set_config_vector(&self, entry: u16) -> bool {
+        self.write(CONFIG_VECTOR, entry);
+        self.read::<u16>(CONFIG_VECTOR) == entry
+    }
+
+    fn set_queue_vector(&self, queue: u16, entry: u16) -> bool {
+        let _lock = self.queue_lock.lock();
+        self.write(QUEUE_SELECT, queue);
+        self.write(QUEUE_VECTOR, entry);
+        self.read::<u16>(QUEUE_VECTOR) == entry
+    }
+
+    // The generation changes whenever the device changes its configuration, so reads are
+    // repeated until they don't straddle a change.
+    fn read_config(&self, offset: usize, buffer: &mut [u8]) {
+        loop {
+            let generation = self.read::<u8>(CONFIG_GENERATION);
+            for (index, byte) in buffer.iter_mut().enumerate() {
+                *byte = unsafe { ptr::read_volatile((self.device.address + offset + index).as_ptr()) };
+            }
+            if self.read::<u8>(CONFIG_GENERATION) == generation {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::PhysAddr;
+use crate::memory::DmaBuffer;
+
+const DESCRIPTOR_SIZE: usize = 16;
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+// Legacy devices only take the queue's first frame, and expect the used ring on a frame boundary.
+const USED_RING_ALIGNMENT: usize = 4096;
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16
+}
+
+// Part of a request. The device reads buffers that aren't writable and fills in the ones that are.
+#[derive(Debug, Copy, Clone)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    pub writable: bool
+}
+
+// A split virtqueue: a table of descriptors, a ring of the chains we've made available to the
+// device, and a ring of the chains it's done with. All three share one allocation, laid out the
+// way legacy devices insist on.
+pub struct Virtqueue {
+    memory: DmaBuffer,
+    size: u16,
+    available_offset: usize,
+    used_offset: usize,
+    free: Vec<u16>,
+    last_used: u16
+}
+
+impl Virtqueue {
+    // The size has to be a power of two.
+    pub fn new(size: u16) -> Option<Self> {
+        if !size.is_power_of_two() {
+            return None;
+        }
+        let available_offset = DESCRIPTOR_SIZE * size as usize;
+        let used_offset = (available_offset + 6 + 2 * size as usize).next_multiple_of(USED_RING_ALIGNMENT);
+        let memory = DmaBuffer::new(used_offset + 6 + 8 * size as usize)?;
+        Some(Self { memory, size, available_offset, used_offset, free: (0..size).rev().collect(), last_used: 0 })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn descriptor_address(&self) -> PhysAddr {
+        self.memory.physical_address()
+    }
+
+    pub fn available_address(&self) -> PhysAddr {
+        self.memory.physical_address() + self.available_offset
+    }
+
+    pub fn used_address(&self) -> PhysAddr {
+        self.memory.physical_address() + self.used_offset
+    }
+
+    pub fn free_descriptors(&self) -> usize {
+        self.free.len()
+    }
+
+    fn pointer<T>(&self, offset: usize) -> *mut T {
+        unsafe { self.memory.as_ptr().add(offset) as *mut T }
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        self.pointer(index as usize * DESCRIPTOR_SIZE)
+    }
+
+    // Chains the buffers together and makes them available to the device, returning the index
+    // of the chain's head, which is how the device refers to it once it's done. The device still
+    // has to be notified afterwards.
+    pub fn push(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+        let indices: Vec<u16> = (0..buffers.len()).map(|_| self.free.pop().unwrap()).collect();
+        for (position, buffer) in buffers.iter().enumerate() {
+            let mut flags = if buffer.writable { DESCRIPTOR_WRITE } else { 0 };
+            let next = indices.get(position + 1).copied();
+            if next.is_some() {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            let descriptor = Descriptor {
+                address: buffer.address.as_u64(),
+                length: buffer.length,
+                flags,
+                next: next.unwrap_or(0)
+            };
+            unsafe { ptr::write_volatile(self.descriptor(indices[position]), descriptor) };
+        }
+
+        let head = indices[0];
+        let index_pointer = self.pointer::<u16>(self.available_offset + 2);
+        unsafe {
+            let index = ptr::read_volatile(index_pointer);
+            let slot = self.pointer::<u16>(self.available_offset + 4 + 2 * (index % self.size) as usize);
+            ptr::write_volatile(slot, head);
+            // The device mustn't see the new index before the entry it covers.
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(index_pointer, index.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    // Takes the next chain the device has finished with, returning its head and how much the
+    // device wrote into it. Its descriptors go back to being free.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        fence(Ordering::SeqCst);
+        let device_index = unsafe { ptr::read_volatile(self.pointer::<u16>(self.used_offset + 2)) };
+        if device_index == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let element = self.used_offset + 4 + 8 * (self.last_used % self.size) as usize;
+        let head = unsafe { ptr::read_volatile(self.pointer::<u32>(element)) } as u16;
+        let length = unsafe { ptr::read_volatile(self.pointer::<u32>(element + 4)) };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut index = head;
+        loop {
+            self.free.push(index);
+            let descriptor = unsafe { ptr::read_volatile(self.descriptor(index)) };
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                break;
+            }
+            index = descriptor.next;
+        }
+        Some((head, length))
+    }
+}
@@ -0,0 +1,202 @@
+use alloc::vec::Vec;
+use core::arch::x86_64::_rdtsc;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::memory::{AddressSpace, Protection, FRAME_SIZE, USER_SPACE_START};
+use crate::usermode::{self, USER_STACK_BOTTOM, USER_STACK_PAGES, USER_STACK_TOP};
+use super::{Elf, ElfError, ProgramHeader, PF_W, PF_X, PT_INTERP, PT_LOAD, PT_PHDR};
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+// Arguments and environment can take up to a quarter of the stack.
+const MAX_ARGUMENTS_SIZE: u64 = USER_STACK_PAGES * FRAME_SIZE / 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedImage {
+    pub entry: VirtAddr,
+    // Where the program headers ended up in memory, if they were loaded at all.
+    pub program_headers: Option<VirtAddr>,
+    pub program_header_size: usize,
+    pub program_header_count: usize,
+    // The end of the highest segment, which is where a program break would start.
+    pub end: VirtAddr
+}
+
+// Maps the loadable segments of a statically linked executable into an address space. The whole
+// file is checked before anything gets mapped, so a malformed one leaves the address space alone.
+// Running out of memory can still stop this partway through though, with some of the segments
+// already mapped, so the address space should be thrown away if this fails.
+//
+// The kernel image sits at the bottom of the address space, so programs have to be linked at
+// USER_SPACE_START or above instead of the usual 0x400000. With GNU ld that means passing
+// `-Ttext-segment=0x8000000000` (`-Wl,-Ttext-segment=0x8000000000` through gcc), and with lld
+// `--image-base=0x8000000000`. Anything linked the usual way fails with SegmentOutsideUserSpace.
+pub fn load(address_space: &mut AddressSpace, bytes: &[u8]) -> Result<LoadedImage, ElfError> {
+    let elf = Elf::parse(bytes)?;
+    let mut loadable = false;
+    let mut entry_is_executable = false;
+    let mut end = 0;
+    let mut previous_end = 0;
+    let mut program_headers = None;
+    for header in elf.program_headers() {
+        match header.kind {
+            PT_INTERP => return Err(ElfError::NeedsInterpreter),
+            PT_PHDR => program_headers = Some(header.virtual_address),
+            PT_LOAD => {
+                check_segment(&header, bytes.len())?;
+                loadable = true;
+                let segment_end = header.virtual_address + header.memory_size;
+                if header.memory_size > 0 {
+                    if header.virtual_address < previous_end {
+                        return Err(ElfError::OverlappingSegments);
+                    }
+                    previous_end = segment_end;
+                }
+                if header.flags & PF_X != 0 && (header.virtual_address..segment_end).contains(&elf.entry()) {
+                    entry_is_executable = true;
+                }
+                // Without a PT_PHDR, the headers are still mapped if the segment covering the
+                // start of the file includes them.
+                let table_end = elf.program_header_offset() + (elf.program_header_size() * elf.program_header_count()) as u64;
+                if header.offset == 0 && table_end <= header.file_size && program_headers.is_none() {
+                    program_headers = Some(header.virtual_address + elf.program_header_offset());
+                }
+                end = end.max(segment_end);
+            }
+            _ => {}
+        }
+    }
+    if !loadable {
+        return Err(ElfError::NoLoadableSegments);
+    }
+    if !entry_is_executable {
+        return Err(ElfError::BadEntryPoint);
+    }
+
+    for header in elf.program_headers().filter(|header| header.kind == PT_LOAD) {
+        map_segment(address_space, &header, bytes)?;
+    }
+    Ok(LoadedImage {
+        entry: VirtAddr::new(elf.entry()),
+        program_headers: program_headers.map(VirtAddr::new),
+        program_header_size: elf.program_header_size(),
+        program_header_count: elf.program_header_count(),
+        end: VirtAddr::new(end)
+    })
+}
+
+fn check_segment(header: &ProgramHeader, file_length: usize) -> Result<(), ElfError> {
+    let file_end = header.offset.checked_add(header.file_size).ok_or(ElfError::SegmentOutOfBounds)?;
+    if file_end > file_length as u64 || header.file_size > header.memory_size {
+        return Err(ElfError::SegmentOutOfBounds);
+    }
+    let end = header.virtual_address.checked_add(header.memory_size).ok_or(ElfError::SegmentOutsideUserSpace)?;
+    // The top of user space is kept free for the stack.
+    if header.virtual_address < USER_SPACE_START || end > USER_STACK_BOTTOM {
+        return Err(ElfError::SegmentOutsideUserSpace);
+    }
+    Ok(())
+}
+
+fn map_segment(address_space: &mut AddressSpace, header: &ProgramHeader, bytes: &[u8]) -> Result<(), ElfError> {
+    if header.memory_size == 0 {
+        return Ok(());
+    }
+    let protection = Protection { read: true, write: header.flags & PF_W != 0, execute: header.flags & PF_X != 0 };
+    let flags = protection.page_flags();
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::new(header.virtual_address));
+    let end = Page::containing_address(VirtAddr::new(header.virtual_address + header.memory_size - 1));
+
+    // A first page shared with the previous segment already belongs to that segment's region.
+    let region_start = if address_space.region_at(start.start_address()).is_some() { start + 1 } else { start };
+    if region_start <= end {
+        let region_end = end.start_address() + FRAME_SIZE;
+        address_space.add_region(region_start.start_address(), region_end - region_start.start_address(), protection)?;
+    }
+
+    // Only pages holding part of the file are mapped now, along with the pages at the edges in
+    // case they're shared. The rest of the zero filled part gets mapped the first time it's used.
+    let file_end = Page::containing_address(VirtAddr::new(header.virtual_address + header.file_size.max(1) - 1));
+    let holds_data = |page| header.file_size > 0 && page <= file_end;
+    for page in Page::range_inclusive(start, end) {
+        if page != start && page != end && !holds_data(page) {
+            continue;
+        }
+        match address_space.page_flags(page) {
+            // Segments can share a page at their edges, which then needs the permissions of both.
+            Some(existing) => {
+                let mut merged = existing | (flags & PageTableFlags::WRITABLE);
+                if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                address_space.set_page_flags(page, merged).expect("Failed to update a mapped page!");
+            }
+            None => address_space.map_page(page, flags)?
+        }
+    }
+
+    // The pages start out zeroed, which takes care of the part that isn't in the file.
+    let data = &bytes[header.offset as usize..(header.offset + header.file_size) as usize];
+    if !address_space.write(VirtAddr::new(header.virtual_address), data) {
+        return Err(ElfError::OutOfMemory);
+    }
+    Ok(())
+}
+
+// Reserves the user stack and lays out the arguments, environment and auxiliary vector on it the
+// way the System V ABI expects, returning the initial stack pointer.
+pub fn setup_stack(address_space: &mut AddressSpace, image: &LoadedImage, arguments: &[&str], environment: &[&str]) -> Result<VirtAddr, ElfError> {
+    let strings_size: u64 = arguments.iter().chain(environment).map(|string| string.len() as u64 + 1).sum();
+    let vectors_size = (arguments.len() + environment.len() + 16) as u64 * 8;
+    if strings_size + vectors_size + 16 > MAX_ARGUMENTS_SIZE {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+    usermode::reserve_user_stack(address_space)?;
+
+    // The strings and random bytes go right at the top, with the pointers to them underneath.
+    // Anything that doesn't make it onto the stack is because memory ran out, which is only
+    // reported once everything has been pushed.
+    let mut top = USER_STACK_TOP;
+    let mut written = true;
+    let mut push = |bytes: &[u8]| {
+        top -= bytes.len() as u64;
+        written &= address_space.write(VirtAddr::new(top), bytes);
+        top
+    };
+    let random = unsafe { [_rdtsc(), _rdtsc().rotate_left(32) ^ image.entry.as_u64()] };
+    let random_address = push(&[random[0].to_le_bytes(), random[1].to_le_bytes()].concat());
+    let mut push_string = |string: &str| {
+        push(&[0]);
+        push(string.as_bytes())
+    };
+    let argument_pointers: Vec<u64> = arguments.iter().map(|argument| push_string(argument)).collect();
+    let environment_pointers: Vec<u64> = environment.iter().map(|variable| push_string(variable)).collect();
+    if !written {
+        return Err(ElfError::OutOfMemory);
+    }
+
+    let mut words = Vec::new();
+    words.push(arguments.len() as u64);
+    words.extend(argument_pointers);
+    words.push(0);
+    words.extend(environment_pointers);
+    words.push(0);
+    if let Some(program_headers) = image.program_headers {
+        words.extend([AT_PHDR, program_headers.as_u64(), AT_PHENT, image.program_header_size as u64, AT_PHNUM, image.program_header_count as u64]);
+    }
+    words.extend([AT_PAGESZ, FRAME_SIZE, AT_ENTRY, image.entry.as_u64(), AT_RANDOM, random_address, AT_NULL, 0]);
+
+    // The stack pointer has to be 16 byte aligned on entry, pointing at the argument count.
+    let stack_pointer = (top - words.len() as u64 * 8) & !0xF;
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    if !address_space.write(VirtAddr::new(stack_pointer), &bytes) {
+        return Err(ElfError::OutOfMemory);
+    }
+    Ok(VirtAddr::new(stack_pointer))
+}
@@ -0,0 +1,177 @@
+mod loader;
+
+pub use loader::{load, setup_stack, LoadedImage};
+
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
+use crate::memory::RegionError;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3E;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeaders,
+    // Dynamically linked executables need an interpreter, which we can't run.
+    NeedsInterpreter,
+    NoLoadableSegments,
+    SegmentOutOfBounds,
+    SegmentOutsideUserSpace,
+    // Loadable segments have to be sorted by address, and may only share the pages at their edges.
+    OverlappingSegments,
+    BadEntryPoint,
+    ArgumentsTooLarge,
+    OutOfMemory
+}
+
+impl From<MapToError<Size4KiB>> for ElfError {
+    fn from(_: MapToError<Size4KiB>) -> Self {
+        ElfError::OutOfMemory
+    }
+}
+
+impl From<RegionError> for ElfError {
+    fn from(error: RegionError) -> Self {
+        match error {
+            RegionError::Overlapping => ElfError::OverlappingSegments,
+            _ => ElfError::SegmentOutsideUserSpace
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub alignment: u64
+}
+
+// A statically linked x86_64 executable, checked well enough that its program headers can be
+// read without going out of bounds.
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    entry: u64,
+    program_header_offset: usize,
+    program_header_size: usize,
+    program_header_count: usize
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ElfError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if bytes[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if bytes[4] != ELFCLASS64 {
+            return Err(ElfError::NotElf64);
+        }
+        if bytes[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if bytes[6] != EV_CURRENT || read_u32(bytes, 20) != EV_CURRENT as u32 {
+            return Err(ElfError::BadVersion);
+        }
+        if read_u16(bytes, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(bytes, 18) != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let program_header_offset = read_u64(bytes, 32);
+        let program_header_size = read_u16(bytes, 54) as usize;
+        let program_header_count = read_u16(bytes, 56) as usize;
+        let table_size = (program_header_size * program_header_count) as u64;
+        let table_fits = program_header_offset.checked_add(table_size).is_some_and(|end| end <= bytes.len() as u64);
+        if program_header_size < PROGRAM_HEADER_SIZE || !table_fits {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        Ok(Elf {
+            bytes,
+            entry: read_u64(bytes, 24),
+            program_header_offset: program_header_offset as usize,
+            program_header_size,
+            program_header_count
+        })
+    }
+
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_header_offset(&self) -> u64 {
+        self.program_header_offset as u64
+    }
+
+    pub fn program_header_size(&self) -> usize {
+        self.program_header_size
+    }
+
+    pub fn program_header_count(&self) -> usize {
+        self.program_header_count
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let bytes = self.bytes;
+        let offset = self.program_header_offset;
+        let size = self.program_header_size;
+        (0..self.program_header_count).map(move |index| {
+            let header = &bytes[offset + index * size..];
+            ProgramHeader {
+                kind: read_u32(header, 0),
+                flags: read_u32(header, 4),
+                offset: read_u64(header, 8),
+                virtual_address: read_u64(header, 16),
+                file_size: read_u64(header, 32),
+                memory_size: read_u64(header, 40),
+                alignment: read_u64(header, 48)
+            }
+        })
+    }
+}
+
+// Callers make sure the offsets are in bounds first.
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
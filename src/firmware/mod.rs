@@ -0,0 +1,88 @@
+mod power;
+
+pub use power::{reboot, shutdown};
+
+use acpi::{AcpiHandler, AcpiTables, HpetInfo, PhysicalMapping, PlatformInfo};
+use acpi::fadt::Fadt;
+use acpi::madt::Madt;
+use acpi::mcfg::PciConfigRegions;
+use core::ptr::NonNull;
+use core::slice;
+use log::{info, warn};
+use spin::Once;
+use x86_64::VirtAddr;
+use crate::memory;
+
+static TABLES: Once<AcpiTables<PhysicalMemoryHandler>> = Once::new();
+
+// ACPI tables live in ordinary RAM, so they can be read straight through the physical
+// memory mapping without having to map anything.
+#[derive(Debug, Clone, Copy)]
+pub struct PhysicalMemoryHandler {
+    physical_memory_offset: VirtAddr
+}
+
+impl PhysicalMemoryHandler {
+    pub fn new(physical_memory_offset: VirtAddr) -> Self {
+        Self { physical_memory_offset }
+    }
+}
+
+impl AcpiHandler for PhysicalMemoryHandler {
+    unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> PhysicalMapping<Self, T> {
+        let virtual_address = self.physical_memory_offset + physical_address;
+        let virtual_start = NonNull::new(virtual_address.as_mut_ptr()).expect("ACPI table mapped to null!");
+        PhysicalMapping::new(physical_address, virtual_start, size, size, *self)
+    }
+
+    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
+}
+
+pub fn init(rsdp_address: Option<u64>) {
+    let rsdp_address = match rsdp_address {
+        Some(value) => value,
+        None => {
+            warn!("Bootloader did not provide an RSDP address, ACPI will be unavailable!");
+            return;
+        }
+    };
+    let handler = PhysicalMemoryHandler::new(memory::physical_memory_offset());
+    match unsafe { AcpiTables::from_rsdp(handler, rsdp_address as usize) } {
+        Ok(tables) => {
+            info!("Found ACPI tables (revision {}).", tables.revision);
+            TABLES.call_once(|| tables);
+        }
+        Err(error) => warn!("Failed to parse ACPI tables: {:?}", error)
+    }
+}
+
+pub fn tables() -> Option<&'static AcpiTables<PhysicalMemoryHandler>> {
+    TABLES.get()
+}
+
+pub fn platform_info() -> Option<PlatformInfo> {
+    tables()?.platform_info().ok()
+}
+
+pub fn fadt() -> Option<PhysicalMapping<PhysicalMemoryHandler, Fadt>> {
+    tables()?.find_table::<Fadt>().ok()
+}
+
+pub fn madt() -> Option<PhysicalMapping<PhysicalMemoryHandler, Madt>> {
+    tables()?.find_table::<Madt>().ok()
+}
+
+pub fn hpet() -> Option<HpetInfo> {
+    HpetInfo::new(tables()?).ok()
+}
+
+pub fn pci_config_regions() -> Option<PciConfigRegions> {
+    PciConfigRegions::new(tables()?).ok()
+}
+
+// The DSDT's AML byte code, which we don't interpret, but sometimes need to search through.
+pub fn dsdt() -> Option<&'static [u8]> {
+    let dsdt = tables()?.dsdt.as_ref()?;
+    let address = memory::physical_memory_offset() + dsdt.address;
+    Some(unsafe { slice::from_raw_parts(address.as_ptr(), dsdt.length as usize) })
+}
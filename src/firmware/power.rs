@@ -0,0 +1,216 @@
+use acpi::address::{AddressSpace, GenericAddress};
+use acpi::fadt::Fadt;
+use core::ptr;
+use log::{info, warn};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::{halt_loop, memory};
+use super::{dsdt, fadt};
+
+const SLP_EN: u16 = 1 << 13;
+const SCI_EN: u16 = 1;
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+// Puts the machine into the S5 (soft off) sleep state. If ACPI isn't available or the firmware
+// ignores the request, this halts instead.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    info!("Shutting down.");
+    match (fadt(), dsdt().and_then(find_s5_sleep_types)) {
+        (Some(fadt), Some((sleep_type_a, sleep_type_b))) => unsafe {
+            let control_a = fadt.pm1a_control_block().ok().and_then(|block| Register::new(&block));
+            let control_b = fadt.pm1b_control_block().ok().flatten().and_then(|block| Register::new(&block));
+            if let Some(control) = &control_a {
+                enable_acpi_mode(&fadt, control);
+                write_pm1_control(control, sleep_type_a << 10 | SLP_EN);
+            }
+            if let Some(control) = &control_b {
+                write_pm1_control(control, sleep_type_b << 10 | SLP_EN);
+            }
+        },
+        _ => warn!("Can't shut down without ACPI!")
+    }
+    warn!("Shutdown failed, halting.");
+    halt_loop()
+}
+
+// Resets the machine through the FADT reset register, if the firmware says it has one, falling
+// back to pulsing the reset line through the keyboard controller, and finally to a triple fault.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    info!("Rebooting.");
+    if let Some(fadt) = fadt() {
+        let flags = fadt.flags;
+        let register = fadt.reset_register().ok().and_then(|register| Register::new(&register));
+        if let (true, Some(register)) = (flags.supports_system_reset(), register) {
+            unsafe { register.write(fadt.reset_value as u64) };
+        }
+    }
+
+    unsafe {
+        let mut status = Port::<u8>::new(0x64);
+        for _ in 0..0x10000 {
+            if status.read() & 0x02 == 0 {
+                break;
+            }
+        }
+        status.write(0xFE);
+    }
+
+    // If we're still here, loading an empty IDT and raising an exception will triple fault.
+    unsafe { lidt(&DescriptorTablePointer { limit: 0, base: VirtAddr::zero() }) };
+    interrupts::int3();
+    halt_loop()
+}
+
+// Some firmware starts out in legacy mode, where writes to the PM1 control block are ignored.
+unsafe fn enable_acpi_mode(fadt: &Fadt, control: &Register) {
+    let smi_command_port = fadt.smi_cmd_port;
+    if control.read() as u16 & SCI_EN != 0 || smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    Port::<u8>::new(smi_command_port as u16).write(fadt.acpi_enable);
+    for _ in 0..0x100000 {
+        if control.read() as u16 & SCI_EN != 0 {
+            break;
+        }
+    }
+}
+
+unsafe fn write_pm1_control(control: &Register, value: u16) {
+    // Preserve everything apart from the sleep type and enable bits.
+    let current = control.read() as u16 & !(0x7 << 10 | SLP_EN);
+    control.write((current | value) as u64);
+}
+
+// A register from one of the ACPI tables. Ones in memory belong to devices, so they're mapped
+// through the MMIO window once up front, rather than read through the physical memory mapping.
+enum Register {
+    Io { port: u16, width: u8 },
+    Memory { address: VirtAddr, width: u8 }
+}
+
+impl Register {
+    fn new(register: &GenericAddress) -> Option<Self> {
+        let width = register.bit_width;
+        match register.address_space {
+            AddressSpace::SystemIo => Some(Register::Io { port: register.address as u16, width }),
+            AddressSpace::SystemMemory => match memory::map_mmio(PhysAddr::new(register.address), 8) {
+                Ok(address) => Some(Register::Memory { address, width }),
+                Err(error) => {
+                    warn!("Failed to map ACPI register at {:#x}: {:?}", register.address, error);
+                    None
+                }
+            },
+            _ => {
+                warn!("Unsupported ACPI register address space {:?}!", register.address_space);
+                None
+            }
+        }
+    }
+
+    unsafe fn read(&self) -> u64 {
+        match *self {
+            Register::Io { port, width } => match width {
+                8 => Port::<u8>::new(port).read() as u64,
+                32 => Port::<u32>::new(port).read() as u64,
+                _ => Port::<u16>::new(port).read() as u64
+            },
+            Register::Memory { address, width } => match width {
+                8 => ptr::read_volatile(address.as_ptr::<u8>()) as u64,
+                32 => ptr::read_volatile(address.as_ptr::<u32>()) as u64,
+                64 => ptr::read_volatile(address.as_ptr::<u64>()),
+                _ => ptr::read_volatile(address.as_ptr::<u16>()) as u64
+            }
+        }
+    }
+
+    unsafe fn write(&self, value: u64) {
+        match *self {
+            Register::Io { port, width } => match width {
+                8 => Port::<u8>::new(port).write(value as u8),
+                32 => Port::<u32>::new(port).write(value as u32),
+                _ => Port::<u16>::new(port).write(value as u16)
+            },
+            Register::Memory { address, width } => match width {
+                8 => ptr::write_volatile(address.as_mut_ptr::<u8>(), value as u8),
+                32 => ptr::write_volatile(address.as_mut_ptr::<u32>(), value as u32),
+                64 => ptr::write_volatile(address.as_mut_ptr::<u64>(), value),
+                _ => ptr::write_volatile(address.as_mut_ptr::<u16>(), value as u16)
+            }
+        }
+    }
+}
+
+// Finds the SLP_TYPa and SLP_TYPb values for S5 by looking for the `\_S5_` package in the DSDT,
+// rather than pulling in a full AML interpreter just for this.
+// The name can also turn up where something refers to it, so only the one that's defined as a
+// package counts.
+fn find_s5_sleep_types(aml: &[u8]) -> Option<(u16, u16)> {
+    let is_definition = |position: usize| {
+        let is_name = (position >= 1 && aml[position - 1] == AML_NAME_OP)
+            || (position >= 2 && aml[position - 2] == AML_NAME_OP && aml[position - 1] == b'\\');
+        is_name && aml.get(position + 4) == Some(&AML_PACKAGE_OP)
+    };
+    let position = (0..aml.len().saturating_sub(3)).find(|&position| &aml[position..position + 4] == b"_S5_" && is_definition(position))?;
+
+    // Skip the package length (whose first byte says how many more bytes follow it) and the
+    // element count to get to the first element.
+    let mut offset = position + 5;
+    offset += ((*aml.get(offset)? & 0xC0) >> 6) as usize + 2;
+    let mut read_element = || {
+        if *aml.get(offset)? == AML_BYTE_PREFIX {
+            offset += 1;
+        }
+        let value = *aml.get(offset)? as u16;
+        offset += 1;
+        Some(value)
+    };
+    let sleep_type_a = read_element()?;
+    let sleep_type_b = read_element()?;
+    Some((sleep_type_a, sleep_type_b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find_s5_sleep_types;
+
+    // Name (_S5, Package (0x04) { 0x05, 0x05, Zero, Zero }), the way QEMU's DSDT has it.
+    const S5: &[u8] = &[0x08, b'_', b'S', b'5', b'_', 0x12, 0x0A, 0x04, 0x0A, 0x05, 0x0A, 0x05, 0x00, 0x00];
+
+    #[test_case]
+    fn finds_sleep_types() {
+        assert_eq!(find_s5_sleep_types(S5), Some((5, 5)));
+        let mut aml = [0xFF; 64];
+        aml[20..20 + S5.len()].copy_from_slice(S5);
+        assert_eq!(find_s5_sleep_types(&aml), Some((5, 5)));
+    }
+
+    #[test_case]
+    fn reads_values_without_a_byte_prefix() {
+        // Name (\_S5, Package (0x02) { Zero, One }), with a two byte package length.
+        let aml = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x40, 0x00, 0x02, 0x00, 0x01];
+        assert_eq!(find_s5_sleep_types(&aml), Some((0, 1)));
+    }
+
+    #[test_case]
+    fn skips_references_to_the_name() {
+        let mut aml = [0; 32];
+        // Something that only refers to _S5_ comes first.
+        aml[..5].copy_from_slice(&[0x70, b'_', b'S', b'5', b'_']);
+        aml[10..10 + S5.len()].copy_from_slice(S5);
+        assert_eq!(find_s5_sleep_types(&aml), Some((5, 5)));
+    }
+
+    #[test_case]
+    fn rejects_missing_or_cut_off_packages() {
+        assert_eq!(find_s5_sleep_types(&[]), None);
+        assert_eq!(find_s5_sleep_types(&S5[1..]), None);
+        assert_eq!(find_s5_sleep_types(&S5[..9]), None);
+    }
+}
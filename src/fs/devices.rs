@@ -0,0 +1,80 @@
+use alloc::string::String;
+use core::time::Duration;
+use crate::print;
+use super::{FileType, FsResult, Inode, Metadata};
+
+// Device files don't live on any real filesystem, so they share device number 0 and get fixed
+// inode numbers.
+fn device_metadata(inode: u64) -> Metadata {
+    Metadata {
+        device: 0,
+        inode,
+        kind: FileType::CharDevice,
+        mode: 0o666,
+        links: 1,
+        uid: 0,
+        gid: 0,
+        size: 0,
+        block_size: 4096,
+        blocks: 0,
+        accessed: Duration::ZERO,
+        modified: Duration::ZERO,
+        changed: Duration::ZERO
+    }
+}
+
+// Output goes wherever `print!` goes. There's no way to read from the keyboard through here yet,
+// so reading always hits the end of the file.
+pub struct Console;
+
+impl Inode for Console {
+    fn metadata(&self) -> FsResult<Metadata> {
+        Ok(device_metadata(1))
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> FsResult<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> FsResult<usize> {
+        print!("{}", String::from_utf8_lossy(data));
+        Ok(data.len())
+    }
+
+    fn is_seekable(&self) -> bool {
+        false
+    }
+}
+
+pub struct Null;
+
+impl Inode for Null {
+    fn metadata(&self) -> FsResult<Metadata> {
+        Ok(device_metadata(2))
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> FsResult<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> FsResult<usize> {
+        Ok(data.len())
+    }
+}
+
+pub struct Zero;
+
+impl Inode for Zero {
+    fn metadata(&self) -> FsResult<Metadata> {
+        Ok(device_metadata(3))
+    }
+
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> FsResult<usize> {
+        Ok(data.len())
+    }
+}
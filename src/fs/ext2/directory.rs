@@ -0,0 +1,156 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::fs::{FileType, FsResult};
+use crate::syscall::Errno;
+use super::{u16_at, u32_at};
+
+// The file types directory entries carry when the volume has them.
+const TYPE_FILE: u8 = 1;
+const TYPE_DIRECTORY: u8 = 2;
+const TYPE_CHAR_DEVICE: u8 = 3;
+const TYPE_BLOCK_DEVICE: u8 = 4;
+const TYPE_SYMLINK: u8 = 7;
+
+const HEADER_SIZE: usize = 8;
+
+// An entry within a single block of a directory. Entries can't cross blocks, and an entry with
+// no inode is just space that's left over.
+pub(super) struct Entry {
+    pub offset: usize,
+    pub length: usize,
+    pub inode: u32,
+    pub name: Vec<u8>,
+    // Only there when the volume keeps file types in its directories.
+    pub kind: Option<FileType>
+}
+
+impl Entry {
+    pub fn is_dot(&self) -> bool {
+        self.name == b"." || self.name == b".."
+    }
+
+    pub fn display_name(&self) -> String {
+        String::from_utf8_lossy(&self.name).into()
+    }
+}
+
+// How much room an entry with a name this long takes up at the least.
+fn entry_length(name_length: usize) -> usize {
+    (HEADER_SIZE + name_length + 3) & !3
+}
+
+pub(super) fn file_type(kind: FileType) -> u8 {
+    match kind {
+        FileType::File => TYPE_FILE,
+        FileType::Directory => TYPE_DIRECTORY,
+        FileType::Symlink => TYPE_SYMLINK,
+        FileType::CharDevice => TYPE_CHAR_DEVICE,
+        FileType::BlockDevice => TYPE_BLOCK_DEVICE
+    }
+}
+
+// FIFOs and sockets have nowhere to go, and get treated as regular files.
+fn kind_from_type(file_type: u8) -> Option<FileType> {
+    match file_type {
+        0 => None,
+        TYPE_DIRECTORY => Some(FileType::Directory),
+        TYPE_SYMLINK => Some(FileType::Symlink),
+        TYPE_CHAR_DEVICE => Some(FileType::CharDevice),
+        TYPE_BLOCK_DEVICE => Some(FileType::BlockDevice),
+        _ => Some(FileType::File)
+    }
+}
+
+// Every entry in a block, used or not. Entries that run past the end of the block or into each
+// other mean the directory is damaged.
+pub(super) fn parse(block: &[u8], file_types: bool) -> FsResult<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < block.len() {
+        if block.len() - offset < HEADER_SIZE {
+            return Err(Errno::EIO);
+        }
+        let length = u16_at(block, offset + 4) as usize;
+        // Without file types the name length takes up both bytes.
+        let (name_length, file_type) = if file_types {
+            (block[offset + 6] as usize, block[offset + 7])
+        } else {
+            (u16_at(block, offset + 6) as usize, 0)
+        };
+        if length < HEADER_SIZE || !length.is_multiple_of(4) || offset + length > block.len() || HEADER_SIZE + name_length > length {
+            return Err(Errno::EIO);
+        }
+        entries.push(Entry {
+            offset,
+            length,
+            inode: u32_at(block, offset),
+            name: block[offset + HEADER_SIZE..offset + HEADER_SIZE + name_length].into(),
+            kind: kind_from_type(file_type)
+        });
+        offset += length;
+    }
+    Ok(entries)
+}
+
+fn write_entry(block: &mut [u8], offset: usize, length: usize, inode: u32, name: &[u8], file_type: u8, file_types: bool) {
+    block[offset..offset + 4].copy_from_slice(&inode.to_le_bytes());
+    block[offset + 4..offset + 6].copy_from_slice(&(length as u16).to_le_bytes());
+    if file_types {
+        block[offset + 6] = name.len() as u8;
+        block[offset + 7] = file_type;
+    } else {
+        block[offset + 6..offset + 8].copy_from_slice(&(name.len() as u16).to_le_bytes());
+    }
+    block[offset + HEADER_SIZE..offset + HEADER_SIZE + name.len()].copy_from_slice(name);
+}
+
+// Puts an entry into a block if there's room, either in an unused entry or in the space at the
+// end of a used one. Returns whether it fit.
+pub(super) fn insert(block: &mut [u8], inode: u32, name: &[u8], file_type: u8, file_types: bool) -> FsResult<bool> {
+    let needed = entry_length(name.len());
+    for entry in parse(block, file_types)? {
+        if entry.inode == 0 && entry.length >= needed {
+            write_entry(block, entry.offset, entry.length, inode, name, file_type, file_types);
+            return Ok(true);
+        }
+        let used = entry_length(entry.name.len());
+        if entry.inode != 0 && entry.length - used >= needed {
+            block[entry.offset + 4..entry.offset + 6].copy_from_slice(&(used as u16).to_le_bytes());
+            write_entry(block, entry.offset + used, entry.length - used, inode, name, file_type, file_types);
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Takes an entry out of a block by giving its space to the one before it. The first entry in a
+// block has nothing before it, so it's just left without an inode.
+pub(super) fn remove(block: &mut [u8], offset: usize, file_types: bool) -> FsResult<()> {
+    let entries = parse(block, file_types)?;
+    let index = entries.iter().position(|entry| entry.offset == offset).ok_or(Errno::EIO)?;
+    match index.checked_sub(1).map(|previous| &entries[previous]) {
+        Some(previous) => {
+            let length = previous.length + entries[index].length;
+            block[previous.offset + 4..previous.offset + 6].copy_from_slice(&(length as u16).to_le_bytes());
+        }
+        None => block[offset..offset + 4].fill(0)
+    }
+    Ok(())
+}
+
+// A block with nothing in it, for when a directory grows.
+pub(super) fn empty_block(block_size: usize) -> Vec<u8> {
+    let mut block = vec![0; block_size];
+    block[4..6].copy_from_slice(&(block_size as u16).to_le_bytes());
+    block
+}
+
+// The first block of a new directory, with `.` and `..` in it.
+pub(super) fn dot_block(block_size: usize, inode: u32, parent: u32, file_types: bool) -> Vec<u8> {
+    let mut block = vec![0; block_size];
+    let dot_length = entry_length(1);
+    write_entry(&mut block, 0, dot_length, inode, b".", TYPE_DIRECTORY, file_types);
+    write_entry(&mut block, dot_length, block_size - dot_length, parent, b"..", TYPE_DIRECTORY, file_types);
+    block
+}
@@ -0,0 +1,173 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::fs::FsResult;
+use crate::syscall::Errno;
+use super::{u16_at, Shared, GROUP_DESCRIPTOR_SIZE};
+
+// How much of each block group is free, kept in step with the group descriptors on disk.
+#[derive(Default)]
+pub(super) struct Groups {
+    counts: Vec<Counts>,
+    pub free_blocks: u32,
+    pub free_inodes: u32
+}
+
+#[derive(Clone, Copy)]
+struct Counts {
+    free_blocks: u16,
+    free_inodes: u16,
+    directories: u16
+}
+
+impl Groups {
+    // The totals in the superblock are only written now and then, so they're added up from the
+    // groups instead.
+    pub(super) fn load(descriptors: &[u8]) -> Self {
+        let mut groups = Groups::default();
+        for descriptor in descriptors.chunks(GROUP_DESCRIPTOR_SIZE as usize) {
+            let counts = Counts {
+                free_blocks: u16_at(descriptor, 12),
+                free_inodes: u16_at(descriptor, 14),
+                directories: u16_at(descriptor, 16)
+            };
+            groups.free_blocks += counts.free_blocks as u32;
+            groups.free_inodes += counts.free_inodes as u32;
+            groups.counts.push(counts);
+        }
+        groups
+    }
+}
+
+impl Shared {
+    fn write_counts(&self, groups: &Groups, group: u32) -> FsResult<()> {
+        let counts = groups.counts[group as usize];
+        let mut bytes = [0; 6];
+        bytes[0..2].copy_from_slice(&counts.free_blocks.to_le_bytes());
+        bytes[2..4].copy_from_slice(&counts.free_inodes.to_le_bytes());
+        bytes[4..6].copy_from_slice(&counts.directories.to_le_bytes());
+        self.cache.write_bytes(self.layout.descriptors + group as u64 * GROUP_DESCRIPTOR_SIZE + 12, &bytes)
+    }
+
+    // Sets the first clear bit of a bitmap from `first` up to `limit`, and says which it was.
+    fn take_bit(&self, bitmap: u32, first: u32, limit: u32) -> FsResult<Option<u32>> {
+        self.cache.with_block_mut(bitmap as u64, |data| {
+            let bit = (first..limit).find(|&bit| data[bit as usize / 8] & (1 << (bit % 8)) == 0)?;
+            data[bit as usize / 8] |= 1 << (bit % 8);
+            Some(bit)
+        })
+    }
+
+    // Freeing something that's already free means the volume is damaged, and carrying on would
+    // only throw the counts off.
+    fn clear_bit(&self, bitmap: u32, bit: u32) -> FsResult<()> {
+        let was_set = self.cache.with_block_mut(bitmap as u64, |data| {
+            let was_set = data[bit as usize / 8] & (1 << (bit % 8)) != 0;
+            data[bit as usize / 8] &= !(1 << (bit % 8));
+            was_set
+        })?;
+        if was_set { Ok(()) } else { Err(Errno::EIO) }
+    }
+
+    // Takes a free block, starting with the group that `goal` is in so that files stay close to
+    // their inodes. It comes zeroed, since a new indirect block must map nothing and the unwritten
+    // end of a data block reads back as zeroes.
+    pub(super) fn allocate_block(&self, groups: &mut Groups, goal: u32) -> FsResult<u32> {
+        self.release_orphans(groups)?;
+        let layout = &self.layout;
+        let count = layout.group_count();
+        let goal_group = (goal.saturating_sub(layout.first_data_block) / layout.blocks_per_group).min(count - 1);
+        for step in 0..count {
+            let group = (goal_group + step) % count;
+            if groups.counts[group as usize].free_blocks == 0 {
+                continue;
+            }
+            let bitmap = layout.groups[group as usize].block_bitmap;
+            let bit = match self.take_bit(bitmap, 0, layout.blocks_in_group(group))? {
+                Some(bit) => bit,
+                None => continue
+            };
+            let block = layout.first_data_block + group * layout.blocks_per_group + bit;
+            self.cache.with_block_mut(block as u64, |data| data.fill(0))?;
+            groups.counts[group as usize].free_blocks -= 1;
+            groups.free_blocks -= 1;
+            self.write_counts(groups, group)?;
+            return Ok(block);
+        }
+        Err(Errno::ENOSPC)
+    }
+
+    pub(super) fn free_block(&self, groups: &mut Groups, block: u32) -> FsResult<()> {
+        let layout = &self.layout;
+        if !layout.is_block(block) {
+            return Err(Errno::EIO);
+        }
+        let group = (block - layout.first_data_block) / layout.blocks_per_group;
+        self.clear_bit(layout.groups[group as usize].block_bitmap, (block - layout.first_data_block) % layout.blocks_per_group)?;
+        groups.counts[group as usize].free_blocks += 1;
+        groups.free_blocks += 1;
+        self.write_counts(groups, group)
+    }
+
+    // Directories are spread out over the groups, and everything else is kept in the same group
+    // as its directory, which is roughly what Linux does. The inode comes zeroed.
+    pub(super) fn allocate_inode(&self, groups: &mut Groups, parent: u32, directory: bool) -> FsResult<u32> {
+        let layout = &self.layout;
+        let count = layout.group_count();
+        let parent_group = (parent - 1) / layout.inodes_per_group;
+        let mut order: Vec<u32> = (0..count).map(|step| (parent_group + step) % count).collect();
+        if directory {
+            order.sort_by_key(|&group| {
+                let counts = groups.counts[group as usize];
+                (counts.directories, u16::MAX - counts.free_blocks)
+            });
+        }
+        for group in order {
+            if groups.counts[group as usize].free_inodes == 0 {
+                continue;
+            }
+            // The reserved inodes at the start of the first group should be marked as used
+            // already, but don't count on it.
+            let first = if group == 0 { layout.first_inode - 1 } else { 0 };
+            let bitmap = layout.groups[group as usize].inode_bitmap;
+            let bit = match self.take_bit(bitmap, first, layout.inodes_per_group)? {
+                Some(bit) => bit,
+                None => continue
+            };
+            let inode = group * layout.inodes_per_group + bit + 1;
+            if !layout.is_inode(inode) {
+                self.clear_bit(bitmap, bit)?;
+                continue;
+            }
+            self.cache.write_bytes(self.inode_offset(inode), &vec![0; layout.inode_size as usize])?;
+            let counts = &mut groups.counts[group as usize];
+            counts.free_inodes -= 1;
+            if directory {
+                counts.directories += 1;
+            }
+            groups.free_inodes -= 1;
+            self.write_counts(groups, group)?;
+            return Ok(inode);
+        }
+        Err(Errno::ENOSPC)
+    }
+
+    pub(super) fn free_inode(&self, groups: &mut Groups, inode: u32, directory: bool) -> FsResult<()> {
+        let layout = &self.layout;
+        let group = (inode - 1) / layout.inodes_per_group;
+        self.clear_bit(layout.groups[group as usize].inode_bitmap, (inode - 1) % layout.inodes_per_group)?;
+        let counts = &mut groups.counts[group as usize];
+        counts.free_inodes += 1;
+        if directory {
+            counts.directories = counts.directories.saturating_sub(1);
+        }
+        groups.free_inodes += 1;
+        self.write_counts(groups, group)
+    }
+
+    pub(super) fn inode_offset(&self, inode: u32) -> u64 {
+        let layout = &self.layout;
+        let group = (inode - 1) / layout.inodes_per_group;
+        let index = (inode - 1) % layout.inodes_per_group;
+        layout.block_offset(layout.groups[group as usize].inode_table) + index as u64 * layout.inode_size
+    }
+}
@@ -0,0 +1,821 @@
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr;
+use core::time::Duration;
+use crate::fs::{DirEntry, FileType, FsResult, Inode, Metadata, MAX_NAME_LENGTH};
+use crate::sync::SleepMutex;
+use crate::syscall::Errno;
+use crate::time;
+use super::directory::{self, Entry};
+use super::groups::Groups;
+use super::{u16_at, u32_at, Shared};
+
+const MODE: usize = 0;
+const UID: usize = 2;
+const SIZE: usize = 4;
+const ACCESSED: usize = 8;
+const CHANGED: usize = 12;
+const MODIFIED: usize = 16;
+const DELETED: usize = 20;
+const GID: usize = 24;
+const LINKS: usize = 26;
+// Counted in 512 byte sectors, including indirect blocks.
+const SECTORS: usize = 28;
+const FLAGS: usize = 32;
+const BLOCKS: usize = 40;
+const FILE_ACL: usize = 104;
+const SIZE_HIGH: usize = 108;
+const UID_HIGH: usize = 120;
+const GID_HIGH: usize = 122;
+
+const DIRECT_BLOCKS: u64 = 12;
+// The pointers after the direct ones, and how many levels of indirect blocks are under each.
+const INDIRECT: [(usize, u32); 3] = [(12, 1), (13, 2), (14, 3)];
+// Symlinks shorter than this keep their target where the block pointers would be.
+const FAST_SYMLINK_LENGTH: usize = 60;
+const LINK_MAX: u16 = 32000;
+
+const MODE_TYPE: u16 = 0xF000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_SYMLINK: u16 = 0xA000;
+const MODE_CHAR_DEVICE: u16 = 0x2000;
+const MODE_BLOCK_DEVICE: u16 = 0x6000;
+// Directories with a hash tree index. Nothing here keeps the index up to date, so the flag goes
+// as soon as the directory changes, which leaves a plain directory that still works.
+const FLAG_INDEX: u32 = 0x1000;
+
+// There's no clock yet, so times are seconds since boot like everywhere else.
+fn now() -> u32 {
+    time::uptime().as_secs() as u32
+}
+
+// The part of an inode that ext2 uses. Bigger inodes have more after it, which is left alone.
+#[derive(Clone, Copy)]
+struct RawInode([u8; 128]);
+
+impl RawInode {
+    fn new(mode: u16, links: u16) -> Self {
+        let mut raw = RawInode([0; 128]);
+        raw.set_u16(MODE, mode);
+        raw.set_u16(LINKS, links);
+        let now = now();
+        for offset in [ACCESSED, CHANGED, MODIFIED] {
+            raw.set_u32(offset, now);
+        }
+        raw
+    }
+
+    fn u16(&self, offset: usize) -> u16 {
+        u16_at(&self.0, offset)
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        u32_at(&self.0, offset)
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) {
+        self.0[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // FIFOs and sockets have nowhere to go, and get treated as regular files.
+    fn kind(&self) -> FileType {
+        match self.u16(MODE) & MODE_TYPE {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            MODE_CHAR_DEVICE => FileType::CharDevice,
+            MODE_BLOCK_DEVICE => FileType::BlockDevice,
+            _ => FileType::File
+        }
+    }
+
+    // Only regular files use the top half of the size, directories used to keep an ACL there.
+    fn size(&self) -> u64 {
+        let high = if self.u16(MODE) & MODE_TYPE == MODE_FILE { self.u32(SIZE_HIGH) } else { 0 };
+        ((high as u64) << 32) | self.u32(SIZE) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.set_u32(SIZE, size as u32);
+        if self.u16(MODE) & MODE_TYPE == MODE_FILE {
+            self.set_u32(SIZE_HIGH, (size >> 32) as u32);
+        }
+    }
+
+    fn links(&self) -> u16 {
+        self.u16(LINKS)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        self.set_u16(LINKS, links);
+        self.set_u32(CHANGED, now());
+    }
+
+    fn block(&self, index: usize) -> u32 {
+        self.u32(BLOCKS + index * 4)
+    }
+
+    fn set_block(&mut self, index: usize, block: u32) {
+        self.set_u32(BLOCKS + index * 4, block);
+    }
+
+    fn add_sectors(&mut self, sectors: i64) {
+        self.set_u32(SECTORS, (self.u32(SECTORS) as i64 + sectors).max(0) as u32);
+    }
+
+    fn drop_index(&mut self) {
+        self.set_u32(FLAGS, self.u32(FLAGS) & !FLAG_INDEX);
+    }
+
+    fn touch(&mut self) {
+        let now = now();
+        self.set_u32(MODIFIED, now);
+        self.set_u32(CHANGED, now);
+    }
+}
+
+// Where a block of a file is found: which pointer in the inode, then which pointer in each
+// indirect block on the way down.
+struct BlockPath {
+    root: usize,
+    indices: Vec<usize>
+}
+
+impl Shared {
+    fn read_raw(&self, inode: u32) -> FsResult<RawInode> {
+        let mut raw = RawInode([0; 128]);
+        self.cache.read_bytes(self.inode_offset(inode), &mut raw.0)?;
+        Ok(raw)
+    }
+
+    fn write_raw(&self, inode: u32, raw: &RawInode) -> FsResult<()> {
+        self.cache.write_bytes(self.inode_offset(inode), &raw.0)
+    }
+
+    fn pointers_per_block(&self) -> u64 {
+        self.layout.block_size / 4
+    }
+
+    fn sectors_per_block(&self) -> i64 {
+        (self.layout.block_size / 512) as i64
+    }
+
+    // Files are limited by how far the pointers reach and by the sector count, and to 2 GiB on
+    // volumes that don't allow any more.
+    fn max_file_size(&self) -> u64 {
+        let per_block = self.pointers_per_block();
+        let blocks = DIRECT_BLOCKS + per_block + per_block.pow(2) + per_block.pow(3);
+        let limit = (blocks * self.layout.block_size).min(u32::MAX as u64 * 512);
+        if self.layout.large_files { limit } else { limit.min(i32::MAX as u64) }
+    }
+
+    // New blocks go in the same group as their inode if there's room.
+    fn goal(&self, inode: u32) -> u32 {
+        let group = (inode - 1) / self.layout.inodes_per_group;
+        self.layout.first_data_block + group * self.layout.blocks_per_group
+    }
+
+    fn path(&self, logical: u64) -> FsResult<BlockPath> {
+        if logical < DIRECT_BLOCKS {
+            return Ok(BlockPath { root: logical as usize, indices: Vec::new() });
+        }
+        let per_block = self.pointers_per_block();
+        let mut remaining = logical - DIRECT_BLOCKS;
+        for (root, depth) in INDIRECT {
+            let span = per_block.pow(depth);
+            if remaining < span {
+                let indices = (0..depth).rev().map(|level| (remaining / per_block.pow(level) % per_block) as usize).collect();
+                return Ok(BlockPath { root, indices });
+            }
+            remaining -= span;
+        }
+        Err(Errno::EFBIG)
+    }
+
+    fn pointer(&self, block: u32, index: usize) -> FsResult<u32> {
+        let mut bytes = [0; 4];
+        self.cache.read_bytes(self.layout.block_offset(block) + index as u64 * 4, &mut bytes)?;
+        match u32::from_le_bytes(bytes) {
+            pointer if pointer != 0 && !self.layout.is_block(pointer) => Err(Errno::EIO),
+            pointer => Ok(pointer)
+        }
+    }
+
+    fn set_pointer(&self, block: u32, index: usize, pointer: u32) -> FsResult<()> {
+        self.cache.write_bytes(self.layout.block_offset(block) + index as u64 * 4, &pointer.to_le_bytes())
+    }
+
+    // Where a block of a file is on the volume, or 0 if it's a hole.
+    fn block_at(&self, raw: &RawInode, logical: u64) -> FsResult<u32> {
+        let path = self.path(logical)?;
+        let mut block = raw.block(path.root);
+        for index in path.indices {
+            if block == 0 {
+                return Ok(0);
+            }
+            if !self.layout.is_block(block) {
+                return Err(Errno::EIO);
+            }
+            block = self.pointer(block, index)?;
+        }
+        if block != 0 && !self.layout.is_block(block) {
+            return Err(Errno::EIO);
+        }
+        Ok(block)
+    }
+
+    // Like `block_at`, but fills in the block and any indirect blocks on the way to it.
+    fn map_block(&self, groups: &mut Groups, raw: &mut RawInode, inode: u32, logical: u64) -> FsResult<u32> {
+        let path = self.path(logical)?;
+        let goal = self.goal(inode);
+        let mut block = raw.block(path.root);
+        if block == 0 {
+            block = self.allocate_block(groups, goal)?;
+            raw.set_block(path.root, block);
+            raw.add_sectors(self.sectors_per_block());
+        } else if !self.layout.is_block(block) {
+            return Err(Errno::EIO);
+        }
+        for index in path.indices {
+            let mut next = self.pointer(block, index)?;
+            if next == 0 {
+                next = self.allocate_block(groups, goal)?;
+                self.set_pointer(block, index, next)?;
+                raw.add_sectors(self.sectors_per_block());
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    // Frees what's under a pointer `depth` levels of indirect blocks above the data, apart from
+    // the first `keep` blocks of the file it covers. Returns how many blocks went.
+    fn free_tree(&self, groups: &mut Groups, block: u32, depth: u32, keep: u64) -> FsResult<i64> {
+        if !self.layout.is_block(block) {
+            return Err(Errno::EIO);
+        }
+        let mut freed = 0;
+        if depth > 0 {
+            let span = self.pointers_per_block().pow(depth - 1);
+            let pointers: Vec<u32> = self.cache.with_block(block as u64, |data| {
+                data.chunks(4).map(|pointer| u32::from_le_bytes(pointer.try_into().unwrap())).collect()
+            })?;
+            for (index, &pointer) in pointers.iter().enumerate().skip((keep / span) as usize) {
+                if pointer == 0 {
+                    continue;
+                }
+                let child_keep = keep.saturating_sub(index as u64 * span);
+                freed += self.free_tree(groups, pointer, depth - 1, child_keep)?;
+                if child_keep == 0 && keep > 0 {
+                    self.set_pointer(block, index, 0)?;
+                }
+            }
+        }
+        if keep == 0 {
+            self.free_block(groups, block)?;
+            freed += 1;
+        }
+        Ok(freed)
+    }
+
+    // Frees every block of a file after the first `keep`.
+    fn free_blocks(&self, groups: &mut Groups, raw: &mut RawInode, keep: u64) -> FsResult<()> {
+        // Fast symlinks have their target where the pointers would be.
+        if raw.kind() == FileType::Symlink && self.is_fast_symlink(raw) {
+            return Ok(());
+        }
+        let mut freed = 0;
+        for index in keep.min(DIRECT_BLOCKS) as usize..DIRECT_BLOCKS as usize {
+            if raw.block(index) != 0 {
+                self.free_block(groups, raw.block(index))?;
+                raw.set_block(index, 0);
+                freed += 1;
+            }
+        }
+        let mut start = DIRECT_BLOCKS;
+        for (root, depth) in INDIRECT {
+            let span = self.pointers_per_block().pow(depth);
+            let child_keep = keep.saturating_sub(start);
+            if child_keep < span && raw.block(root) != 0 {
+                freed += self.free_tree(groups, raw.block(root), depth, child_keep)?;
+                if child_keep == 0 {
+                    raw.set_block(root, 0);
+                }
+            }
+            start += span;
+        }
+        raw.add_sectors(-freed * self.sectors_per_block());
+        Ok(())
+    }
+
+    fn is_fast_symlink(&self, raw: &RawInode) -> bool {
+        let attribute_sectors = if raw.u32(FILE_ACL) != 0 { self.sectors_per_block() } else { 0 };
+        raw.u32(SECTORS) as i64 == attribute_sectors
+    }
+
+    // Blocks of extended attributes can be shared, and count how many inodes use them.
+    fn release_attributes(&self, groups: &mut Groups, raw: &mut RawInode) -> FsResult<()> {
+        let block = raw.u32(FILE_ACL);
+        if block == 0 {
+            return Ok(());
+        }
+        if !self.layout.is_block(block) {
+            return Err(Errno::EIO);
+        }
+        let references = self.cache.with_block_mut(block as u64, |data| {
+            let references = u32_at(data, 4).saturating_sub(1);
+            data[4..8].copy_from_slice(&references.to_le_bytes());
+            references
+        })?;
+        if references == 0 {
+            self.free_block(groups, block)?;
+        }
+        raw.set_u32(FILE_ACL, 0);
+        raw.add_sectors(-self.sectors_per_block());
+        Ok(())
+    }
+
+    pub(super) fn release_orphans(&self, groups: &mut Groups) -> FsResult<()> {
+        let orphans: Vec<u32> = self.orphans.lock().drain(..).collect();
+        for inode in orphans {
+            let mut raw = self.read_raw(inode)?;
+            self.free_blocks(groups, &mut raw, 0)?;
+            self.release_attributes(groups, &mut raw)?;
+            raw.set_u32(DELETED, now());
+            self.write_raw(inode, &raw)?;
+            self.free_inode(groups, inode, raw.kind() == FileType::Directory)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_name(name: &str) -> FsResult<()> {
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    if name.is_empty() || name == "." || name == ".." || name.contains(|c| c == '/' || c == '\0') {
+        return Err(Errno::EINVAL);
+    }
+    Ok(())
+}
+
+pub(super) struct Ext2Inode {
+    shared: Arc<Shared>,
+    number: u32,
+    raw: SleepMutex<RawInode>
+}
+
+impl Ext2Inode {
+    // Only one inode is ever made for a file, so everyone sees the same size and blocks. An
+    // entry that leads to an inode that isn't in use means the volume is damaged.
+    pub(super) fn load(shared: &Arc<Shared>, number: u32) -> FsResult<Arc<Self>> {
+        if !shared.layout.is_inode(number) {
+            return Err(Errno::EIO);
+        }
+        let mut inodes = shared.inodes.lock();
+        if let Some(existing) = inodes.get(&number).and_then(Weak::upgrade) {
+            return Ok(existing);
+        }
+        let raw = shared.read_raw(number)?;
+        if raw.links() == 0 || raw.u16(MODE) == 0 {
+            return Err(Errno::EIO);
+        }
+        let inode = Arc::new(Ext2Inode { shared: shared.clone(), number, raw: SleepMutex::new(raw) });
+        inodes.insert(number, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    pub(super) fn is_directory(&self) -> bool {
+        self.raw.lock().kind() == FileType::Directory
+    }
+
+    fn write_raw(&self, raw: &RawInode) -> FsResult<()> {
+        self.shared.write_raw(self.number, raw)
+    }
+
+    // The blocks of a directory as they are on the volume. Directories don't have holes.
+    fn directory_blocks(&self, raw: &RawInode) -> FsResult<Vec<u32>> {
+        if raw.kind() != FileType::Directory {
+            return Err(Errno::ENOTDIR);
+        }
+        let block_size = self.shared.layout.block_size;
+        (0..raw.size().div_ceil(block_size)).map(|logical| {
+            match self.shared.block_at(raw, logical)? {
+                0 => Err(Errno::EIO),
+                block => Ok(block)
+            }
+        }).collect()
+    }
+
+    fn block_entries(&self, block: u32) -> FsResult<Vec<Entry>> {
+        let file_types = self.shared.layout.file_types;
+        self.shared.cache.with_block(block as u64, |data| directory::parse(data, file_types))?
+    }
+
+    // Every entry that's in use, apart from `.` and `..`, along with the block it's in.
+    fn entries(&self, raw: &RawInode) -> FsResult<Vec<(u32, Entry)>> {
+        let mut entries = Vec::new();
+        for block in self.directory_blocks(raw)? {
+            let used = self.block_entries(block)?.into_iter().filter(|entry| entry.inode != 0 && !entry.is_dot());
+            entries.extend(used.map(|entry| (block, entry)));
+        }
+        Ok(entries)
+    }
+
+    fn find(&self, raw: &RawInode, name: &str) -> FsResult<(u32, Entry)> {
+        self.entries(raw)?.into_iter().find(|(_, entry)| entry.name == name.as_bytes()).ok_or(Errno::ENOENT)
+    }
+
+    // Fails with EEXIST if the name is taken, since everything that calls it is adding one.
+    fn check_free(&self, raw: &RawInode, name: &str) -> FsResult<()> {
+        match self.find(raw, name) {
+            Ok(_) => Err(Errno::EEXIST),
+            Err(Errno::ENOENT) => Ok(()),
+            Err(errno) => Err(errno)
+        }
+    }
+
+    fn add_entry(&self, groups: &mut Groups, raw: &mut RawInode, name: &str, inode: u32, kind: FileType) -> FsResult<()> {
+        let layout = &self.shared.layout;
+        let file_type = directory::file_type(kind);
+        raw.drop_index();
+        raw.touch();
+        for block in self.directory_blocks(raw)? {
+            let inserted = self.shared.cache.with_block_mut(block as u64, |data| {
+                directory::insert(data, inode, name.as_bytes(), file_type, layout.file_types)
+            })??;
+            if inserted {
+                return Ok(());
+            }
+        }
+        let size = raw.size();
+        let block = self.shared.map_block(groups, raw, self.number, size / layout.block_size)?;
+        let mut data = directory::empty_block(layout.block_size as usize);
+        directory::insert(&mut data, inode, name.as_bytes(), file_type, layout.file_types)?;
+        self.shared.cache.write_bytes(layout.block_offset(block), &data)?;
+        raw.set_size(size + layout.block_size);
+        Ok(())
+    }
+
+    // Sets up what a new inode holds: the first block of a directory, or a symlink's target.
+    fn fill(&self, groups: &mut Groups, raw: &mut RawInode, number: u32, target: Option<&str>) -> FsResult<()> {
+        let layout = &self.shared.layout;
+        match (raw.kind(), target) {
+            (FileType::Directory, _) => {
+                let block = self.shared.map_block(groups, raw, number, 0)?;
+                let data = directory::dot_block(layout.block_size as usize, number, self.number, layout.file_types);
+                self.shared.cache.write_bytes(layout.block_offset(block), &data)?;
+                raw.set_size(layout.block_size);
+            }
+            (FileType::Symlink, Some(target)) if target.len() < FAST_SYMLINK_LENGTH => {
+                raw.0[BLOCKS..BLOCKS + target.len()].copy_from_slice(target.as_bytes());
+                raw.set_size(target.len() as u64);
+            }
+            (FileType::Symlink, Some(target)) => {
+                let block = self.shared.map_block(groups, raw, number, 0)?;
+                self.shared.cache.write_bytes(layout.block_offset(block), target.as_bytes())?;
+                raw.set_size(target.len() as u64);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Makes a new inode and gives it a name here. If anything fails along the way, whatever was
+    // taken for it is given back.
+    fn add_child(&self, name: &str, mode: u16, target: Option<&str>) -> FsResult<Arc<dyn Inode>> {
+        check_name(name)?;
+        if target.is_some_and(|target| target.len() > self.shared.layout.block_size as usize) {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        self.shared.check_writable()?;
+        let mut groups = self.shared.groups.lock();
+        let mut raw = self.raw.lock();
+        // A directory that's been deleted can't get anything new in it.
+        if raw.links() == 0 {
+            return Err(Errno::ENOENT);
+        }
+        self.check_free(&raw, name)?;
+        let directory = mode & MODE_TYPE == MODE_DIRECTORY;
+        if directory && raw.links() >= LINK_MAX {
+            return Err(Errno::EMLINK);
+        }
+
+        let number = self.shared.allocate_inode(&mut groups, self.number, directory)?;
+        let mut child = RawInode::new(mode, if directory { 2 } else { 1 });
+        let kind = child.kind();
+        let result = self.fill(&mut groups, &mut child, number, target)
+            .and_then(|_| self.add_entry(&mut groups, &mut raw, name, number, kind));
+        if let Err(errno) = result {
+            self.shared.free_blocks(&mut groups, &mut child, 0)?;
+            self.shared.free_inode(&mut groups, number, directory)?;
+            self.write_raw(&raw)?;
+            return Err(errno);
+        }
+        self.shared.write_raw(number, &child)?;
+        if directory {
+            let links = raw.links() + 1;
+            raw.set_links(links);
+        }
+        self.write_raw(&raw)?;
+        Ok(Ext2Inode::load(&self.shared, number)?)
+    }
+
+    fn is_empty(&self, raw: &RawInode) -> FsResult<bool> {
+        Ok(self.entries(raw)?.is_empty())
+    }
+
+    fn remove(&self, name: &str, directory: bool) -> FsResult<()> {
+        self.shared.check_writable()?;
+        let mut groups = self.shared.groups.lock();
+        let mut raw = self.raw.lock();
+        let (block, entry) = self.find(&raw, name)?;
+        let child = Ext2Inode::load(&self.shared, entry.inode)?;
+        {
+            let mut child_raw = child.raw.lock();
+            match (child_raw.kind() == FileType::Directory, directory) {
+                (true, false) => return Err(Errno::EISDIR),
+                (false, true) => return Err(Errno::ENOTDIR),
+                (true, true) if !child.is_empty(&child_raw)? => return Err(Errno::ENOTEMPTY),
+                _ => {}
+            }
+            let file_types = self.shared.layout.file_types;
+            self.shared.cache.with_block_mut(block as u64, |data| directory::remove(data, entry.offset, file_types))??;
+            // A directory goes along with its `.` entry and the `..` entry that counted towards
+            // its parent.
+            if directory {
+                child_raw.set_links(0);
+                let links = raw.links().saturating_sub(1);
+                raw.set_links(links);
+            } else {
+                let links = child_raw.links().saturating_sub(1);
+                child_raw.set_links(links);
+            }
+            child.write_raw(&child_raw)?;
+        }
+        raw.drop_index();
+        raw.touch();
+        self.write_raw(&raw)?;
+        // If that was the last link and nobody has it open, it goes right away.
+        drop(child);
+        self.shared.release_orphans(&mut groups)
+    }
+
+    // Whatever's past the end of the file in its last block has to read as zeroes once the file
+    // grows over it.
+    fn zero_tail(&self, raw: &RawInode) -> FsResult<()> {
+        let layout = &self.shared.layout;
+        let size = raw.size();
+        let within = size % layout.block_size;
+        if within == 0 {
+            return Ok(());
+        }
+        match self.shared.block_at(raw, size / layout.block_size)? {
+            0 => Ok(()),
+            block => self.shared.cache.write_bytes(layout.block_offset(block) + within, &vec![0; (layout.block_size - within) as usize])
+        }
+    }
+
+    fn check_file(raw: &RawInode) -> FsResult<()> {
+        match raw.kind() {
+            FileType::File => Ok(()),
+            FileType::Directory => Err(Errno::EISDIR),
+            _ => Err(Errno::EINVAL)
+        }
+    }
+}
+
+// Inodes that lost their last link are only freed once nobody has them open. That has to wait
+// for the next time the groups are locked, since they may well be locked right now.
+impl Drop for Ext2Inode {
+    fn drop(&mut self) {
+        if self.raw.get_mut().links() == 0 {
+            self.shared.orphans.lock().push(self.number);
+        }
+        let mut inodes = self.shared.inodes.lock();
+        if inodes.get(&self.number).is_some_and(|inode| ptr::eq(inode.as_ptr(), self)) {
+            inodes.remove(&self.number);
+        }
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> FsResult<Metadata> {
+        let raw = self.raw.lock();
+        Ok(Metadata {
+            device: self.shared.device,
+            inode: self.number as u64,
+            kind: raw.kind(),
+            mode: raw.u16(MODE) & 0o7777,
+            links: raw.links() as u32,
+            uid: raw.u16(UID) as u32 | ((raw.u16(UID_HIGH) as u32) << 16),
+            gid: raw.u16(GID) as u32 | ((raw.u16(GID_HIGH) as u32) << 16),
+            size: raw.size(),
+            block_size: self.shared.layout.block_size as u32,
+            blocks: raw.u32(SECTORS) as u64,
+            accessed: Duration::from_secs(raw.u32(ACCESSED) as u64),
+            modified: Duration::from_secs(raw.u32(MODIFIED) as u64),
+            changed: Duration::from_secs(raw.u32(CHANGED) as u64)
+        })
+    }
+
+    // Holes read as zeroes.
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let raw = self.raw.lock();
+        Self::check_file(&raw)?;
+        let size = raw.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let block_size = self.shared.layout.block_size;
+        let length = (size - offset).min(buffer.len() as u64) as usize;
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let within = position % block_size;
+            let chunk = ((block_size - within) as usize).min(length - done);
+            match self.shared.block_at(&raw, position / block_size)? {
+                0 => buffer[done..done + chunk].fill(0),
+                block => self.shared.cache.read_bytes(self.shared.layout.block_offset(block) + within, &mut buffer[done..done + chunk])?
+            }
+            done += chunk;
+        }
+        Ok(length)
+    }
+
+    // Running out of space part of the way through makes for a short write. Writing past the
+    // end leaves a hole rather than filling the gap in.
+    fn write_at(&self, offset: u64, data: &[u8]) -> FsResult<usize> {
+        self.shared.check_writable()?;
+        let mut groups = self.shared.groups.lock();
+        let mut raw = self.raw.lock();
+        Self::check_file(&raw)?;
+        offset.checked_add(data.len() as u64).filter(|&end| end <= self.shared.max_file_size()).ok_or(Errno::EFBIG)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let size = raw.size();
+        if offset > size {
+            self.zero_tail(&raw)?;
+        }
+        let block_size = self.shared.layout.block_size;
+        let mut written = 0;
+        let mut error = None;
+        while written < data.len() {
+            let position = offset + written as u64;
+            let within = position % block_size;
+            let chunk = ((block_size - within) as usize).min(data.len() - written);
+            let block = match self.shared.map_block(&mut groups, &mut raw, self.number, position / block_size) {
+                Ok(block) => block,
+                Err(errno) => {
+                    error = Some(errno);
+                    break;
+                }
+            };
+            self.shared.cache.write_bytes(self.shared.layout.block_offset(block) + within, &data[written..written + chunk])?;
+            written += chunk;
+        }
+        if written > 0 {
+            raw.set_size(size.max(offset + written as u64));
+            raw.touch();
+        }
+        // Indirect blocks may have been added even if nothing was written.
+        self.write_raw(&raw)?;
+        match error {
+            Some(Errno::ENOSPC) if written > 0 => Ok(written),
+            Some(errno) => Err(errno),
+            None => Ok(written)
+        }
+    }
+
+    // Growing a file leaves a hole, so it doesn't take up any space until it's written.
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        self.shared.check_writable()?;
+        if size > self.shared.max_file_size() {
+            return Err(Errno::EFBIG);
+        }
+        let mut groups = self.shared.groups.lock();
+        let mut raw = self.raw.lock();
+        Self::check_file(&raw)?;
+        if size > raw.size() {
+            self.zero_tail(&raw)?;
+        } else {
+            let block_size = self.shared.layout.block_size;
+            self.shared.free_blocks(&mut groups, &mut raw, size.div_ceil(block_size))?;
+        }
+        raw.set_size(size);
+        raw.touch();
+        self.write_raw(&raw)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let _groups = self.shared.groups.lock();
+        let raw = self.raw.lock();
+        let (_, entry) = self.find(&raw, name)?;
+        Ok(Ext2Inode::load(&self.shared, entry.inode)?)
+    }
+
+    // Without file types in the directory, each inode has to be read to find out what it is.
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        let _groups = self.shared.groups.lock();
+        let raw = self.raw.lock();
+        self.entries(&raw)?.into_iter().map(|(_, entry)| {
+            let kind = match entry.kind {
+                Some(kind) => kind,
+                None => self.shared.read_raw(entry.inode)?.kind()
+            };
+            Ok(DirEntry { name: entry.display_name(), inode: entry.inode as u64, kind })
+        }).collect()
+    }
+
+    // Everything belongs to root, since there's nobody else yet.
+    fn create(&self, name: &str, kind: FileType, mode: u16) -> FsResult<Arc<dyn Inode>> {
+        let mode_type = match kind {
+            FileType::File => MODE_FILE,
+            FileType::Directory => MODE_DIRECTORY,
+            _ => return Err(Errno::EINVAL)
+        };
+        self.add_child(name, mode_type | (mode & 0o7777), None)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> FsResult<Arc<dyn Inode>> {
+        if target.is_empty() {
+            return Err(Errno::ENOENT);
+        }
+        self.add_child(name, MODE_SYMLINK | 0o777, Some(target))
+    }
+
+    fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> FsResult<()> {
+        let metadata = inode.metadata()?;
+        if metadata.device != self.shared.device {
+            return Err(Errno::EXDEV);
+        }
+        if metadata.kind == FileType::Directory {
+            return Err(Errno::EPERM);
+        }
+        check_name(name)?;
+        self.shared.check_writable()?;
+        let target = self.shared.inodes.lock().get(&(metadata.inode as u32)).and_then(Weak::upgrade).ok_or(Errno::ENOENT)?;
+
+        let mut groups = self.shared.groups.lock();
+        let mut raw = self.raw.lock();
+        if raw.links() == 0 {
+            return Err(Errno::ENOENT);
+        }
+        self.check_free(&raw, name)?;
+        let mut target_raw = target.raw.lock();
+        if target_raw.links() == 0 {
+            return Err(Errno::ENOENT);
+        }
+        if target_raw.links() >= LINK_MAX {
+            return Err(Errno::EMLINK);
+        }
+        self.add_entry(&mut groups, &mut raw, name, target.number, target_raw.kind())?;
+        self.write_raw(&raw)?;
+        let links = target_raw.links() + 1;
+        target_raw.set_links(links);
+        target.write_raw(&target_raw)
+    }
+
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        self.remove(name, false)
+    }
+
+    fn rmdir(&self, name: &str) -> FsResult<()> {
+        self.remove(name, true)
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        let raw = self.raw.lock();
+        if raw.kind() != FileType::Symlink {
+            return Err(Errno::EINVAL);
+        }
+        let length = raw.size() as usize;
+        if self.shared.is_fast_symlink(&raw) {
+            if length >= FAST_SYMLINK_LENGTH {
+                return Err(Errno::EIO);
+            }
+            return Ok(String::from_utf8_lossy(&raw.0[BLOCKS..BLOCKS + length]).into());
+        }
+        if length > self.shared.layout.block_size as usize {
+            return Err(Errno::EIO);
+        }
+        let block = match self.shared.block_at(&raw, 0)? {
+            0 => return Err(Errno::EIO),
+            block => block
+        };
+        let mut target = vec![0; length];
+        self.shared.cache.read_bytes(self.shared.layout.block_offset(block), &mut target)?;
+        Ok(String::from_utf8_lossy(&target).into())
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        self.shared.sync()
+    }
+}
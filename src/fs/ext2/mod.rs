@@ -0,0 +1,263 @@
+// ext2, for a root filesystem with owners, permissions and symlinks that can be made and checked
+// on Linux with mke2fs and e2fsck. The volume is split into block groups, each with a bitmap of
+// its blocks, a bitmap of its inodes and a table of the inodes themselves. Files find their data
+// through twelve direct block pointers followed by single, double and triple indirect ones.
+//
+// Features from ext3 and ext4 that change how anything is found on disk make a volume impossible
+// to mount, and ones that only change how it has to be written make it read only.
+
+mod directory;
+mod groups;
+mod inode;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::block::{BlockDevice, BufferCache, SECTOR_SIZE};
+use crate::sync::SleepMutex;
+use crate::syscall::Errno;
+use groups::Groups;
+use inode::Ext2Inode;
+use super::{allocate_device_id, FileSystem, FsResult, Inode};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+// How much of the volume is kept in memory.
+const CACHE_SIZE: usize = 512 * 1024;
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+
+const SUPERBLOCK_FREE_BLOCKS: u64 = 12;
+const SUPERBLOCK_FREE_INODES: u64 = 16;
+
+// Directory entries say what kind of file they name.
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+// Regular files can be bigger than 2 GiB.
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_BTREE_DIR: u32 = 0x4;
+const RO_COMPAT_SUPPORTED: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR;
+
+// Where a block group keeps its bitmaps and inodes, which never moves.
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32
+}
+
+// Everything about the volume that's fixed when it's made, from the superblock and the group
+// descriptors. Block numbers are in blocks, and offsets in bytes.
+struct Layout {
+    block_size: u64,
+    block_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_count: u32,
+    inode_size: u64,
+    // Inodes below this one are reserved for the filesystem itself.
+    first_inode: u32,
+    descriptors: u64,
+    groups: Vec<Group>,
+    file_types: bool,
+    large_files: bool
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+impl Layout {
+    // Checks the block size and group geometry against each other and the device, and returns
+    // whether the superblock lists read only features we don't know, which makes it read only.
+    fn parse(superblock: &[u8], device_sectors: u64) -> FsResult<(Self, bool)> {
+        if u16_at(superblock, 56) != MAGIC {
+            return Err(Errno::EINVAL);
+        }
+        let inode_count = u32_at(superblock, 0);
+        let block_count = u32_at(superblock, 4);
+        let first_data_block = u32_at(superblock, 20);
+        let log_block_size = u32_at(superblock, 24);
+        let blocks_per_group = u32_at(superblock, 32);
+        let inodes_per_group = u32_at(superblock, 40);
+        // Revision 0 has none of the fields from here on, and fixed values for them.
+        let (first_inode, inode_size, incompat, ro_compat) = match u32_at(superblock, 76) {
+            0 => (11, 128, 0, 0),
+            _ => (u32_at(superblock, 84), u16_at(superblock, 88) as u64, u32_at(superblock, 96), u32_at(superblock, 100))
+        };
+        // Linux can't mount blocks bigger than a page either.
+        if log_block_size > 2 || incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(Errno::EINVAL);
+        }
+        let block_size = 1024 << log_block_size;
+        if blocks_per_group == 0
+            || blocks_per_group as u64 > block_size * 8
+            || inodes_per_group == 0
+            || inodes_per_group as u64 > block_size * 8
+            || !inode_size.is_power_of_two()
+            || !(128..=block_size).contains(&inode_size)
+            || first_data_block >= block_count
+            || block_count as u64 * block_size > device_sectors * SECTOR_SIZE as u64
+        {
+            return Err(Errno::EINVAL);
+        }
+        let group_count = (block_count - first_data_block).div_ceil(blocks_per_group);
+        if inode_count > group_count * inodes_per_group || first_inode <= ROOT_INODE || first_inode > inode_count {
+            return Err(Errno::EINVAL);
+        }
+        let layout = Layout {
+            block_size,
+            block_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_count,
+            inode_size,
+            first_inode,
+            descriptors: (first_data_block as u64 + 1) * block_size,
+            groups: Vec::new(),
+            file_types: incompat & INCOMPAT_FILETYPE != 0,
+            large_files: ro_compat & RO_COMPAT_LARGE_FILE != 0
+        };
+        Ok((layout, ro_compat & !RO_COMPAT_SUPPORTED != 0))
+    }
+
+    fn group_count(&self) -> u32 {
+        (self.block_count - self.first_data_block).div_ceil(self.blocks_per_group)
+    }
+
+    // The last group is usually cut short.
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        let start = self.first_data_block + group * self.blocks_per_group;
+        (self.block_count - start).min(self.blocks_per_group)
+    }
+
+    fn is_block(&self, block: u32) -> bool {
+        (self.first_data_block..self.block_count).contains(&block)
+    }
+
+    fn is_inode(&self, inode: u32) -> bool {
+        (1..=self.inode_count).contains(&inode)
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size
+    }
+}
+
+struct Shared {
+    device: u64,
+    layout: Layout,
+    cache: Arc<BufferCache>,
+    read_only: bool,
+    // Each group's free block, free inode and directory counts, which change along with the
+    // bitmaps. Directory changes hold it too, so nobody reads a block of entries mid rewrite.
+    groups: SleepMutex<Groups>,
+    // Inodes that are loaded, so two opens of a number share one copy of the on-disk inode.
+    inodes: SleepMutex<BTreeMap<u32, Weak<Ext2Inode>>>,
+    // Inodes that lost their last link, which can be freed now that nobody has them open.
+    orphans: Mutex<Vec<u32>>
+}
+
+impl Shared {
+    fn check_writable(&self) -> FsResult<()> {
+        if self.read_only { Err(Errno::EROFS) } else { Ok(()) }
+    }
+
+    // Writes the free counts back to the superblock, along with everything cached. Only the
+    // first copy of the superblock is kept up to date, which is what Linux does too.
+    fn sync(&self) -> FsResult<()> {
+        if !self.read_only {
+            let mut groups = self.groups.lock();
+            self.release_orphans(&mut groups)?;
+            self.cache.write_bytes(SUPERBLOCK_OFFSET + SUPERBLOCK_FREE_BLOCKS, &groups.free_blocks.to_le_bytes())?;
+            self.cache.write_bytes(SUPERBLOCK_OFFSET + SUPERBLOCK_FREE_INODES, &groups.free_inodes.to_le_bytes())?;
+        }
+        self.cache.sync()
+    }
+}
+
+pub struct Ext2Fs {
+    shared: Arc<Shared>,
+    root: Arc<Ext2Inode>
+}
+
+impl Ext2Fs {
+    pub fn new(device: Arc<dyn BlockDevice>) -> FsResult<Arc<Self>> {
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        device.read_sectors(SUPERBLOCK_OFFSET / SECTOR_SIZE as u64, &mut superblock)?;
+        let (mut layout, unsupported) = Layout::parse(&superblock, device.sector_count())?;
+        let read_only = unsupported || device.read_only();
+        let block_size = layout.block_size as usize;
+        let cache = BufferCache::new(device, block_size, CACHE_SIZE / block_size);
+
+        let mut descriptors = vec![0; layout.group_count() as usize * GROUP_DESCRIPTOR_SIZE as usize];
+        cache.read_bytes(layout.descriptors, &mut descriptors)?;
+        let inode_table_blocks = (layout.inodes_per_group as u64 * layout.inode_size).div_ceil(layout.block_size);
+        for descriptor in descriptors.chunks(GROUP_DESCRIPTOR_SIZE as usize) {
+            let group = Group { block_bitmap: u32_at(descriptor, 0), inode_bitmap: u32_at(descriptor, 4), inode_table: u32_at(descriptor, 8) };
+            let table_end = group.inode_table as u64 + inode_table_blocks;
+            if !layout.is_block(group.block_bitmap) || !layout.is_block(group.inode_bitmap) || table_end > layout.block_count as u64 {
+                return Err(Errno::EINVAL);
+            }
+            layout.groups.push(group);
+        }
+
+        let shared = Arc::new(Shared {
+            device: allocate_device_id(),
+            layout,
+            cache,
+            read_only,
+            groups: SleepMutex::new(Groups::default()),
+            inodes: SleepMutex::new(BTreeMap::new()),
+            orphans: Mutex::new(Vec::new())
+        });
+        *shared.groups.lock() = Groups::load(&descriptors);
+        let root = Ext2Inode::load(&shared, ROOT_INODE)?;
+        if !root.is_directory() {
+            return Err(Errno::EINVAL);
+        }
+        Ok(Arc::new(Ext2Fs { shared, root }))
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.shared.layout.block_size
+    }
+
+    pub fn free_blocks(&self) -> u32 {
+        self.shared.groups.lock().free_blocks
+    }
+
+    pub fn free_inodes(&self) -> u32 {
+        self.shared.groups.lock().free_inodes
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.shared.read_only
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        self.shared.sync()
+    }
+}
+
+// Writes back the free counts and cached blocks on the way out.
+impl Drop for Ext2Fs {
+    fn drop(&mut self) {
+        let _ = self.shared.sync();
+    }
+}
@@ -0,0 +1,395 @@
+// Directory entries. Every file has a short 8.3 name in an entry of its own, which is all DOS
+// ever knew about. A long name is stored in extra entries in front of it, 13 UTF-16 units at a
+// time and last part first, each carrying a checksum of the short name so that ones left behind
+// by something that only knows about short names can be told apart.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+use crate::fs::{FsResult, MAX_NAME_LENGTH};
+use crate::syscall::Errno;
+use super::u16_at;
+
+pub(super) const ENTRY_SIZE: usize = 32;
+
+pub(super) const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+pub(super) const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+pub(super) const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+
+// The first byte of the name says whether the entry is free. Everything after an end marker
+// is free as well.
+pub(super) const DELETED: u8 = 0xE5;
+pub(super) const END: u8 = 0x00;
+// A short name really starting with 0xE5 is stored with this instead.
+const ESCAPED_DELETED: u8 = 0x05;
+
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_ENTRY_UNITS: usize = 13;
+// Where each long name entry keeps its share of the name.
+const LONG_ENTRY_OFFSETS: [usize; LONG_ENTRY_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+// Windows NT stores names that are all lowercase as short names with these set, rather than
+// giving them a long name.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+// Anything else is allowed in a long name, but has to be replaced in a short one.
+const SHORT_NAME_SPECIALS: &[u8] = b"$%'-_@~`!(){}^#&";
+const FORBIDDEN: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+// Timestamps count from the start of 1980, with two second resolution. There's no clock to tell
+// the date yet, so anything from before then is stored as the very start.
+const FAT_EPOCH: u64 = 315_532_800;
+const FAT_LAST: u64 = 4_354_819_199;
+const SECONDS_PER_DAY: u64 = 86_400;
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ShortEntry(pub [u8; ENTRY_SIZE]);
+
+impl ShortEntry {
+    pub fn new(name: [u8; 11], case: u8, attributes: u8, now: Duration) -> Self {
+        let mut entry = ShortEntry([0; ENTRY_SIZE]);
+        entry.0[0..11].copy_from_slice(&name);
+        entry.0[11] = attributes;
+        entry.0[12] = case;
+        let (date, time) = encode_timestamp(now);
+        for offset in [14, 22] {
+            entry.0[offset..offset + 2].copy_from_slice(&time.to_le_bytes());
+        }
+        for offset in [16, 18, 24] {
+            entry.0[offset..offset + 2].copy_from_slice(&date.to_le_bytes());
+        }
+        entry
+    }
+
+    pub fn short_name(&self) -> [u8; 11] {
+        self.0[0..11].try_into().unwrap()
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.0[11]
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes() & ATTRIBUTE_DIRECTORY != 0
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        ((u16_at(&self.0, 20) as u32) << 16) | u16_at(&self.0, 26) as u32
+    }
+
+    pub fn set_first_cluster(&mut self, cluster: u32) {
+        self.0[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        self.0[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    }
+
+    pub fn size(&self) -> u32 {
+        u32::from_le_bytes(self.0[28..32].try_into().unwrap())
+    }
+
+    pub fn set_size(&mut self, size: u32) {
+        self.0[28..32].copy_from_slice(&size.to_le_bytes());
+    }
+
+    pub fn modified(&self) -> Duration {
+        decode_timestamp(u16_at(&self.0, 24), u16_at(&self.0, 22))
+    }
+
+    // Only the date is kept for the last access.
+    pub fn accessed(&self) -> Duration {
+        decode_timestamp(u16_at(&self.0, 18), 0)
+    }
+
+    // Changing a file also marks it for backing up.
+    pub fn touch_modified(&mut self, now: Duration) {
+        let (date, time) = encode_timestamp(now);
+        self.0[22..24].copy_from_slice(&time.to_le_bytes());
+        self.0[24..26].copy_from_slice(&date.to_le_bytes());
+        self.0[18..20].copy_from_slice(&date.to_le_bytes());
+        if !self.is_directory() {
+            self.0[11] |= ATTRIBUTE_ARCHIVE;
+        }
+    }
+
+    // The 8.3 name as it's shown, with the padding taken out and the dot put in.
+    pub fn display_name(&self) -> String {
+        let mut name = self.short_name();
+        if name[0] == ESCAPED_DELETED {
+            name[0] = DELETED;
+        }
+        let case = self.0[12];
+        let part = |bytes: &[u8], lowercase: bool| -> String {
+            let trimmed = &bytes[..bytes.iter().rposition(|&byte| byte != b' ').map_or(0, |end| end + 1)];
+            let case = |byte: u8| if lowercase { byte.to_ascii_lowercase() } else { byte };
+            trimmed.iter().map(|&byte| case(byte) as char).collect()
+        };
+        let base = part(&name[0..8], case & LOWERCASE_BASE != 0);
+        let extension = part(&name[8..11], case & LOWERCASE_EXTENSION != 0);
+        if extension.is_empty() { base } else { format!("{}.{}", base, extension) }
+    }
+
+    // Names are looked up without regard to case, by either the long or the short name.
+    pub fn matches(&self, long_name: &str, name: &str) -> bool {
+        long_name.eq_ignore_ascii_case(name) || self.display_name().eq_ignore_ascii_case(name)
+    }
+}
+
+// An entry as it was found in a directory, with its long name put back together if it has one.
+pub(super) struct Entry {
+    pub name: String,
+    pub short: ShortEntry,
+    // The long name entries come first, and the short entry is the last slot.
+    pub first_slot: usize,
+    pub slot: usize
+}
+
+struct LongName {
+    checksum: u8,
+    // The next part expected, counting down to the first.
+    remaining: u8,
+    units: Vec<u16>,
+    first_slot: usize
+}
+
+pub(super) fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+// Goes through a directory's raw contents. Volume labels, `.` and `..` are left out, and so are
+// long names that don't belong to the short entry after them.
+pub(super) fn parse(data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut long: Option<LongName> = None;
+    for (slot, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        match raw[0] {
+            END => break,
+            DELETED => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+        if raw[11] == ATTRIBUTE_LONG_NAME {
+            let order = raw[0];
+            if order & LAST_LONG_ENTRY != 0 {
+                let count = order & 0x1F;
+                long = Some(LongName { checksum: raw[13], remaining: count, units: vec![0xFFFF; count as usize * LONG_ENTRY_UNITS], first_slot: slot });
+            }
+            match &mut long {
+                Some(name) if name.remaining != 0 && order & 0x1F == name.remaining && raw[13] == name.checksum => {
+                    name.remaining -= 1;
+                    let start = name.remaining as usize * LONG_ENTRY_UNITS;
+                    for (index, &offset) in LONG_ENTRY_OFFSETS.iter().enumerate() {
+                        name.units[start + index] = u16_at(raw, offset);
+                    }
+                }
+                _ => long = None
+            }
+            continue;
+        }
+
+        let short = ShortEntry(raw.try_into().unwrap());
+        let long = long.take().filter(|name| name.remaining == 0 && name.checksum == checksum(&short.short_name()));
+        if short.attributes() & ATTRIBUTE_VOLUME_ID != 0 || raw[0] == b'.' {
+            continue;
+        }
+        let (name, first_slot) = match long {
+            Some(long) => {
+                let length = long.units.iter().position(|&unit| unit == 0 || unit == 0xFFFF).unwrap_or(long.units.len());
+                (String::from_utf16_lossy(&long.units[..length]), long.first_slot)
+            }
+            None => (short.display_name(), slot)
+        };
+        entries.push(Entry { name, short, first_slot, slot });
+    }
+    entries
+}
+
+// The slot of the first run of free entries long enough for `count` of them. If there isn't one,
+// it's where the free entries at the end start, so that the directory can be grown to fit them.
+pub(super) fn free_slots(data: &[u8], count: usize) -> Result<usize, usize> {
+    let mut run_start = 0;
+    let mut run = 0;
+    for (slot, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        if raw[0] == END {
+            return if data.len() / ENTRY_SIZE - run_start >= count { Ok(run_start) } else { Err(run_start) };
+        }
+        if raw[0] == DELETED {
+            if run == 0 {
+                run_start = slot;
+            }
+            run += 1;
+            if run == count {
+                return Ok(run_start);
+            }
+        } else {
+            run = 0;
+            run_start = slot + 1;
+        }
+    }
+    Err(run_start)
+}
+
+// Entries that went in over the end marker push it along, to the slot after them. It only has to
+// be written if that slot is in the directory already, since anything added to the directory to
+// fit them starts out zeroed.
+pub(super) fn moved_end(data: &[u8], start: usize, count: usize) -> Option<usize> {
+    let end = data.chunks_exact(ENTRY_SIZE).position(|raw| raw[0] == END)?;
+    let after = start + count;
+    (end < after && after < data.len() / ENTRY_SIZE).then_some(after)
+}
+
+// Checks that a name can be stored at all.
+pub(super) fn check_name(name: &str) -> FsResult<()> {
+    if name.encode_utf16().count() > MAX_NAME_LENGTH {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    // Windows quietly drops dots and spaces from the end of names, so they'd never match.
+    if name.is_empty() || name.ends_with(|c| c == '.' || c == ' ') || name.chars().any(|c| c < ' ' || FORBIDDEN.contains(&c)) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(())
+}
+
+fn short_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || SHORT_NAME_SPECIALS.contains(&byte)
+}
+
+// Returns the short name, and which parts of it are lowercase, if the name fits into 8.3 as it
+// is and doesn't need a long one.
+pub(super) fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) => (base, extension),
+        None => (name, "")
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || !base.bytes().chain(extension.bytes()).all(short_name_byte) {
+        return None;
+    }
+    let mut case = 0;
+    for (part, flag) in [(base, LOWERCASE_BASE), (extension, LOWERCASE_EXTENSION)] {
+        let lower = part.bytes().any(|byte| byte.is_ascii_lowercase());
+        let upper = part.bytes().any(|byte| byte.is_ascii_uppercase());
+        match (lower, upper) {
+            (true, true) => return None,
+            (true, false) => case |= flag,
+            _ => {}
+        }
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    short.make_ascii_uppercase();
+    // 0xE5 can't start a name, but nothing short_name_byte allows is that.
+    Some((short, case))
+}
+
+// Makes up a short name for a long one the way Windows does, from as much of the name as fits
+// with a `~` and a number on the end to keep it unique.
+pub(super) fn generate_short_name(name: &str, taken: &[[u8; 11]]) -> FsResult<[u8; 11]> {
+    let convert = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| if c.is_ascii() && short_name_byte(c as u8) { c.to_ascii_uppercase() as u8 } else { b'_' })
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, extension) = match trimmed.rsplit_once('.') {
+        Some((base, extension)) => (convert(base), convert(extension)),
+        None => (convert(trimmed), Vec::new())
+    };
+    let base = if base.is_empty() { vec![b'_'] } else { base };
+
+    let mut short = [b' '; 11];
+    let extension_length = extension.len().min(3);
+    short[8..8 + extension_length].copy_from_slice(&extension[..extension_length]);
+    for number in 1..1_000_000 {
+        let tail = format!("~{}", number);
+        let kept = base.len().min(8 - tail.len());
+        short[..8].fill(b' ');
+        short[..kept].copy_from_slice(&base[..kept]);
+        short[kept..kept + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(Errno::ENOSPC)
+}
+
+// The long name entries for a name, in the order they go in the directory.
+pub(super) fn long_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    // The name is ended with a zero if there's room, and the rest padded out.
+    if !units.len().is_multiple_of(LONG_ENTRY_UNITS) {
+        units.push(0);
+    }
+    units.resize(units.len().next_multiple_of(LONG_ENTRY_UNITS), 0xFFFF);
+    let count = units.len() / LONG_ENTRY_UNITS;
+    let checksum = checksum(short_name);
+    (0..count).rev().map(|index| {
+        let mut entry = [0; ENTRY_SIZE];
+        entry[0] = (index + 1) as u8 | if index + 1 == count { LAST_LONG_ENTRY } else { 0 };
+        entry[11] = ATTRIBUTE_LONG_NAME;
+        entry[13] = checksum;
+        for (position, &offset) in LONG_ENTRY_OFFSETS.iter().enumerate() {
+            let unit = units[index * LONG_ENTRY_UNITS + position];
+            entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entry
+    }).collect()
+}
+
+// The `.` and `..` entries that start every directory but the root. A parent that's the root is
+// always given as cluster 0, even on FAT32.
+pub(super) fn dot_entries(cluster: u32, parent: u32, now: Duration) -> [[u8; ENTRY_SIZE]; 2] {
+    let mut dot = ShortEntry::new(*b".          ", 0, ATTRIBUTE_DIRECTORY, now);
+    dot.set_first_cluster(cluster);
+    let mut dot_dot = ShortEntry::new(*b"..         ", 0, ATTRIBUTE_DIRECTORY, now);
+    dot_dot.set_first_cluster(parent);
+    [dot.0, dot_dot.0]
+}
+
+// Days since 1970 from a date, and back, for the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400) as u64;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era as i64 - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097) as u64;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era as i64 + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn decode_timestamp(date: u16, time: u16) -> Duration {
+    let (year, month, day) = (1980 + (date >> 9) as i64, ((date >> 5) & 0xF) as u64, (date & 0x1F) as u64);
+    if !(1..=12).contains(&month) || day == 0 {
+        return Duration::ZERO;
+    }
+    let days = days_from_civil(year, month, day) as u64;
+    let seconds = (time >> 11) as u64 * 3600 + ((time >> 5) & 0x3F) as u64 * 60 + (time & 0x1F) as u64 * 2;
+    Duration::from_secs(days * SECONDS_PER_DAY + seconds)
+}
+
+fn encode_timestamp(time: Duration) -> (u16, u16) {
+    let seconds = time.as_secs().clamp(FAT_EPOCH, FAT_LAST);
+    let (year, month, day) = civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+    let of_day = seconds % SECONDS_PER_DAY;
+    let date = (((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let time = (((of_day / 3600) as u16) << 11) | (((of_day / 60 % 60) as u16) << 5) | (of_day % 60 / 2) as u16;
+    (date, time)
+}
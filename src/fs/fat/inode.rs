@@ -0,0 +1,497 @@
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::ptr;
+use core::time::Duration;
+use crate::fs::{DirEntry, FileType, FsResult, Inode, Metadata};
+use crate::sync::SleepMutex;
+use crate::syscall::Errno;
+use crate::time;
+use super::directory::{
+    self, Entry, ShortEntry, ATTRIBUTE_ARCHIVE, ATTRIBUTE_DIRECTORY, ATTRIBUTE_READ_ONLY, DELETED, END, ENTRY_SIZE
+};
+use super::table::Table;
+use super::{FatKind, Shared};
+
+// The root has no directory entry to number it by, and nothing else can be this low.
+const ROOT_INODE: u64 = 1;
+// The most entries a directory can have, so that they can be counted in 16 bits.
+const MAX_DIRECTORY_ENTRIES: usize = 65536;
+
+// Where a directory entry is: the slot within the directory that starts at the given cluster,
+// where 0 is the fixed root directory of FAT12 and FAT16.
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    directory: u32,
+    slot: usize
+}
+
+struct State {
+    // Everything but the root has an entry, which is kept here and written back whenever it
+    // changes.
+    entry: Option<(Position, ShortEntry)>,
+    clusters: Vec<u32>,
+    directory: bool,
+    // Once its name is gone, a file's clusters go as soon as nobody has it open.
+    unlinked: bool
+}
+
+impl State {
+    fn size(&self) -> u64 {
+        self.entry.map_or(0, |(_, entry)| entry.size() as u64)
+    }
+}
+
+pub(super) struct FatInode {
+    shared: Arc<Shared>,
+    inode: u64,
+    state: SleepMutex<State>
+}
+
+impl FatInode {
+    pub(super) fn root(shared: &Arc<Shared>) -> FsResult<Arc<Self>> {
+        let clusters = match shared.layout.kind {
+            FatKind::Fat32 => shared.chain(shared.layout.root_cluster)?,
+            _ => Vec::new()
+        };
+        let state = State { entry: None, clusters, directory: true, unlinked: false };
+        Ok(Arc::new(FatInode { shared: shared.clone(), inode: ROOT_INODE, state: SleepMutex::new(state) }))
+    }
+
+    fn fixed_root(&self, state: &State) -> bool {
+        state.entry.is_none() && self.shared.layout.kind != FatKind::Fat32
+    }
+
+    // The cluster a directory starts at, as its entries' positions and `..` entries refer to it.
+    fn directory_cluster(&self, state: &State) -> u32 {
+        if self.fixed_root(state) { 0 } else { state.clusters[0] }
+    }
+
+    fn slot_offset(&self, state: &State, slot: usize) -> u64 {
+        let layout = &self.shared.layout;
+        let offset = (slot * ENTRY_SIZE) as u64;
+        if self.fixed_root(state) {
+            layout.root_start + offset
+        } else {
+            layout.cluster_offset(state.clusters[(offset / layout.cluster_size) as usize]) + offset % layout.cluster_size
+        }
+    }
+
+    fn position_offset(&self, position: Position) -> FsResult<u64> {
+        let offset = (position.slot * ENTRY_SIZE) as u64;
+        match position.directory {
+            0 => Ok(self.shared.layout.root_start + offset),
+            first => self.shared.chain_offset(first, offset)
+        }
+    }
+
+    fn read_clusters(&self, clusters: &[u32]) -> FsResult<Vec<u8>> {
+        let layout = &self.shared.layout;
+        let mut data = vec![0; clusters.len() * layout.cluster_size as usize];
+        for (&cluster, chunk) in clusters.iter().zip(data.chunks_mut(layout.cluster_size as usize)) {
+            self.shared.cache.read_bytes(layout.cluster_offset(cluster), chunk)?;
+        }
+        Ok(data)
+    }
+
+    fn directory_data(&self, state: &State) -> FsResult<Vec<u8>> {
+        if !state.directory {
+            return Err(Errno::ENOTDIR);
+        }
+        if self.fixed_root(state) {
+            let layout = &self.shared.layout;
+            let mut data = vec![0; layout.root_entries as usize * ENTRY_SIZE];
+            self.shared.cache.read_bytes(layout.root_start, &mut data)?;
+            return Ok(data);
+        }
+        self.read_clusters(&state.clusters)
+    }
+
+    fn find(&self, state: &State, name: &str) -> FsResult<Entry> {
+        let data = self.directory_data(state)?;
+        directory::parse(&data).into_iter().find(|entry| entry.short.matches(&entry.name, name)).ok_or(Errno::ENOENT)
+    }
+
+    // Only one inode is ever made for a file, so everyone sees the same size and clusters.
+    fn child(&self, state: &State, entry: &Entry) -> FsResult<Arc<FatInode>> {
+        let number = self.slot_offset(state, entry.slot) / ENTRY_SIZE as u64;
+        let existing = self.shared.inodes.lock().get(&number).and_then(Weak::upgrade);
+        if let Some(existing) = existing {
+            return Ok(existing);
+        }
+        let position = Position { directory: self.directory_cluster(state), slot: entry.slot };
+        let child_state = State {
+            entry: Some((position, entry.short)),
+            clusters: self.shared.chain(entry.short.first_cluster())?,
+            directory: entry.short.is_directory(),
+            unlinked: false
+        };
+        let child = Arc::new(FatInode { shared: self.shared.clone(), inode: number, state: SleepMutex::new(child_state) });
+        self.shared.inodes.lock().insert(number, Arc::downgrade(&child));
+        Ok(child)
+    }
+
+    // A file that's been deleted has nowhere to write its entry to, and its slot may well have
+    // been taken by something else already.
+    fn write_entry(&self, state: &State) -> FsResult<()> {
+        match state.entry {
+            Some((position, entry)) if !state.unlinked => {
+                let offset = self.position_offset(position)?;
+                self.shared.cache.write_bytes(offset, &entry.0)
+            }
+            _ => Ok(())
+        }
+    }
+
+    fn touch(&self, state: &mut State) -> FsResult<()> {
+        if let Some((_, entry)) = &mut state.entry {
+            entry.touch_modified(time::uptime());
+        }
+        self.write_entry(state)
+    }
+
+    // Goes through the part of the file from `offset` on, handing over where on the volume each
+    // piece of it is and which part of the buffer it goes with.
+    fn for_each_extent(
+        &self,
+        state: &State,
+        offset: u64,
+        length: usize,
+        mut f: impl FnMut(u64, Range<usize>) -> FsResult<()>
+    ) -> FsResult<()> {
+        let layout = &self.shared.layout;
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let cluster = *state.clusters.get((position / layout.cluster_size) as usize).ok_or(Errno::EIO)?;
+            let within = position % layout.cluster_size;
+            let chunk = ((layout.cluster_size - within) as usize).min(length - done);
+            f(layout.cluster_offset(cluster) + within, done..done + chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn zero(&self, state: &State, from: u64, to: u64) -> FsResult<()> {
+        if from >= to {
+            return Ok(());
+        }
+        let zeroes = vec![0; self.shared.layout.cluster_size as usize];
+        self.for_each_extent(state, from, (to - from) as usize, |disk, range| {
+            self.shared.cache.write_bytes(disk, &zeroes[..range.len()])
+        })
+    }
+
+    // Adds clusters until the file can hold `size` bytes, or there are no more. Returns how much
+    // it can hold.
+    fn grow(&self, table: &mut Table, state: &mut State, size: u64) -> FsResult<u64> {
+        let cluster_size = self.shared.layout.cluster_size;
+        let needed = size.div_ceil(cluster_size) as usize;
+        while state.clusters.len() < needed {
+            let cluster = match self.shared.allocate(table, state.clusters.last().copied()) {
+                Ok(cluster) => cluster,
+                Err(Errno::ENOSPC) => break,
+                Err(errno) => return Err(errno)
+            };
+            if state.clusters.is_empty() {
+                if let Some((_, entry)) = &mut state.entry {
+                    entry.set_first_cluster(cluster);
+                }
+            }
+            state.clusters.push(cluster);
+        }
+        Ok(state.clusters.len() as u64 * cluster_size)
+    }
+
+    // Frees whatever clusters aren't needed to hold `size` bytes.
+    fn shrink(&self, table: &mut Table, state: &mut State, size: u64) -> FsResult<()> {
+        let cluster_size = self.shared.layout.cluster_size;
+        let kept = size.div_ceil(cluster_size) as usize;
+        if kept >= state.clusters.len() {
+            return Ok(());
+        }
+        let removed = state.clusters.split_off(kept);
+        self.shared.free(table, state.clusters.last().copied(), &removed)?;
+        if kept == 0 {
+            if let Some((_, entry)) = &mut state.entry {
+                entry.set_first_cluster(0);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_size(&self, state: &mut State, size: u64) {
+        if let Some((_, entry)) = &mut state.entry {
+            entry.set_size(size as u32);
+        }
+    }
+
+    // Makes room for `count` entries in a row, growing the directory if it has to. The fixed
+    // root directory can't grow.
+    fn reserve_slots(&self, table: &mut Table, state: &mut State, data: &[u8], count: usize) -> FsResult<usize> {
+        let start = match directory::free_slots(data, count) {
+            Ok(slot) => return Ok(slot),
+            Err(start) => start
+        };
+        if self.fixed_root(state) || start + count > MAX_DIRECTORY_ENTRIES {
+            return Err(Errno::ENOSPC);
+        }
+        let slots_per_cluster = self.shared.layout.cluster_size as usize / ENTRY_SIZE;
+        while state.clusters.len() * slots_per_cluster < start + count {
+            let cluster = self.shared.allocate(table, state.clusters.last().copied())?;
+            state.clusters.push(cluster);
+        }
+        Ok(start)
+    }
+
+    fn remove(&self, name: &str, directory: bool) -> FsResult<()> {
+        self.shared.check_writable()?;
+        let mut table = self.shared.table.lock();
+        let mut state = self.state.lock();
+        let entry = self.find(&state, name)?;
+        match (entry.short.is_directory(), directory) {
+            (true, false) => return Err(Errno::EISDIR),
+            (false, true) => return Err(Errno::ENOTDIR),
+            (true, true) => {
+                let contents = self.read_clusters(&self.shared.chain(entry.short.first_cluster())?)?;
+                if !directory::parse(&contents).is_empty() {
+                    return Err(Errno::ENOTEMPTY);
+                }
+            }
+            (false, false) => {}
+        }
+
+        for slot in entry.first_slot..=entry.slot {
+            self.shared.cache.write_bytes(self.slot_offset(&state, slot), &[DELETED])?;
+        }
+        let number = self.slot_offset(&state, entry.slot) / ENTRY_SIZE as u64;
+        let open = self.shared.inodes.lock().remove(&number).and_then(|inode| inode.upgrade());
+        match open {
+            Some(inode) => inode.state.lock().unlinked = true,
+            None => {
+                let clusters = self.shared.chain(entry.short.first_cluster())?;
+                self.shared.free(&mut table, None, &clusters)?;
+            }
+        }
+        self.touch(&mut state)
+    }
+}
+
+// Files deleted while they were open only give their clusters back now. That has to wait for
+// the next time the allocation table is locked, since it may well be locked right now.
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+        if state.unlinked {
+            if let Some(&first) = state.clusters.first() {
+                self.shared.orphans.lock().push(first);
+            }
+        }
+        let mut inodes = self.shared.inodes.lock();
+        if inodes.get(&self.inode).is_some_and(|inode| ptr::eq(inode.as_ptr(), self)) {
+            inodes.remove(&self.inode);
+        }
+    }
+}
+
+impl Inode for FatInode {
+    // FAT has no owners or permissions, so everything belongs to root and only the read only
+    // attribute makes a difference.
+    fn metadata(&self) -> FsResult<Metadata> {
+        let state = self.state.lock();
+        let layout = &self.shared.layout;
+        let (kind, mode, links) = if state.directory {
+            let data = self.directory_data(&state)?;
+            let subdirectories = directory::parse(&data).iter().filter(|entry| entry.short.is_directory()).count();
+            (FileType::Directory, 0o755, 2 + subdirectories as u32)
+        } else {
+            (FileType::File, 0o644, 1)
+        };
+        let entry = state.entry.map(|(_, entry)| entry);
+        let read_only = entry.is_some_and(|entry| entry.attributes() & ATTRIBUTE_READ_ONLY != 0);
+        let modified = entry.map_or(Duration::ZERO, |entry| entry.modified());
+        Ok(Metadata {
+            device: self.shared.device,
+            inode: self.inode,
+            kind,
+            mode: if read_only { mode & !0o222 } else { mode },
+            links,
+            uid: 0,
+            gid: 0,
+            size: if state.directory { 0 } else { state.size() },
+            block_size: layout.cluster_size as u32,
+            blocks: state.clusters.len() as u64 * layout.cluster_size / 512,
+            accessed: entry.map_or(Duration::ZERO, |entry| entry.accessed()),
+            modified,
+            changed: modified
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let state = self.state.lock();
+        if state.directory {
+            return Err(Errno::EISDIR);
+        }
+        let size = state.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let length = (size - offset).min(buffer.len() as u64) as usize;
+        self.for_each_extent(&state, offset, length, |disk, range| {
+            self.shared.cache.read_bytes(disk, &mut buffer[range])
+        })?;
+        Ok(length)
+    }
+
+    // Files can't be any bigger than 4 GiB, and running out of space part of the way through
+    // makes for a short write.
+    fn write_at(&self, offset: u64, data: &[u8]) -> FsResult<usize> {
+        self.shared.check_writable()?;
+        let mut table = self.shared.table.lock();
+        let mut state = self.state.lock();
+        if state.directory {
+            return Err(Errno::EISDIR);
+        }
+        let end = offset.checked_add(data.len() as u64).filter(|&end| end <= u32::MAX as u64).ok_or(Errno::EFBIG)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let size = state.size();
+        let old_capacity = state.clusters.len() as u64 * self.shared.layout.cluster_size;
+        let capacity = self.grow(&mut table, &mut state, end)?;
+        if capacity <= offset {
+            self.write_entry(&state)?;
+            return Err(Errno::ENOSPC);
+        }
+        // New clusters come zeroed, but whatever's past the end in the old last one doesn't.
+        self.zero(&state, size, offset.min(old_capacity))?;
+        let end = end.min(capacity);
+        let length = (end - offset) as usize;
+        self.for_each_extent(&state, offset, length, |disk, range| {
+            self.shared.cache.write_bytes(disk, &data[range])
+        })?;
+        self.set_size(&mut state, size.max(end));
+        self.touch(&mut state)?;
+        Ok(length)
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        self.shared.check_writable()?;
+        if size > u32::MAX as u64 {
+            return Err(Errno::EFBIG);
+        }
+        let mut table = self.shared.table.lock();
+        let mut state = self.state.lock();
+        if state.directory {
+            return Err(Errno::EISDIR);
+        }
+        let old_size = state.size();
+        if size > old_size {
+            let old_capacity = state.clusters.len() as u64 * self.shared.layout.cluster_size;
+            if self.grow(&mut table, &mut state, size)? < size {
+                self.shrink(&mut table, &mut state, old_size)?;
+                self.write_entry(&state)?;
+                return Err(Errno::ENOSPC);
+            }
+            self.zero(&state, old_size, size.min(old_capacity))?;
+        } else {
+            self.shrink(&mut table, &mut state, size)?;
+        }
+        self.set_size(&mut state, size);
+        self.touch(&mut state)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let _table = self.shared.table.lock();
+        let state = self.state.lock();
+        let entry = self.find(&state, name)?;
+        Ok(self.child(&state, &entry)?)
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        let _table = self.shared.table.lock();
+        let state = self.state.lock();
+        let data = self.directory_data(&state)?;
+        Ok(directory::parse(&data).into_iter().map(|entry| DirEntry {
+            inode: self.slot_offset(&state, entry.slot) / ENTRY_SIZE as u64,
+            kind: if entry.short.is_directory() { FileType::Directory } else { FileType::File },
+            name: entry.name
+        }).collect())
+    }
+
+    // Names that fit into 8.3 are stored as they are, anything else gets a long name and a
+    // short one made up for it. Taking away write permission sets the read only attribute.
+    fn create(&self, name: &str, kind: FileType, mode: u16) -> FsResult<Arc<dyn Inode>> {
+        let mut attributes = match kind {
+            FileType::File => ATTRIBUTE_ARCHIVE,
+            FileType::Directory => ATTRIBUTE_DIRECTORY,
+            _ => return Err(Errno::EINVAL)
+        };
+        if mode & 0o222 == 0 {
+            attributes |= ATTRIBUTE_READ_ONLY;
+        }
+        directory::check_name(name)?;
+        self.shared.check_writable()?;
+        let mut table = self.shared.table.lock();
+        let mut state = self.state.lock();
+        // A directory that's been deleted can't get anything new in it.
+        if state.unlinked {
+            return Err(Errno::ENOENT);
+        }
+        let data = self.directory_data(&state)?;
+        let entries = directory::parse(&data);
+        if entries.iter().any(|entry| entry.short.matches(&entry.name, name)) {
+            return Err(Errno::EEXIST);
+        }
+        let (short_name, case, mut slots) = match directory::exact_short_name(name) {
+            Some((short_name, case)) => (short_name, case, Vec::new()),
+            None => {
+                let taken: Vec<[u8; 11]> = entries.iter().map(|entry| entry.short.short_name()).collect();
+                let short_name = directory::generate_short_name(name, &taken)?;
+                (short_name, 0, directory::long_entries(name, &short_name))
+            }
+        };
+
+        let now = time::uptime();
+        let mut short = ShortEntry::new(short_name, case, attributes, now);
+        if kind == FileType::Directory {
+            let cluster = self.shared.allocate(&mut table, None)?;
+            let parent = if state.entry.is_none() { 0 } else { self.directory_cluster(&state) };
+            let dots = directory::dot_entries(cluster, parent, now);
+            self.shared.cache.write_bytes(self.shared.layout.cluster_offset(cluster), &dots.concat())?;
+            short.set_first_cluster(cluster);
+        }
+        slots.push(short.0);
+        let slot = match self.reserve_slots(&mut table, &mut state, &data, slots.len()) {
+            Ok(slot) => slot,
+            Err(errno) => {
+                if kind == FileType::Directory {
+                    self.shared.free(&mut table, None, &[short.first_cluster()])?;
+                }
+                return Err(errno);
+            }
+        };
+        for (index, raw) in slots.iter().enumerate() {
+            self.shared.cache.write_bytes(self.slot_offset(&state, slot + index), raw)?;
+        }
+        if let Some(end) = directory::moved_end(&data, slot, slots.len()) {
+            self.shared.cache.write_bytes(self.slot_offset(&state, end), &[END])?;
+        }
+        self.touch(&mut state)?;
+        let entry = Entry { name: name.into(), short, first_slot: slot, slot: slot + slots.len() - 1 };
+        Ok(self.child(&state, &entry)?)
+    }
+
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        self.remove(name, false)
+    }
+
+    fn rmdir(&self, name: &str) -> FsResult<()> {
+        self.remove(name, true)
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        self.shared.sync()
+    }
+}
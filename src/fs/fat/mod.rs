@@ -0,0 +1,272 @@
+// FAT12, FAT16 and FAT32, with long file names, so that files can be swapped with the host on a
+// disk image. Which of the three a volume is depends only on how many clusters it has, and apart
+// from how the allocation table is packed and where the root directory lives they all work the
+// same way.
+//
+// FAT has no inodes, so a file is identified by where its directory entry is, and everything
+// about it apart from its clusters lives in that entry. There's no rename, so entries don't move.
+
+mod directory;
+mod inode;
+mod table;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::block::{BlockDevice, BufferCache, SECTOR_SIZE};
+use crate::sync::SleepMutex;
+use crate::syscall::Errno;
+use inode::FatInode;
+use table::Table;
+use super::{allocate_device_id, FileSystem, FsResult, Inode};
+
+// How many sectors of the volume are kept in memory.
+const CACHE_SECTORS: usize = 512;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_FREE_COUNT: u64 = 488;
+const FS_INFO_NEXT_FREE: u64 = 492;
+// Either field of the FSInfo sector can be left as this when it isn't known.
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatKind {
+    Fat12,
+    Fat16,
+    Fat32
+}
+
+// Where everything is on the volume, worked out from the boot sector. Offsets are in bytes.
+struct Layout {
+    kind: FatKind,
+    sector_size: u64,
+    cluster_size: u64,
+    // The first of the copies of the allocation table, which follow each other.
+    fat_start: u64,
+    fat_size: u64,
+    fat_count: u32,
+    // FAT32 can turn off mirroring, leaving only one copy in use.
+    active_fat: Option<u32>,
+    // FAT12 and FAT16 keep the root directory in a fixed area just before the data.
+    root_start: u64,
+    root_entries: u32,
+    // FAT32 keeps it in clusters like any other directory.
+    root_cluster: u32,
+    data_start: u64,
+    cluster_count: u32,
+    fs_info: Option<u64>
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+impl Layout {
+    // Checks the boot sector's parameters over well enough that nothing here can point outside
+    // the volume.
+    fn parse(boot: &[u8], device_sectors: u64) -> FsResult<Self> {
+        if boot[510..512] != [0x55, 0xAA] {
+            return Err(Errno::EINVAL);
+        }
+        let bytes_per_sector = u16_at(boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = u16_at(boot, 14) as u64;
+        let fat_count = boot[16] as u32;
+        let root_entries = u16_at(boot, 17) as u32;
+        let total_sectors = match u16_at(boot, 19) {
+            0 => u32_at(boot, 32) as u64,
+            sectors => sectors as u64
+        };
+        let fat_sectors = match u16_at(boot, 22) {
+            0 => u32_at(boot, 36) as u64,
+            sectors => sectors as u64
+        };
+        if ![512, 1024, 2048, 4096].contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || fat_sectors == 0
+            || total_sectors * bytes_per_sector > device_sectors * SECTOR_SIZE as u64
+        {
+            return Err(Errno::EINVAL);
+        }
+
+        let root_sectors = (root_entries as u64 * 32).div_ceil(bytes_per_sector);
+        let fat_start = reserved_sectors * bytes_per_sector;
+        let root_start = fat_start + fat_count as u64 * fat_sectors * bytes_per_sector;
+        let data_sector = reserved_sectors + fat_count as u64 * fat_sectors + root_sectors;
+        let cluster_count = total_sectors.checked_sub(data_sector).ok_or(Errno::EINVAL)? / sectors_per_cluster;
+        // The cluster count alone decides the kind, whatever the boot sector says.
+        let kind = match cluster_count {
+            0 => return Err(Errno::EINVAL),
+            1..=4084 => FatKind::Fat12,
+            4085..=65524 => FatKind::Fat16,
+            _ => FatKind::Fat32
+        };
+        let entry_bits = match kind {
+            FatKind::Fat12 => 12,
+            FatKind::Fat16 => 16,
+            FatKind::Fat32 => 32
+        };
+        if (cluster_count + 2) * entry_bits > fat_sectors * bytes_per_sector * 8 {
+            return Err(Errno::EINVAL);
+        }
+
+        let mut layout = Layout {
+            kind,
+            sector_size: bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_start,
+            fat_size: fat_sectors * bytes_per_sector,
+            fat_count,
+            active_fat: None,
+            root_start,
+            root_entries,
+            root_cluster: 0,
+            data_start: data_sector * bytes_per_sector,
+            cluster_count: cluster_count as u32,
+            fs_info: None
+        };
+        if kind == FatKind::Fat32 {
+            let flags = u16_at(boot, 40);
+            if flags & 0x80 != 0 {
+                layout.active_fat = Some((flags & 0xF) as u32).filter(|&fat| fat < fat_count);
+                layout.active_fat.ok_or(Errno::EINVAL)?;
+            }
+            // Only version 0.0 exists.
+            if u16_at(boot, 42) != 0 || root_entries != 0 {
+                return Err(Errno::EINVAL);
+            }
+            layout.root_cluster = u32_at(boot, 44);
+            if !layout.is_cluster(layout.root_cluster) {
+                return Err(Errno::EINVAL);
+            }
+            layout.fs_info = match u16_at(boot, 48) as u64 {
+                0 | 0xFFFF => None,
+                sector if sector < reserved_sectors => Some(sector * bytes_per_sector),
+                _ => None
+            };
+        } else if root_entries == 0 {
+            return Err(Errno::EINVAL);
+        }
+        Ok(layout)
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - 2) as u64 * self.cluster_size
+    }
+}
+
+struct Shared {
+    device: u64,
+    layout: Layout,
+    cache: Arc<BufferCache>,
+    read_only: bool,
+    // Anything that changes the allocation table or a directory holds this throughout, which
+    // keeps directories from changing under anyone reading them as well.
+    table: SleepMutex<Table>,
+    // Every inode that's in use by number, so that a file only ever has one.
+    inodes: Mutex<BTreeMap<u64, Weak<FatInode>>>,
+    // Clusters of files that were deleted while they were still open, which can be freed now.
+    orphans: Mutex<Vec<u32>>
+}
+
+impl Shared {
+    fn check_writable(&self) -> FsResult<()> {
+        if self.read_only { Err(Errno::EROFS) } else { Ok(()) }
+    }
+
+    // Writes the allocation hints back for next time, along with everything cached.
+    fn sync(&self) -> FsResult<()> {
+        if !self.read_only {
+            let mut table = self.table.lock();
+            self.release_orphans(&mut table)?;
+            if let Some(fs_info) = self.layout.fs_info {
+                self.cache.write_bytes(fs_info + FS_INFO_FREE_COUNT, &table.free_count.to_le_bytes())?;
+                self.cache.write_bytes(fs_info + FS_INFO_NEXT_FREE, &table.next_free.to_le_bytes())?;
+            }
+        }
+        self.cache.sync()
+    }
+}
+
+pub struct FatFs {
+    shared: Arc<Shared>,
+    root: Arc<FatInode>
+}
+
+impl FatFs {
+    pub fn new(device: Arc<dyn BlockDevice>) -> FsResult<Arc<Self>> {
+        let mut boot = [0; SECTOR_SIZE];
+        device.read_sectors(0, &mut boot)?;
+        let mut layout = Layout::parse(&boot, device.sector_count())?;
+        let read_only = device.read_only();
+        let sector_size = layout.sector_size as usize;
+        let cache = BufferCache::new(device, sector_size, CACHE_SECTORS * SECTOR_SIZE / sector_size);
+
+        // FAT32 keeps track of the free clusters in the FSInfo sector, but it's only a hint.
+        let mut hints = None;
+        if let Some(fs_info) = layout.fs_info {
+            let mut sector = [0; SECTOR_SIZE];
+            cache.read_bytes(fs_info, &mut sector)?;
+            if u32_at(&sector, 0) == FS_INFO_LEAD_SIGNATURE && u32_at(&sector, 484) == FS_INFO_SIGNATURE {
+                hints = Some((u32_at(&sector, FS_INFO_FREE_COUNT as usize), u32_at(&sector, FS_INFO_NEXT_FREE as usize)));
+            } else {
+                layout.fs_info = None;
+            }
+        }
+        let shared = Arc::new(Shared {
+            device: allocate_device_id(),
+            layout,
+            cache,
+            read_only,
+            table: SleepMutex::new(Table { next_free: 2, free_count: 0 }),
+            inodes: Mutex::new(BTreeMap::new()),
+            orphans: Mutex::new(Vec::new())
+        });
+        let table = shared.load_table(hints)?;
+        *shared.table.lock() = table;
+        let root = FatInode::root(&shared)?;
+        Ok(Arc::new(FatFs { shared, root }))
+    }
+
+    pub fn kind(&self) -> FatKind {
+        self.shared.layout.kind
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.shared.layout.cluster_size
+    }
+
+    pub fn free_clusters(&self) -> u32 {
+        self.shared.table.lock().free_count
+    }
+}
+
+impl FileSystem for FatFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        self.shared.sync()
+    }
+}
+
+// Whatever's left is written out when the filesystem goes away, since there's nobody to tell if
+// it fails.
+impl Drop for FatFs {
+    fn drop(&mut self) {
+        let _ = self.shared.sync();
+    }
+}
@@ -0,0 +1,173 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::fs::FsResult;
+use crate::syscall::Errno;
+use super::{FatKind, Shared, FS_INFO_UNKNOWN};
+
+const FREE: u32 = 0;
+
+// What's left of the allocation table once the clusters themselves are in the table.
+pub(super) struct Table {
+    // Where to start looking for a free cluster.
+    pub next_free: u32,
+    pub free_count: u32
+}
+
+impl Shared {
+    // Counts the free clusters, unless the FSInfo sector has already done it for us.
+    pub(super) fn load_table(&self, hints: Option<(u32, u32)>) -> FsResult<Table> {
+        let layout = &self.layout;
+        let mut table = Table { next_free: 2, free_count: 0 };
+        match hints {
+            Some((free_count, next_free)) if free_count != FS_INFO_UNKNOWN && free_count <= layout.cluster_count => {
+                table.free_count = free_count;
+                if layout.is_cluster(next_free) {
+                    table.next_free = next_free;
+                }
+            }
+            _ => {
+                for cluster in 2..layout.cluster_count + 2 {
+                    if self.fat_entry(cluster)? == FREE {
+                        table.free_count += 1;
+                    }
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.layout.kind {
+            FatKind::Fat12 => 0xFF8,
+            FatKind::Fat16 => 0xFFF8,
+            FatKind::Fat32 => 0x0FFF_FFF8
+        }
+    }
+
+    // Where in a copy of the table a cluster's entry is, and how many bytes it takes up. FAT12
+    // packs two entries into three bytes, so its entries take up parts of two.
+    fn entry_position(&self, cluster: u32) -> (u64, usize) {
+        match self.layout.kind {
+            FatKind::Fat12 => ((cluster + cluster / 2) as u64, 2),
+            FatKind::Fat16 => (cluster as u64 * 2, 2),
+            FatKind::Fat32 => (cluster as u64 * 4, 4)
+        }
+    }
+
+    pub(super) fn fat_entry(&self, cluster: u32) -> FsResult<u32> {
+        let layout = &self.layout;
+        let (offset, length) = self.entry_position(cluster);
+        let start = layout.fat_start + layout.active_fat.unwrap_or(0) as u64 * layout.fat_size;
+        let mut bytes = [0; 4];
+        self.cache.read_bytes(start + offset, &mut bytes[..length])?;
+        let value = u32::from_le_bytes(bytes);
+        Ok(match layout.kind {
+            FatKind::Fat12 if cluster.is_multiple_of(2) => value & 0xFFF,
+            FatKind::Fat12 => value >> 4,
+            FatKind::Fat16 => value,
+            // The top four bits are reserved.
+            FatKind::Fat32 => value & 0x0FFF_FFFF
+        })
+    }
+
+    // Changes every copy of the table that's in use, leaving the bits that belong to something
+    // else alone.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> FsResult<()> {
+        let layout = &self.layout;
+        let (offset, length) = self.entry_position(cluster);
+        let copies = match layout.active_fat {
+            Some(fat) => fat..fat + 1,
+            None => 0..layout.fat_count
+        };
+        for copy in copies {
+            let position = layout.fat_start + copy as u64 * layout.fat_size + offset;
+            let mut bytes = [0; 4];
+            self.cache.read_bytes(position, &mut bytes[..length])?;
+            let old = u32::from_le_bytes(bytes);
+            let new = match layout.kind {
+                FatKind::Fat12 if cluster.is_multiple_of(2) => (old & 0xF000) | (value & 0xFFF),
+                FatKind::Fat12 => (old & 0x000F) | ((value & 0xFFF) << 4),
+                FatKind::Fat16 => value & 0xFFFF,
+                FatKind::Fat32 => (old & 0xF000_0000) | (value & 0x0FFF_FFFF)
+            };
+            self.cache.write_bytes(position, &new.to_le_bytes()[..length])?;
+        }
+        Ok(())
+    }
+
+    // Follows a chain of clusters from its first one. A chain that loops or leads somewhere that
+    // isn't a cluster means the volume is damaged.
+    pub(super) fn chain(&self, first: u32) -> FsResult<Vec<u32>> {
+        let mut clusters = Vec::new();
+        if first == FREE {
+            return Ok(clusters);
+        }
+        let mut cluster = first;
+        loop {
+            if !self.layout.is_cluster(cluster) || clusters.len() >= self.layout.cluster_count as usize {
+                return Err(Errno::EIO);
+            }
+            clusters.push(cluster);
+            let next = self.fat_entry(cluster)?;
+            if next >= self.end_of_chain() {
+                return Ok(clusters);
+            }
+            cluster = next;
+        }
+    }
+
+    // Finds the offset of an entry in a directory, knowing only where the directory starts.
+    pub(super) fn chain_offset(&self, first: u32, offset: u64) -> FsResult<u64> {
+        let mut cluster = first;
+        for _ in 0..offset / self.layout.cluster_size {
+            cluster = self.fat_entry(cluster)?;
+            if !self.layout.is_cluster(cluster) {
+                return Err(Errno::EIO);
+            }
+        }
+        Ok(self.layout.cluster_offset(cluster) + offset % self.layout.cluster_size)
+    }
+
+    // Takes a free cluster and puts it on the end of a chain, or starts a new one. The cluster is
+    // zeroed, which is what new directories need and saves files from showing old data.
+    pub(super) fn allocate(&self, table: &mut Table, previous: Option<u32>) -> FsResult<u32> {
+        self.release_orphans(table)?;
+        let count = self.layout.cluster_count;
+        for step in 0..count {
+            let cluster = 2 + (table.next_free - 2 + step) % count;
+            if self.fat_entry(cluster)? != FREE {
+                continue;
+            }
+            self.cache.write_bytes(self.layout.cluster_offset(cluster), &vec![0; self.layout.cluster_size as usize])?;
+            self.set_fat_entry(cluster, 0x0FFF_FFFF)?;
+            if let Some(previous) = previous {
+                self.set_fat_entry(previous, cluster)?;
+            }
+            table.next_free = 2 + (cluster - 2 + 1) % count;
+            table.free_count = table.free_count.saturating_sub(1);
+            return Ok(cluster);
+        }
+        Err(Errno::ENOSPC)
+    }
+
+    // Cuts a chain short after `last`, or frees it all without one.
+    pub(super) fn free(&self, table: &mut Table, last: Option<u32>, clusters: &[u32]) -> FsResult<()> {
+        if let Some(last) = last {
+            self.set_fat_entry(last, 0x0FFF_FFFF)?;
+        }
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, FREE)?;
+            table.free_count += 1;
+        }
+        Ok(())
+    }
+
+    pub(super) fn release_orphans(&self, table: &mut Table) -> FsResult<()> {
+        let orphans: Vec<u32> = self.orphans.lock().drain(..).collect();
+        for first in orphans {
+            let clusters = self.chain(first)?;
+            self.free(table, None, &clusters)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::syscall::Errno;
+use super::{Console, DirEntry, FileType, FsResult, Inode, Metadata};
+
+pub const MAX_FILES: usize = 256;
+
+// The flags `open` takes, with the same values as on Linux so that they can come straight from
+// user code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ_ONLY: OpenFlags = OpenFlags(0);
+    pub const WRITE_ONLY: OpenFlags = OpenFlags(0o1);
+    pub const READ_WRITE: OpenFlags = OpenFlags(0o2);
+    pub const CREATE: OpenFlags = OpenFlags(0o100);
+    pub const EXCLUSIVE: OpenFlags = OpenFlags(0o200);
+    pub const TRUNCATE: OpenFlags = OpenFlags(0o1000);
+    pub const APPEND: OpenFlags = OpenFlags(0o2000);
+    pub const DIRECTORY: OpenFlags = OpenFlags(0o200000);
+    pub const NO_FOLLOW: OpenFlags = OpenFlags(0o400000);
+
+    const ACCESS_MODE: u32 = 0o3;
+
+    pub const fn from_bits(bits: u32) -> Self {
+        OpenFlags(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, flags: OpenFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    pub fn readable(self) -> bool {
+        self.0 & Self::ACCESS_MODE != Self::WRITE_ONLY.0
+    }
+
+    pub fn writable(self) -> bool {
+        matches!(self.0 & Self::ACCESS_MODE, 1 | 2)
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64)
+}
+
+// An open file, which is what file descriptors point to. Descriptors copied by `dup` or `fork`
+// share the same one, and with it the offset.
+pub struct File {
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    path: String,
+    // The directory this was opened through, which stays what `..` means for it even if it gets
+    // moved or its path gets mounted over.
+    parent: Option<Arc<dyn Inode>>,
+    // Not held across reads and writes, since those can block on a disk. Concurrent users of the
+    // same file can lose updates to it, which is just as undefined on Linux.
+    offset: AtomicU64
+}
+
+impl File {
+    pub fn new(inode: Arc<dyn Inode>, flags: OpenFlags, path: String) -> Self {
+        File { inode, flags, path, parent: None, offset: AtomicU64::new(0) }
+    }
+
+    pub fn with_parent(self, parent: Arc<dyn Inode>) -> Self {
+        File { parent: Some(parent), ..self }
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    // Where the file was opened from, which doesn't change if it gets renamed or removed.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn parent(&self) -> Option<&Arc<dyn Inode>> {
+        self.parent.as_ref()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    pub fn metadata(&self) -> FsResult<Metadata> {
+        self.inode.metadata()
+    }
+
+    pub fn read(&self, buffer: &mut [u8]) -> FsResult<usize> {
+        if !self.flags.readable() {
+            return Err(Errno::EBADF);
+        }
+        if self.inode.metadata()?.kind == FileType::Directory {
+            return Err(Errno::EISDIR);
+        }
+        let offset = self.offset();
+        let length = self.inode.read_at(offset, buffer)?;
+        self.offset.store(offset + length as u64, Ordering::Relaxed);
+        Ok(length)
+    }
+
+    pub fn write(&self, data: &[u8]) -> FsResult<usize> {
+        if !self.flags.writable() {
+            return Err(Errno::EBADF);
+        }
+        let offset = if self.flags.contains(OpenFlags::APPEND) { self.inode.metadata()?.size } else { self.offset() };
+        let length = self.inode.write_at(offset, data)?;
+        self.offset.store(offset + length as u64, Ordering::Relaxed);
+        Ok(length)
+    }
+
+    pub fn seek(&self, position: SeekFrom) -> FsResult<u64> {
+        if !self.inode.is_seekable() {
+            return Err(Errno::ESPIPE);
+        }
+        let metadata = self.inode.metadata()?;
+        let (base, delta) = match position {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (self.offset(), delta),
+            SeekFrom::End(delta) => (metadata.size, delta)
+        };
+        let offset = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.unsigned_abs())
+        };
+        let offset = offset.ok_or(Errno::EINVAL)?;
+        self.offset.store(offset, Ordering::Relaxed);
+        Ok(offset)
+    }
+
+    // For directories, the offset counts entries rather than bytes.
+    pub fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        self.inode.read_dir()
+    }
+
+    pub fn set_offset(&self, offset: u64) {
+        self.offset.store(offset, Ordering::Relaxed);
+    }
+}
+
+// A process's file descriptors. The lowest free number is always used for a new file.
+#[derive(Clone, Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<File>>>
+}
+
+impl FileTable {
+    pub fn new() -> Self {
+        FileTable { files: Vec::new() }
+    }
+
+    // Standard input, output and error, all on the console.
+    pub fn with_console() -> Self {
+        let console = Arc::new(File::new(Arc::new(Console), OpenFlags::READ_WRITE, String::from("/dev/console")));
+        FileTable { files: alloc::vec![Some(console.clone()), Some(console.clone()), Some(console)] }
+    }
+
+    pub fn get(&self, fd: usize) -> FsResult<Arc<File>> {
+        self.files.get(fd).cloned().flatten().ok_or(Errno::EBADF)
+    }
+
+    pub fn insert(&mut self, file: Arc<File>) -> FsResult<usize> {
+        let fd = self.files.iter().position(Option::is_none).unwrap_or(self.files.len());
+        if fd >= MAX_FILES {
+            return Err(Errno::EMFILE);
+        }
+        self.insert_at(fd, file)?;
+        Ok(fd)
+    }
+
+    // Puts a file at a particular descriptor, returning whatever was there before.
+    pub fn insert_at(&mut self, fd: usize, file: Arc<File>) -> FsResult<Option<Arc<File>>> {
+        if fd >= MAX_FILES {
+            return Err(Errno::EBADF);
+        }
+        if fd >= self.files.len() {
+            self.files.resize(fd + 1, None);
+        }
+        Ok(self.files[fd].replace(file))
+    }
+
+    pub fn remove(&mut self, fd: usize) -> FsResult<Arc<File>> {
+        let file = self.files.get_mut(fd).and_then(Option::take).ok_or(Errno::EBADF)?;
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+        Ok(file)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,93 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::{parse_number, take, text, Entry, EntryKind, InitramfsError};
+
+// The "newc" format, with or without checksums, which is what Linux uses for its own initramfs.
+// Every header is 110 bytes of ASCII: the magic, then thirteen 8 digit hexadecimal fields.
+const MAGIC: &[u8] = b"070701";
+const CHECKSUM_MAGIC: &[u8] = b"070702";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u64 = 0o170000;
+const S_IFDIR: u64 = 0o040000;
+const S_IFREG: u64 = 0o100000;
+const S_IFLNK: u64 = 0o120000;
+
+pub(super) fn is_cpio(archive: &[u8]) -> bool {
+    archive.starts_with(MAGIC) || archive.starts_with(CHECKSUM_MAGIC)
+}
+
+fn field(header: &[u8], index: usize) -> Result<u64, InitramfsError> {
+    let start = MAGIC.len() + index * 8;
+    parse_number(&header[start..start + 8], 16)
+}
+
+pub(super) fn entries(archive: &[u8]) -> Result<Vec<Entry>, InitramfsError> {
+    let mut entries = Vec::new();
+    // Hard linked files only have their data stored once, usually with the last name. The names
+    // before it are held back until then, and any names after it link back to it.
+    let mut links: BTreeMap<u64, (Option<String>, Vec<String>)> = BTreeMap::new();
+    let mut offset = 0;
+    loop {
+        let (header, next) = take(archive, offset, HEADER_SIZE, 1)?;
+        if !is_cpio(header) {
+            return Err(InitramfsError::InvalidHeader);
+        }
+        let inode = field(header, 0)?;
+        let mode = field(header, 1)?;
+        let links_count = field(header, 4)?;
+        let size = field(header, 6)? as usize;
+        let name_size = field(header, 11)? as usize;
+        if name_size == 0 {
+            return Err(InitramfsError::InvalidHeader);
+        }
+        // The name includes its terminator, and the padding counts from the start of the header.
+        let (name, next) = take(archive, next, name_size, 4)?;
+        let name = text(&name[..name_size - 1])?;
+        let (data, next) = take(archive, next, size, 4)?;
+        offset = next;
+        if name == TRAILER {
+            break;
+        }
+
+        let permissions = (mode & 0o7777) as u16;
+        let kind = match mode & S_IFMT {
+            S_IFDIR => EntryKind::Directory,
+            S_IFLNK => EntryKind::Symlink(text(data)?.into()),
+            S_IFREG if links_count > 1 => {
+                let (stored, pending) = links.entry(inode).or_default();
+                match stored {
+                    Some(stored) => EntryKind::HardLink(stored.clone()),
+                    None if size == 0 => {
+                        pending.push(name.into());
+                        continue;
+                    }
+                    None => {
+                        *stored = Some(name.into());
+                        entries.push(Entry { path: name.into(), kind: EntryKind::File(data), mode: permissions });
+                        for path in pending.drain(..) {
+                            entries.push(Entry { path, kind: EntryKind::HardLink(name.into()), mode: permissions });
+                        }
+                        continue;
+                    }
+                }
+            }
+            S_IFREG => EntryKind::File(data),
+            _ => continue
+        };
+        entries.push(Entry { path: name.into(), kind, mode: permissions });
+    }
+
+    // Links that never got any data were empty files all along.
+    for (_, pending) in links.into_values() {
+        if let Some((first, rest)) = pending.split_first() {
+            entries.push(Entry { path: first.clone(), kind: EntryKind::File(&[]), mode: 0o644 });
+            for path in rest {
+                entries.push(Entry { path: path.clone(), kind: EntryKind::HardLink(first.clone()), mode: 0o644 });
+            }
+        }
+    }
+    Ok(entries)
+}
@@ -0,0 +1,251 @@
+// The initial ramdisk, an archive of files that halogen-boot hands over alongside the kernel so
+// that there's something to run before there are any disk drivers. Both cpio (the "newc" format)
+// and ustar archives can be unpacked.
+//
+// The boot loader has no way to pass extra files along, so halogen-boot gets QEMU to load the
+// archive straight into physical memory at `INITRAMFS_ADDRESS`, behind a small header holding
+// `INITRAMFS_MAGIC` and the archive's length in little endian.
+
+mod cpio;
+mod tar;
+
+use alloc::string::String;
+use bootloader::boot_info::{MemoryRegionKind, MemoryRegions};
+use core::{fmt, slice};
+use log::warn;
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::{FrameDeallocator, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory;
+use crate::syscall::Errno;
+use super::{FileType, OpenFlags};
+
+// halogen-boot has its own copies of these, which have to match.
+pub const INITRAMFS_ADDRESS: u64 = 0x400_0000;
+pub const INITRAMFS_MAGIC: [u8; 8] = *b"HALOINIT";
+const HEADER_SIZE: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitramfsError {
+    UnknownFormat,
+    Truncated,
+    InvalidHeader,
+    InvalidPath,
+    Filesystem(Errno)
+}
+
+impl From<Errno> for InitramfsError {
+    fn from(errno: Errno) -> Self {
+        InitramfsError::Filesystem(errno)
+    }
+}
+
+impl fmt::Display for InitramfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InitramfsError::UnknownFormat => write!(f, "not a cpio or ustar archive"),
+            InitramfsError::Truncated => write!(f, "archive is truncated"),
+            InitramfsError::InvalidHeader => write!(f, "invalid entry header"),
+            InitramfsError::InvalidPath => write!(f, "invalid entry path"),
+            InitramfsError::Filesystem(errno) => write!(f, "failed to create entry ({:?})", errno)
+        }
+    }
+}
+
+// One file, directory or symlink in an archive. Anything else, like device nodes, is skipped.
+// Names are owned since ustar can keep long ones in entries of their own.
+struct Entry<'a> {
+    path: String,
+    kind: EntryKind<'a>,
+    mode: u16
+}
+
+enum EntryKind<'a> {
+    File(&'a [u8]),
+    Directory,
+    Symlink(String),
+    // Another name for a file that's already been unpacked.
+    HardLink(String)
+}
+
+// An archive sitting in physical memory, whose frames are reserved until it's been unpacked.
+pub struct Initramfs {
+    frames: PhysFrameRange,
+    archive: &'static [u8]
+}
+
+impl Initramfs {
+    // Looks for an archive left by halogen-boot. It's only trusted if all of it is in usable
+    // memory, which the boot loader can't have put anything else in, and one that isn't is
+    // warned about.
+    //
+    // Safety: all of physical memory must be mapped at the given offset, and the frames have to be
+    // reserved before anything else is allocated.
+    pub unsafe fn locate(memory_regions: &MemoryRegions, physical_memory_offset: VirtAddr) -> Option<Self> {
+        let is_usable = |start: u64, end: u64| memory_regions.iter()
+            .any(|region| region.kind == MemoryRegionKind::Usable && region.start <= start && end <= region.end);
+        // Whatever the boot loader did with the memory, it's still mapped, so the header can be
+        // read to tell whether there was an archive there at all.
+        let header_end = INITRAMFS_ADDRESS + HEADER_SIZE;
+        if !memory_regions.iter().any(|region| region.start <= INITRAMFS_ADDRESS && header_end <= region.end) {
+            return None;
+        }
+        let header = slice::from_raw_parts((physical_memory_offset + INITRAMFS_ADDRESS).as_ptr::<u8>(), HEADER_SIZE as usize);
+        if header[..8] != INITRAMFS_MAGIC {
+            return None;
+        }
+        let length = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let end = INITRAMFS_ADDRESS.checked_add(HEADER_SIZE)?.checked_add(length)?;
+        if !is_usable(INITRAMFS_ADDRESS, end) {
+            warn!("The initramfs at {:#x} is in memory the boot loader has used, ignoring it.", INITRAMFS_ADDRESS);
+            return None;
+        }
+        let archive = slice::from_raw_parts((physical_memory_offset + INITRAMFS_ADDRESS + HEADER_SIZE).as_ptr::<u8>(), length as usize);
+        let first = PhysFrame::containing_address(PhysAddr::new(INITRAMFS_ADDRESS));
+        let last = PhysFrame::containing_address(PhysAddr::new(end - 1));
+        Some(Initramfs { frames: PhysFrame::range(first, last + 1), archive })
+    }
+
+    pub fn frames(&self) -> PhysFrameRange {
+        self.frames
+    }
+
+    pub fn archive(&self) -> &[u8] {
+        self.archive
+    }
+
+    // Hands the archive's frames back once everything in it has been copied out. They have to
+    // have been taken with `reserve_range`, which either reserves all of them or none.
+    pub fn release(self) {
+        let mut frame_allocator = memory::frame_allocator();
+        for frame in self.frames {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+// Unpacks an archive into a directory, creating any parent directories that the archive leaves
+// out. Existing files are replaced, but existing directories are kept.
+pub fn unpack(archive: &[u8], destination: &str) -> Result<usize, InitramfsError> {
+    let entries = if cpio::is_cpio(archive) {
+        cpio::entries(archive)?
+    } else if tar::is_tar(archive) {
+        tar::entries(archive)?
+    } else {
+        return Err(InitramfsError::UnknownFormat);
+    };
+    for entry in &entries {
+        create(destination, entry)?;
+    }
+    Ok(entries.len())
+}
+
+fn create(destination: &str, entry: &Entry) -> Result<(), InitramfsError> {
+    // Archives usually start with the directory they were made from.
+    if entry.path.split('/').all(|component| component.is_empty() || component == ".") {
+        return Ok(());
+    }
+    let path = entry_path(destination, &entry.path)?;
+    create_parents(&path)?;
+    match &entry.kind {
+        EntryKind::Directory => match super::create_dir(&path, entry.mode) {
+            Ok(()) | Err(Errno::EEXIST) => Ok(()),
+            Err(errno) => Err(errno.into())
+        },
+        EntryKind::File(data) => {
+            remove_existing(&path)?;
+            write_file(&path, data, entry.mode)
+        }
+        EntryKind::Symlink(target) => {
+            remove_existing(&path)?;
+            super::symlink(target, &path)?;
+            Ok(())
+        }
+        EntryKind::HardLink(existing) => {
+            remove_existing(&path)?;
+            let existing = entry_path(destination, existing)?;
+            // Not every filesystem can link, in which case a copy has to do.
+            match super::hard_link(&existing, &path) {
+                Ok(()) => Ok(()),
+                Err(Errno::EPERM) => {
+                    let contents = super::read(&existing)?;
+                    write_file(&path, &contents, super::metadata(&existing)?.mode)
+                }
+                Err(errno) => Err(errno.into())
+            }
+        }
+    }
+}
+
+fn write_file(path: &str, data: &[u8], mode: u16) -> Result<(), InitramfsError> {
+    let file = super::open(path, OpenFlags::WRITE_ONLY | OpenFlags::CREATE | OpenFlags::EXCLUSIVE, mode)?;
+    let mut written = 0;
+    while written < data.len() {
+        written += file.write(&data[written..])?;
+    }
+    Ok(())
+}
+
+// Archive paths are relative, though some tools add a leading `./` or `/`. Going up with `..`
+// could escape the destination, so it isn't allowed.
+fn entry_path(destination: &str, path: &str) -> Result<String, InitramfsError> {
+    let mut joined = String::from(destination.trim_end_matches('/'));
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return Err(InitramfsError::InvalidPath),
+            component => {
+                joined.push('/');
+                joined.push_str(component);
+            }
+        }
+    }
+    if joined.is_empty() {
+        joined.push('/');
+    }
+    Ok(joined)
+}
+
+fn create_parents(path: &str) -> Result<(), InitramfsError> {
+    let mut index = 0;
+    while let Some(offset) = path[index + 1..].find('/') {
+        index += offset + 1;
+        match super::create_dir(&path[..index], 0o755) {
+            Ok(()) | Err(Errno::EEXIST) => {}
+            Err(errno) => return Err(errno.into())
+        }
+    }
+    Ok(())
+}
+
+fn remove_existing(path: &str) -> Result<(), InitramfsError> {
+    match super::symlink_metadata(path) {
+        Ok(metadata) if metadata.kind == FileType::Directory => Err(Errno::EISDIR.into()),
+        Ok(_) => Ok(super::remove_file(path)?),
+        Err(Errno::ENOENT) => Ok(()),
+        Err(errno) => Err(errno.into())
+    }
+}
+
+// Both formats store numbers as ASCII text, in hexadecimal for cpio and octal for ustar.
+fn parse_number(field: &[u8], radix: u32) -> Result<u64, InitramfsError> {
+    let text = core::str::from_utf8(field).map_err(|_| InitramfsError::InvalidHeader)?;
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, radix).map_err(|_| InitramfsError::InvalidHeader)
+}
+
+// Returns `length` bytes from `offset`, along with where the next thing starts once the padding
+// after them is skipped.
+fn take(archive: &[u8], offset: usize, length: usize, alignment: usize) -> Result<(&[u8], usize), InitramfsError> {
+    let end = offset.checked_add(length).ok_or(InitramfsError::Truncated)?;
+    let data = archive.get(offset..end).ok_or(InitramfsError::Truncated)?;
+    let next = end.next_multiple_of(alignment);
+    Ok((data, next))
+}
+
+fn text(bytes: &[u8]) -> Result<&str, InitramfsError> {
+    core::str::from_utf8(bytes).map_err(|_| InitramfsError::InvalidPath)
+}
@@ -0,0 +1,139 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::{parse_number, take, text, Entry, EntryKind, InitramfsError};
+
+// ustar, along with the GNU and pax ways of storing names too long for its header. Every header
+// and every file's data take up whole 512 byte blocks, and the archive ends with empty blocks.
+const BLOCK_SIZE: usize = 512;
+const MAGIC_OFFSET: usize = 257;
+// GNU archives have "ustar  " instead, and keep other things where the prefix would be.
+const POSIX_MAGIC: &[u8] = b"ustar\0";
+
+const REGULAR: u8 = b'0';
+const OLD_REGULAR: u8 = b'\0';
+const CONTIGUOUS: u8 = b'7';
+const HARD_LINK: u8 = b'1';
+const SYMLINK: u8 = b'2';
+const DIRECTORY: u8 = b'5';
+const GNU_LONG_NAME: u8 = b'L';
+const GNU_LONG_LINK: u8 = b'K';
+const PAX_HEADER: u8 = b'x';
+
+pub(super) fn is_tar(archive: &[u8]) -> bool {
+    archive.get(MAGIC_OFFSET..MAGIC_OFFSET + 5) == Some(&b"ustar"[..])
+}
+
+// Fields are NUL terminated unless they fill up all their space.
+fn string(field: &[u8]) -> Result<&str, InitramfsError> {
+    let length = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    text(&field[..length])
+}
+
+// Sizes too big for 11 octal digits are stored as big endian binary, marked by the top bit.
+fn size(field: &[u8]) -> Result<u64, InitramfsError> {
+    if field[0] & 0x80 == 0 {
+        return parse_number(field, 8);
+    }
+    let bytes = &field[field.len() - 8..];
+    if field[1..field.len() - 8].iter().any(|&byte| byte != 0) || field[0] != 0x80 {
+        return Err(InitramfsError::InvalidHeader);
+    }
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+// The checksum adds up every byte in the header, with the checksum field itself as spaces.
+fn checksum_matches(header: &[u8]) -> Result<bool, InitramfsError> {
+    let expected = parse_number(&header[148..156], 8)?;
+    let sum: u64 = header.iter().enumerate()
+        .map(|(index, &byte)| if (148..156).contains(&index) { b' ' as u64 } else { byte as u64 })
+        .sum();
+    Ok(sum == expected)
+}
+
+// Pax headers are a list of "<length> <key>=<value>\n" records, of which only the names matter.
+fn pax_records(data: &[u8]) -> Result<(Option<String>, Option<String>), InitramfsError> {
+    let (mut path, mut link) = (None, None);
+    let mut remaining = data;
+    while !remaining.is_empty() {
+        let space = remaining.iter().position(|&byte| byte == b' ').ok_or(InitramfsError::InvalidHeader)?;
+        let length = parse_number(&remaining[..space], 10)? as usize;
+        if length <= space + 1 || length > remaining.len() {
+            return Err(InitramfsError::InvalidHeader);
+        }
+        let record = text(&remaining[space + 1..length - 1])?;
+        if let Some((key, value)) = record.split_once('=') {
+            match key {
+                "path" => path = Some(value.into()),
+                "linkpath" => link = Some(value.into()),
+                _ => {}
+            }
+        }
+        remaining = &remaining[length..];
+    }
+    Ok((path, link))
+}
+
+pub(super) fn entries(archive: &[u8]) -> Result<Vec<Entry>, InitramfsError> {
+    let mut entries = Vec::new();
+    // Long names come in entries of their own, just before the entry they belong to.
+    let mut long_name: Option<String> = None;
+    let mut long_link: Option<String> = None;
+    let mut offset = 0;
+    // Some tools leave out the empty blocks at the end.
+    while offset < archive.len() {
+        let (header, next) = take(archive, offset, BLOCK_SIZE, BLOCK_SIZE)?;
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        if !is_tar(header) || !checksum_matches(header)? {
+            return Err(InitramfsError::InvalidHeader);
+        }
+        let size = size(&header[124..136])? as usize;
+        let (data, next) = take(archive, next, size, BLOCK_SIZE)?;
+        offset = next;
+
+        let type_flag = header[156];
+        match type_flag {
+            GNU_LONG_NAME => {
+                long_name = Some(string(data)?.into());
+                continue;
+            }
+            GNU_LONG_LINK => {
+                long_link = Some(string(data)?.into());
+                continue;
+            }
+            PAX_HEADER => {
+                let (path, link) = pax_records(data)?;
+                long_name = path.or(long_name);
+                long_link = link.or(long_link);
+                continue;
+            }
+            _ => {}
+        }
+
+        let path = match long_name.take() {
+            Some(path) => path,
+            None => {
+                let name = string(&header[0..100])?;
+                let prefix = if header[MAGIC_OFFSET..].starts_with(POSIX_MAGIC) { string(&header[345..500])? } else { "" };
+                if prefix.is_empty() { name.into() } else { format!("{}/{}", prefix, name) }
+            }
+        };
+        let link = match long_link.take() {
+            Some(link) => link,
+            None => string(&header[157..257])?.into()
+        };
+        let mode = (parse_number(&header[100..108], 8)? & 0o7777) as u16;
+        let kind = match type_flag {
+            REGULAR | OLD_REGULAR | CONTIGUOUS if path.ends_with('/') => EntryKind::Directory,
+            REGULAR | OLD_REGULAR | CONTIGUOUS => EntryKind::File(data),
+            HARD_LINK => EntryKind::HardLink(link),
+            SYMLINK => EntryKind::Symlink(link),
+            DIRECTORY => EntryKind::Directory,
+            _ => continue
+        };
+        entries.push(Entry { path, kind, mode });
+    }
+    Ok(entries)
+}
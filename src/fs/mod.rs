@@ -0,0 +1,273 @@
+// The virtual filesystem. Filesystems hand out inodes through the `Inode` trait and get mounted
+// somewhere in a single tree, so nothing above this layer needs to know which filesystem a file
+// lives on. Errors are plain `Errno`s, since most of them end up going back to user code.
+//
+// Paths are always absolute here. Relative paths are taken from the root, so anything with a
+// working directory has to join it on first.
+
+mod devices;
+pub mod ext2;
+pub mod fat;
+mod file;
+pub mod initramfs;
+mod mount;
+mod path;
+pub mod tmpfs;
+
+pub use devices::{Console, Null, Zero};
+pub use file::{File, FileTable, OpenFlags, SeekFrom, MAX_FILES};
+pub use mount::{mount, mounts, unmount};
+pub use path::{join, MAX_NAME_LENGTH, MAX_PATH_LENGTH};
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use crate::syscall::Errno;
+
+pub type FsResult<T> = Result<T, Errno>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    // Identifies the filesystem, so that together with the inode number it identifies the file.
+    pub device: u64,
+    pub inode: u64,
+    pub kind: FileType,
+    // Just the permission bits, the type is in `kind`.
+    pub mode: u16,
+    pub links: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub block_size: u32,
+    // Counted in 512 byte units, whatever the filesystem's own block size is.
+    pub blocks: u64,
+    pub accessed: Duration,
+    pub modified: Duration,
+    pub changed: Duration
+}
+
+// Directory listings never include `.` and `..`, which are left to the VFS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub kind: FileType
+}
+
+// A file, directory, symlink or device on some filesystem. Everything apart from `metadata` is
+// optional, and fails the way Linux would for something that doesn't support it.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> FsResult<Metadata>;
+
+    // Returns how much was read, which is only short at the end of the file.
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> FsResult<usize> {
+        Err(Errno::EINVAL)
+    }
+
+    // Writing past the end grows the file, filling any gap with zeroes.
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> FsResult<usize> {
+        Err(Errno::EINVAL)
+    }
+
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Err(Errno::EINVAL)
+    }
+
+    // Only things like terminals, where the data goes past rather than sitting anywhere, refuse
+    // to seek.
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn lookup(&self, _name: &str) -> FsResult<Arc<dyn Inode>> {
+        Err(Errno::ENOTDIR)
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        Err(Errno::ENOTDIR)
+    }
+
+    // Only creates regular files and directories, symlinks have their own call.
+    fn create(&self, _name: &str, _kind: FileType, _mode: u16) -> FsResult<Arc<dyn Inode>> {
+        Err(Errno::ENOTDIR)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> FsResult<Arc<dyn Inode>> {
+        Err(Errno::ENOTDIR)
+    }
+
+    // Adds another name for an inode, which has to be on the same filesystem.
+    fn link(&self, _name: &str, _inode: &Arc<dyn Inode>) -> FsResult<()> {
+        Err(Errno::EPERM)
+    }
+
+    // Removes anything but a directory.
+    fn unlink(&self, _name: &str) -> FsResult<()> {
+        Err(Errno::ENOTDIR)
+    }
+
+    // Removes an empty directory.
+    fn rmdir(&self, _name: &str) -> FsResult<()> {
+        Err(Errno::ENOTDIR)
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        Err(Errno::EINVAL)
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        Ok(())
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn root(&self) -> Arc<dyn Inode>;
+
+    // Called before unmounting, to write out anything that's still cached.
+    fn sync(&self) -> FsResult<()> {
+        Ok(())
+    }
+}
+
+// Hands out the device numbers in `Metadata`, one for each filesystem instance.
+pub fn allocate_device_id() -> u64 {
+    static NEXT_DEVICE_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn open(path: &str, flags: OpenFlags, mode: u16) -> FsResult<Arc<File>> {
+    let follow = !flags.contains(OpenFlags::NO_FOLLOW);
+    let resolved = if flags.contains(OpenFlags::CREATE) {
+        let (parent, name) = path::resolve_parent(path)?;
+        match parent.inode.lookup(&name) {
+            Ok(_) if flags.contains(OpenFlags::EXCLUSIVE) => return Err(Errno::EEXIST),
+            // Existing names go through the normal resolution, which takes care of mounts and
+            // symlinks.
+            Ok(_) => path::resolve(path, follow)?,
+            Err(Errno::ENOENT) => {
+                let inode = parent.inode.create(&name, FileType::File, mode)?;
+                path::Resolved { path: join(&parent.path, &name), inode, parent: Some(parent.inode) }
+            }
+            Err(errno) => return Err(errno)
+        }
+    } else {
+        path::resolve(path, follow)?
+    };
+    let path::Resolved { path, inode, parent } = resolved;
+
+    let metadata = inode.metadata()?;
+    match metadata.kind {
+        FileType::Symlink => return Err(Errno::ELOOP),
+        FileType::Directory if flags.writable() => return Err(Errno::EISDIR),
+        FileType::Directory => {}
+        _ if flags.contains(OpenFlags::DIRECTORY) => return Err(Errno::ENOTDIR),
+        _ => {}
+    }
+    if flags.contains(OpenFlags::TRUNCATE) && flags.writable() && metadata.kind == FileType::File {
+        inode.truncate(0)?;
+    }
+    let file = File::new(inode, flags, path);
+    Ok(Arc::new(match parent {
+        Some(parent) => file.with_parent(parent),
+        None => file
+    }))
+}
+
+// Follows symlinks all the way, like `stat`.
+pub fn metadata(path: &str) -> FsResult<Metadata> {
+    path::resolve(path, true)?.inode.metadata()
+}
+
+// Describes a symlink itself rather than what it points to, like `lstat`.
+pub fn symlink_metadata(path: &str) -> FsResult<Metadata> {
+    path::resolve(path, false)?.inode.metadata()
+}
+
+pub fn lookup(path: &str) -> FsResult<Arc<dyn Inode>> {
+    Ok(path::resolve(path, true)?.inode)
+}
+
+// The absolute path with every `.`, `..` and symlink resolved.
+pub fn canonicalize(path: &str) -> FsResult<String> {
+    Ok(path::resolve(path, true)?.path)
+}
+
+pub fn read_dir(path: &str) -> FsResult<Vec<DirEntry>> {
+    path::resolve(path, true)?.inode.read_dir()
+}
+
+// Reads a whole file into memory.
+pub fn read(path: &str) -> FsResult<Vec<u8>> {
+    let file = open(path, OpenFlags::READ_ONLY, 0)?;
+    let mut contents = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(contents),
+            length => contents.extend_from_slice(&buffer[..length])
+        }
+    }
+}
+
+// Creates or replaces a file with the given contents.
+pub fn write(path: &str, contents: &[u8]) -> FsResult<()> {
+    let file = open(path, OpenFlags::WRITE_ONLY | OpenFlags::CREATE | OpenFlags::TRUNCATE, 0o644)?;
+    let mut written = 0;
+    while written < contents.len() {
+        written += file.write(&contents[written..])?;
+    }
+    Ok(())
+}
+
+pub fn create_dir(path: &str, mode: u16) -> FsResult<()> {
+    let (parent, name) = path::resolve_parent(path)?;
+    parent.inode.create(&name, FileType::Directory, mode)?;
+    Ok(())
+}
+
+pub fn remove_dir(path: &str) -> FsResult<()> {
+    let (parent, name) = path::resolve_parent(path)?;
+    // Mount points stay put until they're unmounted.
+    if mount::is_mount_point(&join(&parent.path, &name)) {
+        return Err(Errno::EBUSY);
+    }
+    parent.inode.rmdir(&name)
+}
+
+pub fn remove_file(path: &str) -> FsResult<()> {
+    let (parent, name) = path::resolve_parent(path)?;
+    parent.inode.unlink(&name)
+}
+
+pub fn symlink(target: &str, path: &str) -> FsResult<()> {
+    let (parent, name) = path::resolve_parent(path)?;
+    parent.inode.symlink(&name, target)?;
+    Ok(())
+}
+
+pub fn read_link(path: &str) -> FsResult<String> {
+    path::resolve(path, false)?.inode.read_link()
+}
+
+pub fn hard_link(existing: &str, path: &str) -> FsResult<()> {
+    let inode = path::resolve(existing, false)?.inode;
+    if inode.metadata()?.kind == FileType::Directory {
+        return Err(Errno::EPERM);
+    }
+    let (parent, name) = path::resolve_parent(path)?;
+    if parent.inode.metadata()?.device != inode.metadata()?.device {
+        return Err(Errno::EXDEV);
+    }
+    parent.inode.link(&name, &inode)
+}
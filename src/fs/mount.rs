@@ -0,0 +1,71 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+use crate::syscall::Errno;
+use super::{path, FileSystem, FileType, FsResult, Inode};
+
+struct Mount {
+    // Canonical, so that it can be compared against paths as they get resolved.
+    path: String,
+    filesystem: Arc<dyn FileSystem>
+}
+
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+// Mounts a filesystem over an existing directory. The very first mount has to be the root.
+pub fn mount(path: &str, filesystem: Arc<dyn FileSystem>) -> FsResult<()> {
+    let path = if MOUNTS.read().is_empty() {
+        if path != "/" {
+            return Err(Errno::ENOENT);
+        }
+        String::from("/")
+    } else {
+        let resolved = path::resolve(path, true)?;
+        if resolved.inode.metadata()?.kind != FileType::Directory {
+            return Err(Errno::ENOTDIR);
+        }
+        resolved.path
+    };
+
+    let mut mounts = MOUNTS.write();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(Errno::EBUSY);
+    }
+    mounts.push(Mount { path, filesystem });
+    Ok(())
+}
+
+// Syncs and detaches the filesystem mounted at a path, which can't have anything else mounted
+// inside it.
+pub fn unmount(path: &str) -> FsResult<()> {
+    let path = if path == "/" { String::from("/") } else { path::resolve(path, true)?.path };
+    let filesystem = {
+        let mut mounts = MOUNTS.write();
+        let index = mounts.iter().position(|mount| mount.path == path).ok_or(Errno::EINVAL)?;
+        let prefix = if path == "/" { String::from("/") } else { path.clone() + "/" };
+        if mounts.iter().any(|mount| mount.path != path && mount.path.starts_with(&prefix)) {
+            return Err(Errno::EBUSY);
+        }
+        mounts.remove(index).filesystem
+    };
+    filesystem.sync()
+}
+
+// The paths everything is mounted on, in the order they were mounted.
+pub fn mounts() -> Vec<String> {
+    MOUNTS.read().iter().map(|mount| mount.path.clone()).collect()
+}
+
+pub(super) fn root() -> FsResult<Arc<dyn Inode>> {
+    mounted_at("/").ok_or(Errno::ENOENT)
+}
+
+pub(super) fn mounted_at(path: &str) -> Option<Arc<dyn Inode>> {
+    let mounts = MOUNTS.read();
+    mounts.iter().find(|mount| mount.path == path).map(|mount| mount.filesystem.root())
+}
+
+pub(super) fn is_mount_point(path: &str) -> bool {
+    MOUNTS.read().iter().any(|mount| mount.path == path)
+}
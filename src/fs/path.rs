@@ -0,0 +1,139 @@
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::syscall::Errno;
+use super::{mount, FileType, FsResult, Inode};
+
+pub const MAX_PATH_LENGTH: usize = 4096;
+pub const MAX_NAME_LENGTH: usize = 255;
+
+// The same limit as Linux, which stops symlink loops from going around forever.
+const MAX_SYMLINKS: usize = 40;
+
+pub(super) struct Resolved {
+    // The canonical absolute path, with every `.`, `..` and symlink resolved.
+    pub path: String,
+    pub inode: Arc<dyn Inode>,
+    // The directory the path went through to get here, which is what `..` leads back to. Only
+    // the root has none.
+    pub parent: Option<Arc<dyn Inode>>
+}
+
+// Joins a name onto a canonical path.
+pub fn join(base: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        return path.into();
+    }
+    let mut joined = String::from(base.trim_end_matches('/'));
+    joined.push('/');
+    joined.push_str(path);
+    joined
+}
+
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+// Walks a path one component at a time from the root. The directories on the way are kept on a
+// stack, so `..` goes back to wherever we actually came from, even across mounts and symlinks.
+// The last component is only followed if it's a symlink and `follow` is set, or the path ends
+// in a slash.
+pub(super) fn resolve(path: &str, follow: bool) -> FsResult<Resolved> {
+    if path.len() > MAX_PATH_LENGTH {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    let must_be_directory = path.ends_with('/');
+    let follow = follow || must_be_directory;
+
+    let mut stack: Vec<(String, Arc<dyn Inode>)> = Vec::new();
+    stack.push((String::new(), mount::root()?));
+    let mut pending: VecDeque<String> = components(path).map(ToString::to_string).collect();
+    let mut symlinks = 0;
+    while let Some(name) = pending.pop_front() {
+        match name.as_str() {
+            "." => continue,
+            ".." => {
+                if stack.len() > 1 {
+                    stack.pop();
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(Errno::ENAMETOOLONG);
+        }
+
+        let directory = &stack.last().unwrap().1;
+        if directory.metadata()?.kind != FileType::Directory {
+            return Err(Errno::ENOTDIR);
+        }
+        let mut inode = directory.lookup(&name)?;
+        // A mount point hides whatever directory it was mounted on.
+        if let Some(root) = mount::mounted_at(&canonical_path(&stack, Some(&name))) {
+            inode = root;
+        }
+
+        let is_last = pending.is_empty();
+        if inode.metadata()?.kind == FileType::Symlink && (!is_last || follow) {
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(Errno::ELOOP);
+            }
+            let target = inode.read_link()?;
+            // Relative targets carry on from the directory holding the link.
+            if target.starts_with('/') {
+                stack.truncate(1);
+            }
+            for component in components(&target).rev() {
+                pending.push_front(component.into());
+            }
+            continue;
+        }
+        stack.push((name, inode));
+    }
+
+    let path = canonical_path(&stack, None);
+    let (_, inode) = stack.pop().unwrap();
+    if must_be_directory && inode.metadata()?.kind != FileType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    let parent = stack.pop().map(|(_, parent)| parent);
+    Ok(Resolved { path, inode, parent })
+}
+
+// Resolves everything but the last component, which has to be a plain name, for creating or
+// removing entries in a directory.
+pub(super) fn resolve_parent(path: &str) -> FsResult<(Resolved, String)> {
+    let trimmed = path.trim_end_matches('/');
+    let (parent, name) = match trimmed.rfind('/') {
+        Some(index) => (&trimmed[..index + 1], &trimmed[index + 1..]),
+        None => ("/", trimmed)
+    };
+    match name {
+        "" | "." | ".." => return Err(Errno::EINVAL),
+        name if name.len() > MAX_NAME_LENGTH => return Err(Errno::ENAMETOOLONG),
+        _ => {}
+    }
+    let parent = resolve(parent, true)?;
+    if parent.inode.metadata()?.kind != FileType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok((parent, name.into()))
+}
+
+fn canonical_path(stack: &[(String, Arc<dyn Inode>)], next: Option<&str>) -> String {
+    let mut path = String::new();
+    for name in stack.iter().skip(1).map(|(name, _)| name.as_str()).chain(next) {
+        path.push('/');
+        path.push_str(name);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
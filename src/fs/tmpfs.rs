@@ -0,0 +1,418 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use crate::allocator::HEAP_MAX_SIZE;
+use crate::syscall::Errno;
+use crate::time;
+use super::{allocate_device_id, DirEntry, FileSystem, FileType, FsResult, Inode, Metadata};
+
+// File contents are kept in pages, and pages that have never been written to aren't stored at
+// all, so files can have holes in them that read back as zeroes.
+const PAGE_SIZE: u64 = 4096;
+
+// Like on Linux, the default limit is half of the memory that's available, which here is however
+// big the heap is allowed to grow.
+pub const DEFAULT_LIMIT: u64 = HEAP_MAX_SIZE as u64 / 2;
+
+// A filesystem that only exists in memory, with everything kept on the kernel heap. Only file
+// contents and symlink targets count towards its limit, since they're what can get big.
+pub struct TmpFs {
+    shared: Arc<Shared>,
+    root: Arc<TmpInode>
+}
+
+impl TmpFs {
+    pub fn new() -> Arc<Self> {
+        Self::with_limit(DEFAULT_LIMIT)
+    }
+
+    // There's never more room than the heap can grow to, so bigger limits are cut down to that.
+    pub fn with_limit(limit: u64) -> Arc<Self> {
+        let shared = Arc::new(Shared {
+            device: allocate_device_id(),
+            next_inode: AtomicU64::new(1),
+            limit: limit.min(HEAP_MAX_SIZE as u64),
+            used: AtomicU64::new(0),
+            inodes: Mutex::new(BTreeMap::new())
+        });
+        let root = TmpInode::new(&shared, Contents::Directory(BTreeMap::new()), 0o755);
+        Arc::new(TmpFs { shared, root })
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.shared.limit
+    }
+
+    // How many bytes of the limit are taken up.
+    pub fn used(&self) -> u64 {
+        self.shared.used.load(Ordering::Relaxed)
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+struct Shared {
+    device: u64,
+    next_inode: AtomicU64,
+    limit: u64,
+    used: AtomicU64,
+    // Every inode by number, so that `link` can get back to ours from a `dyn Inode`.
+    inodes: Mutex<BTreeMap<u64, Weak<TmpInode>>>
+}
+
+impl Shared {
+    // Takes space out of the limit, failing if there isn't enough of it left.
+    fn charge(&self, bytes: u64) -> FsResult<()> {
+        self.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            used.checked_add(bytes).filter(|&total| total <= self.limit)
+        }).map(|_| ()).map_err(|_| Errno::ENOSPC)
+    }
+
+    fn refund(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+enum Contents {
+    File(FileData),
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+    Symlink(String)
+}
+
+impl Contents {
+    fn kind(&self) -> FileType {
+        match self {
+            Contents::File(_) => FileType::File,
+            Contents::Directory(_) => FileType::Directory,
+            Contents::Symlink(_) => FileType::Symlink
+        }
+    }
+}
+
+#[derive(Default)]
+struct FileData {
+    size: u64,
+    pages: BTreeMap<u64, Box<[u8]>>
+}
+
+impl FileData {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> usize {
+        let end = self.size.min(offset.saturating_add(buffer.len() as u64));
+        let mut position = offset;
+        while position < end {
+            let page_offset = position % PAGE_SIZE;
+            let length = (PAGE_SIZE - page_offset).min(end - position);
+            let target = &mut buffer[(position - offset) as usize..(position - offset + length) as usize];
+            match self.pages.get(&(position / PAGE_SIZE)) {
+                Some(page) => target.copy_from_slice(&page[page_offset as usize..(page_offset + length) as usize]),
+                None => target.fill(0)
+            }
+            position += length;
+        }
+        end.saturating_sub(offset) as usize
+    }
+
+    fn write(&mut self, shared: &Shared, offset: u64, data: &[u8]) -> FsResult<usize> {
+        let end = offset.checked_add(data.len() as u64).filter(|&end| end <= i64::MAX as u64).ok_or(Errno::EFBIG)?;
+        let mut position = offset;
+        while position < end {
+            let page_offset = position % PAGE_SIZE;
+            let length = (PAGE_SIZE - page_offset).min(end - position);
+            // Running out of space part of the way through makes for a short write.
+            let page = match self.page(shared, position / PAGE_SIZE) {
+                Ok(page) => page,
+                Err(errno) if position == offset => return Err(errno),
+                Err(_) => break
+            };
+            let source = &data[(position - offset) as usize..(position - offset + length) as usize];
+            page[page_offset as usize..(page_offset + length) as usize].copy_from_slice(source);
+            position += length;
+        }
+        self.size = self.size.max(position);
+        Ok((position - offset) as usize)
+    }
+
+    // Running out of heap fails the write rather than the whole kernel.
+    fn page(&mut self, shared: &Shared, index: u64) -> FsResult<&mut Box<[u8]>> {
+        if !self.pages.contains_key(&index) {
+            shared.charge(PAGE_SIZE)?;
+            let mut page = Vec::new();
+            if page.try_reserve_exact(PAGE_SIZE as usize).is_err() {
+                shared.refund(PAGE_SIZE);
+                return Err(Errno::ENOSPC);
+            }
+            page.resize(PAGE_SIZE as usize, 0);
+            self.pages.insert(index, page.into_boxed_slice());
+        }
+        Ok(self.pages.get_mut(&index).unwrap())
+    }
+
+    // Growing only changes the size, which leaves a hole at the end.
+    fn truncate(&mut self, shared: &Shared, size: u64) {
+        if size < self.size {
+            let kept = size.div_ceil(PAGE_SIZE);
+            let removed = self.pages.split_off(&kept);
+            shared.refund(removed.len() as u64 * PAGE_SIZE);
+            // Whatever was past the end in the last page has to read as zeroes if the file grows.
+            if let Some(page) = self.pages.get_mut(&(size / PAGE_SIZE)) {
+                page[(size % PAGE_SIZE) as usize..].fill(0);
+            }
+        }
+        self.size = size;
+    }
+}
+
+struct State {
+    mode: u16,
+    // Only counts names for files and symlinks, since directories work theirs out.
+    links: u32,
+    accessed: Duration,
+    modified: Duration,
+    changed: Duration,
+    contents: Contents
+}
+
+impl State {
+    // Content changes also count as a change to the inode.
+    fn touch_modified(&mut self) {
+        let now = time::uptime();
+        self.modified = now;
+        self.changed = now;
+    }
+}
+
+struct TmpInode {
+    shared: Arc<Shared>,
+    inode: u64,
+    state: Mutex<State>
+}
+
+impl TmpInode {
+    fn new(shared: &Arc<Shared>, contents: Contents, mode: u16) -> Arc<Self> {
+        let inode = shared.next_inode.fetch_add(1, Ordering::Relaxed);
+        let now = time::uptime();
+        let state = State { mode: mode & 0o7777, links: 1, accessed: now, modified: now, changed: now, contents };
+        let node = Arc::new(TmpInode { shared: shared.clone(), inode, state: Mutex::new(state) });
+        shared.inodes.lock().insert(inode, Arc::downgrade(&node));
+        node
+    }
+
+    fn add_entry(&self, name: &str, contents: Contents, mode: u16) -> FsResult<Arc<dyn Inode>> {
+        let mut state = self.state.lock();
+        let entries = match &mut state.contents {
+            Contents::Directory(entries) => entries,
+            _ => return Err(Errno::ENOTDIR)
+        };
+        if entries.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+        let inode = TmpInode::new(&self.shared, contents, mode);
+        entries.insert(name.into(), inode.clone());
+        state.touch_modified();
+        Ok(inode)
+    }
+
+    fn remove_entry(&self, name: &str, directory: bool) -> FsResult<()> {
+        let mut state = self.state.lock();
+        let entries = match &mut state.contents {
+            Contents::Directory(entries) => entries,
+            _ => return Err(Errno::ENOTDIR)
+        };
+        let entry = entries.get(name).ok_or(Errno::ENOENT)?;
+        {
+            let mut entry_state = entry.state.lock();
+            match (&entry_state.contents, directory) {
+                (Contents::Directory(_), false) => return Err(Errno::EISDIR),
+                (Contents::Directory(children), true) if !children.is_empty() => return Err(Errno::ENOTEMPTY),
+                (Contents::Directory(_), true) => {}
+                (_, false) => {
+                    entry_state.links -= 1;
+                    entry_state.changed = time::uptime();
+                }
+                (_, true) => return Err(Errno::ENOTDIR)
+            }
+        }
+        // Open files keep the inode around until they're closed.
+        let removed = entries.remove(name);
+        state.touch_modified();
+        drop(state);
+        drop(removed);
+        Ok(())
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+        let size = match &state.contents {
+            Contents::File(data) => data.pages.len() as u64 * PAGE_SIZE,
+            Contents::Symlink(target) => target.len() as u64,
+            Contents::Directory(_) => 0
+        };
+        self.shared.refund(size);
+        self.shared.inodes.lock().remove(&self.inode);
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> FsResult<Metadata> {
+        let state = self.state.lock();
+        let (size, blocks, links) = match &state.contents {
+            Contents::File(data) => (data.size, data.pages.len() as u64 * PAGE_SIZE / 512, state.links),
+            Contents::Symlink(target) => (target.len() as u64, 0, state.links),
+            Contents::Directory(entries) => {
+                let subdirectories = entries.values().filter(|entry| matches!(entry.state.lock().contents, Contents::Directory(_))).count();
+                (0, 0, 2 + subdirectories as u32)
+            }
+        };
+        Ok(Metadata {
+            device: self.shared.device,
+            inode: self.inode,
+            kind: state.contents.kind(),
+            mode: state.mode,
+            links,
+            uid: 0,
+            gid: 0,
+            size,
+            block_size: PAGE_SIZE as u32,
+            blocks,
+            accessed: state.accessed,
+            modified: state.modified,
+            changed: state.changed
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let mut state = self.state.lock();
+        let length = match &state.contents {
+            Contents::File(data) => data.read(offset, buffer),
+            Contents::Directory(_) => return Err(Errno::EISDIR),
+            Contents::Symlink(_) => return Err(Errno::EINVAL)
+        };
+        state.accessed = time::uptime();
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> FsResult<usize> {
+        let mut state = self.state.lock();
+        let length = match &mut state.contents {
+            Contents::File(contents) => contents.write(&self.shared, offset, data)?,
+            Contents::Directory(_) => return Err(Errno::EISDIR),
+            Contents::Symlink(_) => return Err(Errno::EINVAL)
+        };
+        state.touch_modified();
+        Ok(length)
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        if size > i64::MAX as u64 {
+            return Err(Errno::EFBIG);
+        }
+        let mut state = self.state.lock();
+        match &mut state.contents {
+            Contents::File(contents) => contents.truncate(&self.shared, size),
+            Contents::Directory(_) => return Err(Errno::EISDIR),
+            Contents::Symlink(_) => return Err(Errno::EINVAL)
+        }
+        state.touch_modified();
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        match &self.state.lock().contents {
+            Contents::Directory(entries) => {
+                let entry = entries.get(name).ok_or(Errno::ENOENT)?;
+                Ok(entry.clone())
+            }
+            _ => Err(Errno::ENOTDIR)
+        }
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        let mut state = self.state.lock();
+        let entries = match &state.contents {
+            Contents::Directory(entries) => entries.iter().map(|(name, entry)| DirEntry {
+                name: name.clone(),
+                inode: entry.inode,
+                kind: entry.state.lock().contents.kind()
+            }).collect(),
+            _ => return Err(Errno::ENOTDIR)
+        };
+        state.accessed = time::uptime();
+        Ok(entries)
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u16) -> FsResult<Arc<dyn Inode>> {
+        let contents = match kind {
+            FileType::File => Contents::File(FileData::default()),
+            FileType::Directory => Contents::Directory(BTreeMap::new()),
+            _ => return Err(Errno::EINVAL)
+        };
+        self.add_entry(name, contents, mode)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> FsResult<Arc<dyn Inode>> {
+        self.shared.charge(target.len() as u64)?;
+        let result = self.add_entry(name, Contents::Symlink(target.into()), 0o777);
+        if result.is_err() {
+            self.shared.refund(target.len() as u64);
+        }
+        result
+    }
+
+    fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> FsResult<()> {
+        let metadata = inode.metadata()?;
+        if metadata.device != self.shared.device {
+            return Err(Errno::EXDEV);
+        }
+        if metadata.kind == FileType::Directory {
+            return Err(Errno::EPERM);
+        }
+        let target = self.shared.inodes.lock().get(&metadata.inode).and_then(Weak::upgrade).ok_or(Errno::ENOENT)?;
+
+        let mut state = self.state.lock();
+        let entries = match &mut state.contents {
+            Contents::Directory(entries) => entries,
+            _ => return Err(Errno::ENOTDIR)
+        };
+        if entries.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+        {
+            let mut target_state = target.state.lock();
+            // Files that are only still around because they're open can't get a name back.
+            if target_state.links == 0 {
+                return Err(Errno::ENOENT);
+            }
+            target_state.links = target_state.links.checked_add(1).ok_or(Errno::EMLINK)?;
+            target_state.changed = time::uptime();
+        }
+        entries.insert(name.into(), target);
+        state.touch_modified();
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        self.remove_entry(name, false)
+    }
+
+    fn rmdir(&self, name: &str) -> FsResult<()> {
+        self.remove_entry(name, true)
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        match &self.state.lock().contents {
+            Contents::Symlink(target) => Ok(target.clone()),
+            _ => Err(Errno::EINVAL)
+        }
+    }
+}
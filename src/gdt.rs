@@ -1,39 +1,59 @@
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::instructions::tables::load_tss;
 use x86_64::registers::segmentation::{CS, DS, ES, Segment, SS};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
+use crate::syscall;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
+// Each thread puts its own kernel stack in here when it's scheduled, so this can't sit behind a
+// shared reference like the GDT does. It's filled in when the GDT is first used.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+fn init_tss() -> &'static TaskStateSegment {
+    let tss = unsafe { &mut *addr_of_mut!(TSS) };
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        let stack_end = stack_start + STACK_SIZE;
+        stack_end
     };
+    tss.privilege_stack_table[0] = default_privilege_stack();
+    tss
+}
+
+// The stack used when an interrupt arrives while running in ring 3. Each thread replaces this
+// with its own kernel stack when it is scheduled.
+fn default_privilege_stack() -> VirtAddr {
+    const STACK_SIZE: usize = 4096 * 5;
+    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+    stack_start + STACK_SIZE
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
+        // The order of these matters to SYSCALL and SYSRET, which expect the kernel data segment
+        // straight after the kernel code segment, and the user data segment before the user code.
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
         let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, data_selector, tss_selector })
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(init_tss()));
+        (gdt, Selectors { code_selector, data_selector, user_code_selector, user_data_selector, tss_selector })
     };
 }
 
 pub struct Selectors {
     code_selector: SegmentSelector,
     data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector
 }
 
@@ -47,3 +67,34 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+pub fn kernel_code_selector() -> SegmentSelector {
+    GDT.1.code_selector
+}
+
+pub fn kernel_data_selector() -> SegmentSelector {
+    GDT.1.data_selector
+}
+
+pub fn user_code_selector() -> SegmentSelector {
+    GDT.1.user_code_selector
+}
+
+pub fn user_data_selector() -> SegmentSelector {
+    GDT.1.user_data_selector
+}
+
+pub fn privilege_stack() -> VirtAddr {
+    unsafe { (*addr_of!(TSS)).privilege_stack_table[0] }
+}
+
+// Sets the stack the CPU switches to when an interrupt or system call arrives from ring 3.
+// Passing `None` goes back to the stack that is used before any threads are running.
+pub fn set_privilege_stack(stack_top: Option<VirtAddr>) {
+    let stack_top = stack_top.unwrap_or_else(default_privilege_stack);
+    // The CPU only reads this when switching privilege levels, which can't happen while we're here.
+    unsafe {
+        (*addr_of_mut!(TSS)).privilege_stack_table[0] = stack_top;
+    }
+    syscall::set_kernel_stack(stack_top);
+}
@@ -0,0 +1,197 @@
+use acpi::InterruptModel;
+use acpi::platform::interrupt::{Apic, Polarity, TriggerMode};
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::PhysAddr;
+use crate::{firmware, memory};
+use super::irq::InterruptIndex;
+
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SPURIOUS_VECTOR: usize = 0xF0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+static LOCAL_APIC_BASE: AtomicU64 = AtomicU64::new(0);
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+static ISA_OVERRIDES: Mutex<Vec<IsaOverride>> = Mutex::new(Vec::new());
+
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    redirection_count: u32
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        ptr::write_volatile(self.base as *mut u32, register);
+        ptr::read_volatile((self.base + 0x10) as *const u32)
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        ptr::write_volatile(self.base as *mut u32, register);
+        ptr::write_volatile((self.base + 0x10) as *mut u32, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_count
+    }
+
+    unsafe fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        self.write(register, entry as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+    }
+
+    unsafe fn redirection(&self, gsi: u32) -> u64 {
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        self.read(register) as u64 | (self.read(register + 1) as u64) << 32
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct IsaOverride {
+    isa_source: u8,
+    gsi: u32,
+    active_low: bool,
+    level_triggered: bool
+}
+
+pub fn apic_enabled() -> bool {
+    APIC_ENABLED.load(Ordering::Relaxed)
+}
+
+// Switches interrupt delivery from the 8259 PICs over to the local and I/O APICs described by
+// the MADT. If there is no MADT, the PICs are left as they are.
+pub fn init_apic() {
+    let apic = match firmware::platform_info().map(|info| info.interrupt_model) {
+        Some(InterruptModel::Apic(apic)) => apic,
+        _ => {
+            info!("No APIC described by ACPI, using the 8259 PIC.");
+            return;
+        }
+    };
+    interrupts::without_interrupts(|| {
+        if let Err(error) = unsafe { configure(&apic) } {
+            warn!("Failed to set up the APIC, using the 8259 PIC: {:?}", error);
+            return;
+        }
+        APIC_ENABLED.store(true, Ordering::SeqCst);
+        enable_isa_irq(InterruptIndex::Timer.irq(), InterruptIndex::Timer.as_u8());
+        enable_isa_irq(InterruptIndex::Keyboard.irq(), InterruptIndex::Keyboard.as_u8());
+        info!("Using the APIC with {} I/O APIC(s).", apic.io_apics.len());
+    })
+}
+
+unsafe fn configure(apic: &Apic) -> Result<(), MapToError<Size4KiB>> {
+    let local_apic_base = memory::map_mmio(PhysAddr::new(apic.local_apic_address), 4096)?;
+    LOCAL_APIC_BASE.store(local_apic_base.as_u64(), Ordering::SeqCst);
+    let mut apic_base_msr = Msr::new(IA32_APIC_BASE);
+    let apic_base = apic_base_msr.read();
+    apic_base_msr.write(apic_base | APIC_BASE_ENABLE);
+    write_local_apic(LAPIC_TASK_PRIORITY, 0);
+    write_local_apic(LAPIC_SPURIOUS_VECTOR, LAPIC_SOFTWARE_ENABLE | SPURIOUS_INTERRUPT_VECTOR as u32);
+
+    let mut io_apics = IO_APICS.lock();
+    for io_apic in apic.io_apics.iter() {
+        let base = memory::map_mmio(PhysAddr::new(io_apic.address as u64), 4096)?.as_u64();
+        let mut io_apic = IoApic { base, gsi_base: io_apic.global_system_interrupt_base, redirection_count: 0 };
+        io_apic.redirection_count = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+        // Start out with everything masked, lines get unmasked as they are claimed.
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.redirection_count {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+        io_apics.push(io_apic);
+    }
+
+    let mut overrides = ISA_OVERRIDES.lock();
+    for source_override in apic.interrupt_source_overrides.iter() {
+        overrides.push(IsaOverride {
+            isa_source: source_override.isa_source,
+            gsi: source_override.global_system_interrupt,
+            active_low: matches!(source_override.polarity, Polarity::ActiveLow),
+            level_triggered: matches!(source_override.trigger_mode, TriggerMode::Level)
+        });
+    }
+    disable_pics();
+    Ok(())
+}
+
+// Masks every line on both PICs. They still need to have been remapped beforehand, so that
+// any spurious interrupts they raise don't land on the exception vectors.
+unsafe fn disable_pics() {
+    Port::<u8>::new(0x21).write(0xFF);
+    Port::<u8>::new(0xA1).write(0xFF);
+}
+
+// Routes a legacy ISA IRQ to the given vector on the bootstrap processor, taking any interrupt
+// source overrides into account. ISA interrupts are edge triggered and active high by default.
+pub fn enable_isa_irq(irq: u8, vector: u8) {
+    let isa_override = ISA_OVERRIDES.lock().iter().find(|entry| entry.isa_source == irq).copied();
+    let (gsi, active_low, level_triggered) = match isa_override {
+        Some(entry) => (entry.gsi, entry.active_low, entry.level_triggered),
+        None => (irq as u32, false, false)
+    };
+    enable_gsi(gsi, vector, active_low, level_triggered);
+}
+
+pub fn enable_gsi(gsi: u32, vector: u8, active_low: bool, level_triggered: bool) {
+    let destination = unsafe { read_local_apic(LAPIC_ID) } >> 24;
+    let mut entry = vector as u64 | (destination as u64) << 56;
+    if active_low {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if level_triggered {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    match IO_APICS.lock().iter().find(|io_apic| io_apic.handles(gsi)) {
+        Some(io_apic) => unsafe { io_apic.set_redirection(gsi, entry) },
+        None => warn!("No I/O APIC handles global system interrupt {}!", gsi)
+    }
+}
+
+pub fn disable_gsi(gsi: u32) {
+    if let Some(io_apic) = IO_APICS.lock().iter().find(|io_apic| io_apic.handles(gsi)) {
+        unsafe { io_apic.set_redirection(gsi, io_apic.redirection(gsi) | REDIRECTION_MASKED) };
+    }
+}
+
+// The bootstrap processor's local APIC, which is where everything gets delivered.
+pub fn local_apic_id() -> Option<u8> {
+    apic_enabled().then(|| (unsafe { read_local_apic(LAPIC_ID) } >> 24) as u8)
+}
+
+pub fn local_apic_end_of_interrupt() {
+    unsafe { write_local_apic(LAPIC_EOI, 0) };
+}
+
+unsafe fn read_local_apic(register: usize) -> u32 {
+    ptr::read_volatile((LOCAL_APIC_BASE.load(Ordering::Relaxed) as usize + register) as *const u32)
+}
+
+unsafe fn write_local_apic(register: usize, value: u32) {
+    ptr::write_volatile((LOCAL_APIC_BASE.load(Ordering::Relaxed) as usize + register) as *mut u32, value);
+}
+
+// Spurious interrupts must not be acknowledged.
+pub(super) extern "x86-interrupt" fn handle_spurious(_frame: InterruptStackFrame) {}
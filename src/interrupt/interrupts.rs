@@ -1,8 +1,13 @@
+use core::fmt;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use crate::{gdt, halt_loop, print, println};
+use x86_64::VirtAddr;
+use log::{debug, error, info, warn};
+use crate::{gdt, halt_loop, memory, print, process, syscall, thread};
+use crate::memory::Access;
 use super::irq::*;
 
 lazy_static! {
@@ -37,6 +42,9 @@ lazy_static! {
 
         // Setup hardware interrupts
         super::initialize_irqs(&mut table);
+
+        // Let user code make system calls with `int 0x80`
+        syscall::initialize_interrupt_gate(&mut table);
         table
     };
 }
@@ -47,90 +55,163 @@ pub fn init_idt() {
 
 /* --- Exception interrupt handlers --- */
 
+// Exceptions raised by user code only take down the process that was running it, rather than
+// being treated as a kernel bug.
+fn kill_faulting_process(frame: &InterruptStackFrame, description: impl fmt::Display, signal: i32) {
+    if frame.code_segment & 3 != 3 {
+        return;
+    }
+    match process::current() {
+        Some(pid) => {
+            warn!("{} in user mode at {:?}, killing process {}.", description, frame.instruction_pointer, pid.as_u64());
+            process::kill(signal)
+        }
+        None => {
+            warn!("{} in user mode at {:?}, killing thread {:?}.", description, frame.instruction_pointer, thread::current());
+            thread::exit()
+        }
+    }
+}
+
 extern "x86-interrupt" fn handle_divide_error(frame: InterruptStackFrame) {
-    println!("Cannot divide by zero! {:?}", frame);
+    kill_faulting_process(&frame, "Divide error", process::SIGFPE);
+    error!("Cannot divide by zero! {:?}", frame);
 }
 
 extern "x86-interrupt" fn handle_debug(frame: InterruptStackFrame) {
-    println!("Debug: {:?}", frame);
+    debug!("Debug: {:?}", frame);
 }
 
 extern "x86-interrupt" fn handle_non_maskable_interrupt(frame: InterruptStackFrame) {
-    println!("Non-maskable interrupt! {:?}", frame);
+    error!("Non-maskable interrupt! {:?}", frame);
 }
 
 extern "x86-interrupt" fn handle_breakpoint(frame: InterruptStackFrame) {
-    println!("Breakpoint: {:?}", frame);
+    info!("Breakpoint: {:?}", frame);
 }
 
 extern "x86-interrupt" fn handle_overflow(frame: InterruptStackFrame) {
-    println!("Overflow! {:?}", frame);
+    kill_faulting_process(&frame, "Overflow", process::SIGSEGV);
+    warn!("Overflow! {:?}", frame);
 }
 
 extern "x86-interrupt" fn handle_bound_range_exceeded(frame: InterruptStackFrame) {
-    println!("Bound range exceeded! {:?}", frame);
+    kill_faulting_process(&frame, "Bound range exceeded", process::SIGSEGV);
+    warn!("Bound range exceeded! {:?}", frame);
 }
 
 extern "x86-interrupt" fn handle_invalid_opcode(frame: InterruptStackFrame) {
-    println!("Invalid opcode! {:?}", frame);
+    kill_faulting_process(&frame, "Invalid opcode", process::SIGILL);
+    error!("Invalid opcode! {:?}", frame);
 }
 
 extern "x86-interrupt" fn handle_device_not_available(frame: InterruptStackFrame) {
-    println!("Device not available! {:?}", frame);
+    error!("Device not available! {:?}", frame);
 }
 
 extern "x86-interrupt" fn handle_double_fault(frame: InterruptStackFrame, error_code: u64) -> ! {
-    println!("Double fault! {:?} (error code: {})", frame, error_code);
+    error!("Double fault! {:?} (error code: {})", frame, error_code);
     halt_loop()
 }
 
 extern "x86-interrupt" fn handle_invalid_tss(frame: InterruptStackFrame, error_code: u64) {
-    println!("Invalid TSS! {:?} (error code: {})", frame, error_code);
+    error!("Invalid TSS! {:?} (error code: {})", frame, error_code);
 }
 
 extern "x86-interrupt" fn handle_segment_not_present(frame: InterruptStackFrame, error_code: u64) {
-    println!("Segment not present! {:?} (error code: {})", frame, error_code);
+    error!("Segment not present! {:?} (error code: {})", frame, error_code);
 }
 
 extern "x86-interrupt" fn handle_stack_segment_fault(frame: InterruptStackFrame, error_code: u64) {
-    println!("Stack segment fault! {:?} (error code: {})", frame, error_code);
+    kill_faulting_process(&frame, "Stack segment fault", process::SIGBUS);
+    error!("Stack segment fault! {:?} (error code: {})", frame, error_code);
 }
 
 extern "x86-interrupt" fn handle_general_protection_fault(frame: InterruptStackFrame, error_code: u64) {
-    println!("General protection fault! {:?} (error code: {})", frame, error_code);
+    kill_faulting_process(&frame, "General protection fault", process::SIGSEGV);
+    error!("General protection fault! {:?} (error code: {})", frame, error_code);
+}
+
+// Decodes a page fault for the logs, without allocating in case the heap is what went wrong.
+struct PageFault {
+    address: VirtAddr,
+    error_code: PageFaultErrorCode
+}
+
+impl PageFault {
+    fn access(&self) -> Access {
+        if self.error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            Access::Execute
+        } else if self.error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            Access::Write
+        } else {
+            Access::Read
+        }
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = match self.access() {
+            Access::Read => "Read from",
+            Access::Write => "Write to",
+            Access::Execute => "Instruction fetch from"
+        };
+        let page = if self.error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) { "protected" } else { "non-present" };
+        write!(f, "{} {} page at {:?}", access, page, self.address)?;
+        if self.error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", with a reserved bit set in the page tables")?;
+        }
+        Ok(())
+    }
 }
 
 extern "x86-interrupt" fn handle_page_fault(frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    println!("Page fault! {:?} (error code: {:?})", frame, error_code);
+    let fault = PageFault { address: Cr2::read(), error_code };
+    // Untouched pages in a process's regions only get mapped now, and copy on write pages only
+    // get copied now. This applies to the kernel too, since system calls can be the first thing
+    // to touch a user buffer.
+    let malformed = error_code.contains(PageFaultErrorCode::MALFORMED_TABLE);
+    if !malformed && memory::is_user_address(fault.address) && process::handle_page_fault(fault.address, fault.access()) {
+        return;
+    }
+    kill_faulting_process(&frame, &fault, process::SIGSEGV);
+    // Returning would just fault again, so a bad access from the kernel itself is fatal. It's
+    // logged here rather than panicking, so the faulting frame makes it into the log as is.
+    error!("Kernel oops! {}. {:?}", fault, frame);
+    halt_loop()
 }
 
 extern "x86-interrupt" fn handle_x87_floating_point(frame: InterruptStackFrame) {
-    println!("Error with x87 floating point! {:?}", frame);
+    kill_faulting_process(&frame, "x87 floating point error", process::SIGFPE);
+    error!("Error with x87 floating point! {:?}", frame);
 }
 
 extern "x86-interrupt" fn handle_alignment_check(frame: InterruptStackFrame, error_code: u64) {
-    println!("Alignment check failed! {:?} (error code: {})", frame, error_code);
+    kill_faulting_process(&frame, "Alignment check failure", process::SIGBUS);
+    error!("Alignment check failed! {:?} (error code: {})", frame, error_code);
 }
 
 extern "x86-interrupt" fn handle_machine_check(frame: InterruptStackFrame) -> ! {
-    println!("Machine check failed! {:?}", frame);
+    error!("Machine check failed! {:?}", frame);
     halt_loop()
 }
 
 extern "x86-interrupt" fn handle_simd_floating_point(frame: InterruptStackFrame) {
-    println!("Error with SIMD floating point! {:?}", frame);
+    kill_faulting_process(&frame, "SIMD floating point error", process::SIGFPE);
+    error!("Error with SIMD floating point! {:?}", frame);
 }
 
 extern "x86-interrupt" fn handle_virtualization(frame: InterruptStackFrame) {
-    println!("Virtualization error! {:?}", frame);
+    error!("Virtualization error! {:?}", frame);
 }
 
 extern "x86-interrupt" fn handle_vmm_communication(frame: InterruptStackFrame, error_code: u64) {
-    println!("VMM communication error! {:?} (error code: {})", frame, error_code);
+    error!("VMM communication error! {:?} (error code: {})", frame, error_code);
 }
 
 extern "x86-interrupt" fn handle_security(frame: InterruptStackFrame, error_code: u64) {
-    println!("Security error! {:?} (error code: {})", frame, error_code);
+    error!("Security error! {:?} (error code: {})", frame, error_code);
 }
 
 /* --- Hardware interrupt handlers --- */
@@ -1,22 +1,38 @@
-use lazy_static::lazy_static;
-use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-use pc_keyboard::layouts::Us104Key;
+use alloc::sync::Arc;
 use pic8259::ChainedPics;
 use spin::Mutex;
+use x86_64::instructions::interrupts;
 use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use crate::print;
+use crate::{thread, time};
+use crate::drivers::ata;
+use crate::io::keyboard;
+use crate::sync::IrqMutex;
+use super::apic::{apic_enabled, enable_isa_irq, handle_spurious, local_apic_end_of_interrupt, SPURIOUS_INTERRUPT_VECTOR};
 
 const PIC_1_OFFSET: u8 = 32;
 const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+// Vectors handed out to drivers at run time, for MSIs and lines routed through the I/O APIC.
+// Only the APIC delivers to them, the PICs are remapped just below.
+pub const DEVICE_VECTOR_START: u8 = PIC_2_OFFSET + 8;
+const DEVICE_VECTOR_COUNT: usize = 16;
+
+pub type DeviceHandler = Arc<dyn Fn() + Send + Sync>;
+
+const NO_HANDLER: Option<DeviceHandler> = None;
+static DEVICE_HANDLERS: IrqMutex<[Option<DeviceHandler>; DEVICE_VECTOR_COUNT]> = IrqMutex::new([NO_HANDLER; DEVICE_VECTOR_COUNT]);
+
 pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 #[derive(Debug, Copy, Clone)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
-    Keyboard
+    Keyboard,
+    // The IDE channels keep the lines they had on the PC/AT.
+    PrimaryAta = PIC_1_OFFSET + 14,
+    SecondaryAta
 }
 
 impl InterruptIndex {
@@ -27,37 +43,124 @@ impl InterruptIndex {
     pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+// Acknowledges an interrupt on whichever interrupt controller is currently delivering them.
+pub fn end_of_interrupt(index: InterruptIndex) {
+    if apic_enabled() {
+        local_apic_end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+    }
+}
+
+// Lets a legacy line with a fixed vector through, on whichever interrupt controller is currently
+// delivering them. The timer and keyboard get theirs from the start, anything else is enabled by
+// the driver that handles it.
+pub fn enable_legacy_irq(index: InterruptIndex) {
+    if apic_enabled() {
+        enable_isa_irq(index.irq(), index.as_u8());
+        return;
+    }
+    // Lines on the second PIC come through the first one's cascade line.
+    let irq = index.irq();
+    let (port, line) = if irq < 8 { (0x21, irq) } else { (0xA1, irq - 8) };
+    interrupts::without_interrupts(|| unsafe {
+        unmask_pic_line(port, line);
+        if irq >= 8 {
+            unmask_pic_line(0x21, 2);
+        }
+    });
+}
+
+unsafe fn unmask_pic_line(port: u16, line: u8) {
+    let mut port = Port::<u8>::new(port);
+    let masks = port.read();
+    port.write(masks & !(1 << line));
+}
+
+// Finds a free device vector and has the handler called whenever it's raised. There are none to
+// give out without the APIC.
+pub fn allocate_vector(handler: DeviceHandler) -> Option<u8> {
+    if !apic_enabled() {
+        return None;
+    }
+    let mut handlers = DEVICE_HANDLERS.lock();
+    let index = handlers.iter().position(Option::is_none)?;
+    handlers[index] = Some(handler);
+    Some(DEVICE_VECTOR_START + index as u8)
 }
 
-lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<Us104Key, ScancodeSet1>> = Mutex::new(
-        Keyboard::new(Us104Key, ScancodeSet1, HandleControl::Ignore)
-    );
+pub fn free_vector(vector: u8) {
+    let index = vector.wrapping_sub(DEVICE_VECTOR_START) as usize;
+    if let Some(handler) = DEVICE_HANDLERS.lock().get_mut(index) {
+        *handler = None;
+    }
 }
 
 pub fn initialize_irqs(table: &mut InterruptDescriptorTable) {
     table[InterruptIndex::Timer.as_usize()].set_handler_fn(handle_timer);
     table[InterruptIndex::Keyboard.as_usize()].set_handler_fn(handle_keyboard);
+    table[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(handle_primary_ata);
+    table[InterruptIndex::SecondaryAta.as_usize()].set_handler_fn(handle_secondary_ata);
+    for (index, &handler) in DEVICE_VECTOR_HANDLERS.iter().enumerate() {
+        table[DEVICE_VECTOR_START as usize + index].set_handler_fn(handler);
+    }
+    table[SPURIOUS_INTERRUPT_VECTOR as usize].set_handler_fn(handle_spurious);
 }
 
 extern "x86-interrupt" fn handle_timer(_frame: InterruptStackFrame) {
-    print!(".");
-    unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8()) };
+    time::tick();
+    // Acknowledge the interrupt first, since this might not return until much later.
+    end_of_interrupt(InterruptIndex::Timer);
+    thread::preempt();
 }
 
 extern "x86-interrupt" fn handle_keyboard(_frame: InterruptStackFrame) {
-    let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
-
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
+    keyboard::add_scancode(scancode);
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+extern "x86-interrupt" fn handle_primary_ata(_frame: InterruptStackFrame) {
+    ata::handle_interrupt(0);
+    end_of_interrupt(InterruptIndex::PrimaryAta);
+}
+
+extern "x86-interrupt" fn handle_secondary_ata(_frame: InterruptStackFrame) {
+    ata::handle_interrupt(1);
+    end_of_interrupt(InterruptIndex::SecondaryAta);
+}
+
+// The handler is called without holding the table, so that it's free to free its own vector.
+fn handle_device(index: usize) {
+    let handler = DEVICE_HANDLERS.lock()[index].clone();
+    if let Some(handler) = handler {
+        handler();
     }
+    local_apic_end_of_interrupt();
+}
+
+macro_rules! device_vector_handlers {
+    ($($name:ident = $index:literal),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_frame: InterruptStackFrame) {
+                handle_device($index);
+            }
+        )*
 
-    unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8()) };
+        const DEVICE_VECTOR_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); DEVICE_VECTOR_COUNT] = [$($name),*];
+    };
 }
+
+device_vector_handlers!(
+    handle_device_0 = 0, handle_device_1 = 1, handle_device_2 = 2, handle_device_3 = 3,
+    handle_device_4 = 4, handle_device_5 = 5, handle_device_6 = 6, handle_device_7 = 7,
+    handle_device_8 = 8, handle_device_9 = 9, handle_device_10 = 10, handle_device_11 = 11,
+    handle_device_12 = 12, handle_device_13 = 13, handle_device_14 = 14, handle_device_15 = 15
+);
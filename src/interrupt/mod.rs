@@ -1,5 +1,7 @@
+mod apic;
 mod interrupts;
 pub(self) mod irq;
 
+pub use apic::{apic_enabled, disable_gsi, enable_gsi, enable_isa_irq, init_apic, local_apic_end_of_interrupt, local_apic_id};
 pub use interrupts::*;
 pub use irq::*;
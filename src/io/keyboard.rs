@@ -0,0 +1,200 @@
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::layouts::Us104Key;
+use crate::print;
+
+const SCANCODE_QUEUE_SIZE: usize = 128;
+
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static DROPPED_SCANCODES: AtomicU64 = AtomicU64::new(0);
+
+// Called by the keyboard interrupt handler, so this must not block or allocate.
+pub(crate) fn add_scancode(scancode: u8) {
+    match SCANCODE_QUEUE.try_get() {
+        Ok(queue) => {
+            if queue.push(scancode).is_err() {
+                DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+            } else {
+                WAKER.wake();
+            }
+        }
+        // Nobody is listening for keyboard input yet.
+        Err(_) => {
+            DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// The number of scancodes that were thrown away because the queue was full, or because
+// nothing had started reading from it yet.
+pub fn dropped_scancodes() -> u64 {
+    DROPPED_SCANCODES.load(Ordering::Relaxed)
+}
+
+// The raw scancodes received from the keyboard. There can only be one of these, since each
+// scancode is only delivered once.
+pub struct ScancodeStream {
+    _private: ()
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
+            .expect("ScancodeStream::new should only be called once!");
+        ScancodeStream { _private: () }
+    }
+
+    // Returns the next scancode if one is available, without waiting for one.
+    pub fn try_next(&mut self) -> Option<u8> {
+        SCANCODE_QUEUE.try_get().ok()?.pop()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE.try_get().expect("Scancode queue not initialized!");
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+        // Register before checking again, so that a scancode pushed in between can't be missed.
+        WAKER.register(context.waker());
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending
+        }
+    }
+}
+
+// Turns scancodes into key presses using the US 104 key layout, keeping track of which
+// modifiers are held down along the way.
+struct KeyDecoder {
+    keyboard: Keyboard<Us104Key, ScancodeSet1>
+}
+
+impl KeyDecoder {
+    fn new() -> Self {
+        KeyDecoder { keyboard: Keyboard::new(Us104Key, ScancodeSet1, HandleControl::Ignore) }
+    }
+
+    fn decode(&mut self, scancode: u8) -> Option<DecodedKey> {
+        let key_event = self.keyboard.add_byte(scancode).ok()??;
+        self.keyboard.process_keyevent(key_event)
+    }
+}
+
+// Decoded key presses.
+pub struct KeyEventStream {
+    scancodes: ScancodeStream,
+    decoder: KeyDecoder
+}
+
+impl KeyEventStream {
+    pub fn new() -> Self {
+        KeyEventStream { scancodes: ScancodeStream::new(), decoder: KeyDecoder::new() }
+    }
+
+    // Returns the next key press if one is available, without waiting for one.
+    pub fn try_next(&mut self) -> Option<DecodedKey> {
+        while let Some(scancode) = self.scancodes.try_next() {
+            if let Some(key) = self.decoder.decode(scancode) {
+                return Some(key);
+            }
+        }
+        None
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = DecodedKey;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<DecodedKey>> {
+        // Keep going until a scancode completes a key press, or we run out of scancodes.
+        loop {
+            match Pin::new(&mut self.scancodes).poll_next(context) {
+                Poll::Ready(Some(scancode)) => {
+                    if let Some(key) = self.decoder.decode(scancode) {
+                        return Poll::Ready(Some(key));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending
+            }
+        }
+    }
+}
+
+pub async fn print_keypresses() {
+    let mut keys = KeyEventStream::new();
+    while let Some(key) = keys.next().await {
+        match key {
+            DecodedKey::Unicode(character) => print!("{}", character),
+            DecodedKey::RawKey(key) => print!("{:?}", key)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pc_keyboard::{DecodedKey, KeyCode};
+    use super::KeyDecoder;
+
+    const A_PRESSED: u8 = 0x1E;
+    const A_RELEASED: u8 = 0x9E;
+    const LEFT_SHIFT_PRESSED: u8 = 0x2A;
+    const LEFT_SHIFT_RELEASED: u8 = 0xAA;
+    const CAPS_LOCK_PRESSED: u8 = 0x3A;
+    const CAPS_LOCK_RELEASED: u8 = 0xBA;
+
+    // Feeds in the scancodes, returning whatever the last one decoded to.
+    fn decode(decoder: &mut KeyDecoder, scancodes: &[u8]) -> Option<DecodedKey> {
+        scancodes.iter().map(|&scancode| decoder.decode(scancode)).last().flatten()
+    }
+
+    #[test_case]
+    fn translates_presses_and_ignores_releases() {
+        let mut decoder = KeyDecoder::new();
+        assert_eq!(decode(&mut decoder, &[A_PRESSED]), Some(DecodedKey::Unicode('a')));
+        assert_eq!(decode(&mut decoder, &[A_RELEASED]), None);
+        assert_eq!(decode(&mut decoder, &[0x02]), Some(DecodedKey::Unicode('1')));
+        assert_eq!(decode(&mut decoder, &[0x1C]), Some(DecodedKey::Unicode('\n')));
+    }
+
+    #[test_case]
+    fn translates_extended_scancodes() {
+        let mut decoder = KeyDecoder::new();
+        // The 0xE0 prefix doesn't make a key on its own.
+        assert_eq!(decode(&mut decoder, &[0xE0]), None);
+        assert_eq!(decode(&mut decoder, &[0x48]), Some(DecodedKey::RawKey(KeyCode::ArrowUp)));
+        assert_eq!(decode(&mut decoder, &[0xE0, 0xC8]), None);
+    }
+
+    #[test_case]
+    fn shift_applies_only_while_held() {
+        let mut decoder = KeyDecoder::new();
+        assert_eq!(decode(&mut decoder, &[LEFT_SHIFT_PRESSED, A_PRESSED]), Some(DecodedKey::Unicode('A')));
+        assert_eq!(decode(&mut decoder, &[A_RELEASED, 0x02]), Some(DecodedKey::Unicode('!')));
+        assert_eq!(decode(&mut decoder, &[LEFT_SHIFT_RELEASED, A_PRESSED]), Some(DecodedKey::Unicode('a')));
+    }
+
+    #[test_case]
+    fn caps_lock_toggles_letters_only() {
+        let mut decoder = KeyDecoder::new();
+        assert_eq!(decode(&mut decoder, &[CAPS_LOCK_PRESSED, CAPS_LOCK_RELEASED, A_PRESSED]), Some(DecodedKey::Unicode('A')));
+        assert_eq!(decode(&mut decoder, &[0x02]), Some(DecodedKey::Unicode('1')));
+        // Shift undoes caps lock for letters.
+        assert_eq!(decode(&mut decoder, &[LEFT_SHIFT_PRESSED, A_PRESSED]), Some(DecodedKey::Unicode('a')));
+        assert_eq!(decode(&mut decoder, &[LEFT_SHIFT_RELEASED, CAPS_LOCK_PRESSED, CAPS_LOCK_RELEASED, A_PRESSED]), Some(DecodedKey::Unicode('a')));
+    }
+}
@@ -0,0 +1,114 @@
+use core::fmt::{self, Arguments, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use super::serial::SERIAL1;
+
+pub const KMSG_SIZE: usize = 64 * 1024;
+
+// The kernel message buffer. This lives in a static rather than on the heap so that it can
+// capture output from before the heap or any output device has been set up.
+static KMSG: Mutex<RingBuffer> = Mutex::new(RingBuffer::new());
+
+pub struct RingBuffer {
+    buffer: [u8; KMSG_SIZE],
+    start: usize,
+    len: usize,
+    wrapped: bool
+}
+
+impl RingBuffer {
+    pub const fn new() -> Self {
+        Self { buffer: [0; KMSG_SIZE], start: 0, len: 0, wrapped: false }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+        self.wrapped = false;
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let end = (self.start + self.len) % KMSG_SIZE;
+            self.buffer[end] = byte;
+            if self.len == KMSG_SIZE {
+                self.start = (self.start + 1) % KMSG_SIZE;
+                self.wrapped = true;
+            } else {
+                self.len += 1;
+            }
+        }
+    }
+
+    // Returns the contents of the buffer, oldest first, as two slices.
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        let end = self.start + self.len;
+        if end <= KMSG_SIZE {
+            (&self.buffer[self.start..end], &[])
+        } else {
+            (&self.buffer[self.start..], &self.buffer[..end - KMSG_SIZE])
+        }
+    }
+
+    pub fn replay(&self, writer: &mut impl Write) -> fmt::Result {
+        let (first, second) = self.as_slices();
+        // Once older messages have been overwritten, the first line is most likely cut off,
+        // so skip it entirely.
+        let skip = if self.wrapped {
+            first.iter().chain(second).position(|&byte| byte == b'\n').map_or(self.len, |index| index + 1)
+        } else {
+            0
+        };
+        let (first, second) = if skip < first.len() {
+            (&first[skip..], second)
+        } else {
+            (&[][..], &second[(skip - first.len()).min(second.len())..])
+        };
+        // Anything that isn't plain ASCII could have been split across the end of the buffer,
+        // so it is replayed as a placeholder rather than risking invalid UTF-8.
+        for &byte in first.iter().chain(second) {
+            writer.write_char(if byte.is_ascii() { byte as char } else { '?' })?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RingBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+pub fn write(args: Arguments) {
+    interrupts::without_interrupts(|| {
+        let _ = KMSG.lock().write_fmt(args);
+    })
+}
+
+pub fn replay(writer: &mut impl Write) -> fmt::Result {
+    interrupts::without_interrupts(|| KMSG.lock().replay(writer))
+}
+
+pub fn clear() {
+    interrupts::without_interrupts(|| KMSG.lock().clear())
+}
+
+// Dumps the buffer to the first serial port. Meant for use from the panic handler, so this
+// forcibly takes the locks, since whoever was holding them is never going to release them.
+pub fn dump_to_serial() {
+    interrupts::disable();
+    unsafe {
+        KMSG.force_unlock();
+        SERIAL1.force_unlock();
+    }
+    let _ = KMSG.lock().replay(&mut *SERIAL1.lock());
+}
@@ -1,16 +1,25 @@
-use core::fmt::{Arguments, Write};
+use core::fmt::{self, Arguments, Write};
 use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use bootloader::boot_info::FrameBuffer;
 use conquer_once::spin::OnceCell;
-use log::LevelFilter;
+use log::{LevelFilter, Log, Metadata, Record};
 use printk::Printk;
 use spin::{Mutex, Once};
 use x86_64::instructions::interrupts;
+use crate::time;
+use super::kmsg;
+use super::serial::SERIAL1;
 
 static PRINTK: Once<Mutex<Printk>> = Once::new();
+static LOGGER: KernelLogger = KernelLogger;
 
 pub fn init(buffer: &'static FrameBuffer) {
-    PRINTK.call_once(|| Mutex::new(Printk::new(buf_to_mut(buffer.buffer()), buffer.info())));
+    let printk = PRINTK.call_once(|| Mutex::new(Printk::new(buf_to_mut(buffer.buffer()), buffer.info())));
+    // Show everything that was logged before the framebuffer was available.
+    interrupts::without_interrupts(|| {
+        let _ = kmsg::replay(&mut *printk.lock());
+    })
 }
 
 // This is really, really unsafe, and is really not an example to follow,
@@ -34,6 +43,126 @@ macro_rules! println {
 
 pub fn _print(args: Arguments) {
     interrupts::without_interrupts(|| {
-        PRINTK.get().unwrap().lock().write_fmt(args).expect("Printing to logger failed!");
+        kmsg::write(args);
+        if let Some(printk) = PRINTK.get() {
+            printk.lock().write_fmt(args).expect("Printing to logger failed!");
+        }
     })
 }
+
+// Shows a panic everywhere it can be seen. The buffer goes out to the serial port first, in
+// case the framebuffer is missing or unreadable, and then the panic itself is recorded and shown.
+// The panicking code could have been holding any of the locks and is never going to release
+// them, so they're all taken by force.
+pub fn print_panic(args: Arguments) {
+    kmsg::dump_to_serial();
+    kmsg::write(args);
+    let _ = SERIAL1.lock().write_fmt(args);
+    if let Some(printk) = PRINTK.get() {
+        unsafe { printk.force_unlock() };
+        let _ = printk.lock().write_fmt(args);
+    }
+}
+
+/* --- log crate integration --- */
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Sink {
+    Framebuffer,
+    Serial,
+    Buffer
+}
+
+impl Sink {
+    fn level(self) -> &'static AtomicUsize {
+        static FRAMEBUFFER_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+        static SERIAL_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Debug as usize);
+        static BUFFER_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Debug as usize);
+        match self {
+            Sink::Framebuffer => &FRAMEBUFFER_LEVEL,
+            Sink::Serial => &SERIAL_LEVEL,
+            Sink::Buffer => &BUFFER_LEVEL
+        }
+    }
+
+    fn enabled(self, level: log::Level) -> bool {
+        level as usize <= self.level().load(Ordering::Relaxed)
+    }
+}
+
+pub fn init_logger() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        log::set_logger(&LOGGER).expect("Logger was already set!");
+        update_max_level();
+    });
+}
+
+pub fn set_level(sink: Sink, level: LevelFilter) {
+    sink.level().store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+fn update_max_level() {
+    let max = [Sink::Framebuffer, Sink::Serial, Sink::Buffer].iter()
+        .map(|sink| sink.level().load(Ordering::Relaxed))
+        .max()
+        .unwrap_or(0);
+    log::set_max_level(match max {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace
+    });
+}
+
+// Fans every record out to the framebuffer, the first serial port and the kernel message
+// buffer, each of which has its own level filter. The framebuffer only receives records
+// once it has been initialized, but the message buffer can be replayed to it at that point.
+struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        [Sink::Framebuffer, Sink::Serial, Sink::Buffer].iter().any(|sink| sink.enabled(metadata.level()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        interrupts::without_interrupts(|| {
+            if Sink::Buffer.enabled(record.level()) {
+                kmsg::write(format_args!("{}", RecordDisplay(record)));
+            }
+            if Sink::Framebuffer.enabled(record.level()) {
+                if let Some(printk) = PRINTK.get() {
+                    let _ = write!(printk.lock(), "{}", RecordDisplay(record));
+                }
+            }
+            if Sink::Serial.enabled(record.level()) {
+                let _ = write!(SERIAL1.lock(), "{}", RecordDisplay(record));
+            }
+        })
+    }
+
+    fn flush(&self) {}
+}
+
+struct RecordDisplay<'a, 'b>(&'a Record<'b>);
+
+impl fmt::Display for RecordDisplay<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let uptime = time::uptime();
+        writeln!(
+            f,
+            "[{:>5}.{:03}] {:<5} {}: {}",
+            uptime.as_secs(),
+            uptime.subsec_millis(),
+            self.0.level(),
+            self.0.module_path().unwrap_or("unknown"),
+            self.0.args()
+        )
+    }
+}
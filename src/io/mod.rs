@@ -1,3 +1,5 @@
+pub mod keyboard;
+pub mod kmsg;
 pub mod logging;
 pub mod serial;
 
@@ -5,6 +7,7 @@ use bootloader::boot_info::Optional;
 use bootloader::BootInfo;
 
 pub fn init(boot_info: &'static BootInfo) {
+    logging::init_logger();
     let framebuffer = match &boot_info.framebuffer {
         Optional::Some(value) => value,
         Optional::None => return,
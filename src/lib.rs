@@ -14,10 +14,23 @@ use core::panic::PanicInfo;
 use x86_64::instructions::port::Port;
 
 pub mod allocator;
+pub mod block;
+pub mod drivers;
+pub mod elf;
+pub mod firmware;
+pub mod fs;
 pub mod memory;
 pub mod interrupt;
 pub mod gdt;
 pub mod io;
+pub mod pci;
+pub mod process;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod thread;
+pub mod time;
+pub mod usermode;
 
 pub fn init(boot_info: &'static BootInfo) {
     io::init(boot_info);
@@ -27,7 +40,9 @@ pub fn init(boot_info: &'static BootInfo) {
 pub fn init_headless() {
     gdt::init();
     interrupt::init_idt();
+    syscall::init();
     unsafe { interrupt::PICS.lock().initialize() };
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
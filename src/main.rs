@@ -6,10 +6,22 @@
 
 extern crate alloc;
 
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use halogen_os::{allocator, halt_loop, init, init_headless, println};
-use halogen_os::memory::{self, BootInfoFrameAllocator};
+use halogen_os::{allocator, block, drivers, firmware, fs, halt_loop, init, init_headless, interrupt, pci, thread};
+use halogen_os::fs::FileSystem;
+use halogen_os::fs::ext2::Ext2Fs;
+use halogen_os::fs::fat::FatFs;
+use halogen_os::fs::initramfs::{self, Initramfs};
+use halogen_os::fs::tmpfs::TmpFs;
+use halogen_os::io::{keyboard, logging};
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::syscall::Errno;
+use halogen_os::task::{Executor, Task};
+use log::{info, warn};
 use x86_64::VirtAddr;
 
 entry_point!(kernel_main);
@@ -19,13 +31,33 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     run_tests();
 
     init(boot_info);
-    println!("Starting Halogen OS version 0.1.0.");
+    info!("Starting Halogen OS version 0.1.0.");
 
     // Setup heap memory so we can perform heap allocations
-    setup_heap_memory(boot_info);
+    let initramfs = setup_heap_memory(boot_info);
 
-    println!("It did not crash!");
-    halt_loop()
+    // Move interrupt delivery over to the APIC if the firmware describes one
+    firmware::init(boot_info.rsdp_addr.into_option());
+    interrupt::init_apic();
+
+    // Turn ourselves into the first kernel thread, so that other threads can be spawned
+    thread::init();
+
+    // Find out what's plugged in and hand it to drivers, which may need to wait on the devices
+    pci::init();
+    drivers::init();
+
+    // Give ourselves a root filesystem, with whatever halogen-boot packed into the initramfs
+    mount_root(initramfs);
+
+    // Mount whatever ext2 and FAT volumes the disks hold
+    mount_disks();
+
+    info!("It did not crash!");
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.run()
 }
 
 #[cfg(test)]
@@ -35,17 +67,67 @@ fn run_tests() -> ! {
     halt_loop()
 }
 
-fn setup_heap_memory(boot_info: &'static BootInfo) {
+// The initramfs has to be found before anything gets allocated, or it could be overwritten.
+fn setup_heap_memory(boot_info: &'static BootInfo) -> Option<Initramfs> {
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
-    let mut mapper = unsafe { memory::init(physical_memory_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(&boot_info.memory_regions) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed!");
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    // The frame bitmap goes at the start of usable memory, which mustn't land on the archive.
+    let initramfs = unsafe { Initramfs::locate(&boot_info.memory_regions, physical_memory_offset) };
+    let frames = initramfs.as_ref().map(Initramfs::frames);
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::new_avoiding(&boot_info.memory_regions, physical_memory_offset, frames) };
+    let initramfs = initramfs.filter(|initramfs| {
+        let reserved = frame_allocator.reserve_range(initramfs.frames());
+        if !reserved {
+            warn!("The initramfs overlaps memory that's already in use, ignoring it.");
+        }
+        reserved
+    });
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    initramfs
+}
+
+fn mount_root(initramfs: Option<Initramfs>) {
+    fs::mount("/", TmpFs::new()).expect("Failed to mount the root filesystem!");
+    if let Some(initramfs) = initramfs {
+        match initramfs::unpack(initramfs.archive(), "/") {
+            Ok(count) => info!("Unpacked {} entries from the initramfs.", count),
+            Err(error) => warn!("Failed to unpack the initramfs: {}.", error)
+        }
+        initramfs.release();
+    }
+}
+
+// Each volume goes under /mnt, named after the disk or partition it's on.
+fn mount_disks() {
+    for name in block::devices() {
+        let device = match block::device(&name) {
+            Some(device) => device,
+            None => continue
+        };
+        let probed = Ext2Fs::new(device.clone())
+            .map(|ext2| (String::from("ext2"), ext2 as Arc<dyn FileSystem>))
+            .or_else(|_| FatFs::new(device).map(|fat| (format!("{:?}", fat.kind()), fat as Arc<dyn FileSystem>)));
+        let (kind, filesystem) = match probed {
+            Ok(probed) => probed,
+            Err(_) => continue
+        };
+        let path = format!("/mnt/{}", name);
+        let result = match fs::create_dir("/mnt", 0o755) {
+            Ok(()) | Err(Errno::EEXIST) => fs::create_dir(&path, 0o755),
+            Err(errno) => Err(errno)
+        };
+        match result.and_then(|_| fs::mount(&path, filesystem)) {
+            Ok(()) => info!("Mounted the {} volume on {} at {}.", kind, name, path),
+            Err(errno) => warn!("Failed to mount {}: {:?}.", name, errno)
+        }
+    }
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    logging::print_panic(format_args!("{}\n", info));
     halt_loop();
 }
 
@@ -1,44 +0,0 @@
-use bootloader::boot_info::{MemoryRegionKind, MemoryRegions};
-use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB};
-use x86_64::{PhysAddr, VirtAddr};
-
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
-    let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
-}
-
-unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    let (level_4_table_frame, _) = Cr3::read();
-    let physical_addr = level_4_table_frame.start_address();
-    let virtual_addr = physical_memory_offset + physical_addr.as_u64();
-    let page_table_ptr: *mut PageTable = virtual_addr.as_mut_ptr();
-    &mut *page_table_ptr
-}
-
-pub struct BootInfoFrameAllocator {
-    memory_regions: &'static MemoryRegions,
-    next: usize
-}
-
-impl BootInfoFrameAllocator {
-    pub unsafe fn new(memory_regions: &'static MemoryRegions) -> Self {
-        Self { memory_regions, next: 0 }
-    }
-
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        self.memory_regions.iter()
-            .filter(|region| region.kind == MemoryRegionKind::Usable)
-            .map(|region| region.start..region.end)
-            .flat_map(|region| region.step_by(4096))
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
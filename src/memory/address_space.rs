@@ -0,0 +1,478 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ptr;
+use lazy_static::lazy_static;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    mapper::{FlagUpdateError, MapToError, TranslateResult}, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+    PageTableEntry, PageTableFlags, PhysFrame, Size4KiB, Translate
+};
+use x86_64::VirtAddr;
+use crate::sync::IrqMutex;
+use super::{frame_allocator, mapper, physical_memory_offset, BitmapFrameAllocator, FRAME_SIZE};
+
+// User programs get the level 4 entries from 1 up to 127. Everything else belongs to the kernel
+// and is shared between all address spaces, with the kernel image itself sitting in entry 0.
+pub const USER_SPACE_START: u64 = 0x0000_0080_0000_0000;
+pub const USER_SPACE_END: u64 = 0x0000_4000_0000_0000;
+
+// Marks pages that were writable before a fork. They stay read only until the first write, which
+// gives the writer its own copy of the frame if anything else still refers to it.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+lazy_static! {
+    // How many page tables map each user frame, for frames shared by forking. Frames that aren't
+    // in here have just the one owner. Never insert while holding the frame allocator, since the
+    // heap might need to grow.
+    static ref SHARED_FRAMES: IrqMutex<BTreeMap<PhysFrame, usize>> = IrqMutex::new(BTreeMap::new());
+}
+
+pub fn is_user_address(address: VirtAddr) -> bool {
+    (USER_SPACE_START..USER_SPACE_END).contains(&address.as_u64())
+}
+
+fn is_user_entry(index: usize) -> bool {
+    let start = (USER_SPACE_START >> 39) as usize;
+    let end = (USER_SPACE_END >> 39) as usize;
+    (start..end).contains(&index)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute
+}
+
+// What user code may do with a region. Pages can't be write or execute only on x86, so any
+// access at all makes a page readable, and a region that allows nothing never gets any pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool
+}
+
+impl Protection {
+    pub const NONE: Protection = Protection { read: false, write: false, execute: false };
+    pub const READ: Protection = Protection { read: true, write: false, execute: false };
+    pub const READ_WRITE: Protection = Protection { read: true, write: true, execute: false };
+    pub const READ_EXECUTE: Protection = Protection { read: true, write: false, execute: true };
+
+    pub fn allows(self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read || self.write || self.execute,
+            Access::Write => self.write,
+            Access::Execute => self.execute
+        }
+    }
+
+    pub fn page_flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.write {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.execute {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+// A page aligned range of user space that the process is allowed to use. Pages inside a region
+// don't have to be mapped yet, they get a zeroed frame the first time something touches them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub protection: Protection
+}
+
+impl Region {
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    Unaligned,
+    OutsideUserSpace,
+    Overlapping
+}
+
+// A set of page tables with its own user half, sharing the kernel half with every other
+// address space.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    // Sorted by start address, and never overlapping.
+    regions: Vec<Region>
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let mut kernel_mapper = mapper();
+        let level_4_frame = frame_allocator().allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        let level_4_table = unsafe { table_at(level_4_frame) };
+        level_4_table.zero();
+        // The kernel's level 3 tables are shared rather than copied, so later kernel mappings
+        // show up everywhere as long as their level 4 entry already existed.
+        for (index, entry) in kernel_mapper.level_4_table().iter().enumerate() {
+            if !is_user_entry(index) {
+                level_4_table[index] = entry.clone();
+            }
+        }
+        Ok(AddressSpace { level_4_frame, regions: Vec::new() })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    // Loads these page tables straight into CR3. Threads should use `thread::set_page_table`
+    // instead, which keeps the address space across context switches.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn region_at(&self, address: VirtAddr) -> Option<&Region> {
+        self.regions.iter().find(|region| region.contains(address))
+    }
+
+    // Reserves a range without mapping anything, which happens lazily as the pages get used.
+    pub fn add_region(&mut self, start: VirtAddr, size: u64, protection: Protection) -> Result<Region, RegionError> {
+        let end = check_range(start, size)?;
+        if self.regions.iter().any(|region| region.start < end && start < region.end) {
+            return Err(RegionError::Overlapping);
+        }
+        let region = Region { start, end, protection };
+        let index = self.regions.partition_point(|region| region.start < start);
+        self.regions.insert(index, region);
+        Ok(region)
+    }
+
+    // Takes a range out of whichever regions it overlaps, splitting them if needed, and frees
+    // every page that was mapped inside it.
+    pub fn remove_range(&mut self, start: VirtAddr, size: u64) -> Result<(), RegionError> {
+        let end = check_range(start, size)?;
+        let mut remaining = Vec::with_capacity(self.regions.len() + 1);
+        for region in self.regions.drain(..) {
+            if region.end <= start || end <= region.start {
+                remaining.push(region);
+                continue;
+            }
+            if region.start < start {
+                remaining.push(Region { end: start, ..region });
+            }
+            if end < region.end {
+                remaining.push(Region { start: end, ..region });
+            }
+        }
+        self.regions = remaining;
+
+        let last_page = Page::<Size4KiB>::containing_address(end - 1u64);
+        for page in Page::range_inclusive(Page::containing_address(start), last_page) {
+            self.unmap_page(page);
+        }
+        Ok(())
+    }
+
+    // Finds the lowest range of the given size at or above `from` that isn't part of a region.
+    pub fn find_free_range(&self, from: VirtAddr, size: u64) -> Option<VirtAddr> {
+        let mut candidate = from.align_up(FRAME_SIZE).as_u64().max(USER_SPACE_START);
+        for region in &self.regions {
+            if region.end.as_u64() <= candidate {
+                continue;
+            }
+            if candidate.checked_add(size)? <= region.start.as_u64() {
+                break;
+            }
+            candidate = region.end.as_u64();
+        }
+        (candidate.checked_add(size)? <= USER_SPACE_END).then(|| VirtAddr::new(candidate))
+    }
+
+    // Gives an untouched page inside a region its frame, or a copy on write page its own copy,
+    // if the access is allowed. This is what turns a page fault into demand paging, so false
+    // means the fault is a real one.
+    pub fn handle_fault(&mut self, address: VirtAddr, access: Access) -> bool {
+        let page = Page::containing_address(address);
+        if let Some(flags) = self.page_flags(page) {
+            return match access {
+                Access::Read => true,
+                Access::Write if flags.contains(COPY_ON_WRITE) => self.copy_on_write(page, flags),
+                Access::Write => flags.contains(PageTableFlags::WRITABLE),
+                Access::Execute => !flags.contains(PageTableFlags::NO_EXECUTE)
+            };
+        }
+        match self.region_at(address) {
+            Some(region) if region.protection.allows(access) => {
+                let flags = region.protection.page_flags();
+                self.map_page(page, flags).is_ok()
+            }
+            _ => false
+        }
+    }
+
+    fn copy_on_write(&mut self, page: Page<Size4KiB>, flags: PageTableFlags) -> bool {
+        let mut mapper = unsafe { self.mapper() };
+        let frame = match mapper.translate_page(page) {
+            Ok(frame) => frame,
+            Err(_) => return false
+        };
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        if !is_shared(frame) {
+            // Everyone else already made their own copy, so this one can be written in place.
+            unsafe { mapper.update_flags(page, flags).expect("Failed to update a mapped page!").flush() };
+            return true;
+        }
+
+        let mut frame_allocator = frame_allocator();
+        let copy = match frame_allocator.allocate_frame() {
+            Some(copy) => copy,
+            None => return false
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(frame_pointer(frame), frame_pointer(copy), FRAME_SIZE as usize);
+            mapper.unmap(page).expect("Failed to unmap a mapped page!").1.flush();
+            let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+            mapper.map_to_with_table_flags(page, copy, flags, table_flags, &mut *frame_allocator)
+                .expect("Failed to remap a page that was just unmapped!")
+                .flush();
+            release_frame(&mut frame_allocator, frame);
+        }
+        true
+    }
+
+    // Makes a copy of this address space that shares every frame with it. Writable pages turn
+    // into read only copy on write pages in both, so nothing gets copied until it's written to.
+    pub fn fork(&mut self) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let mut child = AddressSpace::new()?;
+        child.regions = self.regions.clone();
+        for (page, frame, mut flags) in self.mapped_pages() {
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                unsafe { self.mapper().update_flags(page, flags).expect("Failed to update a mapped page!").flush() };
+            }
+            share_frame(frame);
+            let mut frame_allocator = frame_allocator();
+            let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+            let mapped = unsafe { child.mapper().map_to_with_table_flags(page, frame, flags, table_flags, &mut *frame_allocator) };
+            if let Err(error) = mapped {
+                // The child doesn't hold this reference yet, so it has to be given back by hand.
+                unsafe { release_frame(&mut frame_allocator, frame) };
+                return Err(error);
+            }
+        }
+        Ok(child)
+    }
+
+    // Every page mapped into the user half, along with its frame and flags.
+    fn mapped_pages(&self) -> Vec<(Page<Size4KiB>, PhysFrame, PageTableFlags)> {
+        let mut pages = Vec::new();
+        let present = |entry: &PageTableEntry| {
+            let flags = entry.flags();
+            flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE)
+        };
+        let level_4_table = unsafe { table_at(self.level_4_frame) };
+        for (level_4_index, level_4_entry) in level_4_table.iter().enumerate() {
+            if !is_user_entry(level_4_index) || !present(level_4_entry) {
+                continue;
+            }
+            let level_3_table = unsafe { table_at(PhysFrame::containing_address(level_4_entry.addr())) };
+            for (level_3_index, level_3_entry) in level_3_table.iter().enumerate().filter(|(_, entry)| present(entry)) {
+                let level_2_table = unsafe { table_at(PhysFrame::containing_address(level_3_entry.addr())) };
+                for (level_2_index, level_2_entry) in level_2_table.iter().enumerate().filter(|(_, entry)| present(entry)) {
+                    let level_1_table = unsafe { table_at(PhysFrame::containing_address(level_2_entry.addr())) };
+                    for (level_1_index, entry) in level_1_table.iter().enumerate().filter(|(_, entry)| present(entry)) {
+                        let address = ((level_4_index << 39) | (level_3_index << 30) | (level_2_index << 21) | (level_1_index << 12)) as u64;
+                        let page = Page::containing_address(VirtAddr::new(address));
+                        pages.push((page, PhysFrame::containing_address(entry.addr()), entry.flags()));
+                    }
+                }
+            }
+        }
+        pages
+    }
+
+    // Maps zeroed pages covering the given range. The pages are always present and accessible
+    // from ring 3, any other flags have to be passed in.
+    pub fn map_range(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        let start_page = Page::<Size4KiB>::containing_address(start);
+        let end_page = Page::containing_address(start + size.max(1) - 1u64);
+        for page in Page::range_inclusive(start_page, end_page) {
+            self.map_page(page, flags)?;
+        }
+        Ok(())
+    }
+
+    pub fn map_page(&mut self, page: Page<Size4KiB>, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        assert!(is_user_address(page.start_address()), "Tried to map a kernel page into a user address space!");
+        let mut frame_allocator = frame_allocator();
+        let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            ptr::write_bytes(frame_pointer(frame), 0, FRAME_SIZE as usize);
+            let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+            let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+            // This address space isn't necessarily active, so there is nothing to flush.
+            self.mapper().map_to_with_table_flags(page, frame, flags, table_flags, &mut *frame_allocator)?.ignore();
+        }
+        Ok(())
+    }
+
+    pub fn unmap_page(&mut self, page: Page<Size4KiB>) {
+        if let Ok((frame, flush)) = unsafe { self.mapper() }.unmap(page) {
+            // Flushing only matters if these tables are active, and is harmless otherwise.
+            flush.flush();
+            unsafe { release_frame(&mut frame_allocator(), frame) };
+        }
+    }
+
+    pub fn page_flags(&mut self, page: Page<Size4KiB>) -> Option<PageTableFlags> {
+        match unsafe { self.mapper() }.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None
+        }
+    }
+
+    pub fn set_page_flags(&mut self, page: Page<Size4KiB>, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        unsafe { self.mapper().update_flags(page, flags)?.flush() };
+        Ok(())
+    }
+
+    // Copies data into the address space, going through the physical memory mapping so that this
+    // works whether or not it's active. Untouched pages of a region get mapped on the way and
+    // copy on write pages get copied, but the write ignores the region's protection. Returns
+    // false if part of the range isn't in any region or mapped.
+    pub fn write(&mut self, address: VirtAddr, data: &[u8]) -> bool {
+        let mut written = 0;
+        while written < data.len() {
+            let current = address + written;
+            let page = Page::containing_address(current);
+            match self.page_flags(page) {
+                // Shared frames must never be written to directly.
+                Some(flags) if flags.contains(COPY_ON_WRITE) => {
+                    self.copy_on_write(page, flags);
+                }
+                None => {
+                    self.handle_fault(current, Access::Read);
+                }
+                _ => {}
+            }
+            let physical = match unsafe { self.mapper() }.translate_addr(current) {
+                Some(physical) => physical,
+                None => return false
+            };
+            let length = ((FRAME_SIZE - current.as_u64() % FRAME_SIZE) as usize).min(data.len() - written);
+            let destination = (physical_memory_offset() + physical.as_u64()).as_mut_ptr::<u8>();
+            unsafe { ptr::copy_nonoverlapping(data[written..].as_ptr(), destination, length) };
+            written += length;
+        }
+        true
+    }
+
+    // The returned mapper must not outlive the address space.
+    pub unsafe fn mapper(&mut self) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(table_at(self.level_4_frame), physical_memory_offset())
+    }
+}
+
+// Checks that a range is page aligned and inside user space, returning its end.
+fn check_range(start: VirtAddr, size: u64) -> Result<VirtAddr, RegionError> {
+    if size == 0 || !start.is_aligned(FRAME_SIZE) || !size.is_multiple_of(FRAME_SIZE) {
+        return Err(RegionError::Unaligned);
+    }
+    let end = start.as_u64().checked_add(size).ok_or(RegionError::OutsideUserSpace)?;
+    if !is_user_address(start) || end > USER_SPACE_END {
+        return Err(RegionError::OutsideUserSpace);
+    }
+    Ok(VirtAddr::new(end))
+}
+
+// Looks up how an address is mapped in whichever page tables are currently active.
+pub fn active_page_flags(address: VirtAddr) -> Option<PageTableFlags> {
+    let (level_4_frame, _) = Cr3::read();
+    let mapper = unsafe { OffsetPageTable::new(table_at(level_4_frame), physical_memory_offset()) };
+    match mapper.translate(address) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None
+    }
+}
+
+fn share_frame(frame: PhysFrame) {
+    let mut shared_frames = SHARED_FRAMES.lock();
+    *shared_frames.entry(frame).or_insert(1) += 1;
+}
+
+fn is_shared(frame: PhysFrame) -> bool {
+    SHARED_FRAMES.lock().contains_key(&frame)
+}
+
+// Drops one reference to a user frame, freeing it once nothing refers to it any more.
+unsafe fn release_frame(frame_allocator: &mut BitmapFrameAllocator, frame: PhysFrame) {
+    let mut shared_frames = SHARED_FRAMES.lock();
+    match shared_frames.get_mut(&frame) {
+        Some(count) if *count > 2 => *count -= 1,
+        Some(_) => {
+            shared_frames.remove(&frame);
+        }
+        None => frame_allocator.deallocate_frame(frame)
+    }
+}
+
+// Frees every frame mapped into the user half along with the page tables holding them, or at
+// least this address space's reference to them. The kernel half is shared, so it's left alone.
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "Tried to free the active address space!");
+        let mut frame_allocator = frame_allocator();
+        unsafe {
+            let level_4_table = table_at(self.level_4_frame);
+            for index in (0..512).filter(|&index| is_user_entry(index)) {
+                free_table(&mut *frame_allocator, &level_4_table[index], 3);
+            }
+            frame_allocator.deallocate_frame(self.level_4_frame);
+        }
+    }
+}
+
+// Frees whatever an entry points to, along with everything below it. The level counts how many
+// tables are left underneath, so entries at level 0 point at the mapped frames themselves.
+unsafe fn free_table(frame_allocator: &mut BitmapFrameAllocator, entry: &PageTableEntry, level: u8) {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+        return;
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    if level == 0 {
+        release_frame(frame_allocator, frame);
+        return;
+    }
+    for entry in table_at(frame).iter() {
+        free_table(frame_allocator, entry, level - 1);
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *(frame_pointer(frame) as *mut PageTable)
+}
+
+fn frame_pointer(frame: PhysFrame) -> *mut u8 {
+    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
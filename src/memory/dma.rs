@@ -0,0 +1,52 @@
+use core::slice;
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::PhysAddr;
+use super::{frame_allocator, physical_memory_offset, FRAME_SIZE};
+
+// Physically contiguous, zeroed memory for devices to read and write directly. It's reached
+// through the physical memory mapping, which is cached, so it's only suitable for devices that
+// snoop the cache, which is all of them on x86.
+pub struct DmaBuffer {
+    frames: PhysFrameRange,
+    size: usize
+}
+
+impl DmaBuffer {
+    pub fn new(size: usize) -> Option<Self> {
+        let count = (size.max(1) as u64).div_ceil(FRAME_SIZE) as usize;
+        let frames = frame_allocator().allocate_contiguous(count, 1)?;
+        let mut buffer = Self { frames, size };
+        buffer.as_mut_slice().fill(0);
+        Some(buffer)
+    }
+
+    pub fn physical_address(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        (physical_memory_offset() + self.physical_address().as_u64()).as_mut_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe { frame_allocator().deallocate_contiguous(self.frames) };
+    }
+}
@@ -0,0 +1,193 @@
+use bootloader::boot_info::{MemoryRegionKind, MemoryRegions};
+use core::slice;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+// A physical frame allocator that tracks every frame below the highest usable address
+// with a single bit (set = in use). The bitmap itself is carved out of the first usable
+// region large enough to hold it, and is accessed through the physical memory mapping.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    total_frames: usize,
+    used_frames: usize,
+    next_word: usize
+}
+
+impl BitmapFrameAllocator {
+    // Safety: the caller must guarantee that the memory regions are valid, that all of
+    // physical memory is mapped at the given offset, and that this is only called once.
+    pub unsafe fn new(memory_regions: &'static MemoryRegions, physical_memory_offset: VirtAddr) -> Self {
+        Self::new_avoiding(memory_regions, physical_memory_offset, None)
+    }
+
+    // Like `new`, but keeps the bitmap out of a range of usable memory that still holds something,
+    // which can then be taken with `reserve_range`.
+    pub unsafe fn new_avoiding(memory_regions: &'static MemoryRegions, physical_memory_offset: VirtAddr, avoid: Option<PhysFrameRange>) -> Self {
+        let usable_regions = || memory_regions.iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| (align_up(region.start), align_down(region.end)))
+            .filter(|(start, end)| start < end);
+
+        let highest_address = usable_regions().map(|(_, end)| end).max().unwrap_or(0);
+        let frame_count = (highest_address / FRAME_SIZE) as usize;
+        let word_count = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_size = align_up((word_count * 8) as u64);
+
+        let (avoid_start, avoid_end) = match avoid {
+            Some(range) => (range.start.start_address().as_u64(), range.end.start_address().as_u64()),
+            None => (0, 0)
+        };
+        let bitmap_start = usable_regions()
+            .map(|(start, end)| {
+                // Skip past the avoided range if the bitmap would overlap it.
+                let overlaps = start < avoid_end && avoid_start < start + bitmap_size;
+                (if overlaps { avoid_end } else { start }, end)
+            })
+            .find(|&(start, end)| start < end && end - start >= bitmap_size)
+            .map(|(start, _)| start)
+            .expect("No usable memory region is large enough to hold the frame bitmap!");
+        let bitmap_ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, word_count);
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = Self { bitmap, frame_count, total_frames: 0, used_frames: 0, next_word: 0 };
+        for (start, end) in usable_regions() {
+            for index in (start / FRAME_SIZE) as usize..(end / FRAME_SIZE) as usize {
+                allocator.clear_bit(index);
+                allocator.total_frames += 1;
+            }
+        }
+
+        // The bitmap's own frames and the null frame must never be handed out.
+        let bitmap_first_frame = (bitmap_start / FRAME_SIZE) as usize;
+        for index in bitmap_first_frame..bitmap_first_frame + (bitmap_size / FRAME_SIZE) as usize {
+            allocator.reserve(index);
+        }
+        if allocator.frame_count > 0 && !allocator.is_used(0) {
+            allocator.reserve(0);
+        }
+        allocator
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.used_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.total_frames - self.used_frames
+    }
+
+    // Allocates `count` physically contiguous frames, with the first frame aligned to
+    // `alignment` frames. This is intended for DMA buffers that can't be scattered.
+    pub fn allocate_contiguous(&mut self, count: usize, alignment: usize) -> Option<PhysFrameRange> {
+        if count == 0 || alignment == 0 {
+            return None;
+        }
+        let mut start = 0;
+        while start + count <= self.frame_count {
+            match (start..start + count).rev().find(|&index| self.is_used(index)) {
+                Some(used) => start = (used + 1).next_multiple_of(alignment),
+                None => {
+                    for index in start..start + count {
+                        self.set_bit(index);
+                    }
+                    self.used_frames += count;
+                    return Some(PhysFrame::range(frame_at(start), frame_at(start + count)));
+                }
+            }
+        }
+        None
+    }
+
+    // Safety: the frames must have been allocated by `allocate_contiguous` and be unused.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    // Takes frames out of circulation before anything else can allocate them, for memory the
+    // firmware or boot loader left data in. Either every frame gets reserved or, if any of them
+    // is already in use or isn't usable memory, none of them do, so the caller knows whether the
+    // whole range is theirs to free later.
+    pub fn reserve_range(&mut self, range: PhysFrameRange) -> bool {
+        let index = |frame: PhysFrame| (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        if range.into_iter().any(|frame| index(frame) >= self.frame_count || self.is_used(index(frame))) {
+            return false;
+        }
+        for frame in range {
+            self.reserve(index(frame));
+        }
+        true
+    }
+
+    fn reserve(&mut self, index: usize) {
+        self.set_bit(index);
+        self.used_frames += 1;
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let word_count = self.bitmap.len();
+        for offset in 0..word_count {
+            let word_index = (self.next_word + offset) % word_count;
+            let word = self.bitmap[word_index];
+            if word == u64::MAX {
+                continue;
+            }
+            let index = word_index * BITS_PER_WORD + word.trailing_ones() as usize;
+            if index >= self.frame_count {
+                continue;
+            }
+            self.set_bit(index);
+            self.used_frames += 1;
+            self.next_word = word_index;
+            return Some(frame_at(index));
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(self.is_used(index), "Attempted to free unallocated frame {:?}!", frame);
+        self.clear_bit(index);
+        self.used_frames -= 1;
+        self.next_word = self.next_word.min(index / BITS_PER_WORD);
+    }
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+fn align_up(address: u64) -> u64 {
+    (address + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
+
+fn align_down(address: u64) -> u64 {
+    address & !(FRAME_SIZE - 1)
+}
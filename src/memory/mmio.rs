@@ -0,0 +1,61 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::ptr;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB
+};
+use x86_64::{PhysAddr, VirtAddr};
+use super::{frame_allocator, mapper, BitmapFrameAllocator, FRAME_SIZE};
+
+// Device registers are mapped into their own window, uncached, rather than being accessed
+// through the physical memory mapping, which doesn't necessarily cover them.
+pub const MMIO_START: u64 = 0x555500000000;
+
+static NEXT_MMIO_ADDRESS: AtomicU64 = AtomicU64::new(MMIO_START);
+
+pub fn map_mmio(physical_address: PhysAddr, size: usize) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let start_frame = PhysFrame::<Size4KiB>::containing_address(physical_address);
+    let end_frame = PhysFrame::containing_address(physical_address + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(start_frame, end_frame);
+    let page_count = end_frame - start_frame + 1;
+
+    let virtual_start = NEXT_MMIO_ADDRESS.fetch_add(page_count * FRAME_SIZE, Ordering::Relaxed);
+    let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(virtual_start));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+
+    let mut mapper = mapper();
+    let mut frame_allocator = frame_allocator();
+    for (index, frame) in frames.enumerate() {
+        let page = start_page + index as u64;
+        unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator)?.flush() };
+    }
+    Ok(VirtAddr::new(virtual_start + physical_address.as_u64() % FRAME_SIZE))
+}
+
+// Takes a mapping made by `map_mmio` back out, for devices that are done with. The window is
+// never reused, so the addresses just become invalid.
+pub fn unmap_mmio(address: VirtAddr, size: usize) {
+    let start_page = Page::<Size4KiB>::containing_address(address);
+    let end_page = Page::containing_address(address + size.max(1) - 1u64);
+    let mut mapper = mapper();
+    for page in Page::range_inclusive(start_page, end_page) {
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    }
+}
+
+// Address spaces share the kernel's level 3 tables, so the window's level 4 entry has to exist
+// before any of them are made, even if nothing has been mapped into it yet.
+pub(super) fn reserve_window(mapper: &mut OffsetPageTable, frame_allocator: &mut BitmapFrameAllocator) {
+    let index = Page::<Size4KiB>::containing_address(VirtAddr::new(MMIO_START)).p4_index();
+    let physical_memory_offset = mapper.phys_offset();
+    let entry = &mut mapper.level_4_table()[index];
+    if !entry.is_unused() {
+        return;
+    }
+    if let Some(frame) = frame_allocator.allocate_frame() {
+        let table = (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr::<PageTable>();
+        unsafe { ptr::write(table, PageTable::new()) };
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+}
@@ -0,0 +1,59 @@
+mod address_space;
+mod dma;
+mod frame_allocator;
+mod mmio;
+
+pub use address_space::*;
+pub use dma::*;
+pub use frame_allocator::*;
+pub use mmio::*;
+
+use spin::Once;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{OffsetPageTable, PageTable};
+use x86_64::VirtAddr;
+use crate::sync::{IrqMutex, IrqMutexGuard};
+
+// The active mapper and frame allocator, kept around after boot so that subsystems like the
+// heap can map more memory on demand. When both are needed, always lock the mapper first.
+// The heap grows from inside the allocator, so these must be safe to take with interrupts off.
+static MAPPER: Once<IrqMutex<OffsetPageTable<'static>>> = Once::new();
+static FRAME_ALLOCATOR: Once<IrqMutex<BitmapFrameAllocator>> = Once::new();
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    // User pages can be mapped without execute permission, which needs this to be enabled.
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    // Copy on write relies on the kernel faulting on read only user pages too.
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+pub fn install(mut mapper: OffsetPageTable<'static>, mut frame_allocator: BitmapFrameAllocator) {
+    mmio::reserve_window(&mut mapper, &mut frame_allocator);
+    PHYSICAL_MEMORY_OFFSET.call_once(|| mapper.phys_offset());
+    MAPPER.call_once(|| IrqMutex::new(mapper));
+    FRAME_ALLOCATOR.call_once(|| IrqMutex::new(frame_allocator));
+}
+
+pub fn mapper() -> IrqMutexGuard<'static, OffsetPageTable<'static>> {
+    MAPPER.get().expect("Memory has not been initialized!").lock()
+}
+
+pub fn frame_allocator() -> IrqMutexGuard<'static, BitmapFrameAllocator> {
+    FRAME_ALLOCATOR.get().expect("Memory has not been initialized!").lock()
+}
+
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET.get().expect("Memory has not been initialized!")
+}
+
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+    let physical_addr = level_4_table_frame.start_address();
+    let virtual_addr = physical_memory_offset + physical_addr.as_u64();
+    let page_table_ptr: *mut PageTable = virtual_addr.as_mut_ptr();
+    &mut *page_table_ptr
+}
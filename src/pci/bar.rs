@@ -0,0 +1,88 @@
+use super::{config, PciAddress, COMMAND, COMMAND_IO, COMMAND_MEMORY};
+
+const BAR_0: u16 = 0x10;
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEMORY_TYPE: u32 = 0b11 << 1;
+const BAR_MEMORY_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Bar {
+    Memory { address: u64, size: u64, prefetchable: bool, wide: bool },
+    Io { port: u16, size: u32 }
+}
+
+impl Bar {
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => size as u64
+        }
+    }
+
+    pub fn memory_address(&self) -> Option<u64> {
+        match *self {
+            Bar::Memory { address, .. } => Some(address),
+            Bar::Io { .. } => None
+        }
+    }
+
+    pub fn io_port(&self) -> Option<u16> {
+        match *self {
+            Bar::Io { port, .. } => Some(port),
+            Bar::Memory { .. } => None
+        }
+    }
+}
+
+// Writing all ones to a BAR and reading it back leaves only the bits the device lets software
+// choose, which gives away how big the region is.
+fn probe(address: PciAddress, offset: u16) -> (u32, u32) {
+    let original = config::read_u32(address, offset);
+    config::write_u32(address, offset, u32::MAX);
+    let mask = config::read_u32(address, offset);
+    config::write_u32(address, offset, original);
+    (original, mask)
+}
+
+// Reads the first `count` BARs. A 64 bit BAR takes up two slots, of which the second is left empty.
+// Decoding is turned off meanwhile, so the device doesn't briefly claim whatever addresses the
+// all ones pattern happens to look like.
+pub(super) fn read_bars(address: PciAddress, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let command = config::read_u16(address, COMMAND);
+    config::write_u16(address, COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+    let mut index = 0;
+    while index < count {
+        let offset = BAR_0 + index as u16 * 4;
+        let (original, mask) = probe(address, offset);
+        if original & BAR_IO != 0 {
+            // The top half of I/O BARs is allowed to be hardwired to zero.
+            let mask = mask & !0b11;
+            if mask != 0 {
+                let mask = if mask & 0xFFFF_0000 == 0 { mask | 0xFFFF_0000 } else { mask };
+                let port = (original & !0b11) as u16;
+                bars[index] = Some(Bar::Io { port, size: (!mask).wrapping_add(1) });
+            }
+            index += 1;
+            continue;
+        }
+
+        let wide = original & BAR_MEMORY_TYPE == BAR_MEMORY_64 && index + 1 < count;
+        let (high, high_mask) = if wide { probe(address, offset + 4) } else { (0, u32::MAX) };
+        let mask = (high_mask as u64) << 32 | (mask & !0xF) as u64;
+        if mask & 0xFFFF_FFFF != 0 || (wide && high_mask != 0) {
+            bars[index] = Some(Bar::Memory {
+                address: (high as u64) << 32 | (original & !0xF) as u64,
+                size: (!mask).wrapping_add(1),
+                prefetchable: original & BAR_PREFETCHABLE != 0,
+                wide
+            });
+        }
+        index += if wide { 2 } else { 1 };
+    }
+
+    config::write_u16(address, COMMAND, command);
+    bars
+}
@@ -0,0 +1,194 @@
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::PhysAddr;
+use crate::{interrupt, memory};
+use crate::syscall::Errno;
+use super::{config, Bar, PciAddress, PciDevice, COMMAND_MEMORY, STATUS, STATUS_CAPABILITIES};
+
+pub const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+const CAPABILITIES_POINTER: u16 = 0x34;
+// A list can't have more entries than fit after the header, which stops a looping list.
+const MAX_CAPABILITIES: usize = 48;
+
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_MESSAGE_ENABLE: u16 = 0b111 << 4;
+const MSI_64_BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+const MSIX_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+// Messages are memory writes to this window, which the local APIC picks up.
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u8
+}
+
+pub(super) fn read_capabilities(address: PciAddress) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if config::read_u16(address, STATUS) & STATUS_CAPABILITIES == 0 {
+        return capabilities;
+    }
+    let mut offset = config::read_u8(address, CAPABILITIES_POINTER) & !0b11;
+    // Anything before 0x40 would be in the header, so it marks the end just like 0 does.
+    while offset >= 0x40 && capabilities.len() < MAX_CAPABILITIES {
+        let id = config::read_u8(address, offset as u16);
+        capabilities.push(Capability { id, offset });
+        offset = config::read_u8(address, offset as u16 + 1) & !0b11;
+    }
+    capabilities
+}
+
+// Where and with what to send a message so that it arrives at the given vector on the bootstrap
+// processor. Only the APIC can receive messages, so there's nothing to send without it.
+pub fn msi_message(vector: u8) -> Option<(u64, u32)> {
+    let destination = interrupt::local_apic_id()?;
+    Some((MSI_ADDRESS_BASE | (destination as u64) << 12, vector as u32))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Msi {
+    offset: u8,
+    pub wide: bool,
+    pub per_vector_masking: bool,
+    // How many vectors the function would like, though only one is ever used here.
+    pub vectors: u8
+}
+
+impl Msi {
+    pub(super) fn read(address: PciAddress, capability: Capability) -> Self {
+        let control = config::read_u16(address, capability.offset as u16 + 2);
+        Self {
+            offset: capability.offset,
+            wide: control & MSI_64_BIT != 0,
+            per_vector_masking: control & MSI_PER_VECTOR_MASKING != 0,
+            vectors: 1 << ((control >> 1) & 0b111)
+        }
+    }
+
+    fn control(&self) -> u16 {
+        self.offset as u16 + 2
+    }
+
+    pub fn enabled(&self, address: PciAddress) -> bool {
+        config::read_u16(address, self.control()) & MSI_ENABLE != 0
+    }
+
+    // Sends the function's single message to the given vector, which also stops it raising its
+    // legacy interrupt.
+    pub fn enable(&self, address: PciAddress, vector: u8) -> Result<(), Errno> {
+        let (message_address, message_data) = msi_message(vector).ok_or(Errno::ENODEV)?;
+        let base = self.offset as u16;
+        config::write_u32(address, base + 4, message_address as u32);
+        let data_offset = if self.wide {
+            config::write_u32(address, base + 8, (message_address >> 32) as u32);
+            base + 0xC
+        } else {
+            base + 8
+        };
+        config::write_u16(address, data_offset, message_data as u16);
+        if self.per_vector_masking {
+            config::write_u32(address, data_offset + 4, 0);
+        }
+        let control = config::read_u16(address, self.control()) & !MSI_MULTIPLE_MESSAGE_ENABLE;
+        config::write_u16(address, self.control(), control | MSI_ENABLE);
+        Ok(())
+    }
+
+    pub fn disable(&self, address: PciAddress) {
+        let control = config::read_u16(address, self.control());
+        config::write_u16(address, self.control(), control & !MSI_ENABLE);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MsiX {
+    offset: u8,
+    pub table_size: u16,
+    pub table_bar: u8,
+    pub table_offset: u32,
+    pub pending_bar: u8,
+    pub pending_offset: u32
+}
+
+impl MsiX {
+    pub(super) fn read(address: PciAddress, capability: Capability) -> Self {
+        let control = config::read_u16(address, capability.offset as u16 + 2);
+        let table = config::read_u32(address, capability.offset as u16 + 4);
+        let pending = config::read_u32(address, capability.offset as u16 + 8);
+        Self {
+            offset: capability.offset,
+            table_size: (control & MSIX_TABLE_SIZE) + 1,
+            table_bar: (table & 0b111) as u8,
+            table_offset: table & !0b111,
+            pending_bar: (pending & 0b111) as u8,
+            pending_offset: pending & !0b111
+        }
+    }
+
+    fn control(&self) -> u16 {
+        self.offset as u16 + 2
+    }
+
+    pub fn enabled(&self, address: PciAddress) -> bool {
+        config::read_u16(address, self.control()) & MSIX_ENABLE != 0
+    }
+
+    // Points the first entries of the table at the given vectors, one each, and masks the rest.
+    // The table lives in one of the function's memory BARs, which has to be mapped to reach it, and
+    // which the function has to be decoding.
+    pub fn enable(&self, device: &PciDevice, vectors: &[u8]) -> Result<(), Errno> {
+        if vectors.len() > self.table_size as usize {
+            return Err(Errno::EINVAL);
+        }
+        let messages = vectors.iter()
+            .map(|&vector| msi_message(vector).ok_or(Errno::ENODEV))
+            .collect::<Result<Vec<_>, _>>()?;
+        let bar_address = match device.bars.get(self.table_bar as usize) {
+            Some(Some(Bar::Memory { address, .. })) => *address,
+            _ => return Err(Errno::ENODEV)
+        };
+        let table_size = self.table_size as u64 * MSIX_ENTRY_SIZE;
+        let table = memory::map_mmio(PhysAddr::new(bar_address + self.table_offset as u64), table_size as usize)
+            .map_err(|_| Errno::ENOMEM)?;
+
+        device.enable(COMMAND_MEMORY);
+        // Nothing gets sent while the entries are being filled in.
+        let control = config::read_u16(device.address, self.control());
+        config::write_u16(device.address, self.control(), control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+        for index in 0..self.table_size as usize {
+            let entry = (table + index as u64 * MSIX_ENTRY_SIZE).as_mut_ptr::<u32>();
+            let (message_address, message_data) = match messages.get(index) {
+                Some(&message) => message,
+                None => {
+                    unsafe { ptr::write_volatile(entry.add(3), MSIX_ENTRY_MASKED) };
+                    continue;
+                }
+            };
+            unsafe {
+                ptr::write_volatile(entry, message_address as u32);
+                ptr::write_volatile(entry.add(1), (message_address >> 32) as u32);
+                ptr::write_volatile(entry.add(2), message_data);
+                ptr::write_volatile(entry.add(3), 0);
+            }
+        }
+        config::write_u16(device.address, self.control(), (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+        Ok(())
+    }
+
+    pub fn disable(&self, address: PciAddress) {
+        let control = config::read_u16(address, self.control());
+        config::write_u16(address, self.control(), control & !MSIX_ENABLE);
+    }
+}
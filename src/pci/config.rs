@@ -0,0 +1,118 @@
+use acpi::mcfg::PciConfigRegions;
+use alloc::collections::BTreeMap;
+use core::ptr;
+use log::{info, warn};
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::{firmware, memory};
+use crate::sync::IrqMutex;
+use super::PciAddress;
+
+// The legacy mechanism selects a function and register through one port, then moves the data
+// through another. It can only reach the first 256 bytes of each function, on segment 0.
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+const CONFIG_ENABLE: u32 = 1 << 31;
+const LEGACY_CONFIG_SIZE: u16 = 256;
+
+// ECAM gives every function 4 KiB of configuration space in memory, so each bus gets 1 MiB.
+const ECAM_CONFIG_SIZE: u16 = 4096;
+const ECAM_BUS_SIZE: usize = 32 * 8 * ECAM_CONFIG_SIZE as usize;
+
+static ECAM: Once<Option<PciConfigRegions>> = Once::new();
+// Selecting a register and accessing it has to happen without anyone else selecting another.
+static LEGACY_LOCK: IrqMutex<()> = IrqMutex::new(());
+// Each bus's configuration space gets mapped the first time it's accessed, and stays mapped, since
+// mapping all of it up front would take 256 MiB of address space per segment.
+static ECAM_MAPPINGS: IrqMutex<BTreeMap<(u16, u8), u64>> = IrqMutex::new(BTreeMap::new());
+
+// Uses ECAM if the firmware has an MCFG table, otherwise sticks with the legacy ports. Until this
+// has been called, everything goes through the legacy ports.
+pub(super) fn init() {
+    let regions = ECAM.call_once(firmware::pci_config_regions);
+    match regions {
+        Some(_) => info!("Accessing PCI configuration space through ECAM."),
+        None => info!("No MCFG table, accessing PCI configuration space through ports 0xCF8 and 0xCFC.")
+    }
+}
+
+pub fn ecam_enabled() -> bool {
+    matches!(ECAM.get(), Some(Some(_)))
+}
+
+fn ecam_address(regions: &PciConfigRegions, address: PciAddress, offset: u16) -> Option<u64> {
+    if offset >= ECAM_CONFIG_SIZE {
+        return None;
+    }
+    let function_offset = (address.device as u64) << 15 | (address.function as u64) << 12;
+    let mut mappings = ECAM_MAPPINGS.lock();
+    let base = match mappings.get(&(address.segment, address.bus)) {
+        Some(&base) => base,
+        None => {
+            let physical_address = regions.physical_address(address.segment, address.bus, 0, 0)?;
+            match memory::map_mmio(PhysAddr::new(physical_address), ECAM_BUS_SIZE) {
+                Ok(base) => *mappings.entry((address.segment, address.bus)).or_insert(base.as_u64()),
+                Err(error) => {
+                    warn!("Failed to map the configuration space of bus {:02x} on segment {:04x}: {:?}", address.bus, address.segment, error);
+                    return None;
+                }
+            }
+        }
+    };
+    Some(base + function_offset + offset as u64)
+}
+
+fn select(address: PciAddress, offset: u16) {
+    let value = CONFIG_ENABLE
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset as u32 & 0xFC);
+    unsafe { Port::<u32>::new(CONFIG_ADDRESS).write(value) };
+}
+
+fn legacy_reachable(address: PciAddress, offset: u16) -> bool {
+    address.segment == 0 && offset < LEGACY_CONFIG_SIZE
+}
+
+// Registers that can't be reached read as all ones, just like functions that don't exist.
+macro_rules! config_accessors {
+    ($read:ident, $write:ident, $type:ty) => {
+        pub fn $read(address: PciAddress, offset: u16) -> $type {
+            let offset = offset & !(core::mem::size_of::<$type>() as u16 - 1);
+            if let Some(Some(regions)) = ECAM.get() {
+                return match ecam_address(regions, address, offset) {
+                    Some(pointer) => unsafe { ptr::read_volatile(pointer as *const $type) },
+                    None => <$type>::MAX
+                };
+            }
+            if !legacy_reachable(address, offset) {
+                return <$type>::MAX;
+            }
+            let _lock = LEGACY_LOCK.lock();
+            select(address, offset);
+            unsafe { Port::<$type>::new(CONFIG_DATA + (offset & 3)).read() }
+        }
+
+        pub fn $write(address: PciAddress, offset: u16, value: $type) {
+            let offset = offset & !(core::mem::size_of::<$type>() as u16 - 1);
+            if let Some(Some(regions)) = ECAM.get() {
+                if let Some(pointer) = ecam_address(regions, address, offset) {
+                    unsafe { ptr::write_volatile(pointer as *mut $type, value) };
+                }
+                return;
+            }
+            if !legacy_reachable(address, offset) {
+                return;
+            }
+            let _lock = LEGACY_LOCK.lock();
+            select(address, offset);
+            unsafe { Port::<$type>::new(CONFIG_DATA + (offset & 3)).write(value) };
+        }
+    };
+}
+
+config_accessors!(read_u8, write_u8, u8);
+config_accessors!(read_u16, write_u16, u16);
+config_accessors!(read_u32, write_u32, u32);
@@ -0,0 +1,100 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use log::{info, warn};
+use spin::Mutex;
+use crate::syscall::Errno;
+use super::{PciAddress, PciDevice};
+
+// Anything left as `None` matches every device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeviceMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>
+}
+
+impl DeviceMatch {
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        Self { vendor_id: Some(vendor_id), device_id: Some(device_id), class: None, subclass: None, prog_if: None }
+    }
+
+    pub const fn vendor(vendor_id: u16) -> Self {
+        Self { vendor_id: Some(vendor_id), device_id: None, class: None, subclass: None, prog_if: None }
+    }
+
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        Self { vendor_id: None, device_id: None, class: Some(class), subclass: Some(subclass), prog_if: None }
+    }
+
+    pub const fn interface(class: u8, subclass: u8, prog_if: u8) -> Self {
+        Self { vendor_id: None, device_id: None, class: Some(class), subclass: Some(subclass), prog_if: Some(prog_if) }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        fn field<T: PartialEq>(wanted: Option<T>, actual: T) -> bool {
+            wanted.is_none_or(|wanted| wanted == actual)
+        }
+        field(self.vendor_id, device.vendor_id)
+            && field(self.device_id, device.device_id)
+            && field(self.class, device.class)
+            && field(self.subclass, device.subclass)
+            && field(self.prog_if, device.prog_if)
+    }
+}
+
+pub trait Driver: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn matches(&self) -> &[DeviceMatch];
+
+    // Takes over a device that matched. Failing leaves the device for any other driver that
+    // matches it.
+    fn probe(&self, device: &PciDevice) -> Result<(), Errno>;
+}
+
+static DRIVERS: Mutex<Vec<Arc<dyn Driver>>> = Mutex::new(Vec::new());
+static BOUND: Mutex<BTreeMap<PciAddress, &'static str>> = Mutex::new(BTreeMap::new());
+
+// Offers a device to every driver that matches it, in the order they were registered, until
+// one of them takes it.
+fn bind(device: &PciDevice, drivers: &[Arc<dyn Driver>]) -> bool {
+    if BOUND.lock().contains_key(&device.address) {
+        return false;
+    }
+    let candidates = drivers.iter()
+        .filter(|driver| driver.matches().iter().any(|entry| entry.matches(device)));
+    for driver in candidates {
+        match driver.probe(device) {
+            Ok(()) => {
+                info!("Bound {} to the {} driver.", device, driver.name());
+                BOUND.lock().insert(device.address, driver.name());
+                return true;
+            }
+            Err(error) => warn!("The {} driver failed to take {}: {:?}", driver.name(), device, error)
+        }
+    }
+    false
+}
+
+// Drivers are offered every device that's already been found and nobody has taken, as well as
+// any found later on. Returns how many devices it took.
+pub fn register_driver(driver: Arc<dyn Driver>) -> usize {
+    DRIVERS.lock().push(driver.clone());
+    let drivers = [driver];
+    super::devices().iter().filter(|device| bind(device, &drivers)).count()
+}
+
+pub fn bound_driver(address: PciAddress) -> Option<&'static str> {
+    BOUND.lock().get(&address).copied()
+}
+
+// Probing happens without holding on to the registry, so that drivers can register others.
+pub(super) fn bind_all(devices: &[PciDevice]) {
+    let drivers = DRIVERS.lock().clone();
+    for device in devices {
+        bind(device, &drivers);
+    }
+}
@@ -0,0 +1,233 @@
+mod bar;
+mod capability;
+mod config;
+mod driver;
+
+pub use bar::Bar;
+pub use capability::{
+    msi_message, Capability, Msi, MsiX, CAPABILITY_MSI, CAPABILITY_MSIX, CAPABILITY_PCI_EXPRESS,
+    CAPABILITY_POWER_MANAGEMENT, CAPABILITY_VENDOR_SPECIFIC
+};
+pub use config::{ecam_enabled, read_u16, read_u32, read_u8, write_u16, write_u32, write_u8};
+pub use driver::{bound_driver, register_driver, DeviceMatch, Driver};
+
+use alloc::vec::Vec;
+use core::fmt;
+use log::{debug, info};
+use spin::RwLock;
+
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION: u16 = 0x08;
+pub const PROG_IF: u16 = 0x09;
+pub const SUBCLASS: u16 = 0x0A;
+pub const CLASS: u16 = 0x0B;
+pub const HEADER_TYPE: u16 = 0x0E;
+pub const SUBSYSTEM_VENDOR_ID: u16 = 0x2C;
+pub const SUBSYSTEM_ID: u16 = 0x2E;
+pub const INTERRUPT_LINE: u16 = 0x3C;
+pub const INTERRUPT_PIN: u16 = 0x3D;
+const SECONDARY_BUS: u16 = 0x19;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_MULTIFUNCTION: u8 = 1 << 7;
+const HEADER_GENERAL: u8 = 0x00;
+const HEADER_PCI_BRIDGE: u8 = 0x01;
+
+pub const CLASS_MASS_STORAGE: u8 = 0x01;
+pub const CLASS_NETWORK: u8 = 0x02;
+pub const CLASS_DISPLAY: u8 = 0x03;
+pub const CLASS_BRIDGE: u8 = 0x06;
+pub const SUBCLASS_HOST_BRIDGE: u8 = 0x00;
+pub const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+static DEVICES: RwLock<Vec<PciDevice>> = RwLock::new(Vec::new());
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self { segment, bus, device, function }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+// A function as it was when the bus was enumerated.
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    // Bridges don't have a subsystem, so these are 0 for them.
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>
+}
+
+impl PciDevice {
+    fn read(address: PciAddress) -> Option<Self> {
+        let vendor_id = read_u16(address, VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let header_type = read_u8(address, HEADER_TYPE) & HEADER_TYPE_MASK;
+        let (bar_count, subsystem_vendor_id, subsystem_id) = match header_type {
+            HEADER_GENERAL => (6, read_u16(address, SUBSYSTEM_VENDOR_ID), read_u16(address, SUBSYSTEM_ID)),
+            HEADER_PCI_BRIDGE => (2, 0, 0),
+            _ => (0, 0, 0)
+        };
+        Some(Self {
+            address,
+            vendor_id,
+            device_id: read_u16(address, DEVICE_ID),
+            class: read_u8(address, CLASS),
+            subclass: read_u8(address, SUBCLASS),
+            prog_if: read_u8(address, PROG_IF),
+            revision: read_u8(address, REVISION),
+            header_type,
+            subsystem_vendor_id,
+            subsystem_id,
+            interrupt_line: read_u8(address, INTERRUPT_LINE),
+            interrupt_pin: read_u8(address, INTERRUPT_PIN),
+            bars: bar::read_bars(address, bar_count),
+            capabilities: capability::read_capabilities(address)
+        })
+    }
+
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities.iter().find(|capability| capability.id == id).copied()
+    }
+
+    pub fn msi(&self) -> Option<Msi> {
+        Some(Msi::read(self.address, self.capability(CAPABILITY_MSI)?))
+    }
+
+    pub fn msix(&self) -> Option<MsiX> {
+        Some(MsiX::read(self.address, self.capability(CAPABILITY_MSIX)?))
+    }
+
+    // Turns on the given command register bits, such as decoding and bus mastering.
+    pub fn enable(&self, command: u16) {
+        write_u16(self.address, COMMAND, read_u16(self.address, COMMAND) | command);
+    }
+
+    pub fn disable(&self, command: u16) {
+        write_u16(self.address, COMMAND, read_u16(self.address, COMMAND) & !command);
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{:04x}:{:04x}]", self.address, self.vendor_id, self.device_id)
+    }
+}
+
+struct Scan {
+    devices: Vec<PciDevice>,
+    visited: [bool; 256]
+}
+
+impl Scan {
+    fn bus(&mut self, bus: u8) {
+        if self.visited[bus as usize] {
+            return;
+        }
+        self.visited[bus as usize] = true;
+        for device in 0..DEVICES_PER_BUS {
+            self.device(bus, device);
+        }
+    }
+
+    fn device(&mut self, bus: u8, device: u8) {
+        let address = PciAddress::new(0, bus, device, 0);
+        if read_u16(address, VENDOR_ID) == 0xFFFF {
+            return;
+        }
+        let functions = if read_u8(address, HEADER_TYPE) & HEADER_MULTIFUNCTION != 0 { FUNCTIONS_PER_DEVICE } else { 1 };
+        for function in 0..functions {
+            self.function(PciAddress::new(0, bus, device, function));
+        }
+    }
+
+    fn function(&mut self, address: PciAddress) {
+        let device = match PciDevice::read(address) {
+            Some(device) => device,
+            None => return
+        };
+        debug!("PCI {} class {:02x}:{:02x}:{:02x}.", device, device.class, device.subclass, device.prog_if);
+        let secondary_bus = (device.header_type == HEADER_PCI_BRIDGE).then(|| read_u8(address, SECONDARY_BUS));
+        self.devices.push(device);
+        if let Some(secondary_bus) = secondary_bus {
+            self.bus(secondary_bus);
+        }
+    }
+}
+
+// Walks segment 0 from the host bridges down through every PCI to PCI bridge. Each function of
+// a multifunction host bridge is the host bridge for the bus with its function number.
+fn enumerate() -> Vec<PciDevice> {
+    let mut scan = Scan { devices: Vec::new(), visited: [false; 256] };
+    let host = PciAddress::new(0, 0, 0, 0);
+    if read_u8(host, HEADER_TYPE) & HEADER_MULTIFUNCTION == 0 {
+        scan.bus(0);
+    } else {
+        for function in 0..FUNCTIONS_PER_DEVICE {
+            if read_u16(PciAddress::new(0, 0, 0, function), VENDOR_ID) != 0xFFFF {
+                scan.bus(function);
+            }
+        }
+    }
+    scan.devices.sort_by_key(|device| device.address);
+    scan.devices
+}
+
+// Needs the ACPI tables to have been parsed already, to know whether ECAM can be used.
+pub fn init() {
+    config::init();
+    let devices = enumerate();
+    info!("Found {} PCI function(s).", devices.len());
+    *DEVICES.write() = devices.clone();
+    driver::bind_all(&devices);
+}
+
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.read().clone()
+}
+
+pub fn device(address: PciAddress) -> Option<PciDevice> {
+    DEVICES.read().iter().find(|device| device.address == address).cloned()
+}
+
+pub fn find(matching: DeviceMatch) -> Vec<PciDevice> {
+    DEVICES.read().iter().filter(|device| matching.matches(device)).cloned().collect()
+}
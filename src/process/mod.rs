@@ -0,0 +1,381 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use log::debug;
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::VirtAddr;
+use crate::elf::{self, ElfError};
+use crate::fs::FileTable;
+use crate::memory::{Access, AddressSpace};
+use crate::sync::IrqMutex;
+use crate::syscall::{Errno, UserRegisters};
+use crate::thread::{self, ThreadId};
+use crate::usermode::{self, USER_CODE_START, USER_STACK_TOP};
+
+// Processes killed by a fault are reported as killed by the matching signal.
+pub const SIGILL: i32 = 4;
+pub const SIGBUS: i32 = 7;
+pub const SIGFPE: i32 = 8;
+pub const SIGSEGV: i32 = 11;
+
+lazy_static! {
+    static ref PROCESSES: IrqMutex<ProcessTable> = IrqMutex::new(ProcessTable::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Code(i32),
+    Signal(i32)
+}
+
+impl ExitStatus {
+    // The status as wait4 reports it, with the exit code in the second byte, or the signal in the
+    // low 7 bits.
+    pub fn wait_status(self) -> u32 {
+        match self {
+            ExitStatus::Code(code) => ((code & 0xFF) << 8) as u32,
+            ExitStatus::Signal(signal) => (signal & 0x7F) as u32
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    Exited(ExitStatus)
+}
+
+// A snapshot of a process, for looking at it from outside the process table.
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub children: Vec<Pid>,
+    pub state: ProcessState,
+    pub name: String
+}
+
+struct Process {
+    // Processes started by the kernel don't have a parent, and can be waited on by any kernel
+    // thread instead, unless they've been detached.
+    parent: Option<Pid>,
+    children: Vec<Pid>,
+    state: ProcessState,
+    // Set when the parent exits first or the process is detached, so nothing is left to wait for
+    // this process and it's removed as soon as it exits.
+    orphaned: bool,
+    address_space: Option<AddressSpace>,
+    files: FileTable,
+    // Always canonical, since relative paths just get joined onto it.
+    working_directory: String,
+    name: String
+}
+
+#[derive(Default)]
+struct ProcessTable {
+    processes: BTreeMap<Pid, Process>,
+    threads: BTreeMap<ThreadId, Pid>,
+    // Threads blocked in `wait`, which get woken whenever any process exits.
+    waiters: Vec<ThreadId>
+}
+
+impl ProcessTable {
+    fn remove(&mut self, pid: Pid) {
+        if let Some(process) = self.processes.remove(&pid) {
+            if let Some(parent) = process.parent.and_then(|parent| self.processes.get_mut(&parent)) {
+                parent.children.retain(|&child| child != pid);
+            }
+        }
+    }
+}
+
+// Loads a statically linked executable into a new address space and starts running it as a
+// child of the current process.
+pub fn spawn(bytes: &[u8], arguments: &[&str], environment: &[&str]) -> Result<Pid, ElfError> {
+    let mut address_space = AddressSpace::new()?;
+    let image = elf::load(&mut address_space, bytes)?;
+    let stack_pointer = elf::setup_stack(&mut address_space, &image, arguments, environment)?;
+    let name = arguments.first().copied().unwrap_or_default();
+    let pid = start(name.into(), address_space, move || unsafe { usermode::enter(image.entry, stack_pointer) });
+    Ok(pid)
+}
+
+// Starts a process running a flat binary from its first byte.
+pub fn spawn_flat_binary(code: &[u8]) -> Result<Pid, MapToError<Size4KiB>> {
+    let mut address_space = AddressSpace::new()?;
+    usermode::load_flat_binary(&mut address_space, code)?;
+    let pid = start(String::new(), address_space, || unsafe {
+        usermode::enter(VirtAddr::new(USER_CODE_START), VirtAddr::new(USER_STACK_TOP))
+    });
+    Ok(pid)
+}
+
+// Duplicates the current process, with the child getting a copy on write copy of the address
+// space. The child starts out returning from the same system call as the parent, with the saved
+// registers apart from rax, which is zero.
+pub fn fork(registers: &UserRegisters) -> Result<Pid, Errno> {
+    let (address_space, name) = {
+        let thread = thread::current();
+        let mut table = PROCESSES.lock();
+        let pid = *table.threads.get(&thread).ok_or(Errno::EINVAL)?;
+        let process = table.processes.get_mut(&pid).ok_or(Errno::ESRCH)?;
+        let address_space = process.address_space.as_mut().ok_or(Errno::ESRCH)?;
+        (address_space.fork().map_err(|_| Errno::ENOMEM)?, process.name.clone())
+    };
+    let registers = UserRegisters { rax: 0, ..*registers };
+    Ok(start(name, address_space, move || unsafe { usermode::resume(&registers) }))
+}
+
+// Registers a process and starts its first thread, which switches to the new address space and
+// then runs `enter` to get into user mode.
+fn start(name: String, address_space: AddressSpace, enter: impl FnOnce() -> ! + Send + 'static) -> Pid {
+    let pid = Pid::new();
+    let parent = current();
+    let level_4_frame = address_space.level_4_frame();
+    {
+        let mut table = PROCESSES.lock();
+        // Children inherit their parent's files and working directory, while processes started
+        // by the kernel get the console and the root.
+        let (files, working_directory) = match parent.and_then(|parent| table.processes.get_mut(&parent)) {
+            Some(parent) => {
+                parent.children.push(pid);
+                (parent.files.clone(), parent.working_directory.clone())
+            }
+            None => (FileTable::with_console(), String::from("/"))
+        };
+        let process = Process {
+            parent,
+            children: Vec::new(),
+            state: ProcessState::Running,
+            orphaned: false,
+            address_space: Some(address_space),
+            files,
+            working_directory,
+            name
+        };
+        table.processes.insert(pid, process);
+    }
+
+    // The thread registers itself, so it's part of the process before it ever runs user code.
+    thread::spawn(move || {
+        let thread = thread::current();
+        PROCESSES.lock().threads.insert(thread, pid);
+        thread::set_page_table(Some(level_4_frame));
+        enter()
+    });
+    pid
+}
+
+// The process the current thread belongs to, if it isn't a kernel thread.
+pub fn current() -> Option<Pid> {
+    let thread = thread::current();
+    PROCESSES.lock().threads.get(&thread).copied()
+}
+
+// Runs `f` on the current process's address space, or returns `None` on a kernel thread.
+pub fn with_address_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    let thread = thread::current();
+    let mut table = PROCESSES.lock();
+    let pid = *table.threads.get(&thread)?;
+    table.processes.get_mut(&pid)?.address_space.as_mut().map(f)
+}
+
+// Runs `f` on the current process's file descriptors, or returns `None` on a kernel thread.
+// Nothing in `f` should block, since the process table stays locked.
+pub fn with_files<R>(f: impl FnOnce(&mut FileTable) -> R) -> Option<R> {
+    let thread = thread::current();
+    let mut table = PROCESSES.lock();
+    let pid = *table.threads.get(&thread)?;
+    table.processes.get_mut(&pid).map(|process| f(&mut process.files))
+}
+
+// Kernel threads always work from the root.
+pub fn working_directory() -> String {
+    let thread = thread::current();
+    let table = PROCESSES.lock();
+    table.threads.get(&thread)
+        .and_then(|pid| table.processes.get(pid))
+        .map_or_else(|| String::from("/"), |process| process.working_directory.clone())
+}
+
+// The path has to be canonical, which `fs::canonicalize` takes care of.
+pub fn set_working_directory(path: String) -> Result<(), Errno> {
+    let thread = thread::current();
+    let mut table = PROCESSES.lock();
+    let pid = *table.threads.get(&thread).ok_or(Errno::EPERM)?;
+    let process = table.processes.get_mut(&pid).ok_or(Errno::ESRCH)?;
+    process.working_directory = path;
+    Ok(())
+}
+
+// Tries to resolve a fault in the current process, either by mapping an untouched page or by
+// copying a copy on write one. Returns false if the access isn't allowed, so the fault is real.
+// Nothing touches user memory with the process table locked, so if it's locked here the kernel
+// faulted while holding it, and waiting for it would never finish.
+pub fn handle_page_fault(address: VirtAddr, access: Access) -> bool {
+    let thread = thread::current();
+    let mut table = match PROCESSES.try_lock() {
+        Some(table) => table,
+        None => return false
+    };
+    let pid = match table.threads.get(&thread) {
+        Some(&pid) => pid,
+        None => return false
+    };
+    match table.processes.get_mut(&pid).and_then(|process| process.address_space.as_mut()) {
+        Some(address_space) => address_space.handle_fault(address, access),
+        None => false
+    }
+}
+
+pub fn info(pid: Pid) -> Option<ProcessInfo> {
+    let table = PROCESSES.lock();
+    table.processes.get(&pid).map(|process| ProcessInfo {
+        pid,
+        parent: process.parent,
+        children: process.children.clone(),
+        state: process.state,
+        name: process.name.clone()
+    })
+}
+
+pub fn count() -> usize {
+    PROCESSES.lock().processes.len()
+}
+
+// Gives up on waiting for a process the kernel started, so that it's cleaned up as soon as it
+// exits instead of staying around until something waits for it.
+pub fn detach(pid: Pid) -> Result<(), Errno> {
+    let mut table = PROCESSES.lock();
+    let process = table.processes.get_mut(&pid).ok_or(Errno::ESRCH)?;
+    if process.parent.is_some() {
+        return Err(Errno::EPERM);
+    }
+    process.orphaned = true;
+    if matches!(process.state, ProcessState::Exited(_)) {
+        table.processes.remove(&pid);
+    }
+    Ok(())
+}
+
+// Ends the current process, freeing its address space. Its parent can then collect the exit
+// code with `wait`, and any children it leaves behind are cleaned up as soon as they exit.
+pub fn exit(code: i32) -> ! {
+    terminate(ExitStatus::Code(code))
+}
+
+// Ends the current process as though it had been killed by a signal.
+pub fn kill(signal: i32) -> ! {
+    terminate(ExitStatus::Signal(signal))
+}
+
+fn terminate(status: ExitStatus) -> ! {
+    let thread = thread::current();
+    let (address_space, files) = {
+        let mut table = PROCESSES.lock();
+        let pid = table.threads.remove(&thread).expect("Exiting thread doesn't belong to a process!");
+        let process = table.processes.get_mut(&pid).expect("Exiting process is missing!");
+        process.state = ProcessState::Exited(status);
+        let address_space = process.address_space.take();
+        let files = mem::take(&mut process.files);
+        let children = mem::take(&mut process.children);
+        let orphaned = process.orphaned;
+
+        for child in children {
+            let child_process = match table.processes.get_mut(&child) {
+                Some(process) => process,
+                None => continue
+            };
+            child_process.parent = None;
+            child_process.orphaned = true;
+            if matches!(child_process.state, ProcessState::Exited(_)) {
+                table.processes.remove(&child);
+            }
+        }
+        if orphaned {
+            table.processes.remove(&pid);
+        }
+        for waiter in mem::take(&mut table.waiters) {
+            thread::unpark(waiter);
+        }
+        debug!("Process {} exited with {:?}.", pid.as_u64(), status);
+        (address_space, files)
+    };
+
+    // The page tables can only be freed once we're no longer running on them.
+    // Closing files can mean writing them out, so that happens outside the lock too.
+    drop(files);
+    thread::set_page_table(None);
+    drop(address_space);
+    thread::exit()
+}
+
+// Blocks until a child of the current process exits, or a specific one if a PID is given, and
+// returns its PID and exit status. Kernel threads wait on processes the kernel started.
+pub fn wait(target: Option<Pid>) -> Result<(Pid, ExitStatus), Errno> {
+    loop {
+        if let Some(exited) = reap(target, true)? {
+            return Ok(exited);
+        }
+        thread::park();
+    }
+}
+
+// Like `wait`, but returns `None` straight away if no matching child has exited yet.
+pub fn try_wait(target: Option<Pid>) -> Result<Option<(Pid, ExitStatus)>, Errno> {
+    reap(target, false)
+}
+
+fn reap(target: Option<Pid>, register_waiter: bool) -> Result<Option<(Pid, ExitStatus)>, Errno> {
+    let thread = thread::current();
+    let mut table = PROCESSES.lock();
+    let parent = table.threads.get(&thread).copied();
+    let mut candidates = table.processes.iter().filter(|(&pid, process)| {
+        process.parent == parent && !process.orphaned && target.is_none_or(|target| target == pid)
+    });
+
+    let mut found = false;
+    let exited = candidates.find_map(|(&pid, process)| {
+        found = true;
+        match process.state {
+            ProcessState::Exited(status) => Some((pid, status)),
+            ProcessState::Running => None
+        }
+    });
+    // A waiter that stayed registered after returning would keep getting unparked for nothing.
+    table.waiters.retain(|&waiter| waiter != thread);
+    match exited {
+        Some((pid, status)) => {
+            table.remove(pid);
+            Ok(Some((pid, status)))
+        }
+        None if !found => Err(Errno::ECHILD),
+        None => {
+            if register_waiter {
+                table.waiters.push(thread);
+            }
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+use crate::thread::{self, ThreadId};
+
+// A spin lock that keeps interrupts disabled for as long as it is held. Anything that can be
+// locked from an interrupt handler, or from the scheduler, needs to use this, otherwise the
+// holder could be interrupted or preempted while whoever interrupted it spins forever.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self { inner: Mutex::new(value) }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqMutexGuard { guard: ManuallyDrop::new(self.inner.lock()), interrupts_enabled }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard { guard: ManuallyDrop::new(guard), interrupts_enabled }),
+            None => {
+                if interrupts_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    // Safety: the lock must not actually be held by anything that is still running.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock()
+    }
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    interrupts_enabled: bool
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // The lock has to be released before interrupts come back on.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+// A lock that can be held across things that block. Drivers park until their device is done with
+// a request, and caches and filesystems call into them with their own locks held, so whoever
+// holds one of those can be off the CPU for as long as the disk takes. Threads that find it taken
+// park until it's released instead of spinning all that time, which means it can't be used from
+// interrupt handlers. It isn't reentrant either, so whoever holds it mustn't try to take it again.
+pub struct SleepMutex<T> {
+    locked: AtomicBool,
+    waiters: IrqMutex<VecDeque<ThreadId>>,
+    value: UnsafeCell<T>
+}
+
+unsafe impl<T: Send> Send for SleepMutex<T> {}
+unsafe impl<T: Send> Sync for SleepMutex<T> {}
+
+impl<T> SleepMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self { locked: AtomicBool::new(false), waiters: IrqMutex::new(VecDeque::new()), value: UnsafeCell::new(value) }
+    }
+
+    pub fn lock(&self) -> SleepMutexGuard<T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            {
+                // Checking again with the waiters locked means the holder can't release it
+                // between the check and joining the queue, and miss waking us.
+                let mut waiters = self.waiters.lock();
+                if !self.locked.load(Ordering::Acquire) {
+                    continue;
+                }
+                let current = thread::current();
+                if !waiters.contains(&current) {
+                    waiters.push_back(current);
+                }
+            }
+            thread::park();
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SleepMutexGuard<T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SleepMutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+pub struct SleepMutexGuard<'a, T> {
+    mutex: &'a SleepMutex<T>
+}
+
+impl<T> Deref for SleepMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for SleepMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for SleepMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        let next = self.mutex.waiters.lock().pop_front();
+        if let Some(next) = next {
+            thread::unpark(next);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::{PrivilegeLevel, VirtAddr};
+use crate::gdt;
+
+pub const SYSCALL_INTERRUPT_VECTOR: usize = 0x80;
+
+// The kernel stack that SYSCALL switches to, which is the same one the TSS gives interrupts from
+// ring 3. SYSCALL leaves the user stack pointer in place, so it's parked in the second static
+// until it can be pushed onto the kernel stack. Interrupts are masked until then.
+#[no_mangle]
+static HALOGEN_SYSCALL_STACK: AtomicU64 = AtomicU64::new(0);
+#[no_mangle]
+static HALOGEN_SYSCALL_USER_STACK: AtomicU64 = AtomicU64::new(0);
+
+// The user registers as saved on the kernel stack by both entry paths. Handlers can change
+// them, and the changes are picked up when returning to user mode.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct UserRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64
+}
+
+global_asm!(
+    ".global halogen_syscall_entry",
+    "halogen_syscall_entry:",
+    "    mov [rip + HALOGEN_SYSCALL_USER_STACK], rsp",
+    "    mov rsp, [rip + HALOGEN_SYSCALL_STACK]",
+    // SYSCALL put the user's instruction pointer in rcx and their flags in r11.
+    "    push qword ptr [rip + HALOGEN_SYSCALL_USER_STACK]",
+    "    push r11",
+    "    push rcx",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov rdi, rsp",
+    "    mov rbp, rsp",
+    "    and rsp, -16",
+    "    sti",
+    "    call halogen_syscall_dispatch",
+    "    cli",
+    "    mov rsp, rbp",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    "    pop rcx",
+    "    pop r11",
+    "    pop rsp",
+    "    sysretq"
+);
+
+// `int 0x80` saves the same registers, copying the instruction pointer, flags and stack pointer
+// out of the interrupt frame and back again afterwards.
+global_asm!(
+    ".global halogen_syscall_interrupt_entry",
+    "halogen_syscall_interrupt_entry:",
+    "    push qword ptr [rsp + 24]",
+    "    push qword ptr [rsp + 24]",
+    "    push qword ptr [rsp + 16]",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov rdi, rsp",
+    "    mov rbp, rsp",
+    "    and rsp, -16",
+    "    sti",
+    "    call halogen_syscall_dispatch",
+    "    cli",
+    "    mov rsp, rbp",
+    "    mov rax, [rsp + 120]",
+    "    mov [rsp + 144], rax",
+    "    mov rax, [rsp + 128]",
+    "    mov [rsp + 160], rax",
+    "    mov rax, [rsp + 136]",
+    "    mov [rsp + 168], rax",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    "    add rsp, 24",
+    "    iretq"
+);
+
+extern "C" {
+    fn halogen_syscall_entry();
+    fn halogen_syscall_interrupt_entry();
+}
+
+#[no_mangle]
+extern "C" fn halogen_syscall_dispatch(registers: &mut UserRegisters) {
+    let arguments = [registers.rdi, registers.rsi, registers.rdx, registers.r10, registers.r8, registers.r9];
+    registers.rax = match registers.rax {
+        super::SYS_FORK => super::fork(registers),
+        number => super::dispatch(number, arguments)
+    } as u64;
+}
+
+pub fn init() {
+    Star::write(gdt::user_code_selector(), gdt::user_data_selector(), gdt::kernel_code_selector(), gdt::kernel_data_selector())
+        .expect("Failed to set the system call segments!");
+    LStar::write(VirtAddr::new(halogen_syscall_entry as usize as u64));
+    // Interrupts stay off until the entry stub is on the kernel stack.
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG | RFlags::ALIGNMENT_CHECK);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+    set_kernel_stack(gdt::privilege_stack());
+}
+
+pub(crate) fn set_kernel_stack(stack_top: VirtAddr) {
+    HALOGEN_SYSCALL_STACK.store(stack_top.as_u64(), Ordering::SeqCst);
+}
+
+pub(crate) fn initialize_interrupt_gate(table: &mut InterruptDescriptorTable) {
+    let handler = VirtAddr::new(halogen_syscall_interrupt_entry as usize as u64);
+    unsafe {
+        table[SYSCALL_INTERRUPT_VECTOR].set_handler_addr(handler).set_privilege_level(PrivilegeLevel::Ring3);
+    }
+}
@@ -0,0 +1,288 @@
+// File system calls, which go through the calling process's file descriptors and working
+// directory. Kernel threads have neither, so they only get the console on descriptors 0 to 2 and
+// resolve paths from the root.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::mem::size_of;
+use crate::fs::{self, Console, File, FileType, Metadata, OpenFlags, SeekFrom, MAX_PATH_LENGTH};
+use crate::process;
+use super::{user, Errno, SyscallResult};
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFBLK: u32 = 0o060000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_BLK: u8 = 6;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+
+// The same layout as Linux's `struct stat` on x86_64.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Stat {
+    pub device: u64,
+    pub inode: u64,
+    pub links: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    _padding: u32,
+    pub special_device: u64,
+    pub size: i64,
+    pub block_size: i64,
+    pub blocks: i64,
+    pub accessed_seconds: i64,
+    pub accessed_nanoseconds: i64,
+    pub modified_seconds: i64,
+    pub modified_nanoseconds: i64,
+    pub changed_seconds: i64,
+    pub changed_nanoseconds: i64,
+    _reserved: [i64; 3]
+}
+
+impl From<Metadata> for Stat {
+    fn from(metadata: Metadata) -> Self {
+        let kind = match metadata.kind {
+            FileType::File => S_IFREG,
+            FileType::Directory => S_IFDIR,
+            FileType::Symlink => S_IFLNK,
+            FileType::CharDevice => S_IFCHR,
+            FileType::BlockDevice => S_IFBLK
+        };
+        Stat {
+            device: metadata.device,
+            inode: metadata.inode,
+            links: metadata.links as u64,
+            mode: kind | metadata.mode as u32,
+            uid: metadata.uid,
+            gid: metadata.gid,
+            size: metadata.size as i64,
+            block_size: metadata.block_size as i64,
+            blocks: metadata.blocks as i64,
+            accessed_seconds: metadata.accessed.as_secs() as i64,
+            accessed_nanoseconds: metadata.accessed.subsec_nanos() as i64,
+            modified_seconds: metadata.modified.as_secs() as i64,
+            modified_nanoseconds: metadata.modified.subsec_nanos() as i64,
+            changed_seconds: metadata.changed.as_secs() as i64,
+            changed_nanoseconds: metadata.changed.subsec_nanos() as i64,
+            ..Stat::default()
+        }
+    }
+}
+
+fn file(fd: u64) -> Result<Arc<File>, Errno> {
+    match process::with_files(|files| files.get(fd as usize)) {
+        Some(file) => file,
+        None if fd <= 2 => Ok(Arc::new(File::new(Arc::new(Console), OpenFlags::READ_WRITE, String::from("/dev/console")))),
+        None => Err(Errno::EBADF)
+    }
+}
+
+// Reads a path from user memory, relative to the working directory.
+fn path(address: u64) -> Result<String, Errno> {
+    let path = user::c_string(address, MAX_PATH_LENGTH)?;
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    Ok(fs::join(&process::working_directory(), &path))
+}
+
+pub(super) fn read(fd: u64, buffer: u64, length: u64) -> SyscallResult {
+    let buffer = user::slice_mut(buffer, length)?;
+    Ok(file(fd)?.read(buffer)? as u64)
+}
+
+pub(super) fn write(fd: u64, buffer: u64, length: u64) -> SyscallResult {
+    let data = user::slice(buffer, length)?;
+    Ok(file(fd)?.write(data)? as u64)
+}
+
+pub(super) fn open(path_address: u64, flags: u64, mode: u64) -> SyscallResult {
+    let path = path(path_address)?;
+    let file = fs::open(&path, OpenFlags::from_bits(flags as u32), mode as u16)?;
+    let fd = process::with_files(|files| files.insert(file)).ok_or(Errno::EMFILE)??;
+    Ok(fd as u64)
+}
+
+pub(super) fn close(fd: u64) -> SyscallResult {
+    // Dropped after the process table is unlocked, in case this was the last reference.
+    let file = process::with_files(|files| files.remove(fd as usize)).ok_or(Errno::EBADF)??;
+    drop(file);
+    Ok(0)
+}
+
+pub(super) fn stat(path_address: u64, stat: u64) -> SyscallResult {
+    let metadata = fs::metadata(&path(path_address)?)?;
+    user::write(stat, &Stat::from(metadata))?;
+    Ok(0)
+}
+
+pub(super) fn fstat(fd: u64, stat: u64) -> SyscallResult {
+    let metadata = file(fd)?.metadata()?;
+    user::write(stat, &Stat::from(metadata))?;
+    Ok(0)
+}
+
+pub(super) fn lstat(path_address: u64, stat: u64) -> SyscallResult {
+    let metadata = fs::symlink_metadata(&path(path_address)?)?;
+    user::write(stat, &Stat::from(metadata))?;
+    Ok(0)
+}
+
+pub(super) fn lseek(fd: u64, offset: u64, whence: u64) -> SyscallResult {
+    let position = match whence {
+        SEEK_SET if (offset as i64) < 0 => return Err(Errno::EINVAL),
+        SEEK_SET => SeekFrom::Start(offset),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(Errno::EINVAL)
+    };
+    Ok(file(fd)?.seek(position)?)
+}
+
+pub(super) fn dup(fd: u64) -> SyscallResult {
+    let fd = process::with_files(|files| {
+        let file = files.get(fd as usize)?;
+        files.insert(file)
+    }).ok_or(Errno::EBADF)??;
+    Ok(fd as u64)
+}
+
+pub(super) fn dup2(old: u64, new: u64) -> SyscallResult {
+    let replaced = process::with_files(|files| {
+        let file = files.get(old as usize)?;
+        if old == new {
+            return Ok(None);
+        }
+        files.insert_at(new as usize, file)
+    }).ok_or(Errno::EBADF)??;
+    drop(replaced);
+    Ok(new)
+}
+
+// Returns the length including the terminator, like the Linux system call rather than the C
+// library function.
+pub(super) fn getcwd(buffer: u64, size: u64) -> SyscallResult {
+    let directory = process::working_directory();
+    let length = directory.len() as u64 + 1;
+    if size < length {
+        return Err(Errno::ERANGE);
+    }
+    let buffer = user::slice_mut(buffer, length)?;
+    buffer[..directory.len()].copy_from_slice(directory.as_bytes());
+    buffer[directory.len()] = 0;
+    Ok(length)
+}
+
+pub(super) fn chdir(path_address: u64) -> SyscallResult {
+    let path = fs::canonicalize(&path(path_address)?)?;
+    if fs::metadata(&path)?.kind != FileType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    process::set_working_directory(path)?;
+    Ok(0)
+}
+
+pub(super) fn mkdir(path_address: u64, mode: u64) -> SyscallResult {
+    fs::create_dir(&path(path_address)?, mode as u16)?;
+    Ok(0)
+}
+
+pub(super) fn rmdir(path_address: u64) -> SyscallResult {
+    fs::remove_dir(&path(path_address)?)?;
+    Ok(0)
+}
+
+pub(super) fn link(existing: u64, new: u64) -> SyscallResult {
+    fs::hard_link(&path(existing)?, &path(new)?)?;
+    Ok(0)
+}
+
+pub(super) fn unlink(path_address: u64) -> SyscallResult {
+    fs::remove_file(&path(path_address)?)?;
+    Ok(0)
+}
+
+// The target is stored as it is, so relative targets stay relative to the link.
+pub(super) fn symlink(target: u64, path_address: u64) -> SyscallResult {
+    let target = user::c_string(target, MAX_PATH_LENGTH)?;
+    if target.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    fs::symlink(&target, &path(path_address)?)?;
+    Ok(0)
+}
+
+// Doesn't add a terminator, and quietly cuts the target short if it doesn't fit.
+pub(super) fn readlink(path_address: u64, buffer: u64, size: u64) -> SyscallResult {
+    if size == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let target = fs::read_link(&path(path_address)?)?;
+    let length = target.len().min(size as usize);
+    user::slice_mut(buffer, length as u64)?.copy_from_slice(&target.as_bytes()[..length]);
+    Ok(length as u64)
+}
+
+// Fills the buffer with `linux_dirent64` records, starting from `.` and `..`. The file offset
+// counts entries, so it stays valid even if the directory changes in between calls.
+pub(super) fn getdents64(fd: u64, buffer: u64, size: u64) -> SyscallResult {
+    const HEADER_SIZE: usize = size_of::<u64>() * 2 + size_of::<u16>() + size_of::<u8>();
+
+    let buffer = user::slice_mut(buffer, size)?;
+    let file = file(fd)?;
+    let metadata = file.metadata()?;
+    if metadata.kind != FileType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    // The root is its own parent.
+    let parent = match file.parent() {
+        Some(parent) => parent.metadata()?,
+        None => metadata
+    };
+    let entries = file.read_dir()?;
+    let dots = [(".", metadata.inode, DT_DIR), ("..", parent.inode, DT_DIR)];
+    let all = dots.into_iter().chain(entries.iter().map(|entry| (entry.name.as_str(), entry.inode, entry_type(entry.kind))));
+
+    let mut index = file.offset();
+    let mut written = 0;
+    for (name, inode, kind) in all.skip(index as usize) {
+        let length = (HEADER_SIZE + name.len() + 1 + 7) & !7;
+        if written + length > buffer.len() {
+            if written == 0 {
+                return Err(Errno::EINVAL);
+            }
+            break;
+        }
+        index += 1;
+        let record = &mut buffer[written..written + length];
+        record.fill(0);
+        record[0..8].copy_from_slice(&inode.to_ne_bytes());
+        record[8..16].copy_from_slice(&index.to_ne_bytes());
+        record[16..18].copy_from_slice(&(length as u16).to_ne_bytes());
+        record[18] = kind;
+        record[HEADER_SIZE..HEADER_SIZE + name.len()].copy_from_slice(name.as_bytes());
+        written += length;
+    }
+    file.set_offset(index);
+    Ok(written as u64)
+}
+
+fn entry_type(kind: FileType) -> u8 {
+    match kind {
+        FileType::File => DT_REG,
+        FileType::Directory => DT_DIR,
+        FileType::Symlink => DT_LNK,
+        FileType::CharDevice => DT_CHR,
+        FileType::BlockDevice => DT_BLK
+    }
+}
@@ -0,0 +1,288 @@
+// System calls from ring 3, entered through SYSCALL or `int 0x80`.
+//
+// The number goes in rax, and up to six arguments in rdi, rsi, rdx, r10, r8 and r9, which is
+// the same as Linux's x86_64 convention, and the numbers match Linux's where a call exists on
+// both. The result comes back in rax: a value from -4095 to -1 is a negated `Errno`, anything
+// else is a success. SYSCALL clobbers rcx and r11, `int 0x80` preserves every register apart
+// from rax.
+
+mod entry;
+mod fs;
+pub mod user;
+
+pub use entry::{init, UserRegisters, SYSCALL_INTERRUPT_VECTOR};
+pub use fs::{Stat, DT_BLK, DT_CHR, DT_DIR, DT_LNK, DT_REG, SEEK_CUR, SEEK_END, SEEK_SET};
+pub use fs::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFLNK, S_IFREG};
+pub(crate) use entry::{initialize_interrupt_gate, set_kernel_stack};
+
+use core::time::Duration;
+use log::debug;
+use x86_64::VirtAddr;
+use crate::{process, thread, time};
+use crate::memory::{is_user_address, Protection, FRAME_SIZE, USER_SPACE_START};
+use crate::process::Pid;
+
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_OPEN: u64 = 2;
+pub const SYS_CLOSE: u64 = 3;
+pub const SYS_STAT: u64 = 4;
+pub const SYS_FSTAT: u64 = 5;
+pub const SYS_LSTAT: u64 = 6;
+pub const SYS_LSEEK: u64 = 8;
+pub const SYS_MMAP: u64 = 9;
+pub const SYS_MUNMAP: u64 = 11;
+pub const SYS_SCHED_YIELD: u64 = 24;
+pub const SYS_DUP: u64 = 32;
+pub const SYS_DUP2: u64 = 33;
+pub const SYS_NANOSLEEP: u64 = 35;
+pub const SYS_GETPID: u64 = 39;
+pub const SYS_FORK: u64 = 57;
+pub const SYS_EXIT: u64 = 60;
+pub const SYS_WAIT4: u64 = 61;
+pub const SYS_GETCWD: u64 = 79;
+pub const SYS_CHDIR: u64 = 80;
+pub const SYS_MKDIR: u64 = 83;
+pub const SYS_RMDIR: u64 = 84;
+pub const SYS_LINK: u64 = 86;
+pub const SYS_UNLINK: u64 = 87;
+pub const SYS_SYMLINK: u64 = 88;
+pub const SYS_READLINK: u64 = 89;
+pub const SYS_GETPPID: u64 = 110;
+pub const SYS_GETTID: u64 = 186;
+pub const SYS_GETDENTS64: u64 = 217;
+pub const SYS_CLOCK_GETTIME: u64 = 228;
+
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+pub const WNOHANG: u64 = 1;
+
+pub const O_RDONLY: u64 = 0o0;
+pub const O_WRONLY: u64 = 0o1;
+pub const O_RDWR: u64 = 0o2;
+pub const O_CREAT: u64 = 0o100;
+pub const O_EXCL: u64 = 0o200;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+pub const O_DIRECTORY: u64 = 0o200000;
+pub const O_NOFOLLOW: u64 = 0o400000;
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+// Mappings without a fixed address go well clear of where executables get loaded.
+const MMAP_START: u64 = USER_SPACE_START + 0x1000_0000_0000;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EXDEV = 18,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    EFBIG = 27,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EROFS = 30,
+    EMLINK = 31,
+    ERANGE = 34,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    ELOOP = 40,
+    EOPNOTSUPP = 95
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Timespec {
+    pub seconds: i64,
+    pub nanoseconds: i64
+}
+
+type SyscallResult = Result<u64, Errno>;
+
+// Runs a system call on behalf of the current thread and returns the value for rax.
+pub fn dispatch(number: u64, arguments: [u64; 6]) -> i64 {
+    let [first, second, third, fourth, ..] = arguments;
+    let result = match number {
+        SYS_READ => fs::read(first, second, third),
+        SYS_WRITE => fs::write(first, second, third),
+        SYS_OPEN => fs::open(first, second, third),
+        SYS_CLOSE => fs::close(first),
+        SYS_STAT => fs::stat(first, second),
+        SYS_FSTAT => fs::fstat(first, second),
+        SYS_LSTAT => fs::lstat(first, second),
+        SYS_LSEEK => fs::lseek(first, second, third),
+        SYS_MMAP => mmap(first, second, third, fourth),
+        SYS_MUNMAP => munmap(first, second),
+        SYS_SCHED_YIELD => sched_yield(),
+        SYS_DUP => fs::dup(first),
+        SYS_DUP2 => fs::dup2(first, second),
+        SYS_NANOSLEEP => nanosleep(first),
+        SYS_GETPID => Ok(process::current().map_or(0, Pid::as_u64)),
+        SYS_GETTID => Ok(thread::current().as_u64()),
+        SYS_EXIT => exit(first),
+        SYS_WAIT4 => wait4(first, second, third),
+        SYS_GETCWD => fs::getcwd(first, second),
+        SYS_CHDIR => fs::chdir(first),
+        SYS_MKDIR => fs::mkdir(first, second),
+        SYS_RMDIR => fs::rmdir(first),
+        SYS_LINK => fs::link(first, second),
+        SYS_UNLINK => fs::unlink(first),
+        SYS_SYMLINK => fs::symlink(first, second),
+        SYS_READLINK => fs::readlink(first, second, third),
+        SYS_GETDENTS64 => fs::getdents64(first, second, third),
+        SYS_GETPPID => getppid(),
+        SYS_CLOCK_GETTIME => clock_gettime(first, second),
+        _ => Err(Errno::ENOSYS)
+    };
+    match result {
+        Ok(value) => value as i64,
+        Err(errno) => -(errno as i64)
+    }
+}
+
+// Only private anonymous mappings are supported, since there's nothing to map files from yet.
+// The memory is zeroed and only gets frames as it's used.
+fn mmap(address: u64, length: u64, protection: u64, flags: u64) -> SyscallResult {
+    if flags & (MAP_PRIVATE | MAP_ANONYMOUS) != MAP_PRIVATE | MAP_ANONYMOUS || flags & !(MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    if protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let size = page_aligned_length(length)?;
+    let protection = Protection {
+        read: protection & PROT_READ != 0,
+        write: protection & PROT_WRITE != 0,
+        execute: protection & PROT_EXEC != 0
+    };
+    // Hints are only used if they're somewhere sensible, like on Linux.
+    let hint = VirtAddr::try_new(address).ok().filter(|&hint| is_user_address(hint) && hint.is_aligned(FRAME_SIZE));
+    process::with_address_space(|address_space| -> SyscallResult {
+        let start = if flags & MAP_FIXED != 0 {
+            let start = hint.ok_or(Errno::EINVAL)?;
+            address_space.remove_range(start, size).map_err(|_| Errno::EINVAL)?;
+            start
+        } else {
+            let from = hint.unwrap_or(VirtAddr::new(MMAP_START));
+            address_space.find_free_range(from, size).ok_or(Errno::ENOMEM)?
+        };
+        address_space.add_region(start, size, protection).map_err(|_| Errno::ENOMEM)?;
+        Ok(start.as_u64())
+    }).unwrap_or(Err(Errno::EINVAL))
+}
+
+fn munmap(address: u64, length: u64) -> SyscallResult {
+    let size = page_aligned_length(length)?;
+    let start = VirtAddr::try_new(address).map_err(|_| Errno::EINVAL)?;
+    process::with_address_space(|address_space| address_space.remove_range(start, size))
+        .ok_or(Errno::EINVAL)?
+        .map_err(|_| Errno::EINVAL)?;
+    Ok(0)
+}
+
+fn page_aligned_length(length: u64) -> Result<u64, Errno> {
+    if length == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let size = length.checked_add(FRAME_SIZE - 1).ok_or(Errno::ENOMEM)?;
+    Ok(size & !(FRAME_SIZE - 1))
+}
+
+fn sched_yield() -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+fn nanosleep(request: u64) -> SyscallResult {
+    let request: Timespec = user::read(request)?;
+    if request.seconds < 0 || !(0..1_000_000_000).contains(&request.nanoseconds) {
+        return Err(Errno::EINVAL);
+    }
+    thread::sleep(Duration::new(request.seconds as u64, request.nanoseconds as u32));
+    Ok(0)
+}
+
+// Called straight from the entry stubs rather than through `dispatch`, since the child needs the
+// caller's registers to return to the same place.
+fn fork(registers: &UserRegisters) -> i64 {
+    match process::fork(registers) {
+        Ok(pid) => pid.as_u64() as i64,
+        Err(errno) => -(errno as i64)
+    }
+}
+
+fn exit(code: u64) -> SyscallResult {
+    if process::current().is_some() {
+        process::exit(code as i32);
+    }
+    debug!("Thread {:?} exited with code {}.", thread::current(), code as i32);
+    thread::exit()
+}
+
+// Only waiting for any child (-1) or a specific one is supported, not process groups.
+fn wait4(pid: u64, status: u64, options: u64) -> SyscallResult {
+    let target = match pid as i64 {
+        -1 => None,
+        pid if pid > 0 => Some(Pid::from_u64(pid as u64)),
+        _ => return Err(Errno::EINVAL)
+    };
+    if options & !WNOHANG != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let exited = if options & WNOHANG != 0 {
+        process::try_wait(target)?
+    } else {
+        Some(process::wait(target)?)
+    };
+    match exited {
+        Some((pid, exit_status)) => {
+            if status != 0 {
+                user::write(status, &exit_status.wait_status())?;
+            }
+            Ok(pid.as_u64())
+        }
+        None => Ok(0)
+    }
+}
+
+fn getppid() -> SyscallResult {
+    let parent = process::current().and_then(process::info).and_then(|info| info.parent);
+    Ok(parent.map_or(0, Pid::as_u64))
+}
+
+// There is no real time clock yet, so both clocks count from boot.
+fn clock_gettime(clock: u64, timespec: u64) -> SyscallResult {
+    if clock != CLOCK_REALTIME && clock != CLOCK_MONOTONIC {
+        return Err(Errno::EINVAL);
+    }
+    let uptime = time::uptime();
+    user::write(timespec, &Timespec { seconds: uptime.as_secs() as i64, nanoseconds: uptime.subsec_nanos() as i64 })?;
+    Ok(0)
+}
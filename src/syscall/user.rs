@@ -0,0 +1,97 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use core::ptr;
+use core::slice;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::memory::{self, Access, FRAME_SIZE, USER_SPACE_END, USER_SPACE_START};
+use crate::process;
+use super::Errno;
+
+// Pointers handed over by user code can't be trusted, so everything is checked against the
+// active page tables before the kernel touches it. A page that isn't mapped as user accessible
+// (and writable, when writing) fails the whole access with EFAULT, unless it's part of a region
+// that just hasn't been touched yet or a copy on write page.
+fn check_range(address: u64, length: u64, write: bool) -> Result<(), Errno> {
+    if length == 0 {
+        return Ok(());
+    }
+    let end = address.checked_add(length).ok_or(Errno::EFAULT)?;
+    if address < USER_SPACE_START || end > USER_SPACE_END {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let access = if write { Access::Write } else { Access::Read };
+    let mut page = address / FRAME_SIZE * FRAME_SIZE;
+    while page < end {
+        match memory::active_page_flags(VirtAddr::new(page)) {
+            Some(flags) if flags.contains(required) => {}
+            _ if process::handle_page_fault(VirtAddr::new(page), access) => {}
+            _ => return Err(Errno::EFAULT)
+        }
+        page += FRAME_SIZE;
+    }
+    Ok(())
+}
+
+pub fn slice<'a>(address: u64, length: u64) -> Result<&'a [u8], Errno> {
+    check_range(address, length, false)?;
+    if length == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { slice::from_raw_parts(address as *const u8, length as usize) })
+}
+
+pub fn slice_mut<'a>(address: u64, length: u64) -> Result<&'a mut [u8], Errno> {
+    check_range(address, length, true)?;
+    if length == 0 {
+        return Ok(&mut []);
+    }
+    Ok(unsafe { slice::from_raw_parts_mut(address as *mut u8, length as usize) })
+}
+
+// Only meant for plain structures that are valid for any bit pattern.
+pub fn read<T: Copy>(address: u64) -> Result<T, Errno> {
+    let bytes = slice(address, size_of::<T>() as u64)?;
+    let mut value = MaybeUninit::<T>::uninit();
+    unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), value.as_mut_ptr() as *mut u8, bytes.len());
+        Ok(value.assume_init())
+    }
+}
+
+pub fn write<T: Copy>(address: u64, value: &T) -> Result<(), Errno> {
+    let bytes = slice_mut(address, size_of::<T>() as u64)?;
+    unsafe { ptr::copy_nonoverlapping(value as *const T as *const u8, bytes.as_mut_ptr(), bytes.len()) };
+    Ok(())
+}
+
+// Reads a NUL terminated string of at most `max` bytes, not counting the terminator. Each page
+// is only checked once the string gets to it, since the string could end right before an
+// unmapped one.
+pub fn c_string(address: u64, max: usize) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    let mut current = address;
+    loop {
+        let page_end = (current / FRAME_SIZE + 1) * FRAME_SIZE;
+        let chunk = slice(current, page_end - current)?;
+        if let Some(length) = chunk.iter().position(|&byte| byte == 0) {
+            bytes.extend_from_slice(&chunk[..length]);
+            break;
+        }
+        bytes.extend_from_slice(chunk);
+        if bytes.len() > max {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        current = page_end;
+    }
+    if bytes.len() > max {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    String::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+}
@@ -0,0 +1,156 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use log::warn;
+use x86_64::instructions::interrupts;
+use super::{Task, TaskId};
+
+const QUEUE_CAPACITY: usize = 128;
+
+// A cooperative executor. Tasks are only polled after they have been woken, and the CPU is
+// halted whenever no task is ready to make progress.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    spawn_queue: Arc<ArrayQueue<Task>>,
+    wakers: BTreeMap<TaskId, Arc<TaskWaker>>
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(QUEUE_CAPACITY)),
+            spawn_queue: Arc::new(ArrayQueue::new(QUEUE_CAPACITY)),
+            wakers: BTreeMap::new()
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("Task with ID {:?} already exists!", task_id);
+        }
+        let waker = TaskWaker::new(task_id, self.task_queue.clone());
+        self.wakers.insert(task_id, waker.clone());
+        waker.wake_task();
+    }
+
+    // Returns a handle that running tasks can use to spawn more tasks on this executor.
+    pub fn spawner(&self) -> Spawner {
+        Spawner { queue: self.spawn_queue.clone() }
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_until_idle();
+            self.sleep_if_idle();
+        }
+    }
+
+    // Polls tasks until none of them are ready to make progress.
+    pub fn run_until_idle(&mut self) {
+        loop {
+            self.spawn_pending_tasks();
+            if self.task_queue.is_empty() {
+                break;
+            }
+            self.run_ready_tasks();
+        }
+    }
+
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    fn spawn_pending_tasks(&mut self) {
+        while let Some(task) = self.spawn_queue.pop() {
+            self.spawn(task);
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        let Self { tasks, task_queue, wakers, .. } = self;
+        while let Some(task_id) = task_queue.pop() {
+            let (task, waker) = match (tasks.get_mut(&task_id), wakers.get(&task_id)) {
+                (Some(task), Some(waker)) => (task, waker),
+                _ => continue // The task has already finished
+            };
+            // Cleared before polling, so that a wake up while the task runs queues it again.
+            waker.queued.store(false, Ordering::SeqCst);
+            let waker = Waker::from(waker.clone());
+            let mut context = Context::from_waker(&waker);
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                tasks.remove(&task_id);
+                wakers.remove(&task_id);
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // Interrupts are disabled for the check so that a wake up from an interrupt handler
+        // can't slip in between the check and the halt.
+        interrupts::disable();
+        if self.task_queue.is_empty() && self.spawn_queue.is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct Spawner {
+    queue: Arc<ArrayQueue<Task>>
+}
+
+impl Spawner {
+    pub fn spawn(&self, task: Task) {
+        if self.queue.push(task).is_err() {
+            panic!("Spawn queue is full!");
+        }
+    }
+}
+
+// Wakers are called from interrupt handlers, so waking must never panic. Each task is only ever
+// in the queue once, which keeps a burst of wake ups from filling it.
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    queued: AtomicBool
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Arc<Self> {
+        Arc::new(TaskWaker { task_id, task_queue, queued: AtomicBool::new(false) })
+    }
+
+    fn wake_task(&self) {
+        if self.queued.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if self.task_queue.push(self.task_id).is_err() {
+            self.queued.store(false, Ordering::SeqCst);
+            warn!("Task queue is full, dropped a wake up for task {:?}!", self.task_id);
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
@@ -0,0 +1,63 @@
+use alloc::boxed::Box;
+use core::arch::global_asm;
+use x86_64::VirtAddr;
+
+// Switches stacks between two threads. Only the callee saved registers and the flags need to
+// be preserved here, the compiler takes care of everything else around the call.
+global_asm!(
+    ".global halogen_switch_context",
+    "halogen_switch_context:",
+    "    pushfq",
+    "    push rbp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov [rdi], rsp",
+    "    mov rsp, rsi",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    popfq",
+    "    ret",
+    "",
+    // New threads start here, with their entry point in r12.
+    ".global halogen_thread_trampoline",
+    "halogen_thread_trampoline:",
+    "    mov rdi, r12",
+    "    call halogen_thread_start",
+    "    ud2"
+);
+
+extern "C" {
+    fn halogen_switch_context(old_stack_pointer: *mut u64, new_stack_pointer: u64);
+    fn halogen_thread_trampoline();
+}
+
+pub type ThreadEntry = Box<dyn FnOnce() + Send + 'static>;
+
+// Safety: interrupts must be disabled, and the new stack pointer must either have come from a
+// previous switch, or from `initial_stack_pointer`.
+pub unsafe fn switch(old_stack_pointer: *mut u64, new_stack_pointer: u64) {
+    halogen_switch_context(old_stack_pointer, new_stack_pointer);
+}
+
+// Lays out a stack so that switching to it looks like returning from `switch` into the
+// trampoline, which then calls the entry point.
+pub unsafe fn initial_stack_pointer(stack_top: VirtAddr, entry: ThreadEntry) -> u64 {
+    let entry = Box::into_raw(Box::new(entry)) as u64;
+    // Interrupts start off disabled, the new thread enables them once it's running.
+    let initial_flags = 0x2;
+    let frame = [
+        0, 0, 0, entry, 0, 0, // r15, r14, r13, r12, rbx, rbp
+        initial_flags,
+        halogen_thread_trampoline as u64
+    ];
+    let stack_pointer = stack_top.as_u64() - (frame.len() * 8) as u64;
+    core::ptr::copy_nonoverlapping(frame.as_ptr(), stack_pointer as *mut u64, frame.len());
+    stack_pointer
+}
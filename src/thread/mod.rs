@@ -0,0 +1,73 @@
+mod context;
+mod scheduler;
+mod stack;
+
+pub use scheduler::{init, reap_dead_threads, ThreadId, ThreadState};
+pub use stack::STACK_PAGES;
+
+use alloc::boxed::Box;
+use core::time::Duration;
+use x86_64::structures::paging::PhysFrame;
+use crate::time;
+
+pub fn spawn<F>(entry: F) -> JoinHandle where F: FnOnce() + Send + 'static {
+    let id = scheduler::spawn(Box::new(entry)).expect("Failed to allocate a stack for a new thread!");
+    JoinHandle { id }
+}
+
+pub fn current() -> ThreadId {
+    scheduler::current()
+}
+
+pub fn yield_now() {
+    scheduler::yield_now();
+}
+
+// Blocks until another thread calls `unpark` with this thread's ID. Unparking a thread that
+// isn't parked makes its next `park` return immediately.
+pub fn park() {
+    scheduler::park();
+}
+
+// Like `park`, but returns anyway once `time::ticks` reaches the given tick.
+pub fn park_until(tick: u64) {
+    scheduler::park_until(tick);
+}
+
+pub fn unpark(id: ThreadId) {
+    scheduler::unpark(id);
+}
+
+pub fn sleep(duration: Duration) {
+    scheduler::sleep_until(time::ticks() + time::duration_to_ticks(duration));
+}
+
+// Runs the current thread on the given level 4 table from now on, or the kernel's if `None`.
+pub fn set_page_table(page_table: Option<PhysFrame>) {
+    scheduler::set_page_table(page_table);
+}
+
+pub fn exit() -> ! {
+    scheduler::exit()
+}
+
+// Called by the timer interrupt handler to preempt the current thread.
+pub(crate) fn preempt() {
+    scheduler::tick();
+}
+
+#[derive(Debug)]
+pub struct JoinHandle {
+    id: ThreadId
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    // Blocks until the thread has exited.
+    pub fn join(self) {
+        scheduler::join(self.id);
+    }
+}
@@ -0,0 +1,290 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use crate::{gdt, time};
+use super::context::{self, ThreadEntry};
+use super::stack::Stack;
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    Sleeping { until: u64 },
+    Joining(ThreadId),
+    Parked,
+    // Parked, but woken anyway once the tick comes around.
+    ParkedUntil { until: u64 },
+    Exited
+}
+
+struct Thread {
+    id: ThreadId,
+    state: ThreadState,
+    stack_pointer: u64,
+    // The boot thread runs on the stack the bootloader gave us, so it doesn't own one.
+    stack: Option<Stack>,
+    // Threads running user code have their own level 4 table, the rest use the kernel's.
+    page_table: Option<PhysFrame>,
+    // Set when the thread is unparked while it isn't parked, so the next park returns at once.
+    unpark_token: bool
+}
+
+struct Scheduler {
+    // Threads are boxed so that their saved stack pointers stay put while we switch.
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: VecDeque<ThreadId>,
+    current: ThreadId,
+    idle: ThreadId,
+    dead: Vec<Box<Thread>>,
+    kernel_page_table: PhysFrame
+}
+
+// Turns the code that is currently running into the first thread and starts the idle thread.
+pub fn init() {
+    let boot_thread = Box::new(Thread { id: ThreadId::new(), state: ThreadState::Running, stack_pointer: 0, stack: None, page_table: None, unpark_token: false });
+    let idle_thread = new_thread(Box::new(idle)).expect("Failed to create the idle thread!");
+    let current = boot_thread.id;
+    let idle = idle_thread.id;
+
+    let mut threads = BTreeMap::new();
+    threads.insert(current, boot_thread);
+    threads.insert(idle, idle_thread);
+    interrupts::without_interrupts(|| {
+        let (kernel_page_table, _) = Cr3::read();
+        *SCHEDULER.lock() = Some(Scheduler { threads, ready: VecDeque::new(), current, idle, dead: Vec::new(), kernel_page_table });
+        RUNNING.store(true, Ordering::SeqCst);
+    });
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+pub fn spawn(entry: ThreadEntry) -> Option<ThreadId> {
+    reap_dead_threads();
+    let thread = new_thread(entry)?;
+    let id = thread.id;
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("Scheduler has not been initialized!");
+        scheduler.threads.insert(id, thread);
+        scheduler.ready.push_back(id);
+    });
+    Some(id)
+}
+
+fn new_thread(entry: ThreadEntry) -> Option<Box<Thread>> {
+    let stack = Stack::allocate().ok()?;
+    let stack_pointer = unsafe { context::initial_stack_pointer(stack.top(), entry) };
+    Some(Box::new(Thread { id: ThreadId::new(), state: ThreadState::Ready, stack_pointer, stack: Some(stack), page_table: None, unpark_token: false }))
+}
+
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| SCHEDULER.lock().as_ref().expect("Scheduler has not been initialized!").current)
+}
+
+// Switches the current thread over to another level 4 table, or back to the kernel's.
+pub fn set_page_table(page_table: Option<PhysFrame>) {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("Scheduler has not been initialized!");
+        let current = scheduler.threads.get_mut(&scheduler.current).expect("Current thread is missing!");
+        current.page_table = page_table;
+        load_page_table(page_table.unwrap_or(scheduler.kernel_page_table));
+    });
+}
+
+fn load_page_table(page_table: PhysFrame) {
+    let (active, flags) = Cr3::read();
+    if active != page_table {
+        unsafe { Cr3::write(page_table, flags) };
+    }
+}
+
+pub fn yield_now() {
+    interrupts::without_interrupts(|| reschedule(SCHEDULER.lock(), ThreadState::Ready));
+}
+
+pub fn sleep_until(tick: u64) {
+    interrupts::without_interrupts(|| reschedule(SCHEDULER.lock(), ThreadState::Sleeping { until: tick }));
+}
+
+pub fn join(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let guard = SCHEDULER.lock();
+        let scheduler = guard.as_ref().expect("Scheduler has not been initialized!");
+        if id == scheduler.current {
+            panic!("Thread {:?} tried to join itself!", id);
+        }
+        // Threads are removed from the table once they have exited.
+        if scheduler.threads.contains_key(&id) {
+            reschedule(guard, ThreadState::Joining(id));
+        }
+    });
+}
+
+// Blocks the current thread until another thread unparks it. A wakeup that happened since the
+// last park is not lost, it makes this return straight away instead.
+pub fn park() {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("Scheduler has not been initialized!");
+        let current = scheduler.threads.get_mut(&scheduler.current).expect("Current thread is missing!");
+        if core::mem::take(&mut current.unpark_token) {
+            return;
+        }
+        reschedule(guard, ThreadState::Parked);
+    });
+}
+
+// Like `park`, but gives up waiting at the given tick.
+pub fn park_until(tick: u64) {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("Scheduler has not been initialized!");
+        let current = scheduler.threads.get_mut(&scheduler.current).expect("Current thread is missing!");
+        if core::mem::take(&mut current.unpark_token) || time::ticks() >= tick {
+            return;
+        }
+        reschedule(guard, ThreadState::ParkedUntil { until: tick });
+    });
+}
+
+pub fn unpark(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("Scheduler has not been initialized!");
+        if let Some(thread) = scheduler.threads.get_mut(&id) {
+            if matches!(thread.state, ThreadState::Parked | ThreadState::ParkedUntil { .. }) {
+                thread.state = ThreadState::Ready;
+                scheduler.ready.push_back(id);
+            } else {
+                thread.unpark_token = true;
+            }
+        }
+    });
+}
+
+pub fn exit() -> ! {
+    interrupts::disable();
+    let mut guard = SCHEDULER.lock();
+    let scheduler = guard.as_mut().expect("Scheduler has not been initialized!");
+    let current = scheduler.current;
+    for thread in scheduler.threads.values_mut() {
+        if thread.state == ThreadState::Joining(current) {
+            thread.state = ThreadState::Ready;
+            scheduler.ready.push_back(thread.id);
+        }
+    }
+    reschedule(guard, ThreadState::Exited);
+    unreachable!("Exited thread was scheduled again!");
+}
+
+// Called from the timer interrupt, with interrupts disabled.
+pub fn tick() {
+    if !is_running() {
+        return;
+    }
+    // If the interrupted code was in the middle of scheduling, leave it be until the next tick.
+    let mut guard = match SCHEDULER.try_lock() {
+        Some(guard) => guard,
+        None => return
+    };
+    let scheduler = guard.as_mut().expect("Scheduler has not been initialized!");
+    let now = time::ticks();
+    for thread in scheduler.threads.values_mut() {
+        if let ThreadState::Sleeping { until } | ThreadState::ParkedUntil { until } = thread.state {
+            if until <= now {
+                thread.state = ThreadState::Ready;
+                scheduler.ready.push_back(thread.id);
+            }
+        }
+    }
+    reschedule(guard, ThreadState::Ready);
+}
+
+// Puts the current thread into the given state and switches to the next ready thread, if there
+// is one. This must be called with interrupts disabled, and returns once the current thread is
+// scheduled again.
+fn reschedule(mut guard: MutexGuard<Option<Scheduler>>, state: ThreadState) {
+    let scheduler = guard.as_mut().expect("Scheduler has not been initialized!");
+    let current_id = scheduler.current;
+    let next_id = match scheduler.ready.pop_front() {
+        Some(id) => id,
+        // Nothing else wants to run, so just keep going if we can.
+        None if state == ThreadState::Ready => return,
+        None => scheduler.idle
+    };
+
+    if state == ThreadState::Ready && current_id != scheduler.idle {
+        scheduler.ready.push_back(current_id);
+    }
+    let old_stack_pointer = {
+        let current = scheduler.threads.get_mut(&current_id).expect("Current thread is missing!");
+        current.state = state;
+        &mut current.stack_pointer as *mut u64
+    };
+    if state == ThreadState::Exited {
+        // The thread is still running on its stack until the switch, so it's only freed later.
+        let thread = scheduler.threads.remove(&current_id).unwrap();
+        scheduler.dead.push(thread);
+    }
+
+    let next = scheduler.threads.get_mut(&next_id).expect("Next thread is missing!");
+    next.state = ThreadState::Running;
+    let new_stack_pointer = next.stack_pointer;
+    // Interrupts and system calls from ring 3 need to land on the next thread's own stack.
+    gdt::set_privilege_stack(next.stack.as_ref().map(Stack::top));
+    load_page_table(next.page_table.unwrap_or(scheduler.kernel_page_table));
+    scheduler.current = next_id;
+    drop(guard);
+    unsafe { context::switch(old_stack_pointer, new_stack_pointer) };
+}
+
+pub fn reap_dead_threads() {
+    let dead = interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_mut().map(|scheduler| core::mem::take(&mut scheduler.dead))
+    });
+    // Freeing the stacks takes locks that a preempted thread might be holding, so it must
+    // happen outside of the scheduler lock with interrupts enabled.
+    drop(dead);
+}
+
+fn idle() {
+    loop {
+        reap_dead_threads();
+        interrupts::enable_and_hlt();
+        yield_now();
+    }
+}
+
+// The entry point is passed over as a raw pointer to the boxed closure.
+#[no_mangle]
+extern "C" fn halogen_thread_start(entry: u64) -> ! {
+    let entry = unsafe { Box::from_raw(entry as *mut ThreadEntry) };
+    interrupts::enable();
+    entry();
+    exit()
+}
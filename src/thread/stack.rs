@@ -0,0 +1,66 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::memory::{self, FRAME_SIZE};
+
+// Thread stacks live in their own region, each in a slot with an unmapped guard page below it,
+// so that overflowing a stack faults instead of silently corrupting its neighbour.
+const STACK_REGION_START: u64 = 0x666600000000;
+pub const STACK_PAGES: u64 = 16;
+const SLOT_SIZE: u64 = (STACK_PAGES + 1) * FRAME_SIZE;
+
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0);
+static FREE_SLOTS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+pub struct Stack {
+    slot: u64
+}
+
+impl Stack {
+    pub fn allocate() -> Result<Stack, MapToError<Size4KiB>> {
+        let slot = FREE_SLOTS.lock().pop().unwrap_or_else(|| NEXT_SLOT.fetch_add(1, Ordering::Relaxed));
+        let stack = Stack { slot };
+        let mut mapper = memory::mapper();
+        let mut frame_allocator = memory::frame_allocator();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        for page in stack.pages() {
+            let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator)?.flush() };
+        }
+        Ok(stack)
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        // Skip over the guard page
+        VirtAddr::new(STACK_REGION_START + self.slot * SLOT_SIZE + FRAME_SIZE)
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + STACK_PAGES * FRAME_SIZE
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let start = Page::containing_address(self.bottom());
+        Page::range(start, start + STACK_PAGES)
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        let mut mapper = memory::mapper();
+        let mut frame_allocator = memory::frame_allocator();
+        for page in self.pages() {
+            // Pages will be missing if the allocation failed part way through.
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+        }
+        drop(frame_allocator);
+        drop(mapper);
+        FREE_SLOTS.lock().push(self.slot);
+    }
+}
@@ -0,0 +1,39 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+// The PIT runs at 1.193182 MHz, and we program channel 0 to fire roughly 100 times a second.
+const PIT_BASE_FREQUENCY_HZ: u64 = 1_193_182;
+pub const TICKS_PER_SECOND: u64 = 100;
+const PIT_DIVISOR: u64 = PIT_BASE_FREQUENCY_HZ / TICKS_PER_SECOND;
+pub const TICK_NANOS: u64 = PIT_DIVISOR * 1_000_000_000 / PIT_BASE_FREQUENCY_HZ;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    unsafe {
+        // Channel 0, low byte then high byte, mode 3 (square wave generator)
+        Port::<u8>::new(0x43).write(0x36);
+        let mut data = Port::<u8>::new(0x40);
+        data.write(PIT_DIVISOR as u8);
+        data.write((PIT_DIVISOR >> 8) as u8);
+    }
+}
+
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime() -> Duration {
+    Duration::from_nanos(ticks() * TICK_NANOS)
+}
+
+// Converts a duration into a number of ticks, rounding up so that waits are never cut short.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = duration.as_nanos() as u64;
+    nanos.div_ceil(TICK_NANOS)
+}
@@ -0,0 +1,105 @@
+use core::arch::asm;
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::VirtAddr;
+use crate::gdt;
+use crate::syscall::UserRegisters;
+use crate::memory::{AddressSpace, Protection, Region, RegionError, FRAME_SIZE, USER_SPACE_END, USER_SPACE_START};
+
+// Flat binaries are loaded at the bottom of user space, with the stack at the very top.
+pub const USER_CODE_START: u64 = USER_SPACE_START;
+pub const USER_STACK_TOP: u64 = USER_SPACE_END;
+pub const USER_STACK_PAGES: u64 = 16;
+pub const USER_STACK_BOTTOM: u64 = USER_STACK_TOP - USER_STACK_PAGES * FRAME_SIZE;
+
+const USER_RFLAGS: u64 = 0x202;
+// The flags user code can change itself: carry, parity, adjust, zero, sign, direction, overflow,
+// alignment check and ID. Anything else, like the I/O privilege level, stays up to the kernel.
+const USER_CHANGEABLE_RFLAGS: u64 = 0x1 | 0x4 | 0x10 | 0x40 | 0x80 | 0x400 | 0x800 | 0x40000 | 0x200000;
+
+// Maps a flat binary at USER_CODE_START along with a stack, so that it can be entered from its
+// first byte with the stack pointer at USER_STACK_TOP.
+pub fn load_flat_binary(address_space: &mut AddressSpace, code: &[u8]) -> Result<(), MapToError<Size4KiB>> {
+    let code_start = VirtAddr::new(USER_CODE_START);
+    let code_size = (code.len() as u64).max(1);
+    let region = address_space.add_region(code_start, align_up(code_size), Protection::READ_EXECUTE)
+        .expect("Flat binary doesn't fit into user space!");
+    address_space.map_range(code_start, code_size, region.protection.page_flags())?;
+    // The code pages were only just mapped, so this can only fail if there's no memory left.
+    if !address_space.write(code_start, code) {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+    reserve_user_stack(address_space).expect("Failed to reserve the user stack!");
+    Ok(())
+}
+
+// Reserves the stack below USER_STACK_TOP. None of it is mapped until it gets used, and going
+// past the bottom is a fault rather than silently running into whatever is underneath.
+pub fn reserve_user_stack(address_space: &mut AddressSpace) -> Result<Region, RegionError> {
+    let stack_size = USER_STACK_TOP - USER_STACK_BOTTOM;
+    address_space.add_region(VirtAddr::new(USER_STACK_BOTTOM), stack_size, Protection::READ_WRITE)
+}
+
+fn align_up(size: u64) -> u64 {
+    (size + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
+
+// Drops to ring 3 at `entry`, in whichever address space the current thread is using. The thread
+// never comes back here, any further kernel work happens through interrupts and system calls.
+pub unsafe fn enter(entry: VirtAddr, stack_pointer: VirtAddr) -> ! {
+    resume(&UserRegisters {
+        rip: entry.as_u64(),
+        rsp: stack_pointer.as_u64(),
+        rflags: USER_RFLAGS,
+        ..UserRegisters::default()
+    })
+}
+
+// What `iretq` expects on the stack, with the general purpose registers to restore underneath.
+#[repr(C)]
+struct ReturnFrame {
+    general: [u64; 15],
+    rip: u64,
+    code_selector: u64,
+    rflags: u64,
+    rsp: u64,
+    data_selector: u64
+}
+
+// Like `enter`, but with every register set from a saved state, such as a copy of the registers
+// of a system call that should return a second time in a forked process.
+pub unsafe fn resume(registers: &UserRegisters) -> ! {
+    let frame = ReturnFrame {
+        general: [
+            registers.r15, registers.r14, registers.r13, registers.r12, registers.r11,
+            registers.r10, registers.r9, registers.r8, registers.rbp, registers.rdi,
+            registers.rsi, registers.rdx, registers.rcx, registers.rbx, registers.rax
+        ],
+        rip: registers.rip,
+        code_selector: gdt::user_code_selector().0 as u64,
+        // Interrupts always stay enabled in user mode.
+        rflags: registers.rflags & USER_CHANGEABLE_RFLAGS | USER_RFLAGS,
+        rsp: registers.rsp,
+        data_selector: gdt::user_data_selector().0 as u64
+    };
+    asm!(
+        "mov rsp, {frame}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+        frame = in(reg) &frame,
+        options(noreturn)
+    )
+}
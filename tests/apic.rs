@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use halogen_os::{allocator, firmware, interrupt, time};
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use x86_64::VirtAddr;
+
+entry_point!(apic_tests);
+
+fn apic_tests(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    firmware::init(boot_info.rsdp_addr.into_option());
+    interrupt::init_apic();
+
+    test_main();
+    loop {}
+}
+
+// QEMU's MADT always describes a local APIC and an I/O APIC.
+#[test_case]
+fn apic_takes_over() {
+    assert!(interrupt::apic_enabled());
+    assert_eq!(interrupt::local_apic_id(), Some(0));
+}
+
+// The PIT still raises IRQ 0, which now has to come through the I/O APIC.
+#[test_case]
+fn timer_keeps_ticking() {
+    let start = time::ticks();
+    while time::ticks() < start + 5 {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn device_vectors_reach_their_handlers() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let vector = interrupt::allocate_vector(Arc::new(|| {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+    }));
+    assert_eq!(vector, Some(interrupt::DEVICE_VECTOR_START));
+    unsafe { asm!("int 48") };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+    // A freed vector goes back to doing nothing, and can be handed out again.
+    interrupt::free_vector(interrupt::DEVICE_VECTOR_START);
+    unsafe { asm!("int 48") };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    let vector = interrupt::allocate_vector(Arc::new(|| {}));
+    assert_eq!(vector, Some(interrupt::DEVICE_VECTOR_START));
+    interrupt::free_vector(interrupt::DEVICE_VECTOR_START);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
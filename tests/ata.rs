@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::{allocator, block, drivers, firmware, interrupt, pci, thread};
+use halogen_os::block::{BlockDevice, SECTOR_SIZE};
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::pci::DeviceMatch;
+use halogen_os::syscall::Errno;
+use x86_64::VirtAddr;
+use common::pattern;
+
+entry_point!(ata_tests);
+
+fn ata_tests(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    firmware::init(boot_info.rsdp_addr.into_option());
+    interrupt::init_apic();
+    thread::init();
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+// QEMU puts the image halogen-boot boots from on the primary IDE channel.
+fn boot_disk() -> Arc<dyn BlockDevice> {
+    block::device("hda").expect("The boot disk wasn't registered!")
+}
+
+#[test_case]
+fn driver_finds_boot_disk() {
+    drivers::init();
+    let controller = pci::find(DeviceMatch::class(0x01, 0x01)).into_iter().next().expect("No IDE controller!");
+    assert_eq!(pci::bound_driver(controller.address), Some("ata"));
+    let disk = boot_disk();
+    assert!(!disk.read_only());
+    let mut sector = vec![0; SECTOR_SIZE];
+    disk.read_sectors(0, &mut sector).unwrap();
+    // The bootloader starts the image with its boot sector.
+    assert_eq!(&sector[510..], [0x55, 0xAA]);
+}
+
+// Reads of more than 256 sectors need 48 bit commands, and single sectors don't.
+#[test_case]
+fn large_reads_match_small_ones() {
+    let disk = boot_disk();
+    let mut large = vec![0; 300 * SECTOR_SIZE];
+    disk.read_sectors(0, &mut large).unwrap();
+    for (index, expected) in large.chunks(SECTOR_SIZE).enumerate() {
+        let mut sector = [0; SECTOR_SIZE];
+        disk.read_sectors(index as u64, &mut sector).unwrap();
+        assert_eq!(&sector[..], expected);
+    }
+}
+
+// The runner starts QEMU with -snapshot, so this never reaches the boot image itself, even if
+// the test fails partway.
+#[test_case]
+fn writes_reach_the_disk() {
+    let disk = boot_disk();
+    let start = disk.sector_count() - 4;
+    disk.write_sectors(start, &pattern(4 * SECTOR_SIZE, 0x3C)).unwrap();
+    disk.flush().unwrap();
+    let mut buffer = vec![0; 4 * SECTOR_SIZE];
+    disk.read_sectors(start, &mut buffer).unwrap();
+    assert_eq!(buffer, pattern(4 * SECTOR_SIZE, 0x3C));
+    let mut sector = [0; SECTOR_SIZE];
+    disk.read_sectors(start + 2, &mut sector).unwrap();
+    assert_eq!(&sector[..], &pattern(4 * SECTOR_SIZE, 0x3C)[2 * SECTOR_SIZE..3 * SECTOR_SIZE]);
+}
+
+#[test_case]
+fn bad_requests_are_rejected() {
+    let disk = boot_disk();
+    let mut buffer = [0; 2 * SECTOR_SIZE];
+    assert_eq!(disk.read_sectors(disk.sector_count() - 1, &mut buffer), Err(Errno::EINVAL));
+    assert_eq!(disk.read_sectors(0, &mut buffer[..100]), Err(Errno::EINVAL));
+    assert_eq!(disk.write_sectors(u64::MAX, &buffer), Err(Errno::EINVAL));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
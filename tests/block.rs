@@ -0,0 +1,219 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::{allocator, block};
+use halogen_os::block::{BlockDevice, BufferCache, CacheStats, Guid, MemoryDisk, PartitionKind, SECTOR_SIZE};
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::syscall::Errno;
+use x86_64::VirtAddr;
+
+entry_point!(block_tests);
+
+fn block_tests(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn cache_evicts_least_recently_used() {
+    let disk = MemoryDisk::new(64);
+    let cache = BufferCache::new(disk.clone(), 1024, 2);
+    cache.with_block(0, |_| ()).unwrap();
+    cache.with_block(1, |_| ()).unwrap();
+    cache.with_block(0, |_| ()).unwrap();
+    // Block 1 is the one that hasn't been used for longest.
+    cache.with_block(2, |_| ()).unwrap();
+    cache.with_block(0, |_| ()).unwrap();
+    assert_eq!(disk.reads(), 3);
+    assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 3, evictions: 1, write_backs: 0 });
+    assert_eq!(cache.with_block(32, |_| ()), Err(Errno::EINVAL));
+}
+
+#[test_case]
+fn cache_writes_back_dirty_blocks() {
+    let disk = MemoryDisk::new(64);
+    let cache = BufferCache::new(disk.clone(), 1024, 2);
+    // Straddles the first two blocks.
+    cache.write_bytes(1000, &[0xAB; 100]).unwrap();
+    assert_eq!(cache.dirty_blocks(), 2);
+    assert_eq!(disk.writes(), 0);
+    let mut sector = [0; SECTOR_SIZE];
+    disk.read_sectors(1, &mut sector).unwrap();
+    assert_eq!(sector[488], 0);
+
+    // Evicting a dirty block writes it out.
+    cache.with_block(5, |_| ()).unwrap();
+    assert_eq!(disk.writes(), 1);
+    cache.sync().unwrap();
+    assert_eq!(disk.writes(), 2);
+    assert_eq!(cache.dirty_blocks(), 0);
+    let mut buffer = [0; 100];
+    disk.read_sectors(1, &mut sector).unwrap();
+    assert_eq!(&sector[488..], &[0xAB; 24][..]);
+    cache.read_bytes(1000, &mut buffer).unwrap();
+    assert_eq!(buffer, [0xAB; 100]);
+
+    // Nothing is left to write once synced, and forgotten blocks come back from the disk.
+    cache.sync().unwrap();
+    assert_eq!(disk.writes(), 2);
+    cache.invalidate();
+    cache.read_bytes(1000, &mut buffer).unwrap();
+    assert_eq!(buffer, [0xAB; 100]);
+}
+
+#[test_case]
+fn cache_is_a_block_device() {
+    let disk = MemoryDisk::new(64);
+    let cache = BufferCache::new(disk.clone(), 4096, 4);
+    assert_eq!(cache.sector_count(), 64);
+    cache.write_sectors(9, &[7; 2 * SECTOR_SIZE]).unwrap();
+    let mut buffer = [0; 3 * SECTOR_SIZE];
+    cache.read_sectors(8, &mut buffer).unwrap();
+    assert_eq!(&buffer[..SECTOR_SIZE], &[0; SECTOR_SIZE][..]);
+    assert_eq!(&buffer[SECTOR_SIZE..], &[7; 2 * SECTOR_SIZE][..]);
+    assert_eq!(disk.writes(), 0);
+    drop(cache);
+    // Dropping the cache syncs it.
+    disk.read_sectors(9, &mut buffer[..SECTOR_SIZE]).unwrap();
+    assert_eq!(&buffer[..SECTOR_SIZE], &[7; SECTOR_SIZE][..]);
+}
+
+fn mbr_entry(disk: &MemoryDisk, sector: u64, index: usize, partition_type: u8, start: u32, sectors: u32) {
+    let mut entry = [0; 16];
+    entry[4] = partition_type;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    let record = sector * SECTOR_SIZE as u64;
+    disk.patch(record + 446 + index as u64 * 16, &entry);
+    disk.patch(record + 510, &[0x55, 0xAA]);
+}
+
+#[test_case]
+fn mbr_with_logical_partitions() {
+    let disk = MemoryDisk::new(2048);
+    mbr_entry(&disk, 0, 0, 0x83, 64, 256);
+    mbr_entry(&disk, 0, 1, 0x0F, 512, 1024);
+    // Two logical partitions, each with its boot record just in front of it.
+    mbr_entry(&disk, 512, 0, 0x83, 1, 100);
+    mbr_entry(&disk, 512, 1, 0x05, 200, 300);
+    mbr_entry(&disk, 712, 0, 0x0B, 1, 50);
+    disk.patch(600 * SECTOR_SIZE as u64, b"logical");
+
+    let partitions = block::read_partitions(disk.as_ref()).unwrap();
+    let layout: Vec<(usize, u64, u64)> = partitions.iter().map(|info| (info.number, info.start, info.sectors)).collect();
+    assert_eq!(layout, [(1, 64, 256), (5, 513, 100), (6, 713, 50)]);
+    assert_eq!(partitions[2].kind, PartitionKind::Mbr { partition_type: 0x0B, bootable: false });
+
+    let name = block::add_disk("ram", disk.clone());
+    assert_eq!(name, "rama");
+    assert_eq!(block::devices(), ["rama", "rama1", "rama5", "rama6"]);
+    let logical = block::device("rama5").unwrap();
+    assert_eq!(logical.sector_count(), 100);
+    let mut sector = [0; SECTOR_SIZE];
+    logical.read_sectors(87, &mut sector).unwrap();
+    assert_eq!(&sector[..7], b"logical");
+    assert_eq!(logical.read_sectors(100, &mut sector), Err(Errno::EINVAL));
+
+    // Partitions can't reach past the end of the disk.
+    mbr_entry(&disk, 0, 2, 0x83, 2000, 100);
+    assert_eq!(block::read_partitions(disk.as_ref()), Err(Errno::EINVAL));
+}
+
+const LINUX_FILESYSTEM: Guid = Guid([
+    0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4
+]);
+
+// Writes a header for a table of four entries.
+fn gpt_header(disk: &MemoryDisk, sector: u64, entries: u64, entries_crc: u32) {
+    let mut header = [0; 92];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&sector.to_le_bytes());
+    header[72..80].copy_from_slice(&entries.to_le_bytes());
+    header[80..84].copy_from_slice(&4u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let crc = block::crc32(&header);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    disk.patch(sector * SECTOR_SIZE as u64, &header);
+}
+
+#[test_case]
+fn gpt_partitions() {
+    assert_eq!(block::crc32(b"123456789"), 0xCBF4_3926);
+    let disk = MemoryDisk::new(256);
+    mbr_entry(&disk, 0, 0, 0xEE, 1, 255);
+
+    let mut entries = [0; 4 * 128];
+    let unique = Guid([0x11; 16]);
+    entries[0..16].copy_from_slice(&LINUX_FILESYSTEM.0);
+    entries[16..32].copy_from_slice(&unique.0);
+    entries[32..40].copy_from_slice(&34u64.to_le_bytes());
+    entries[40..48].copy_from_slice(&99u64.to_le_bytes());
+    for (index, unit) in "root".encode_utf16().enumerate() {
+        entries[56 + index * 2..58 + index * 2].copy_from_slice(&unit.to_le_bytes());
+    }
+    // The third entry is used, the second and fourth are empty.
+    entries[256..272].copy_from_slice(&LINUX_FILESYSTEM.0);
+    entries[288..296].copy_from_slice(&100u64.to_le_bytes());
+    entries[296..304].copy_from_slice(&199u64.to_le_bytes());
+    disk.patch(2 * SECTOR_SIZE as u64, &entries);
+    disk.patch(251 * SECTOR_SIZE as u64, &entries);
+    let crc = block::crc32(&entries);
+    gpt_header(&disk, 1, 2, crc);
+    gpt_header(&disk, 255, 251, crc);
+
+    let partitions = block::read_partitions(disk.as_ref()).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert_eq!((partitions[0].number, partitions[0].start, partitions[0].sectors), (1, 34, 66));
+    assert_eq!((partitions[1].number, partitions[1].start, partitions[1].sectors), (3, 100, 100));
+    match &partitions[0].kind {
+        PartitionKind::Gpt { type_guid, unique_guid, name } => {
+            assert_eq!(*type_guid, LINUX_FILESYSTEM);
+            assert_eq!(format!("{}", type_guid), "0FC63DAF-8483-4772-8E79-3D69D8477DE4");
+            assert_eq!(*unique_guid, unique);
+            assert_eq!(name, "root");
+        }
+        kind => panic!("Expected a GPT partition, not {:?}!", kind)
+    }
+
+    // A damaged primary header falls back to the backup.
+    disk.patch(SECTOR_SIZE as u64 + 40, &[0xFF]);
+    assert_eq!(block::read_partitions(disk.as_ref()).unwrap(), partitions);
+    // But damaged entries are an error.
+    disk.patch(251 * SECTOR_SIZE as u64 + 60, &[0xFF]);
+    assert_eq!(block::read_partitions(disk.as_ref()), Err(Errno::EINVAL));
+}
+
+#[test_case]
+fn disks_without_partitions() {
+    let disk = MemoryDisk::new(16);
+    assert_eq!(block::read_partitions(disk.as_ref()), Ok(Vec::new()));
+    assert_eq!(block::add_disk("ram", disk), "ramb");
+    assert!(block::device("ramb1").is_none());
+    assert!(!block::register_named("ramb", MemoryDisk::new(1)));
+    assert!(block::register_named("ramz9", MemoryDisk::new(1)));
+    assert!(block::unregister("ramz9").is_some());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
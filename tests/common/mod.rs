@@ -0,0 +1,25 @@
+// Helpers shared by the test kernels. Each of them only uses some of these.
+#![allow(dead_code)]
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use halogen_os::fs::{self, FileSystem};
+
+// Mounts a filesystem on a new directory, handing it back for checking on afterwards.
+pub fn mount<F: FileSystem + 'static>(path: &str, filesystem: Arc<F>) -> Arc<F> {
+    fs::create_dir(path, 0o755).unwrap();
+    fs::mount(path, filesystem.clone()).unwrap();
+    filesystem
+}
+
+pub fn names(path: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(path).unwrap().into_iter().map(|entry| entry.name).collect();
+    names.sort();
+    names
+}
+
+// Data that's different at every offset for a good while, so anything out of place shows.
+pub fn pattern(length: usize, seed: u8) -> Vec<u8> {
+    (0..length).map(|index| (index / 7) as u8 ^ seed).collect()
+}
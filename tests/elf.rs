@@ -0,0 +1,179 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::{allocator, elf, process, thread};
+use halogen_os::elf::ElfError;
+use halogen_os::io::kmsg;
+use halogen_os::memory::{self, AddressSpace, BitmapFrameAllocator, USER_SPACE_START};
+use x86_64::VirtAddr;
+
+entry_point!(elf_loading);
+
+fn elf_loading(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+const BASE: u64 = USER_SPACE_START + 0x400000;
+const HEADERS_SIZE: u64 = 64 + 56;
+
+// Where GNU ld puts the first segment of a static executable unless told otherwise.
+const LD_DEFAULT_BASE: u64 = 0x400000;
+
+// Builds an executable with a single read and execute segment covering the whole file, with the
+// code straight after the headers.
+fn build_elf(code: &[u8]) -> Vec<u8> {
+    build_elf_at(BASE, code)
+}
+
+fn build_elf_at(base: u64, code: &[u8]) -> Vec<u8> {
+    let file_size = HEADERS_SIZE + code.len() as u64;
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    bytes.extend_from_slice(&2u16.to_le_bytes());
+    bytes.extend_from_slice(&0x3Eu16.to_le_bytes());
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&(base + HEADERS_SIZE).to_le_bytes());
+    bytes.extend_from_slice(&64u64.to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    for value in [64u16, 56, 1, 64, 0, 0] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    bytes.extend_from_slice(&elf::PT_LOAD.to_le_bytes());
+    bytes.extend_from_slice(&(elf::PF_R | elf::PF_X).to_le_bytes());
+    for value in [0, base, base, file_size, file_size, 0x1000] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes.extend_from_slice(code);
+    bytes
+}
+
+fn set_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+// Writes the first five bytes of argv[1] to stdout, then exits.
+const PRINT_ARGUMENT: [u8; 29] = [
+    0x48, 0x8B, 0x74, 0x24, 0x10, // mov rsi, [rsp + 16]
+    0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, SYS_WRITE
+    0xBF, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+    0xBA, 0x05, 0x00, 0x00, 0x00, // mov edx, 5
+    0x0F, 0x05,                   // syscall
+    0xB8, 0x3C, 0x00, 0x00, 0x00, // mov eax, SYS_EXIT
+    0x0F, 0x05                    // syscall
+];
+
+fn load(bytes: &[u8]) -> Result<elf::LoadedImage, ElfError> {
+    let mut address_space = AddressSpace::new().expect("Failed to create an address space!");
+    elf::load(&mut address_space, bytes)
+}
+
+#[test_case]
+fn executables_run_with_arguments() {
+    let bytes = build_elf(&PRINT_ARGUMENT);
+    kmsg::clear();
+    let pid = process::spawn(&bytes, &["program", "Howdy"], &["HOME=/"]).expect("Failed to load the executable!");
+    process::wait(Some(pid)).unwrap();
+    let mut messages = String::new();
+    kmsg::replay(&mut messages).unwrap();
+    assert!(messages.contains("Howdy"));
+}
+
+#[test_case]
+fn loaded_images_describe_the_executable() {
+    let bytes = build_elf(&PRINT_ARGUMENT);
+    let image = load(&bytes).expect("Failed to load the executable!");
+    assert_eq!(image.entry.as_u64(), BASE + HEADERS_SIZE);
+    assert_eq!(image.program_headers, Some(VirtAddr::new(BASE + 64)));
+    assert_eq!(image.program_header_count, 1);
+    assert_eq!(image.end.as_u64(), BASE + bytes.len() as u64);
+}
+
+#[test_case]
+fn malformed_headers_are_rejected() {
+    let bytes = build_elf(&PRINT_ARGUMENT);
+    assert_eq!(load(&bytes[..32]).unwrap_err(), ElfError::TooShort);
+
+    let mut bad_magic = bytes.clone();
+    bad_magic[1] = b'X';
+    assert_eq!(load(&bad_magic).unwrap_err(), ElfError::BadMagic);
+
+    let mut elf32 = bytes.clone();
+    elf32[4] = 1;
+    assert_eq!(load(&elf32).unwrap_err(), ElfError::NotElf64);
+
+    let mut wrong_machine = bytes.clone();
+    wrong_machine[18] = 0x28;
+    assert_eq!(load(&wrong_machine).unwrap_err(), ElfError::WrongMachine);
+
+    let mut truncated_headers = bytes.clone();
+    set_u64(&mut truncated_headers, 32, bytes.len() as u64 - 8);
+    assert_eq!(load(&truncated_headers).unwrap_err(), ElfError::BadProgramHeaders);
+}
+
+#[test_case]
+fn malformed_segments_are_rejected() {
+    let bytes = build_elf(&PRINT_ARGUMENT);
+    let mut past_end = bytes.clone();
+    set_u64(&mut past_end, 64 + 32, bytes.len() as u64 + 1);
+    assert_eq!(load(&past_end).unwrap_err(), ElfError::SegmentOutOfBounds);
+
+    let mut in_kernel = bytes.clone();
+    set_u64(&mut in_kernel, 64 + 16, 0x200000);
+    assert_eq!(load(&in_kernel).unwrap_err(), ElfError::SegmentOutsideUserSpace);
+
+    let mut overflowing = bytes.clone();
+    set_u64(&mut overflowing, 64 + 40, u64::MAX);
+    assert_eq!(load(&overflowing).unwrap_err(), ElfError::SegmentOutsideUserSpace);
+
+    let mut bad_entry = bytes.clone();
+    set_u64(&mut bad_entry, 24, BASE - 0x1000);
+    assert_eq!(load(&bad_entry).unwrap_err(), ElfError::BadEntryPoint);
+}
+
+#[test_case]
+fn executables_have_to_be_linked_into_user_space() {
+    // The kernel has the bottom of the address space, so linking the usual way isn't enough.
+    let bytes = build_elf_at(LD_DEFAULT_BASE, &PRINT_ARGUMENT);
+    assert_eq!(load(&bytes).unwrap_err(), ElfError::SegmentOutsideUserSpace);
+    assert_eq!(process::spawn(&bytes, &["program"], &[]).err(), Some(ElfError::SegmentOutsideUserSpace));
+
+    // The same program linked with -Ttext-segment=0x8000000000 loads fine.
+    let bytes = build_elf_at(USER_SPACE_START, &PRINT_ARGUMENT);
+    let image = load(&bytes).expect("Failed to load the executable!");
+    assert_eq!(image.entry.as_u64(), USER_SPACE_START + HEADERS_SIZE);
+}
+
+#[test_case]
+fn huge_argument_lists_are_rejected() {
+    let bytes = build_elf(&PRINT_ARGUMENT);
+    let mut address_space = AddressSpace::new().expect("Failed to create an address space!");
+    let image = elf::load(&mut address_space, &bytes).unwrap();
+    let argument = String::from_utf8(alloc::vec![b'a'; 64 * 1024]).unwrap();
+    let result = elf::setup_stack(&mut address_space, &image, &[&argument], &[]);
+    assert_eq!(result.unwrap_err(), ElfError::ArgumentsTooLarge);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
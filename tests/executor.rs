@@ -0,0 +1,121 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use halogen_os::allocator;
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::task::{Executor, Task};
+use x86_64::VirtAddr;
+
+entry_point!(executor);
+
+fn executor(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+
+    test_main();
+    loop {}
+}
+
+// Returns pending once, waking itself straight away, so the executor has to poll it twice.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn runs_spawned_tasks() {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut executor = Executor::new();
+    for _ in 0..10 {
+        executor.spawn(Task::new(async {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    executor.run_until_idle();
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 10);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn woken_tasks_are_polled_again() {
+    static STEPS: AtomicUsize = AtomicUsize::new(0);
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        STEPS.fetch_add(1, Ordering::SeqCst);
+        YieldNow(false).await;
+        STEPS.fetch_add(1, Ordering::SeqCst);
+    }));
+    executor.run_until_idle();
+    assert_eq!(STEPS.load(Ordering::SeqCst), 2);
+}
+
+// Wakers run in interrupt handlers, which can fire any number of times before the task is polled.
+#[test_case]
+fn repeated_wake_ups_queue_the_task_once() {
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(core::future::poll_fn(|context| {
+        if POLLS.fetch_add(1, Ordering::SeqCst) > 0 {
+            return Poll::Ready(());
+        }
+        for _ in 0..1000 {
+            context.waker().wake_by_ref();
+        }
+        Poll::Pending
+    })));
+    executor.run_until_idle();
+    assert_eq!(POLLS.load(Ordering::SeqCst), 2);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn pending_tasks_are_kept() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(core::future::pending()));
+    executor.run_until_idle();
+    assert_eq!(executor.task_count(), 1);
+}
+
+#[test_case]
+fn tasks_can_spawn_tasks() {
+    static SPAWNED: AtomicUsize = AtomicUsize::new(0);
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(Task::new(async move {
+        spawner.spawn(Task::new(async {
+            SPAWNED.fetch_add(1, Ordering::SeqCst);
+        }));
+    }));
+    executor.run_until_idle();
+    assert_eq!(SPAWNED.load(Ordering::SeqCst), 1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
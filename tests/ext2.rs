@@ -0,0 +1,401 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::{allocator, fs, thread};
+use halogen_os::block::{MemoryDisk, SECTOR_SIZE};
+use halogen_os::fs::{FileSystem, FileType, OpenFlags};
+use halogen_os::fs::ext2::Ext2Fs;
+use halogen_os::fs::tmpfs::TmpFs;
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::syscall::Errno;
+use x86_64::VirtAddr;
+use common::{mount, names, pattern};
+
+entry_point!(ext2_tests);
+
+fn ext2_tests(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    thread::init();
+    fs::mount("/", TmpFs::new()).expect("Failed to mount the root!");
+
+    test_main();
+    loop {}
+}
+
+// A fresh copy of the volume Linux made.
+fn linux_disk() -> Arc<MemoryDisk> {
+    let disk = MemoryDisk::new(256 * 1024 / SECTOR_SIZE as u64);
+    for &(offset, bytes) in IMAGE {
+        disk.patch(offset, bytes);
+    }
+    for &(offset, length) in ONES {
+        disk.patch(offset, &vec![0xFF; length]);
+    }
+    disk
+}
+
+fn u32_at(disk: &MemoryDisk, offset: u64) -> u32 {
+    u32::from_le_bytes(disk.bytes(offset, 4).try_into().unwrap())
+}
+
+#[test_case]
+fn reads_what_linux_wrote() {
+    let disk = linux_disk();
+    let filesystem = mount("/linux", Ext2Fs::new(disk.clone()).unwrap());
+    assert_eq!((filesystem.block_size(), filesystem.free_blocks(), filesystem.free_inodes()), (1024, 223, 16));
+    assert!(!filesystem.read_only());
+    assert_eq!(names("/linux"), ["docs", "hello.txt", "lost+found", "short"]);
+    assert_eq!(fs::read("/linux/hello.txt").unwrap(), b"Hello from Linux!\n");
+    let metadata = fs::metadata("/linux/hello.txt").unwrap();
+    assert_eq!((metadata.inode, metadata.mode, metadata.uid, metadata.gid, metadata.links), (13, 0o640, 1000, 100, 1));
+    assert_eq!(fs::metadata("/linux/docs").unwrap().links, 2);
+
+    // Both kinds of symlink, with the target in the inode and in a block of its own.
+    assert_eq!(fs::read_link("/linux/short").unwrap(), "hello.txt");
+    assert_eq!(fs::read("/linux/short").unwrap(), b"Hello from Linux!\n");
+    let long = fs::read_link("/linux/docs/long").unwrap();
+    assert_eq!(long.len(), 78);
+    assert!(long.starts_with("/xxxxxxxx") && long.ends_with("x/target"));
+    assert_eq!(fs::symlink_metadata("/linux/docs/long").unwrap().kind, FileType::Symlink);
+
+    // The end of this one is only reachable through a double indirect block.
+    let sparse = fs::read("/linux/docs/sparse").unwrap();
+    assert_eq!(sparse.len(), 300 * 1024 + 7);
+    assert!(sparse[..300 * 1024].iter().all(|&byte| byte == 0));
+    assert_eq!(&sparse[300 * 1024..], b"the end");
+    assert_eq!(fs::metadata("/linux/docs/sparse").unwrap().blocks, 6);
+}
+
+#[test_case]
+fn changes_survive_remounting() {
+    let disk = linux_disk();
+    let filesystem = mount("/before", Ext2Fs::new(disk.clone()).unwrap());
+    // Big enough to need an indirect block.
+    let data = pattern(100 * 1024, 3);
+    fs::write("/before/big", &data).unwrap();
+    assert_eq!(filesystem.free_blocks(), 223 - 101);
+    assert_eq!(fs::metadata("/before/big").unwrap().blocks, 202);
+    fs::create_dir("/before/new", 0o700).unwrap();
+    fs::write("/before/new/small", b"small").unwrap();
+    fs::write("/before/hello.txt", b"Hello from Halogen!\n").unwrap();
+    let free = (filesystem.free_blocks(), filesystem.free_inodes());
+    fs::unmount("/before").unwrap();
+    drop(filesystem);
+
+    // The superblock has the new counts, as e2fsck would expect.
+    assert_eq!((u32_at(&disk, 1024 + 12), u32_at(&disk, 1024 + 16)), free);
+
+    let filesystem = mount("/after", Ext2Fs::new(disk.clone()).unwrap());
+    assert_eq!((filesystem.free_blocks(), filesystem.free_inodes()), free);
+    assert_eq!(fs::read("/after/big").unwrap(), data);
+    assert_eq!(fs::read("/after/new/small").unwrap(), b"small");
+    assert_eq!(fs::read("/after/short").unwrap(), b"Hello from Halogen!\n");
+    assert_eq!(fs::metadata("/after/new").unwrap().mode, 0o700);
+    assert_eq!(fs::metadata("/after").unwrap().links, 5);
+}
+
+#[test_case]
+fn directories_grow_and_shrink() {
+    let disk = linux_disk();
+    let filesystem = mount("/directories", Ext2Fs::new(disk.clone()).unwrap());
+    let free = filesystem.free_blocks();
+    fs::create_dir("/directories/many", 0o755).unwrap();
+    // Links rather than files, since there aren't nearly enough inodes.
+    fs::write("/directories/many/a-fairly-long-name-00", b"shared").unwrap();
+    let mut expected = vec![String::from("a-fairly-long-name-00")];
+    for index in 1..60 {
+        let name = format!("a-fairly-long-name-{:02}", index);
+        fs::hard_link("/directories/many/a-fairly-long-name-00", &format!("/directories/many/{}", name)).unwrap();
+        expected.push(name);
+    }
+    assert_eq!(fs::metadata("/directories/many/a-fairly-long-name-59").unwrap().links, 60);
+    assert_eq!(names("/directories/many"), expected);
+    assert_eq!(fs::metadata("/directories/many").unwrap().size, 2048);
+    assert_eq!(fs::remove_dir("/directories/many").err(), Some(Errno::ENOTEMPTY));
+    assert_eq!(fs::remove_file("/directories/many").err(), Some(Errno::EISDIR));
+    assert_eq!(fs::remove_dir("/directories/hello.txt").err(), Some(Errno::ENOTDIR));
+
+    // Space that's freed up gets used again.
+    for name in expected.iter().step_by(2) {
+        fs::remove_file(&format!("/directories/many/{}", name)).unwrap();
+    }
+    fs::write("/directories/many/another", b"").unwrap();
+    assert_eq!(fs::read_dir("/directories/many").unwrap().len(), 31);
+    assert_eq!(fs::metadata("/directories/many").unwrap().size, 2048);
+    for name in fs::read_dir("/directories/many").unwrap() {
+        fs::remove_file(&format!("/directories/many/{}", name.name)).unwrap();
+    }
+    fs::remove_dir("/directories/many").unwrap();
+    assert_eq!(fs::metadata("/directories").unwrap().links, 4);
+    assert_eq!(filesystem.free_blocks(), free);
+}
+
+#[test_case]
+fn symlinks_and_hard_links() {
+    let disk = linux_disk();
+    let filesystem = mount("/links", Ext2Fs::new(disk.clone()).unwrap());
+    let free = filesystem.free_blocks();
+    fs::symlink("hello.txt", "/links/fast").unwrap();
+    let target = format!("/links/{}", "y".repeat(100));
+    fs::symlink(&target, "/links/slow").unwrap();
+    assert_eq!(fs::read_link("/links/fast").unwrap(), "hello.txt");
+    assert_eq!(fs::read_link("/links/slow").unwrap(), target);
+    assert_eq!(fs::symlink_metadata("/links/fast").unwrap().blocks, 0);
+    assert_eq!(fs::symlink_metadata("/links/slow").unwrap().blocks, 2);
+    assert_eq!(fs::read("/links/fast").unwrap(), b"Hello from Linux!\n");
+
+    fs::hard_link("/links/hello.txt", "/links/again").unwrap();
+    assert_eq!(fs::metadata("/links/hello.txt").unwrap().links, 2);
+    fs::remove_file("/links/hello.txt").unwrap();
+    assert_eq!(fs::read("/links/again").unwrap(), b"Hello from Linux!\n");
+    assert_eq!(fs::metadata("/links/again").unwrap().links, 1);
+    fs::write("/elsewhere", b"").unwrap();
+    assert_eq!(fs::hard_link("/elsewhere", "/links/elsewhere").err(), Some(Errno::EXDEV));
+
+    fs::remove_file("/links/slow").unwrap();
+    fs::remove_file("/links/fast").unwrap();
+    assert_eq!(filesystem.free_blocks(), free);
+    // Removing a symlink doesn't touch what it points to.
+    assert_eq!(fs::read("/links/again").unwrap(), b"Hello from Linux!\n");
+}
+
+#[test_case]
+fn sparse_files_and_truncation() {
+    let disk = linux_disk();
+    let filesystem = mount("/sparse", Ext2Fs::new(disk.clone()).unwrap());
+    let free = filesystem.free_blocks();
+    let file = fs::open("/sparse/file", OpenFlags::READ_WRITE | OpenFlags::CREATE, 0o644).unwrap();
+    file.inode().truncate(1024 * 1024).unwrap();
+    assert_eq!(fs::metadata("/sparse/file").unwrap().blocks, 0);
+    assert_eq!(filesystem.free_blocks(), free);
+
+    // A block past the direct ones takes an indirect block with it.
+    assert_eq!(file.inode().write_at(200 * 1024 + 1000, b"middle"), Ok(6));
+    assert_eq!(filesystem.free_blocks(), free - 2);
+    let contents = fs::read("/sparse/file").unwrap();
+    assert_eq!(contents.len(), 1024 * 1024);
+    assert_eq!(&contents[200 * 1024 + 1000..200 * 1024 + 1006], b"middle");
+    assert!(contents[..200 * 1024 + 1000].iter().all(|&byte| byte == 0));
+
+    // Growing again has to show zeroes, not what used to be there.
+    file.inode().truncate(200 * 1024 + 1003).unwrap();
+    file.inode().truncate(200 * 1024 + 1006).unwrap();
+    assert_eq!(&fs::read("/sparse/file").unwrap()[200 * 1024 + 1000..], b"mid\0\0\0");
+    file.inode().truncate(0).unwrap();
+    assert_eq!(filesystem.free_blocks(), free);
+}
+
+#[test_case]
+fn removing_open_files() {
+    let disk = linux_disk();
+    let filesystem = mount("/open", Ext2Fs::new(disk.clone()).unwrap());
+    let (free_blocks, free_inodes) = (filesystem.free_blocks(), filesystem.free_inodes());
+    fs::write("/open/doomed", &pattern(5000, 9)).unwrap();
+    let file = fs::open("/open/doomed", OpenFlags::READ_ONLY, 0).unwrap();
+    fs::remove_file("/open/doomed").unwrap();
+    assert_eq!(fs::metadata("/open/doomed").err(), Some(Errno::ENOENT));
+    assert_eq!(file.metadata().unwrap().links, 0);
+    let mut buffer = [0; 5000];
+    assert_eq!(file.read(&mut buffer), Ok(5000));
+    assert_eq!(&buffer[..], &pattern(5000, 9)[..]);
+    assert!(filesystem.free_blocks() < free_blocks);
+
+    drop(file);
+    filesystem.sync().unwrap();
+    assert_eq!((filesystem.free_blocks(), filesystem.free_inodes()), (free_blocks, free_inodes));
+}
+
+#[test_case]
+fn running_out_of_space() {
+    let disk = linux_disk();
+    mount("/full", Ext2Fs::new(disk.clone()).unwrap());
+    let file = fs::open("/full/big", OpenFlags::WRITE_ONLY | OpenFlags::CREATE, 0o644).unwrap();
+    let data = pattern(256 * 1024, 5);
+    let written = file.write(&data).unwrap();
+    assert!(written > 0 && written < data.len());
+    assert_eq!(file.write(&data), Err(Errno::ENOSPC));
+    assert_eq!(fs::read("/full/big").unwrap(), &data[..written]);
+}
+
+#[test_case]
+fn features_that_are_not_understood() {
+    // Extents change where everything is, so there's no reading the volume at all.
+    let disk = linux_disk();
+    disk.patch(1024 + 96, &(0x2u32 | 0x40).to_le_bytes());
+    assert_eq!(Ext2Fs::new(disk).err(), Some(Errno::EINVAL));
+
+    // Group descriptor checksums only need keeping up to date.
+    let disk = linux_disk();
+    disk.patch(1024 + 100, &(0x3u32 | 0x10).to_le_bytes());
+    let filesystem = mount("/checksums", Ext2Fs::new(disk.clone()).unwrap());
+    assert!(filesystem.read_only());
+    assert_eq!(fs::read("/checksums/hello.txt").unwrap(), b"Hello from Linux!\n");
+    assert_eq!(fs::write("/checksums/new", b"").err(), Some(Errno::EROFS));
+
+    let disk = linux_disk();
+    disk.patch(1024 + 56, &[0, 0]);
+    assert_eq!(Ext2Fs::new(disk).err(), Some(Errno::EINVAL));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
+
+// A 256 KiB volume made by `mke2fs -t ext2 -b 1024 -N 32`, with a directory, a sparse file, a
+// file owned by someone else and both kinds of symlink added by debugfs. Only the parts that
+// aren't zero are here, and the bitmaps are padded out with ones past the end of the volume.
+const IMAGE: &[(u64, &[u8])] = &[
+    (0x400, &[
+        0x20, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x0C, 0x00, 0x00, 0x00, 0xDF, 0x00, 0x00, 0x00,
+        0x10, 0x00, 0x00, 0x00, 0x01
+    ]),
+    (0x421, &[0x20, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x20]),
+    (0x431, &[
+        0xF1, 0x53, 0x65, 0x00, 0x00, 0xFF, 0xFF, 0x53, 0xEF, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0xF1, 0x53, 0x65
+    ]),
+    (0x44C, &[
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0B, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        0x38, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x6F, 0x0D, 0x8B, 0x2E,
+        0x1C, 0x4A, 0x4E, 0x5B, 0x9D, 0x3C, 0x2A, 0x7B, 0x8C, 0x9D, 0x0E, 0x1F, 0x68, 0x61, 0x6C, 0x6F,
+        0x67, 0x65, 0x6E
+    ]),
+    (0x4EC, &[
+        0x6F, 0x0D, 0x8B, 0x2E, 0x1C, 0x4A, 0x4E, 0x5B, 0x9D, 0x3C, 0x2A, 0x7B, 0x8C, 0x9D, 0x0E, 0x1F,
+        0x01, 0x00, 0x00, 0x00, 0x0C
+    ]),
+    (0x509, &[0xF1, 0x53, 0x65]),
+    (0x55C, &[0x20, 0x00, 0x20, 0x00, 0x01]),
+    (0x648, &[0x0D]),
+    (0x800, &[
+        0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0xDF, 0x00, 0x10, 0x00,
+        0x03, 0x00, 0x04
+    ]),
+    (0xC00, &[0xFF, 0xFF, 0xFF, 0xFF]),
+    (0xC1F, &[0x80]),
+    (0x1002, &[0x00, 0x00]),
+    (0x1409, &[0xF1, 0x53, 0x65, 0x00, 0xF1, 0x53, 0x65, 0x00, 0xF1, 0x53, 0x65]),
+    (0x1500, &[
+        0xED, 0x41, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0xF1, 0x53, 0x65, 0x00, 0xF1, 0x53, 0x65,
+        0x00, 0xF1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x02
+    ]),
+    (0x1528, &[0x0D]),
+    (0x1580, &[0x20]),
+    (0x1591, &[0xF1, 0x53, 0x65]),
+    (0x1A00, &[
+        0x80, 0x81, 0x00, 0x00, 0x00, 0x30, 0x04, 0x04, 0x00, 0xF1, 0x53, 0x65, 0x00, 0xF1, 0x53, 0x65,
+        0x00, 0xF1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x02
+    ]),
+    (0x1A5C, &[0x1A]),
+    (0x1A80, &[0x20]),
+    (0x1A91, &[0xF1, 0x53, 0x65]),
+    (0x1E00, &[
+        0xC0, 0x41, 0x00, 0x00, 0x00, 0x30, 0x00, 0x00, 0x00, 0xF1, 0x53, 0x65, 0x00, 0xF1, 0x53, 0x65,
+        0x00, 0xF1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x18
+    ]),
+    (0x1E28, &[
+        0x0E, 0x00, 0x00, 0x00, 0x0F, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00,
+        0x12, 0x00, 0x00, 0x00, 0x13, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x15, 0x00, 0x00, 0x00,
+        0x16, 0x00, 0x00, 0x00, 0x17, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00, 0x19
+    ]),
+    (0x1E80, &[0x20]),
+    (0x1E91, &[0xF1, 0x53, 0x65]),
+    (0x1F00, &[
+        0xED, 0x41, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0xF1, 0x53, 0x65, 0x00, 0xF1, 0x53, 0x65,
+        0x00, 0xF1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x02
+    ]),
+    (0x1F28, &[0x1B]),
+    (0x1F80, &[0x20]),
+    (0x1F91, &[0xF1, 0x53, 0x65]),
+    (0x2000, &[
+        0xA0, 0x81, 0xE8, 0x03, 0x12, 0x00, 0x00, 0x00, 0x00, 0xF1, 0x53, 0x65, 0x00, 0xF1, 0x53, 0x65,
+        0x00, 0xF1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x64, 0x00, 0x01, 0x00, 0x02
+    ]),
+    (0x2028, &[0x1C]),
+    (0x2080, &[0x20]),
+    (0x2091, &[0xF1, 0x53, 0x65]),
+    (0x2100, &[
+        0xA4, 0x81, 0x00, 0x00, 0x07, 0xB0, 0x04, 0x00, 0x00, 0xF1, 0x53, 0x65, 0x00, 0xF1, 0x53, 0x65,
+        0x00, 0xF1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x06
+    ]),
+    (0x215C, &[0x1D]),
+    (0x2180, &[0x20]),
+    (0x2191, &[0xF1, 0x53, 0x65]),
+    (0x2200, &[
+        0xFF, 0xA1, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0xF1, 0x53, 0x65, 0x00, 0xF1, 0x53, 0x65,
+        0x00, 0xF1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01
+    ]),
+    (0x2228, &[0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x2E, 0x74, 0x78, 0x74]),
+    (0x2280, &[0x20]),
+    (0x2291, &[0xF1, 0x53, 0x65]),
+    (0x2300, &[
+        0xFF, 0xA1, 0x00, 0x00, 0x4E, 0x00, 0x00, 0x00, 0x00, 0xF1, 0x53, 0x65, 0x00, 0xF1, 0x53, 0x65,
+        0x00, 0xF1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x02
+    ]),
+    (0x2328, &[0x20]),
+    (0x2380, &[0x20]),
+    (0x2391, &[0xF1, 0x53, 0x65]),
+    (0x3400, &[
+        0x02, 0x00, 0x00, 0x00, 0x0C, 0x00, 0x01, 0x02, 0x2E, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x0C, 0x00, 0x02, 0x02, 0x2E, 0x2E, 0x00, 0x00, 0x0B, 0x00, 0x00, 0x00, 0x14, 0x00, 0x0A, 0x02,
+        0x6C, 0x6F, 0x73, 0x74, 0x2B, 0x66, 0x6F, 0x75, 0x6E, 0x64, 0x00, 0x00, 0x0C, 0x00, 0x00, 0x00,
+        0x0C, 0x00, 0x04, 0x02, 0x64, 0x6F, 0x63, 0x73, 0x0D, 0x00, 0x00, 0x00, 0x14, 0x00, 0x09, 0x01,
+        0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x2E, 0x74, 0x78, 0x74, 0x00, 0x00, 0x00, 0x0F, 0x00, 0x00, 0x00,
+        0xB4, 0x03, 0x05, 0x07, 0x73, 0x68, 0x6F, 0x72, 0x74
+    ]),
+    (0x3800, &[
+        0x0B, 0x00, 0x00, 0x00, 0x0C, 0x00, 0x01, 0x02, 0x2E, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0xF4, 0x03, 0x02, 0x02, 0x2E, 0x2E
+    ]),
+    (0x3C05, &[0x04]),
+    (0x4005, &[0x04]),
+    (0x4405, &[0x04]),
+    (0x4805, &[0x04]),
+    (0x4C05, &[0x04]),
+    (0x5005, &[0x04]),
+    (0x5405, &[0x04]),
+    (0x5805, &[0x04]),
+    (0x5C05, &[0x04]),
+    (0x6005, &[0x04]),
+    (0x6405, &[0x04]),
+    (0x6C00, &[
+        0x0C, 0x00, 0x00, 0x00, 0x0C, 0x00, 0x01, 0x02, 0x2E, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x0C, 0x00, 0x02, 0x02, 0x2E, 0x2E, 0x00, 0x00, 0x0E, 0x00, 0x00, 0x00, 0x10, 0x00, 0x06, 0x01,
+        0x73, 0x70, 0x61, 0x72, 0x73, 0x65, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0xD8, 0x03, 0x04, 0x07,
+        0x6C, 0x6F, 0x6E, 0x67
+    ]),
+    (0x7000, &[
+        0x48, 0x65, 0x6C, 0x6C, 0x6F, 0x20, 0x66, 0x72, 0x6F, 0x6D, 0x20, 0x4C, 0x69, 0x6E, 0x75, 0x78,
+        0x21, 0x0A
+    ]),
+    (0x7400, &[0x1E]),
+    (0x7880, &[0x1F]),
+    (0x7C00, &[0x74, 0x68, 0x65, 0x20, 0x65, 0x6E, 0x64]),
+    (0x8000, &[
+        0x2F, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78,
+        0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78,
+        0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78,
+        0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78,
+        0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x78, 0x2F, 0x74, 0x61, 0x72, 0x67, 0x65, 0x74
+    ])
+];
+const ONES: &[(u64, usize)] = &[(0xC20, 994), (0x1004, 1020)];
@@ -0,0 +1,293 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::{allocator, fs, thread};
+use halogen_os::block::{MemoryDisk, SECTOR_SIZE};
+use halogen_os::fs::{FileType, OpenFlags};
+use halogen_os::fs::fat::{FatFs, FatKind};
+use halogen_os::fs::tmpfs::TmpFs;
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::syscall::Errno;
+use x86_64::VirtAddr;
+use common::{mount, names, pattern};
+
+entry_point!(fat_tests);
+
+fn fat_tests(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    thread::init();
+    fs::mount("/", TmpFs::new()).expect("Failed to mount the root!");
+
+    test_main();
+    loop {}
+}
+
+// Lays out an empty volume the way mkfs.fat would, with two copies of the allocation table, and
+// returns where its root directory is.
+fn format(sectors: u32, sectors_per_cluster: u8, kind: FatKind) -> (Arc<MemoryDisk>, u64) {
+    let disk = MemoryDisk::new(sectors as u64);
+    let fat32 = kind == FatKind::Fat32;
+    let reserved: u32 = if fat32 { 32 } else { 1 };
+    let root_sectors: u32 = if fat32 { 0 } else { 32 };
+    let clusters = sectors / sectors_per_cluster as u32 + 2;
+    let fat_bytes = match kind {
+        FatKind::Fat12 => clusters * 3 / 2 + 1,
+        FatKind::Fat16 => clusters * 2,
+        FatKind::Fat32 => clusters * 4
+    };
+    let fat_sectors = (fat_bytes + 511) / 512;
+
+    let mut boot = [0; SECTOR_SIZE];
+    boot[0..11].copy_from_slice(b"\xEB\x3C\x90HALOGEN ");
+    boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+    boot[13] = sectors_per_cluster;
+    boot[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+    boot[16] = 2;
+    boot[17..19].copy_from_slice(&(root_sectors as u16 * 16).to_le_bytes());
+    boot[21] = 0xF8;
+    boot[32..36].copy_from_slice(&sectors.to_le_bytes());
+    if fat32 {
+        boot[36..40].copy_from_slice(&fat_sectors.to_le_bytes());
+        boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+        boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+        disk.patch(512, &0x4161_5252u32.to_le_bytes());
+        disk.patch(512 + 484, &0x6141_7272u32.to_le_bytes());
+        disk.patch(512 + 488, &[0xFF; 8]);
+    } else {
+        boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+    }
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    disk.patch(0, &boot);
+
+    // The first two entries are reserved, and FAT32's root directory takes the first cluster.
+    let reserved_entries: &[u8] = match kind {
+        FatKind::Fat12 => &[0xF8, 0xFF, 0xFF],
+        FatKind::Fat16 => &[0xF8, 0xFF, 0xFF, 0xFF],
+        FatKind::Fat32 => &[0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F]
+    };
+    for copy in 0..2 {
+        disk.patch(((reserved + copy * fat_sectors) * 512) as u64, reserved_entries);
+    }
+    let root = ((reserved + 2 * fat_sectors) * 512) as u64;
+    (disk, root)
+}
+
+#[test_case]
+fn kinds_are_told_apart() {
+    let kinds = [(4096, 4, FatKind::Fat12), (32768, 4, FatKind::Fat16), (140000, 1, FatKind::Fat32)];
+    for (sectors, sectors_per_cluster, kind) in kinds {
+        let (disk, _) = format(sectors, sectors_per_cluster, kind);
+        let filesystem = FatFs::new(disk).unwrap();
+        assert_eq!(filesystem.kind(), kind);
+        assert_eq!(filesystem.cluster_size(), sectors_per_cluster as u64 * 512);
+    }
+    assert_eq!(FatFs::new(MemoryDisk::new(4096)).err(), Some(Errno::EINVAL));
+}
+
+#[test_case]
+fn files_and_directories_on_every_kind() {
+    let kinds = [(4096, 1, FatKind::Fat12), (32768, 4, FatKind::Fat16), (140000, 1, FatKind::Fat32)];
+    for (index, (sectors, sectors_per_cluster, kind)) in kinds.into_iter().enumerate() {
+        let (disk, _) = format(sectors, sectors_per_cluster, kind);
+        let path = alloc::format!("/every{}", index);
+        let filesystem = mount(&path, FatFs::new(disk.clone()).unwrap());
+        let free = filesystem.free_clusters();
+
+        let data = pattern(5000, index as u8);
+        fs::write(&alloc::format!("{}/DATA.BIN", path), &data).unwrap();
+        assert_eq!(fs::read(&alloc::format!("{}/DATA.BIN", path)).unwrap(), data);
+        let clusters = 5000u64.div_ceil(filesystem.cluster_size());
+        assert_eq!(filesystem.free_clusters(), free - clusters as u32);
+
+        fs::create_dir(&alloc::format!("{}/outer", path), 0o755).unwrap();
+        fs::create_dir(&alloc::format!("{}/outer/inner", path), 0o755).unwrap();
+        fs::write(&alloc::format!("{}/outer/inner/note.txt", path), b"nested").unwrap();
+        assert_eq!(fs::read(&alloc::format!("{}/outer/../outer/inner/note.txt", path)).unwrap(), b"nested");
+        assert_eq!(names(&path), ["DATA.BIN", "outer"]);
+        let metadata = fs::metadata(&alloc::format!("{}/outer", path)).unwrap();
+        assert_eq!((metadata.kind, metadata.links), (FileType::Directory, 3));
+        let metadata = fs::metadata(&alloc::format!("{}/DATA.BIN", path)).unwrap();
+        assert_eq!((metadata.kind, metadata.size, metadata.mode), (FileType::File, 5000, 0o644));
+    }
+}
+
+#[test_case]
+fn long_names() {
+    let (disk, _) = format(140000, 1, FatKind::Fat32);
+    mount("/long", FatFs::new(disk.clone()).unwrap());
+    fs::write("/long/A long file name.txt", b"long").unwrap();
+    fs::write("/long/A long file name, again.txt", b"again").unwrap();
+    fs::write("/long/readme.md", b"short").unwrap();
+    fs::write("/long/MixedCase.TXT", b"mixed").unwrap();
+    fs::write("/long/\u{e9}t\u{e9}", b"unicode").unwrap();
+    assert_eq!(names("/long"), ["A long file name, again.txt", "A long file name.txt", "MixedCase.TXT", "readme.md", "\u{e9}t\u{e9}"]);
+
+    // Names are found whatever their case, and by the short names made up for them.
+    assert_eq!(fs::read("/long/a LONG file NAME.TXT").unwrap(), b"long");
+    assert_eq!(fs::read("/long/ALONGF~1.TXT").unwrap(), b"long");
+    assert_eq!(fs::read("/long/alongf~2.txt").unwrap(), b"again");
+    assert_eq!(fs::read("/long/README.MD").unwrap(), b"short");
+    let exclusive = OpenFlags::WRITE_ONLY | OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+    assert_eq!(fs::open("/long/mixedcase.txt", exclusive, 0o644).err(), Some(Errno::EEXIST));
+    assert_eq!(fs::write("/long/what?", b"").err(), Some(Errno::EINVAL));
+    assert_eq!(fs::write("/long/trailing.", b"").err(), Some(Errno::EINVAL));
+}
+
+#[test_case]
+fn append_and_truncate() {
+    let (disk, _) = format(32768, 4, FatKind::Fat16);
+    let filesystem = mount("/sizes", FatFs::new(disk.clone()).unwrap());
+    let free = filesystem.free_clusters();
+    fs::write("/sizes/log", b"first").unwrap();
+    let appender = fs::open("/sizes/log", OpenFlags::WRITE_ONLY | OpenFlags::APPEND, 0).unwrap();
+    appender.write(b", second").unwrap();
+    assert_eq!(fs::read("/sizes/log").unwrap(), b"first, second");
+
+    let file = fs::open("/sizes/log", OpenFlags::READ_WRITE, 0).unwrap();
+    file.inode().truncate(10000).unwrap();
+    assert_eq!(filesystem.free_clusters(), free - 5);
+    file.inode().truncate(3).unwrap();
+    assert_eq!(filesystem.free_clusters(), free - 1);
+    // Growing again has to show zeroes, not what used to be there.
+    file.inode().truncate(8).unwrap();
+    assert_eq!(fs::read("/sizes/log").unwrap(), b"fir\0\0\0\0\0");
+    file.inode().truncate(0).unwrap();
+    assert_eq!(filesystem.free_clusters(), free);
+
+    // Writing past the end leaves zeroes in between.
+    assert_eq!(file.inode().write_at(4100, b"end"), Ok(3));
+    let contents = fs::read("/sizes/log").unwrap();
+    assert_eq!(contents.len(), 4103);
+    assert!(contents[..4100].iter().all(|&byte| byte == 0));
+}
+
+#[test_case]
+fn removing_files_and_directories() {
+    let (disk, _) = format(4096, 1, FatKind::Fat12);
+    let filesystem = mount("/removal", FatFs::new(disk.clone()).unwrap());
+    let free = filesystem.free_clusters();
+    fs::create_dir("/removal/dir", 0o755).unwrap();
+    fs::write("/removal/dir/a file with a long name", &pattern(2000, 1)).unwrap();
+    assert_eq!(fs::remove_dir("/removal/dir").err(), Some(Errno::ENOTEMPTY));
+    assert_eq!(fs::remove_file("/removal/dir").err(), Some(Errno::EISDIR));
+    assert_eq!(fs::remove_dir("/removal/dir/a file with a long name").err(), Some(Errno::ENOTDIR));
+
+    // An open file keeps its contents until it's closed.
+    let open = fs::open("/removal/dir/a file with a long name", OpenFlags::READ_ONLY, 0).unwrap();
+    fs::remove_file("/removal/dir/a file with a long name").unwrap();
+    assert_eq!(names("/removal/dir"), Vec::<String>::new());
+    let mut buffer = [0; 2000];
+    assert_eq!(open.read(&mut buffer), Ok(2000));
+    assert_eq!(&buffer[..], &pattern(2000, 1)[..]);
+    drop(open);
+
+    fs::remove_dir("/removal/dir").unwrap();
+    assert_eq!(fs::metadata("/removal/dir").err(), Some(Errno::ENOENT));
+    fs::unmount("/removal").unwrap();
+    assert_eq!(filesystem.free_clusters(), free);
+}
+
+#[test_case]
+fn running_out_of_space() {
+    let (disk, _) = format(256, 1, FatKind::Fat12);
+    mount("/full", FatFs::new(disk.clone()).unwrap());
+    let file = fs::open("/full/big", OpenFlags::WRITE_ONLY | OpenFlags::CREATE, 0o644).unwrap();
+    let data = pattern(256 * 512, 7);
+    let written = file.write(&data).unwrap();
+    assert!(written > 0 && written < data.len());
+    assert_eq!(file.write(&data), Err(Errno::ENOSPC));
+    assert_eq!(fs::read("/full/big").unwrap(), &data[..written]);
+}
+
+#[test_case]
+fn changes_survive_remounting() {
+    let (disk, root) = format(32768, 4, FatKind::Fat16);
+    mount("/before", FatFs::new(disk.clone()).unwrap());
+    fs::write("/before/KEEP.TXT", b"kept").unwrap();
+    fs::create_dir("/before/Sub Directory", 0o755).unwrap();
+    fs::write("/before/Sub Directory/inside", b"inside").unwrap();
+    fs::unmount("/before").unwrap();
+
+    // The short name went straight into the root directory.
+    let entry = disk.bytes(root, 32);
+    assert_eq!(&entry[0..11], b"KEEP    TXT");
+    assert_eq!(&entry[28..32], &4u32.to_le_bytes());
+
+    mount("/after", FatFs::new(disk.clone()).unwrap());
+    assert_eq!(names("/after"), ["KEEP.TXT", "Sub Directory"]);
+    assert_eq!(fs::read("/after/KEEP.TXT").unwrap(), b"kept");
+    assert_eq!(fs::read("/after/Sub Directory/inside").unwrap(), b"inside");
+}
+
+// "Hello World.txt" as Windows would write it, followed by a name that's only stored in
+// lowercase through the case flags, and a volume label.
+const LONG_ENTRIES: [u8; 64] = [
+    0x42, 0x78, 0x00, 0x74, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0x00, 0x1B, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+    0x01, 0x48, 0x00, 0x65, 0x00, 0x6C, 0x00, 0x6C, 0x00, 0x6F, 0x00, 0x0F, 0x00, 0x1B, 0x20, 0x00,
+    0x57, 0x00, 0x6F, 0x00, 0x72, 0x00, 0x6C, 0x00, 0x64, 0x00, 0x00, 0x00, 0x2E, 0x00, 0x74, 0x00
+];
+
+#[test_case]
+fn entries_written_elsewhere() {
+    let (disk, root) = format(32768, 4, FatKind::Fat16);
+    let mut label = [0; 32];
+    label[0..11].copy_from_slice(b"HALOGEN    ");
+    label[11] = 0x08;
+    disk.patch(root, &label);
+    disk.patch(root + 32, &LONG_ENTRIES);
+    let mut short = [0; 32];
+    short[0..11].copy_from_slice(b"HELLOW~1TXT");
+    short[11] = 0x20;
+    disk.patch(root + 96, &short);
+    short[0..11].copy_from_slice(b"README  MD ");
+    short[12] = 0x18;
+    disk.patch(root + 128, &short);
+    // A deleted entry, which shouldn't show up.
+    short[0..11].copy_from_slice(b"\xE5ONE    TXT");
+    disk.patch(root + 160, &short);
+
+    mount("/elsewhere", FatFs::new(disk.clone()).unwrap());
+    assert_eq!(names("/elsewhere"), ["Hello World.txt", "readme.md"]);
+    assert_eq!(fs::metadata("/elsewhere/hello world.txt").unwrap().size, 0);
+}
+
+#[test_case]
+fn new_entries_keep_the_end_marker() {
+    // Whatever comes after the end marker is meant to be ignored, even if it looks like an entry.
+    let (disk, root) = format(32768, 4, FatKind::Fat16);
+    let mut stale = [0; 32];
+    stale[0..11].copy_from_slice(b"STALE   TXT");
+    stale[11] = 0x20;
+    disk.patch(root + 32, &stale);
+
+    mount("/ended", FatFs::new(disk.clone()).unwrap());
+    assert!(names("/ended").is_empty());
+    fs::write("/ended/new", b"new").unwrap();
+    assert_eq!(names("/ended"), ["new"]);
+    fs::unmount("/ended").unwrap();
+    assert_eq!(disk.bytes(root + 32, 1), [0]);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::memory::BitmapFrameAllocator;
+use spin::{Mutex, Once};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::VirtAddr;
+
+static FRAME_ALLOCATOR: Once<Mutex<BitmapFrameAllocator>> = Once::new();
+
+entry_point!(frame_allocator);
+
+fn frame_allocator(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    FRAME_ALLOCATOR.call_once(|| {
+        Mutex::new(unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) })
+    });
+
+    test_main();
+    loop {}
+}
+
+fn allocator() -> spin::MutexGuard<'static, BitmapFrameAllocator> {
+    FRAME_ALLOCATOR.get().unwrap().lock()
+}
+
+#[test_case]
+fn frames_are_unique() {
+    let mut allocator = allocator();
+    let first = allocator.allocate_frame().unwrap();
+    let second = allocator.allocate_frame().unwrap();
+    assert_ne!(first, second);
+    assert_ne!(first.start_address().as_u64(), 0);
+    unsafe {
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(second);
+    }
+}
+
+#[test_case]
+fn freed_frames_are_reused() {
+    let mut allocator = allocator();
+    let frame = allocator.allocate_frame().unwrap();
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.allocate_frame(), Some(frame));
+    unsafe { allocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn counts_are_tracked() {
+    let mut allocator = allocator();
+    let used = allocator.used_frames();
+    let free = allocator.free_frames();
+    let frame = allocator.allocate_frame().unwrap();
+    assert_eq!(allocator.used_frames(), used + 1);
+    assert_eq!(allocator.free_frames(), free - 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.used_frames(), used);
+    assert_eq!(allocator.total_frames(), used + free);
+}
+
+#[test_case]
+fn contiguous_allocation() {
+    let mut allocator = allocator();
+    let used = allocator.used_frames();
+    let range = allocator.allocate_contiguous(16, 16).unwrap();
+    assert_eq!(range.count(), 16);
+    assert_eq!(range.start.start_address().as_u64() % (16 * 4096), 0);
+    assert_eq!(allocator.used_frames(), used + 16);
+    unsafe { allocator.deallocate_contiguous(range) };
+    assert_eq!(allocator.used_frames(), used);
+}
+
+#[test_case]
+fn ranges_are_reserved_whole_or_not_at_all() {
+    let mut allocator = allocator();
+    let range = allocator.allocate_contiguous(4, 1).unwrap();
+    let used = allocator.used_frames();
+    // One frame still being in use keeps the rest of the range free.
+    unsafe { allocator.deallocate_contiguous(PhysFrame::range(range.start, range.end - 1)) };
+    assert!(!allocator.reserve_range(range));
+    assert_eq!(allocator.used_frames(), used - 3);
+
+    unsafe { allocator.deallocate_frame(range.end - 1) };
+    assert!(allocator.reserve_range(range));
+    assert_eq!(allocator.used_frames(), used);
+    unsafe { allocator.deallocate_contiguous(range) };
+}
+
+#[test_case]
+fn many_allocations() {
+    for _ in 0..10000 {
+        let mut allocator = allocator();
+        let frame = allocator.allocate_frame().unwrap();
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
@@ -6,11 +6,11 @@
 
 extern crate alloc;
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use halogen_os::allocator::{self, HEAP_SIZE};
-use halogen_os::memory::{self, BootInfoFrameAllocator};
+use halogen_os::allocator::{self, BLOCK_SIZES, HEAP_SIZE};
+use halogen_os::memory::{self, BitmapFrameAllocator};
 use x86_64::VirtAddr;
 
 entry_point!(heap_allocation);
@@ -18,9 +18,10 @@ entry_point!(heap_allocation);
 fn heap_allocation(boot_info: &'static mut BootInfo) -> ! {
     halogen_os::init_headless();
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
-    let mut mapper = unsafe { memory::init(physical_memory_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(&boot_info.memory_regions) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed!");
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
 
     test_main();
     loop {}
@@ -62,6 +63,37 @@ fn many_boxes_long_lived() {
     assert_eq!(*long_lived, 1);
 }
 
+#[test_case]
+fn grows_beyond_initial_size() {
+    let initial_size = allocator::heap_size();
+    let large = vec![0xABu8; HEAP_SIZE * 4];
+    assert!(allocator::heap_size() > initial_size);
+    assert!(large.iter().all(|&byte| byte == 0xAB));
+}
+
+#[test_case]
+fn freed_memory_is_reused_after_growth() {
+    for _ in 0..16 {
+        let large = vec![0u64; HEAP_SIZE / 4];
+        assert_eq!(large.len(), HEAP_SIZE / 4);
+    }
+    assert!(allocator::heap_size() < HEAP_SIZE * 8);
+}
+
+#[test_case]
+fn small_blocks_are_reused() {
+    let before = allocator::class_stats();
+    for i in 0..1000 {
+        let x = Box::new(i as u64);
+        assert_eq!(*x, i);
+    }
+    let after = allocator::class_stats();
+    let class = BLOCK_SIZES.iter().position(|&size| size == 8).unwrap();
+    assert_eq!(after[class].allocations - before[class].allocations, 1000);
+    assert_eq!(after[class].in_use, before[class].in_use);
+    assert!(after[class].free <= before[class].free + 1);
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     halogen_os::test_panic_handler(info)
@@ -0,0 +1,159 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::{allocator, fs, thread};
+use halogen_os::fs::FileType;
+use halogen_os::fs::initramfs::{self, InitramfsError};
+use halogen_os::fs::tmpfs::TmpFs;
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::syscall::Errno;
+use x86_64::VirtAddr;
+
+entry_point!(initramfs_tests);
+
+fn initramfs_tests(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    thread::init();
+    fs::mount("/", TmpFs::new()).expect("Failed to mount the root!");
+
+    test_main();
+    loop {}
+}
+
+fn pad(archive: &mut Vec<u8>, alignment: usize) {
+    archive.resize(archive.len().next_multiple_of(alignment), 0);
+}
+
+fn cpio_entry(archive: &mut Vec<u8>, name: &str, inode: u32, mode: u32, links: u32, contents: &[u8]) {
+    let fields = [inode, mode, 0, 0, links, 0, contents.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    pad(archive, 4);
+    archive.extend_from_slice(contents);
+    pad(archive, 4);
+}
+
+fn tar_entry(archive: &mut Vec<u8>, name: &str, type_flag: u8, link: &str, contents: &[u8]) {
+    let mut header = [0; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..107].copy_from_slice(b"0000644");
+    header[124..135].copy_from_slice(format!("{:011o}", contents.len()).as_bytes());
+    header[156] = type_flag;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(contents);
+    pad(archive, 512);
+}
+
+#[test_case]
+fn cpio_archives_are_unpacked() {
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, ".", 1, 0o040755, 2, b"");
+    cpio_entry(&mut archive, "cpio", 2, 0o040700, 2, b"");
+    cpio_entry(&mut archive, "cpio/hello", 3, 0o100644, 1, b"Hello from cpio!");
+    // Hard linked files only carry their data on the last name.
+    cpio_entry(&mut archive, "cpio/first", 4, 0o100600, 2, b"");
+    cpio_entry(&mut archive, "cpio/second", 4, 0o100600, 2, b"shared");
+    cpio_entry(&mut archive, "cpio/link", 5, 0o120777, 1, b"hello");
+    cpio_entry(&mut archive, "cpio/implied/deep", 6, 0o100644, 1, b"parents");
+    cpio_entry(&mut archive, "cpio/device", 7, 0o020644, 1, b"");
+    cpio_entry(&mut archive, "TRAILER!!!", 0, 0, 1, b"");
+
+    assert_eq!(initramfs::unpack(&archive, "/"), Ok(7));
+    assert_eq!(fs::metadata("/cpio").unwrap().mode, 0o700);
+    assert_eq!(fs::read("/cpio/hello").unwrap(), b"Hello from cpio!");
+    assert_eq!(fs::read("/cpio/first").unwrap(), b"shared");
+    assert_eq!(fs::metadata("/cpio/second").unwrap().mode, 0o600);
+    assert_eq!(fs::read_link("/cpio/link").unwrap(), "hello");
+    assert_eq!(fs::read("/cpio/link").unwrap(), b"Hello from cpio!");
+    assert_eq!(fs::read("/cpio/implied/deep").unwrap(), b"parents");
+    assert_eq!(fs::metadata("/cpio/device").err(), Some(Errno::ENOENT));
+}
+
+#[test_case]
+fn tar_archives_are_unpacked() {
+    let mut archive = Vec::new();
+    tar_entry(&mut archive, "./tar/", b'5', "", b"");
+    tar_entry(&mut archive, "./tar/file", b'0', "", b"Hello from tar!");
+    tar_entry(&mut archive, "./tar/symlink", b'2', "file", b"");
+    tar_entry(&mut archive, "./tar/hard", b'1', "./tar/file", b"");
+    // GNU tar puts names that don't fit in the header in an entry of their own.
+    let long_name = format!("tar/{}", "n".repeat(150));
+    tar_entry(&mut archive, "././@LongLink", b'L', "", format!("{}\0", long_name).as_bytes());
+    tar_entry(&mut archive, "truncated", b'0', "", b"long");
+    archive.extend_from_slice(&[0; 1024]);
+
+    assert_eq!(initramfs::unpack(&archive, "/"), Ok(5));
+    assert_eq!(fs::metadata("/tar").unwrap().kind, FileType::Directory);
+    assert_eq!(fs::read("/tar/file").unwrap(), b"Hello from tar!");
+    assert_eq!(fs::metadata("/tar/file").unwrap().mode, 0o644);
+    assert_eq!(fs::read("/tar/symlink").unwrap(), b"Hello from tar!");
+    assert_eq!(fs::read("/tar/hard").unwrap(), b"Hello from tar!");
+    assert_eq!(fs::read(&format!("/{}", long_name)).unwrap(), b"long");
+    assert_eq!(fs::metadata("/truncated").err(), Some(Errno::ENOENT));
+}
+
+#[test_case]
+fn archives_can_be_unpacked_anywhere() {
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, "etc/motd", 1, 0o100644, 1, b"Welcome!");
+    cpio_entry(&mut archive, "TRAILER!!!", 0, 0, 1, b"");
+    fs::create_dir("/elsewhere", 0o755).unwrap();
+    assert_eq!(initramfs::unpack(&archive, "/elsewhere"), Ok(1));
+    assert_eq!(fs::read("/elsewhere/etc/motd").unwrap(), b"Welcome!");
+
+    // Unpacking again replaces what's there.
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, "etc/motd", 1, 0o100644, 1, b"Hi!");
+    cpio_entry(&mut archive, "TRAILER!!!", 0, 0, 1, b"");
+    assert_eq!(initramfs::unpack(&archive, "/elsewhere"), Ok(1));
+    assert_eq!(fs::read("/elsewhere/etc/motd").unwrap(), b"Hi!");
+}
+
+#[test_case]
+fn broken_archives_are_rejected() {
+    assert_eq!(initramfs::unpack(b"not an archive at all", "/"), Err(InitramfsError::UnknownFormat));
+
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, "cut/short", 1, 0o100644, 1, b"Only half of this is here");
+    archive.truncate(archive.len() - 16);
+    assert_eq!(initramfs::unpack(&archive, "/"), Err(InitramfsError::Truncated));
+
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, "../escape", 1, 0o100644, 1, b"");
+    cpio_entry(&mut archive, "TRAILER!!!", 0, 0, 1, b"");
+    assert_eq!(initramfs::unpack(&archive, "/"), Err(InitramfsError::InvalidPath));
+
+    let mut archive = Vec::new();
+    tar_entry(&mut archive, "checksum", b'0', "", b"");
+    archive[0] = b'C';
+    assert_eq!(initramfs::unpack(&archive, "/"), Err(InitramfsError::InvalidHeader));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
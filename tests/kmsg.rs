@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use halogen_os::io::kmsg::{RingBuffer, KMSG_SIZE};
+use spin::Mutex;
+
+static BUFFER: Mutex<RingBuffer> = Mutex::new(RingBuffer::new());
+
+entry_point!(kmsg);
+
+fn kmsg(_: &'static mut BootInfo) -> ! {
+    test_main();
+    loop {}
+}
+
+struct Collected {
+    bytes: [u8; 256],
+    len: usize
+}
+
+impl Collected {
+    fn new() -> Self {
+        Self { bytes: [0; 256], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+impl Write for Collected {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = (self.len + s.len()).min(self.bytes.len());
+        self.bytes[self.len..end].copy_from_slice(&s.as_bytes()[..end - self.len]);
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn replays_in_order() {
+    let mut buffer = BUFFER.lock();
+    buffer.clear();
+    write!(buffer, "first\nsecond\n").unwrap();
+    let mut collected = Collected::new();
+    buffer.replay(&mut collected).unwrap();
+    assert_eq!(collected.as_str(), "first\nsecond\n");
+}
+
+#[test_case]
+fn keeps_newest_messages_when_full() {
+    let mut buffer = BUFFER.lock();
+    buffer.clear();
+    for _ in 0..KMSG_SIZE / 8 + 1 {
+        buffer.push(b"xxxxxxx\n");
+    }
+    buffer.push(b"newest\n");
+    assert_eq!(buffer.len(), KMSG_SIZE);
+
+    let mut collected = Collected::new();
+    buffer.replay(&mut collected).unwrap();
+    assert!(collected.as_str().starts_with("xxxxxxx\n"));
+    // Only the start of the replay fits in what's collected, so the end is checked in place.
+    let newest = b"xxxxxxx\nnewest\n";
+    let (first, second) = buffer.as_slices();
+    assert!(first.iter().chain(second).rev().take(newest.len()).eq(newest.iter().rev()));
+}
+
+#[test_case]
+fn skips_partially_overwritten_line() {
+    let mut buffer = BUFFER.lock();
+    buffer.clear();
+    buffer.push(&[b'a'; KMSG_SIZE - 4]);
+    buffer.push(b"\nend\n");
+    let mut collected = Collected::new();
+    buffer.replay(&mut collected).unwrap();
+    assert_eq!(collected.as_str(), "end\n");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
@@ -0,0 +1,131 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::{allocator, process, thread};
+use halogen_os::memory::{self, Access, AddressSpace, BitmapFrameAllocator, Protection, RegionError, USER_SPACE_START};
+use halogen_os::process::ExitStatus;
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
+
+entry_point!(page_faults);
+
+fn page_faults(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+// Maps a megabyte of anonymous memory with the given protection, leaving its address in rax,
+// then carries on with the rest of the code.
+fn mmap_program(protection: u8, rest: &[u8]) -> Vec<u8> {
+    let mut code = Vec::new();
+    code.extend_from_slice(&[0xB8, 0x09, 0x00, 0x00, 0x00]); // mov eax, SYS_MMAP
+    code.extend_from_slice(&[0x31, 0xFF]); // xor edi, edi
+    code.extend_from_slice(&[0xBE, 0x00, 0x00, 0x10, 0x00]); // mov esi, 0x100000
+    code.extend_from_slice(&[0xBA, protection, 0x00, 0x00, 0x00]); // mov edx, protection
+    code.extend_from_slice(&[0x41, 0xBA, 0x22, 0x00, 0x00, 0x00]); // mov r10d, MAP_PRIVATE | MAP_ANONYMOUS
+    code.extend_from_slice(&[0x49, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF]); // mov r8, -1
+    code.extend_from_slice(&[0x45, 0x31, 0xC9]); // xor r9d, r9d
+    code.extend_from_slice(&[0x0F, 0x05]); // syscall
+    code.extend_from_slice(rest);
+    code
+}
+
+// mov byte [rax + 0x80000], 42; movzx edi, byte [rax + 0x80000]
+const STORE_AND_LOAD: [u8; 14] = [0xC6, 0x80, 0x00, 0x00, 0x08, 0x00, 0x2A, 0x0F, 0xB6, 0xB8, 0x00, 0x00, 0x08, 0x00];
+// mov eax, SYS_EXIT; syscall
+const EXIT: [u8; 7] = [0xB8, 0x3C, 0x00, 0x00, 0x00, 0x0F, 0x05];
+
+fn run(code: &[u8]) -> ExitStatus {
+    let pid = process::spawn_flat_binary(code).expect("Failed to load user code!");
+    process::wait(Some(pid)).expect("Failed to wait for the process!").1
+}
+
+#[test_case]
+fn anonymous_memory_is_mapped_on_first_use() {
+    let code = mmap_program(3, &[&STORE_AND_LOAD[..], &EXIT].concat());
+    assert_eq!(run(&code), ExitStatus::Code(42));
+}
+
+#[test_case]
+fn writes_to_read_only_memory_kill_the_process() {
+    let code = mmap_program(1, &[&STORE_AND_LOAD[..], &EXIT].concat());
+    assert_eq!(run(&code), ExitStatus::Signal(process::SIGSEGV));
+}
+
+#[test_case]
+fn system_calls_can_touch_untouched_memory() {
+    // mov rsi, rax; mov eax, SYS_WRITE; mov edi, 1; mov edx, 16; syscall; mov edi, eax
+    let write = [
+        0x48, 0x89, 0xC6, 0xB8, 0x01, 0x00, 0x00, 0x00, 0xBF, 0x01, 0x00, 0x00, 0x00,
+        0xBA, 0x10, 0x00, 0x00, 0x00, 0x0F, 0x05, 0x89, 0xC7
+    ];
+    let code = mmap_program(3, &[&write[..], &EXIT].concat());
+    assert_eq!(run(&code), ExitStatus::Code(16));
+}
+
+#[test_case]
+fn the_stack_grows_until_its_limit() {
+    // sub rsp, 0xF000; mov byte [rsp], 1; xor edi, edi
+    let within = [0x48, 0x81, 0xEC, 0x00, 0xF0, 0x00, 0x00, 0xC6, 0x04, 0x24, 0x01, 0x31, 0xFF];
+    assert_eq!(run(&[&within[..], &EXIT].concat()), ExitStatus::Code(0));
+
+    // sub rsp, 0x11000; mov byte [rsp], 1; xor edi, edi
+    let beyond = [0x48, 0x81, 0xEC, 0x00, 0x10, 0x01, 0x00, 0xC6, 0x04, 0x24, 0x01, 0x31, 0xFF];
+    assert_eq!(run(&[&beyond[..], &EXIT].concat()), ExitStatus::Signal(process::SIGSEGV));
+}
+
+#[test_case]
+fn regions_are_checked_and_split() {
+    let mut address_space = AddressSpace::new().expect("Failed to create an address space!");
+    let start = VirtAddr::new(USER_SPACE_START + 0x10_0000);
+    address_space.add_region(start, 4 * 4096, Protection::READ).unwrap();
+    assert_eq!(address_space.add_region(start + 0x1000u64, 4096, Protection::READ_WRITE), Err(RegionError::Overlapping));
+    assert_eq!(address_space.add_region(start - 1u64, 4096, Protection::READ_WRITE), Err(RegionError::Unaligned));
+    assert_eq!(address_space.add_region(VirtAddr::new(0x1000), 4096, Protection::READ_WRITE), Err(RegionError::OutsideUserSpace));
+
+    address_space.remove_range(start + 0x1000u64, 0x1000).unwrap();
+    let sizes: Vec<u64> = address_space.regions().iter().map(|region| region.size()).collect();
+    assert_eq!(sizes, [0x1000, 0x2000]);
+    assert_eq!(address_space.find_free_range(start, 0x1000), Some(start + 0x1000u64));
+    assert_eq!(address_space.find_free_range(start, 0x2000), Some(start + 0x4000u64));
+}
+
+#[test_case]
+fn faults_only_map_what_the_region_allows() {
+    let mut address_space = AddressSpace::new().expect("Failed to create an address space!");
+    let start = VirtAddr::new(USER_SPACE_START + 0x10_0000);
+    address_space.add_region(start, 256 * 4096, Protection::READ).unwrap();
+    let used_frames = memory::frame_allocator().used_frames();
+
+    let page = Page::containing_address(start + 0x8000u64);
+    assert!(!address_space.handle_fault(page.start_address(), Access::Write));
+    assert!(!address_space.handle_fault(page.start_address(), Access::Execute));
+    assert!(address_space.page_flags(page).is_none());
+    assert!(!address_space.handle_fault(start - 1u64, Access::Read));
+
+    assert!(address_space.handle_fault(page.start_address(), Access::Read));
+    assert!(address_space.page_flags(page).is_some());
+    // One frame for the page, plus at most three new tables to hold it.
+    assert!(memory::frame_allocator().used_frames() <= used_frames + 4);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
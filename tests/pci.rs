@@ -0,0 +1,171 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use halogen_os::{allocator, firmware, pci};
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::pci::{Bar, DeviceMatch, Driver, PciAddress, PciDevice};
+use halogen_os::syscall::Errno;
+use x86_64::VirtAddr;
+
+entry_point!(pci_tests);
+
+fn pci_tests(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    firmware::init(boot_info.rsdp_addr.into_option());
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+// QEMU's default machine is an i440FX with a PIIX3, which has no MCFG table.
+const HOST_BRIDGE: DeviceMatch = DeviceMatch::device(0x8086, 0x1237);
+const IDE_CONTROLLER: DeviceMatch = DeviceMatch::device(0x8086, 0x7010);
+const VGA: DeviceMatch = DeviceMatch::device(0x1234, 0x1111);
+
+#[test_case]
+fn host_bridge_is_found() {
+    assert!(!pci::ecam_enabled());
+    let host_bridge = pci::find(HOST_BRIDGE);
+    assert_eq!(host_bridge.len(), 1);
+    assert_eq!(host_bridge[0].address, PciAddress::new(0, 0, 0, 0));
+    assert_eq!(host_bridge[0].class, pci::CLASS_BRIDGE);
+    assert_eq!(host_bridge[0].subclass, pci::SUBCLASS_HOST_BRIDGE);
+    assert_eq!(pci::find(DeviceMatch::class(pci::CLASS_BRIDGE, pci::SUBCLASS_HOST_BRIDGE)).len(), 1);
+
+    // Functions come out in order, and only once each.
+    let devices = pci::devices();
+    assert!(devices.windows(2).all(|pair| pair[0].address < pair[1].address));
+    assert!(devices.iter().all(|device| device.vendor_id != 0xFFFF));
+}
+
+#[test_case]
+fn configuration_space_can_be_written() {
+    let ide = &pci::find(IDE_CONTROLLER)[0];
+    // The interrupt line is only there for software to keep notes in.
+    let original = pci::read_u8(ide.address, pci::INTERRUPT_LINE);
+    pci::write_u8(ide.address, pci::INTERRUPT_LINE, 0x5A);
+    assert_eq!(pci::read_u8(ide.address, pci::INTERRUPT_LINE), 0x5A);
+    assert_eq!(pci::read_u16(ide.address, pci::VENDOR_ID), 0x8086);
+    pci::write_u8(ide.address, pci::INTERRUPT_LINE, original);
+
+    // Functions that aren't there, and registers past what the ports reach, read as all ones.
+    assert_eq!(pci::read_u32(PciAddress::new(0, 0, 31, 7), pci::VENDOR_ID), 0xFFFF_FFFF);
+    assert_eq!(pci::read_u32(ide.address, 0x100), 0xFFFF_FFFF);
+}
+
+#[test_case]
+fn bars_are_sized() {
+    // The PIIX3's IDE controller only uses BAR 4, for bus master DMA.
+    let ide = &pci::find(IDE_CONTROLLER)[0];
+    assert_eq!(ide.bars[0], None);
+    match ide.bars[4] {
+        Some(Bar::Io { size, .. }) => assert_eq!(size, 16),
+        other => panic!("Unexpected IDE BAR 4: {:?}!", other)
+    }
+
+    // The VGA framebuffer is 16 MiB of prefetchable memory.
+    let vga = &pci::find(VGA)[0];
+    match vga.bars[0] {
+        Some(Bar::Memory { address, size, prefetchable, .. }) => {
+            assert_eq!(size, 16 * 1024 * 1024);
+            assert!(prefetchable);
+            assert_eq!(address % size, 0);
+        }
+        other => panic!("Unexpected VGA BAR 0: {:?}!", other)
+    }
+
+    // Sizing mustn't have left anything changed.
+    let bar = pci::read_u32(vga.address, 0x10) & !0xF;
+    assert_eq!(bar as u64, vga.bars[0].unwrap().memory_address().unwrap());
+}
+
+#[test_case]
+fn capabilities_are_listed() {
+    for device in pci::devices() {
+        for capability in &device.capabilities {
+            assert!(capability.offset >= 0x40);
+            assert_eq!(pci::read_u8(device.address, capability.offset as u16), capability.id);
+        }
+        if let Some(msix) = device.msix() {
+            assert!(msix.table_size >= 1);
+            assert!(msix.table_bar < 6);
+        }
+    }
+}
+
+struct CountingDriver {
+    name: &'static str,
+    matches: &'static [DeviceMatch],
+    accept: bool,
+    probed: AtomicUsize
+}
+
+impl Driver for CountingDriver {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn matches(&self) -> &[DeviceMatch] {
+        self.matches
+    }
+
+    fn probe(&self, _device: &PciDevice) -> Result<(), Errno> {
+        self.probed.fetch_add(1, Ordering::SeqCst);
+        if self.accept { Ok(()) } else { Err(Errno::ENODEV) }
+    }
+}
+
+#[test_case]
+fn drivers_are_matched() {
+    let refusing = Arc::new(CountingDriver {
+        name: "refusing",
+        matches: &[IDE_CONTROLLER],
+        accept: false,
+        probed: AtomicUsize::new(0)
+    });
+    assert_eq!(pci::register_driver(refusing.clone()), 0);
+    assert_eq!(refusing.probed.load(Ordering::SeqCst), 1);
+
+    let accepting = Arc::new(CountingDriver {
+        name: "accepting",
+        matches: &[DeviceMatch::interface(0x01, 0x01, 0x80), DeviceMatch::class(0x01, 0x01)],
+        accept: true,
+        probed: AtomicUsize::new(0)
+    });
+    assert_eq!(pci::register_driver(accepting.clone()), 1);
+    assert_eq!(accepting.probed.load(Ordering::SeqCst), 1);
+    let ide = &pci::find(IDE_CONTROLLER)[0];
+    assert_eq!(pci::bound_driver(ide.address), Some("accepting"));
+
+    // Devices that have been taken aren't offered to anyone else.
+    let late = Arc::new(CountingDriver {
+        name: "late",
+        matches: &[DeviceMatch::vendor(0x8086)],
+        accept: true,
+        probed: AtomicUsize::new(0)
+    });
+    let intel = pci::find(DeviceMatch::vendor(0x8086)).len();
+    assert_eq!(pci::register_driver(late), intel - 1);
+    assert_eq!(pci::bound_driver(ide.address), Some("accepting"));
+    assert_eq!(pci::bound_driver(pci::find(HOST_BRIDGE)[0].address), Some("late"));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
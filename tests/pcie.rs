@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::{allocator, firmware, interrupt, pci};
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::pci::{DeviceMatch, PciAddress};
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+
+entry_point!(pcie_tests);
+
+fn pcie_tests(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    firmware::init(boot_info.rsdp_addr.into_option());
+    interrupt::init_apic();
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+// The runner boots anything named `pcie` on QEMU's q35 machine, an ICH9 based one with an MCFG
+// table.
+const HOST_BRIDGE: DeviceMatch = DeviceMatch::device(0x8086, 0x29C0);
+const LPC_BRIDGE: DeviceMatch = DeviceMatch::device(0x8086, 0x2918);
+const AHCI_CONTROLLER: DeviceMatch = DeviceMatch::device(0x8086, 0x2922);
+
+// Reads a register the old way, to check ECAM against.
+fn read_legacy(address: PciAddress, offset: u16) -> u32 {
+    let value = 1 << 31 | (address.bus as u32) << 16 | (address.device as u32) << 11 | (address.function as u32) << 8 | offset as u32;
+    unsafe {
+        Port::<u32>::new(0xCF8).write(value);
+        Port::<u32>::new(0xCFC).read()
+    }
+}
+
+#[test_case]
+fn configuration_space_is_memory_mapped() {
+    assert!(pci::ecam_enabled());
+    let host_bridge = pci::find(HOST_BRIDGE);
+    assert_eq!(host_bridge.len(), 1);
+    assert_eq!(host_bridge[0].address, PciAddress::new(0, 0, 0, 0));
+
+    // Functions all over the bus share its mapping, and ECAM agrees with the ports about them.
+    let lpc = &pci::find(LPC_BRIDGE)[0];
+    assert_eq!(lpc.address, PciAddress::new(0, 0, 31, 0));
+    for device in [&host_bridge[0], lpc] {
+        assert_eq!(pci::read_u32(device.address, pci::VENDOR_ID), read_legacy(device.address, pci::VENDOR_ID));
+        assert_eq!(pci::read_u32(device.address, pci::REVISION), read_legacy(device.address, pci::REVISION));
+    }
+    let devices = pci::devices();
+    assert!(devices.windows(2).all(|pair| pair[0].address < pair[1].address));
+    assert!(devices.iter().all(|device| device.vendor_id != 0xFFFF));
+
+    // Writes go through too, and registers past the end of a function still read as all ones.
+    let original = pci::read_u8(lpc.address, pci::INTERRUPT_LINE);
+    pci::write_u8(lpc.address, pci::INTERRUPT_LINE, 0xA5);
+    assert_eq!(read_legacy(lpc.address, pci::INTERRUPT_LINE) & 0xFF, 0xA5);
+    pci::write_u8(lpc.address, pci::INTERRUPT_LINE, original);
+    assert_eq!(pci::read_u32(lpc.address, 0x1000), 0xFFFF_FFFF);
+    assert_eq!(pci::read_u32(PciAddress::new(0, 0, 30, 7), pci::VENDOR_ID), 0xFFFF_FFFF);
+}
+
+#[test_case]
+fn msi_is_enabled_and_disabled() {
+    let ahci = &pci::find(AHCI_CONTROLLER)[0];
+    let capability = ahci.capability(pci::CAPABILITY_MSI).expect("The AHCI controller has no MSI capability!");
+    let msi = ahci.msi().unwrap();
+    assert!(msi.wide);
+    assert!(!msi.enabled(ahci.address));
+
+    let vector = 0x60;
+    let (message_address, message_data) = pci::msi_message(vector).unwrap();
+    msi.enable(ahci.address, vector).unwrap();
+    assert!(msi.enabled(ahci.address));
+    let base = capability.offset as u16;
+    assert_eq!(pci::read_u32(ahci.address, base + 4), message_address as u32);
+    assert_eq!(pci::read_u32(ahci.address, base + 8), (message_address >> 32) as u32);
+    assert_eq!(pci::read_u16(ahci.address, base + 0xC) as u32, message_data);
+    // Only the one message is ever asked for.
+    assert_eq!(pci::read_u16(ahci.address, base + 2) >> 4 & 0b111, 0);
+
+    msi.disable(ahci.address);
+    assert!(!msi.enabled(ahci.address));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
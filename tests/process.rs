@@ -0,0 +1,196 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use halogen_os::{allocator, process, thread};
+use halogen_os::memory::{self, Access, AddressSpace, BitmapFrameAllocator, COPY_ON_WRITE, USER_SPACE_START};
+use halogen_os::process::{ExitStatus, ProcessState};
+use halogen_os::syscall::Errno;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(processes);
+
+fn processes(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+// mov eax, SYS_EXIT; mov edi, 42; syscall
+const EXIT_42: [u8; 12] = [0xB8, 0x3C, 0x00, 0x00, 0x00, 0xBF, 0x2A, 0x00, 0x00, 0x00, 0x0F, 0x05];
+
+// mov ecx, 0x8000000; dec ecx; jnz -4; mov eax, SYS_EXIT; mov edi, 7; syscall
+const SPIN_THEN_EXIT_7: [u8; 21] = [
+    0xB9, 0x00, 0x00, 0x00, 0x08, 0xFF, 0xC9, 0x75, 0xFC,
+    0xB8, 0x3C, 0x00, 0x00, 0x00, 0xBF, 0x07, 0x00, 0x00, 0x00, 0x0F, 0x05
+];
+
+// Stores 3 on the stack and forks. The child overwrites it with 9 and exits with it, while the
+// parent waits for the child and exits with the child's code plus its own copy of the value.
+const FORK_AND_WAIT: [u8; 75] = [
+    0x48, 0x83, 0xEC, 0x10,                         // sub rsp, 16
+    0x48, 0xC7, 0x04, 0x24, 0x03, 0x00, 0x00, 0x00, // mov qword [rsp], 3
+    0xB8, 0x39, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov eax, SYS_FORK; syscall
+    0x85, 0xC0, 0x75, 0x13,                         // test eax, eax; jnz parent
+    0x48, 0xC7, 0x04, 0x24, 0x09, 0x00, 0x00, 0x00, // mov qword [rsp], 9
+    0x48, 0x8B, 0x3C, 0x24,                         // mov rdi, [rsp]
+    0xB8, 0x3C, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov eax, SYS_EXIT; syscall
+    0x89, 0xC7, 0x48, 0x8D, 0x74, 0x24, 0x08,       // parent: mov edi, eax; lea rsi, [rsp + 8]
+    0x31, 0xD2, 0xB8, 0x3D, 0x00, 0x00, 0x00, 0x0F, 0x05, // xor edx, edx; mov eax, SYS_WAIT4; syscall
+    0x8B, 0x7C, 0x24, 0x08, 0xC1, 0xEF, 0x08,       // mov edi, [rsp + 8]; shr edi, 8
+    0x03, 0x3C, 0x24,                               // add edi, [rsp]
+    0xB8, 0x3C, 0x00, 0x00, 0x00, 0x0F, 0x05        // mov eax, SYS_EXIT; syscall
+];
+
+// Forks a child that gets killed for running a privileged instruction, and exits with the status
+// wait4 reports for it.
+const FORK_AND_KILL: [u8; 43] = [
+    0x48, 0x83, 0xEC, 0x10,                         // sub rsp, 16
+    0xB8, 0x39, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov eax, SYS_FORK; syscall
+    0x85, 0xC0, 0x75, 0x01,                         // test eax, eax; jnz parent
+    0xF4,                                           // hlt
+    0x89, 0xC7, 0x48, 0x8D, 0x74, 0x24, 0x08,       // parent: mov edi, eax; lea rsi, [rsp + 8]
+    0x31, 0xD2, 0xB8, 0x3D, 0x00, 0x00, 0x00, 0x0F, 0x05, // xor edx, edx; mov eax, SYS_WAIT4; syscall
+    0x8B, 0x7C, 0x24, 0x08,                         // mov edi, [rsp + 8]
+    0xB8, 0x3C, 0x00, 0x00, 0x00, 0x0F, 0x05        // mov eax, SYS_EXIT; syscall
+];
+
+fn settle() {
+    // Give exited threads a chance to switch away for the last time, then free their stacks.
+    thread::sleep(Duration::from_millis(20));
+    thread::reap_dead_threads();
+}
+
+#[test_case]
+fn exit_codes_reach_the_waiter() {
+    let pid = process::spawn_flat_binary(&EXIT_42).expect("Failed to start the process!");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Code(42))));
+    assert!(process::info(pid).is_none());
+}
+
+#[test_case]
+fn running_processes_are_not_reaped() {
+    let pid = process::spawn_flat_binary(&SPIN_THEN_EXIT_7).expect("Failed to start the process!");
+    let info = process::info(pid).unwrap();
+    assert_eq!(info.parent, None);
+    assert_eq!(info.state, ProcessState::Running);
+    assert_eq!(process::try_wait(Some(pid)), Ok(None));
+    assert_eq!(process::wait(None), Ok((pid, ExitStatus::Code(7))));
+}
+
+#[test_case]
+fn killed_processes_report_the_signal() {
+    // Only the signal is set, in the low 7 bits, which is what WIFSIGNALED and WTERMSIG look at.
+    assert_eq!(ExitStatus::Signal(process::SIGSEGV).wait_status(), process::SIGSEGV as u32);
+    assert_eq!(ExitStatus::Code(3).wait_status(), 3 << 8);
+    let pid = process::spawn_flat_binary(&FORK_AND_KILL).expect("Failed to start the process!");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Code(process::SIGSEGV))));
+}
+
+#[test_case]
+fn detached_processes_are_reaped_on_exit() {
+    let pid = process::spawn_flat_binary(&SPIN_THEN_EXIT_7).expect("Failed to start the process!");
+    assert_eq!(process::detach(pid), Ok(()));
+    assert_eq!(process::wait(Some(pid)), Err(Errno::ECHILD));
+    while process::info(pid).is_some() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    settle();
+}
+
+#[test_case]
+fn waiting_without_children_fails() {
+    assert_eq!(process::wait(None), Err(Errno::ECHILD));
+    assert_eq!(process::try_wait(None), Err(Errno::ECHILD));
+}
+
+#[test_case]
+fn exited_processes_free_their_memory() {
+    let run = || {
+        let pid = process::spawn_flat_binary(&EXIT_42).expect("Failed to start the process!");
+        process::wait(Some(pid)).unwrap();
+        settle();
+    };
+    // The first run can leave behind kernel page tables and heap growth that stick around.
+    run();
+    let used_frames = memory::frame_allocator().used_frames();
+    for _ in 0..16 {
+        run();
+    }
+    assert_eq!(memory::frame_allocator().used_frames(), used_frames);
+}
+
+#[test_case]
+fn forked_children_get_their_own_copy() {
+    let pid = process::spawn_flat_binary(&FORK_AND_WAIT).expect("Failed to start the process!");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Code(12))));
+}
+
+#[test_case]
+fn forked_processes_free_their_memory() {
+    let run = || {
+        let pid = process::spawn_flat_binary(&FORK_AND_WAIT).expect("Failed to start the process!");
+        process::wait(Some(pid)).unwrap();
+        settle();
+    };
+    run();
+    let used_frames = memory::frame_allocator().used_frames();
+    for _ in 0..8 {
+        run();
+    }
+    assert_eq!(memory::frame_allocator().used_frames(), used_frames);
+}
+
+#[test_case]
+fn writes_after_fork_copy_only_shared_frames() {
+    let mut parent = AddressSpace::new().expect("Failed to create an address space!");
+    let page = Page::containing_address(VirtAddr::new(USER_SPACE_START + 0x1234_0000));
+    parent.map_page(page, PageTableFlags::WRITABLE).expect("Failed to map a page!");
+    let mut child = parent.fork().expect("Failed to fork the address space!");
+    for address_space in [&mut parent, &mut child] {
+        let flags = address_space.page_flags(page).unwrap();
+        assert!(flags.contains(COPY_ON_WRITE) && !flags.contains(PageTableFlags::WRITABLE));
+    }
+
+    // The first writer gets a copy, after which the other is the only owner and keeps the frame.
+    let used_frames = memory::frame_allocator().used_frames();
+    assert!(child.handle_fault(page.start_address(), Access::Write));
+    assert_eq!(memory::frame_allocator().used_frames(), used_frames + 1);
+    assert!(parent.handle_fault(page.start_address(), Access::Write));
+    assert_eq!(memory::frame_allocator().used_frames(), used_frames + 1);
+    for address_space in [&mut parent, &mut child] {
+        let flags = address_space.page_flags(page).unwrap();
+        assert!(flags.contains(PageTableFlags::WRITABLE) && !flags.contains(COPY_ON_WRITE));
+    }
+}
+
+#[test_case]
+fn dropped_address_spaces_free_their_page_tables() {
+    let used_frames = memory::frame_allocator().used_frames();
+    let mut address_space = AddressSpace::new().expect("Failed to create an address space!");
+    let start = VirtAddr::new(USER_SPACE_START + 0x1234_0000);
+    address_space.map_range(start, 64 * 4096, PageTableFlags::WRITABLE).expect("Failed to map pages!");
+    assert!(memory::frame_allocator().used_frames() > used_frames + 64);
+    drop(address_space);
+    assert_eq!(memory::frame_allocator().used_frames(), used_frames);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
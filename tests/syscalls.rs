@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::{allocator, process, thread};
+use halogen_os::io::kmsg;
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::process::ExitStatus;
+use halogen_os::syscall::{self, Errno};
+use x86_64::VirtAddr;
+
+entry_point!(syscalls);
+
+fn syscalls(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+const SYSCALL: [u8; 2] = [0x0F, 0x05];
+const INT_0X80: [u8; 2] = [0xCD, 0x80];
+
+// Writes the message to stdout and exits, using the given instruction to make the calls.
+fn hello_program(instruction: [u8; 2], message: &[u8]) -> Vec<u8> {
+    let mut code = Vec::new();
+    // mov eax, SYS_WRITE; mov edi, 1; lea rsi, [rip + 16]; mov edx, message.len()
+    code.extend_from_slice(&[0xB8, 0x01, 0x00, 0x00, 0x00, 0xBF, 0x01, 0x00, 0x00, 0x00]);
+    code.extend_from_slice(&[0x48, 0x8D, 0x35, 0x10, 0x00, 0x00, 0x00, 0xBA]);
+    code.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    code.extend_from_slice(&instruction);
+    // mov eax, SYS_EXIT; xor edi, edi
+    code.extend_from_slice(&[0xB8, 0x3C, 0x00, 0x00, 0x00, 0x31, 0xFF]);
+    code.extend_from_slice(&instruction);
+    code.extend_from_slice(message);
+    code
+}
+
+fn kernel_messages() -> String {
+    let mut messages = String::new();
+    kmsg::replay(&mut messages).unwrap();
+    messages
+}
+
+#[test_case]
+fn syscall_writes_and_exits() {
+    let program = hello_program(SYSCALL, b"Hello through SYSCALL!\n");
+    let pid = process::spawn_flat_binary(&program).expect("Failed to load user code!");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Code(0))));
+    assert!(kernel_messages().contains("Hello through SYSCALL!"));
+}
+
+#[test_case]
+fn interrupt_gate_writes_and_exits() {
+    let program = hello_program(INT_0X80, b"Hello through int 0x80!\n");
+    let pid = process::spawn_flat_binary(&program).expect("Failed to load user code!");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Code(0))));
+    assert!(kernel_messages().contains("Hello through int 0x80!"));
+}
+
+#[test_case]
+fn unknown_syscalls_are_rejected() {
+    assert_eq!(syscall::dispatch(0xFFFF, [0; 6]), -(Errno::ENOSYS as i64));
+}
+
+#[test_case]
+fn kernel_pointers_are_rejected() {
+    let message = b"Not from user space";
+    let arguments = [1, message.as_ptr() as u64, message.len() as u64, 0, 0, 0];
+    assert_eq!(syscall::dispatch(syscall::SYS_WRITE, arguments), -(Errno::EFAULT as i64));
+    assert_eq!(syscall::dispatch(syscall::SYS_NANOSLEEP, [0; 6]), -(Errno::EFAULT as i64));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
@@ -0,0 +1,137 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use halogen_os::{allocator, thread, time};
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::sync::SleepMutex;
+use x86_64::VirtAddr;
+
+entry_point!(threads);
+
+fn threads(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn spawned_threads_run_and_join() {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let handles: Vec<_> = (0..8)
+        .map(|_| thread::spawn(|| {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        }))
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 8);
+}
+
+#[test_case]
+fn busy_threads_are_preempted() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static OTHER_RAN: AtomicBool = AtomicBool::new(false);
+    // This thread never yields, so the second one can only run if the timer preempts it.
+    let spinner = thread::spawn(|| {
+        while !STOP.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    });
+    let other = thread::spawn(|| {
+        OTHER_RAN.store(true, Ordering::SeqCst);
+        STOP.store(true, Ordering::SeqCst);
+    });
+    other.join();
+    spinner.join();
+    assert!(OTHER_RAN.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn sleep_waits_for_ticks() {
+    let start = time::ticks();
+    thread::sleep(Duration::from_millis(50));
+    assert!(time::ticks() - start >= time::duration_to_ticks(Duration::from_millis(50)));
+}
+
+#[test_case]
+fn yield_lets_other_threads_run() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+    let handle = thread::spawn(|| RAN.store(true, Ordering::SeqCst));
+    while !RAN.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+    handle.join();
+}
+
+#[test_case]
+fn exited_thread_stacks_are_reused() {
+    for _ in 0..64 {
+        thread::spawn(|| {}).join();
+    }
+    thread::reap_dead_threads();
+    let used = memory::frame_allocator().used_frames();
+    for _ in 0..64 {
+        thread::spawn(|| {}).join();
+    }
+    thread::reap_dead_threads();
+    assert_eq!(memory::frame_allocator().used_frames(), used);
+}
+
+#[test_case]
+fn parking_gives_up_at_the_deadline() {
+    let deadline = time::ticks() + time::duration_to_ticks(Duration::from_millis(30));
+    thread::park_until(deadline);
+    assert!(time::ticks() >= deadline);
+
+    // Being unparked first still wakes it early.
+    let current = thread::current();
+    let handle = thread::spawn(move || thread::unpark(current));
+    let start = time::ticks();
+    thread::park_until(start + time::duration_to_ticks(Duration::from_secs(5)));
+    assert!(time::ticks() < start + time::duration_to_ticks(Duration::from_secs(5)));
+    handle.join();
+}
+
+#[test_case]
+fn sleep_mutex_can_be_held_while_parked() {
+    static LOCK: SleepMutex<usize> = SleepMutex::new(0);
+    // The holder sleeps with the lock taken, so everyone else has to wait their turn for it.
+    let handles = {
+        let mut guard = LOCK.lock();
+        let handles: Vec<_> = (0..4)
+            .map(|_| thread::spawn(|| *LOCK.lock() += 1))
+            .collect();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(*guard, 0);
+        *guard = 10;
+        handles
+    };
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(*LOCK.lock(), 14);
+    assert!(LOCK.try_lock().is_some());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
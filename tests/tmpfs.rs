@@ -0,0 +1,145 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use halogen_os::{allocator, fs, thread};
+use halogen_os::fs::{FileSystem, FileType, OpenFlags};
+use halogen_os::fs::tmpfs::{TmpFs, DEFAULT_LIMIT};
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::syscall::Errno;
+use x86_64::VirtAddr;
+
+entry_point!(tmpfs);
+
+fn tmpfs(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    thread::init();
+    fs::mount("/", TmpFs::new()).expect("Failed to mount the root!");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn hard_links_share_contents() {
+    fs::write("/original", b"shared").unwrap();
+    fs::hard_link("/original", "/alias").unwrap();
+    let original = fs::metadata("/original").unwrap();
+    assert_eq!(original.links, 2);
+    assert_eq!(fs::metadata("/alias").unwrap().inode, original.inode);
+
+    fs::write("/alias", b"changed").unwrap();
+    assert_eq!(fs::read("/original").unwrap(), b"changed");
+    fs::remove_file("/original").unwrap();
+    assert_eq!(fs::metadata("/alias").unwrap().links, 1);
+    assert_eq!(fs::read("/alias").unwrap(), b"changed");
+
+    fs::create_dir("/directory", 0o755).unwrap();
+    assert_eq!(fs::hard_link("/directory", "/directory-alias"), Err(Errno::EPERM));
+    assert_eq!(fs::hard_link("/alias", "/directory"), Err(Errno::EEXIST));
+}
+
+#[test_case]
+fn files_can_be_truncated() {
+    fs::write("/truncated", b"0123456789").unwrap();
+    let file = fs::open("/truncated", OpenFlags::READ_WRITE, 0).unwrap();
+    file.inode().truncate(4).unwrap();
+    assert_eq!(fs::read("/truncated").unwrap(), b"0123");
+
+    // Growing again mustn't bring back what was cut off.
+    file.inode().truncate(8).unwrap();
+    assert_eq!(fs::read("/truncated").unwrap(), b"0123\0\0\0\0");
+
+    fs::open("/truncated", OpenFlags::WRITE_ONLY | OpenFlags::TRUNCATE, 0).unwrap();
+    assert_eq!(fs::metadata("/truncated").unwrap().size, 0);
+}
+
+#[test_case]
+fn holes_take_no_space() {
+    let filesystem = TmpFs::new();
+    let file = filesystem.root().create("sparse", FileType::File, 0o644).unwrap();
+    file.write_at(10 * 1024 * 1024, b"end").unwrap();
+    assert_eq!(file.metadata().unwrap().size, 10 * 1024 * 1024 + 3);
+    assert_eq!(file.metadata().unwrap().blocks, 8);
+    assert_eq!(filesystem.used(), 4096);
+
+    let mut buffer = [0xFF; 16];
+    assert_eq!(file.read_at(4096 * 100, &mut buffer), Ok(16));
+    assert_eq!(buffer, [0; 16]);
+    assert_eq!(file.read_at(10 * 1024 * 1024 + 1, &mut buffer), Ok(2));
+    assert_eq!(&buffer[..2], b"nd");
+
+    file.truncate(0).unwrap();
+    assert_eq!(filesystem.used(), 0);
+}
+
+#[test_case]
+fn timestamps_follow_changes() {
+    fs::write("/timestamps", b"first").unwrap();
+    let created = fs::metadata("/timestamps").unwrap();
+    assert_eq!(created.modified, created.changed);
+    thread::sleep(Duration::from_millis(30));
+
+    let file = fs::open("/timestamps", OpenFlags::READ_WRITE, 0).unwrap();
+    file.read(&mut [0; 5]).unwrap();
+    let read = fs::metadata("/timestamps").unwrap();
+    assert!(read.accessed > created.accessed);
+    assert_eq!(read.modified, created.modified);
+
+    thread::sleep(Duration::from_millis(30));
+    file.write(b"second").unwrap();
+    let written = fs::metadata("/timestamps").unwrap();
+    assert!(written.modified > read.modified);
+    assert_eq!(written.changed, written.modified);
+
+    thread::sleep(Duration::from_millis(30));
+    fs::hard_link("/timestamps", "/timestamps-link").unwrap();
+    let linked = fs::metadata("/timestamps").unwrap();
+    assert!(linked.changed > written.changed);
+    assert_eq!(linked.modified, written.modified);
+    assert!(fs::metadata("/").unwrap().modified >= linked.changed);
+}
+
+#[test_case]
+fn size_limit_is_enforced() {
+    let filesystem = TmpFs::with_limit(3 * 4096);
+    assert_eq!(TmpFs::new().limit(), DEFAULT_LIMIT);
+    assert_eq!(TmpFs::with_limit(u64::MAX).limit(), allocator::HEAP_MAX_SIZE as u64);
+    fs::create_dir("/small", 0o755).unwrap();
+    fs::mount("/small", filesystem.clone()).unwrap();
+
+    fs::write("/small/fits", &vec![1; 2 * 4096]).unwrap();
+    assert_eq!(filesystem.used(), 2 * 4096);
+    // Whatever fits gets written, and only then does it fail.
+    let file = fs::open("/small/overflow", OpenFlags::WRITE_ONLY | OpenFlags::CREATE, 0o644).unwrap();
+    assert_eq!(file.write(&vec![2; 2 * 4096]), Ok(4096));
+    assert_eq!(file.write(&[2]), Err(Errno::ENOSPC));
+    assert_eq!(fs::symlink("/somewhere", "/small/link"), Err(Errno::ENOSPC));
+
+    // Space comes back once nothing refers to a file any more.
+    fs::remove_file("/small/fits").unwrap();
+    assert_eq!(filesystem.used(), 4096);
+    fs::remove_file("/small/overflow").unwrap();
+    assert_eq!(filesystem.used(), 4096);
+    drop(file);
+    assert_eq!(filesystem.used(), 0);
+    fs::unmount("/small").unwrap();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::ptr;
+use halogen_os::{allocator, process, thread};
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::process::ExitStatus;
+use x86_64::VirtAddr;
+
+entry_point!(usermode);
+
+fn usermode(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+// hlt
+const HALT: [u8; 1] = [0xF4];
+
+#[test_case]
+fn privileged_instructions_kill_the_process() {
+    let pid = process::spawn_flat_binary(&HALT).expect("Failed to load user code!");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Signal(process::SIGSEGV))));
+}
+
+#[test_case]
+fn user_code_cannot_write_kernel_memory() {
+    let target = Box::new(0u8);
+    let address = &*target as *const u8 as u64;
+    // mov rax, address; mov byte [rax], 1; hlt
+    let mut code = Vec::from([0x48, 0xB8]);
+    code.extend_from_slice(&address.to_le_bytes());
+    code.extend_from_slice(&[0xC6, 0x00, 0x01, 0xF4]);
+    let pid = process::spawn_flat_binary(&code).expect("Failed to load user code!");
+    process::wait(Some(pid)).unwrap();
+    assert_eq!(unsafe { ptr::read_volatile(&*target) }, 0);
+}
+
+#[test_case]
+fn user_code_survives_interrupts() {
+    // mov ecx, 0x10000000; dec ecx; jnz -4; hlt
+    let code = [0xB9, 0x00, 0x00, 0x00, 0x10, 0xFF, 0xC9, 0x75, 0xFC, 0xF4];
+    let pids: Vec<_> = (0..2)
+        .map(|_| process::spawn_flat_binary(&code).expect("Failed to load user code!"))
+        .collect();
+    for pid in pids {
+        process::wait(Some(pid)).unwrap();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
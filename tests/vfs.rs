@@ -0,0 +1,293 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::{allocator, fs, process, thread};
+use halogen_os::fs::{File, FileTable, FileType, OpenFlags, SeekFrom, Zero, MAX_FILES};
+use halogen_os::fs::tmpfs::TmpFs;
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::process::ExitStatus;
+use halogen_os::syscall::Errno;
+use x86_64::VirtAddr;
+
+entry_point!(vfs);
+
+fn vfs(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    thread::init();
+    fs::mount("/", TmpFs::new()).expect("Failed to mount the root!");
+
+    test_main();
+    loop {}
+}
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path).unwrap().into_iter().map(|entry| entry.name).collect()
+}
+
+#[test_case]
+fn files_can_be_written_and_read_back() {
+    fs::create_dir("/files", 0o755).unwrap();
+    fs::write("/files/greeting", b"Hello, world!").unwrap();
+    assert_eq!(fs::read("/files/greeting").unwrap(), b"Hello, world!");
+
+    let file = fs::open("/files/greeting", OpenFlags::READ_WRITE, 0).unwrap();
+    assert_eq!(file.seek(SeekFrom::Start(7)), Ok(7));
+    assert_eq!(file.write(b"there!"), Ok(6));
+    assert_eq!(file.seek(SeekFrom::End(2)), Ok(15));
+    assert_eq!(file.write(b"?"), Ok(1));
+    assert_eq!(fs::read("/files/greeting").unwrap(), b"Hello, there!\0\0?");
+
+    let appender = fs::open("/files/greeting", OpenFlags::WRITE_ONLY | OpenFlags::APPEND, 0).unwrap();
+    appender.write(b"!").unwrap();
+    assert_eq!(fs::metadata("/files/greeting").unwrap().size, 17);
+    assert_eq!(fs::open("/files/greeting", OpenFlags::WRITE_ONLY | OpenFlags::CREATE | OpenFlags::EXCLUSIVE, 0).err(), Some(Errno::EEXIST));
+    assert_eq!(appender.read(&mut [0; 4]), Err(Errno::EBADF));
+}
+
+#[test_case]
+fn directories_list_and_describe_their_entries() {
+    fs::create_dir("/listing", 0o755).unwrap();
+    fs::create_dir("/listing/inner", 0o700).unwrap();
+    fs::write("/listing/b", b"12345").unwrap();
+    fs::write("/listing/a", b"").unwrap();
+    assert_eq!(names("/listing"), ["a", "b", "inner"]);
+
+    let metadata = fs::metadata("/listing/b").unwrap();
+    assert_eq!((metadata.kind, metadata.size, metadata.mode), (FileType::File, 5, 0o644));
+    let metadata = fs::metadata("/listing/inner").unwrap();
+    assert_eq!((metadata.kind, metadata.mode), (FileType::Directory, 0o700));
+    assert_eq!(fs::metadata("/listing").unwrap().links, 3);
+    assert_eq!(fs::metadata("/listing/missing").err(), Some(Errno::ENOENT));
+    assert_eq!(fs::metadata("/listing/b/c").err(), Some(Errno::ENOTDIR));
+    assert_eq!(fs::metadata("/listing/b/").err(), Some(Errno::ENOTDIR));
+    assert_eq!(fs::open("/listing", OpenFlags::WRITE_ONLY, 0).err(), Some(Errno::EISDIR));
+}
+
+#[test_case]
+fn entries_can_be_removed() {
+    fs::create_dir("/removal", 0o755).unwrap();
+    fs::create_dir("/removal/full", 0o755).unwrap();
+    fs::write("/removal/full/file", b"data").unwrap();
+    let open = fs::open("/removal/full/file", OpenFlags::READ_ONLY, 0).unwrap();
+
+    assert_eq!(fs::remove_dir("/removal/full"), Err(Errno::ENOTEMPTY));
+    assert_eq!(fs::remove_file("/removal/full"), Err(Errno::EISDIR));
+    assert_eq!(fs::remove_dir("/removal/full/file"), Err(Errno::ENOTDIR));
+    fs::remove_file("/removal/full/file").unwrap();
+    fs::remove_dir("/removal/full").unwrap();
+    assert!(names("/removal").is_empty());
+
+    // Open files outlive their names.
+    let mut buffer = [0; 4];
+    assert_eq!(open.read(&mut buffer), Ok(4));
+    assert_eq!(&buffer, b"data");
+}
+
+#[test_case]
+fn dots_and_symlinks_are_resolved() {
+    fs::create_dir("/links", 0o755).unwrap();
+    fs::create_dir("/links/target", 0o755).unwrap();
+    fs::write("/links/target/file", b"linked").unwrap();
+    fs::symlink("/links/target", "/links/absolute").unwrap();
+    fs::symlink("target/file", "/links/relative").unwrap();
+    fs::symlink("../../links/./absolute/", "/links/target/back").unwrap();
+
+    assert_eq!(fs::canonicalize("/links/./target/../target/file").unwrap(), "/links/target/file");
+    assert_eq!(fs::canonicalize("/../..").unwrap(), "/");
+    assert_eq!(fs::read("/links/absolute/file").unwrap(), b"linked");
+    assert_eq!(fs::read("/links/relative").unwrap(), b"linked");
+    assert_eq!(fs::canonicalize("/links/target/back/back/file").unwrap(), "/links/target/file");
+    // `..` goes back to wherever the symlink led, not to where the link itself is.
+    assert_eq!(fs::canonicalize("/links/absolute/..").unwrap(), "/links");
+
+    assert_eq!(fs::read_link("/links/relative").unwrap(), "target/file");
+    assert_eq!(fs::symlink_metadata("/links/relative").unwrap().kind, FileType::Symlink);
+    assert_eq!(fs::metadata("/links/relative").unwrap().kind, FileType::File);
+    assert_eq!(fs::open("/links/relative", OpenFlags::READ_ONLY | OpenFlags::NO_FOLLOW, 0).err(), Some(Errno::ELOOP));
+
+    fs::symlink("loop", "/links/loop").unwrap();
+    assert_eq!(fs::metadata("/links/loop").err(), Some(Errno::ELOOP));
+    fs::symlink("missing", "/links/dangling").unwrap();
+    assert_eq!(fs::metadata("/links/dangling").err(), Some(Errno::ENOENT));
+}
+
+#[test_case]
+fn filesystems_can_be_mounted_inside_others() {
+    fs::create_dir("/mnt", 0o755).unwrap();
+    fs::write("/mnt/hidden", b"underneath").unwrap();
+    fs::mount("/mnt", TmpFs::new()).unwrap();
+    assert!(names("/mnt").is_empty());
+    assert_eq!(fs::mount("/mnt", TmpFs::new()), Err(Errno::EBUSY));
+
+    fs::create_dir("/mnt/nested", 0o755).unwrap();
+    fs::write("/mnt/nested/file", b"mounted").unwrap();
+    assert_eq!(fs::canonicalize("/mnt/nested/../..").unwrap(), "/");
+    assert_ne!(fs::metadata("/mnt/nested/file").unwrap().device, fs::metadata("/").unwrap().device);
+    assert_eq!(fs::hard_link("/mnt/nested/file", "/crossed"), Err(Errno::EXDEV));
+
+    fs::mount("/mnt/nested", TmpFs::new()).unwrap();
+    assert_eq!(fs::unmount("/mnt"), Err(Errno::EBUSY));
+    assert_eq!(fs::remove_dir("/mnt/nested"), Err(Errno::EBUSY));
+    fs::unmount("/mnt/nested").unwrap();
+    assert_eq!(fs::read("/mnt/nested/file").unwrap(), b"mounted");
+    fs::unmount("/mnt").unwrap();
+    assert_eq!(fs::read("/mnt/hidden").unwrap(), b"underneath");
+    assert_eq!(fs::mounts(), ["/"]);
+}
+
+#[test_case]
+fn file_tables_reuse_the_lowest_descriptor() {
+    let zero = Arc::new(File::new(Arc::new(Zero), OpenFlags::READ_ONLY, String::from("/dev/zero")));
+    let mut files = FileTable::with_console();
+    assert_eq!(files.insert(zero.clone()), Ok(3));
+    assert_eq!(files.insert(zero.clone()), Ok(4));
+    assert!(files.remove(1).is_ok());
+    assert_eq!(files.insert(zero.clone()), Ok(1));
+    assert_eq!(files.remove(7).err(), Some(Errno::EBADF));
+
+    let mut buffer = [0xFF; 8];
+    assert_eq!(files.get(1).unwrap().read(&mut buffer), Ok(8));
+    assert_eq!(buffer, [0; 8]);
+    // Devices like /dev/zero can seek, unlike the console.
+    assert_eq!(files.get(1).unwrap().seek(SeekFrom::Start(16)), Ok(16));
+    assert_eq!(files.get(0).unwrap().seek(SeekFrom::Start(0)), Err(Errno::ESPIPE));
+
+    while files.len() < MAX_FILES {
+        files.insert(zero.clone()).unwrap();
+    }
+    assert_eq!(files.insert(zero).err(), Some(Errno::EMFILE));
+}
+
+// open("notes.txt", O_WRONLY | O_CREAT, 0644), then writes the message to it, closes it and exits
+// with what close returned.
+const WRITE_NOTES: [u8; 65] = [
+    0xB8, 0x02, 0x00, 0x00, 0x00,                   // mov eax, SYS_OPEN
+    0x48, 0x8D, 0x3D, 0x35, 0x00, 0x00, 0x00,       // lea rdi, [rip + path]
+    0xBE, 0x41, 0x00, 0x00, 0x00,                   // mov esi, O_WRONLY | O_CREAT
+    0xBA, 0xA4, 0x01, 0x00, 0x00, 0x0F, 0x05,       // mov edx, 0o644; syscall
+    0x89, 0xC3, 0x89, 0xC7,                         // mov ebx, eax; mov edi, eax
+    0xB8, 0x01, 0x00, 0x00, 0x00,                   // mov eax, SYS_WRITE
+    0x48, 0x8D, 0x35, 0x23, 0x00, 0x00, 0x00,       // lea rsi, [rip + message]
+    0xBA, 0x12, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov edx, message.len(); syscall
+    0x89, 0xDF, 0xB8, 0x03, 0x00, 0x00, 0x00,       // mov edi, ebx; mov eax, SYS_CLOSE
+    0x0F, 0x05, 0x89, 0xC7,                         // syscall; mov edi, eax
+    0xB8, 0x3C, 0x00, 0x00, 0x00, 0x0F, 0x05        // mov eax, SYS_EXIT; syscall
+];
+
+#[test_case]
+fn user_code_writes_files_through_descriptors() {
+    let mut program = Vec::from(WRITE_NOTES);
+    program.extend_from_slice(b"notes.txt\0");
+    program.extend_from_slice(b"Written by ring 3\n");
+    let pid = process::spawn_flat_binary(&program).expect("Failed to load user code!");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Code(0))));
+    assert_eq!(fs::read("/notes.txt").unwrap(), b"Written by ring 3\n");
+}
+
+// Goes through the descriptor and directory calls from ring 3, leaving what they did in
+// /calls/out: "abc" written through a dup of a dup2'd descriptor, then seeked back over and
+// patched to "abZ", followed by what getcwd, readlink and getdents64 put in their buffers.
+const DESCRIPTOR_CALLS: [u8; 257] = [
+    0xB8, 0x50, 0x00, 0x00, 0x00,                   // mov eax, SYS_CHDIR
+    0x48, 0x8D, 0x3D, 0xF5, 0x00, 0x00, 0x00,       // lea rdi, [rip + "calls"]
+    0x0F, 0x05, 0xB8, 0x02, 0x00, 0x00, 0x00,       // syscall; mov eax, SYS_OPEN
+    0x48, 0x8D, 0x3D, 0xED, 0x00, 0x00, 0x00,       // lea rdi, [rip + "out"]
+    0xBE, 0x42, 0x00, 0x00, 0x00,                   // mov esi, O_RDWR | O_CREAT
+    0xBA, 0xA4, 0x01, 0x00, 0x00, 0x0F, 0x05,       // mov edx, 0o644; syscall
+    0x89, 0xC7, 0xBE, 0x01, 0x00, 0x00, 0x00,       // mov edi, eax; mov esi, 1
+    0xB8, 0x21, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov eax, SYS_DUP2; syscall
+    0xBF, 0x01, 0x00, 0x00, 0x00,                   // mov edi, 1
+    0xB8, 0x20, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov eax, SYS_DUP; syscall
+    0x89, 0xC7, 0xB8, 0x01, 0x00, 0x00, 0x00,       // mov edi, eax; mov eax, SYS_WRITE
+    0x48, 0x8D, 0x35, 0xBD, 0x00, 0x00, 0x00,       // lea rsi, [rip + "abc"]
+    0xBA, 0x03, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov edx, 3; syscall
+    0xBF, 0x01, 0x00, 0x00, 0x00,                   // mov edi, 1
+    0x48, 0xC7, 0xC6, 0xFF, 0xFF, 0xFF, 0xFF,       // mov rsi, -1
+    0xBA, 0x01, 0x00, 0x00, 0x00,                   // mov edx, SEEK_CUR
+    0xB8, 0x08, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov eax, SYS_LSEEK; syscall
+    0xBF, 0x01, 0x00, 0x00, 0x00,                   // mov edi, 1
+    0xB8, 0x01, 0x00, 0x00, 0x00,                   // mov eax, SYS_WRITE
+    0x48, 0x8D, 0x35, 0x90, 0x00, 0x00, 0x00,       // lea rsi, [rip + "Z"]
+    0xBA, 0x01, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov edx, 1; syscall
+    0x48, 0x81, 0xEC, 0x00, 0x01, 0x00, 0x00,       // sub rsp, 256
+    0x48, 0x89, 0xE7, 0xBE, 0x00, 0x01, 0x00, 0x00, // mov rdi, rsp; mov esi, 256
+    0xB8, 0x4F, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov eax, SYS_GETCWD; syscall
+    0xE8, 0x4D, 0x00, 0x00, 0x00,                   // call record
+    0x48, 0x8D, 0x3D, 0x68, 0x00, 0x00, 0x00,       // lea rdi, [rip + "link"]
+    0x48, 0x89, 0xE6, 0xBA, 0x00, 0x01, 0x00, 0x00, // mov rsi, rsp; mov edx, 256
+    0xB8, 0x59, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov eax, SYS_READLINK; syscall
+    0xE8, 0x32, 0x00, 0x00, 0x00,                   // call record
+    0xB8, 0x02, 0x00, 0x00, 0x00,                   // mov eax, SYS_OPEN
+    0x48, 0x8D, 0x3D, 0x4D, 0x00, 0x00, 0x00,       // lea rdi, [rip + "dir"]
+    0xBE, 0x00, 0x00, 0x01, 0x00, 0x0F, 0x05,       // mov esi, O_DIRECTORY; syscall
+    0x89, 0xC7, 0x48, 0x89, 0xE6,                   // mov edi, eax; mov rsi, rsp
+    0xBA, 0x00, 0x01, 0x00, 0x00,                   // mov edx, 256
+    0xB8, 0xD9, 0x00, 0x00, 0x00, 0x0F, 0x05,       // mov eax, SYS_GETDENTS64; syscall
+    0xE8, 0x09, 0x00, 0x00, 0x00,                   // call record
+    0x31, 0xFF, 0xB8, 0x3C, 0x00, 0x00, 0x00,       // xor edi, edi; mov eax, SYS_EXIT
+    0x0F, 0x05,                                     // syscall
+    0x89, 0xC2, 0x48, 0x8D, 0x74, 0x24, 0x08,       // record: mov edx, eax; lea rsi, [rsp + 8]
+    0xBF, 0x01, 0x00, 0x00, 0x00,                   // mov edi, 1
+    0xB8, 0x01, 0x00, 0x00, 0x00, 0x0F, 0x05, 0xC3  // mov eax, SYS_WRITE; syscall; ret
+];
+
+#[test_case]
+fn user_code_uses_descriptors_and_directories() {
+    fs::create_dir("/calls", 0o755).unwrap();
+    fs::create_dir("/calls/dir", 0o755).unwrap();
+    fs::symlink("dir/target", "/calls/link").unwrap();
+    // The listed directory is a mount root, whose `..` is still the directory it's mounted in.
+    fs::mount("/calls/dir", TmpFs::new()).unwrap();
+    fs::write("/calls/dir/target", b"").unwrap();
+
+    let mut program = Vec::from(DESCRIPTOR_CALLS);
+    program.extend_from_slice(b"calls\0out\0abcZlink\0dir\0");
+    let pid = process::spawn_flat_binary(&program).expect("Failed to load user code!");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Code(0))));
+
+    let out = fs::read("/calls/out").unwrap();
+    let (written, rest) = out.split_at(3);
+    assert_eq!(written, b"abZ");
+    let (working_directory, rest) = rest.split_at(7);
+    assert_eq!(working_directory, b"/calls\0");
+    let (target, mut records) = rest.split_at(10);
+    assert_eq!(target, b"dir/target");
+
+    // Each linux_dirent64 is the inode, the offset of the next one, the record length and the
+    // type, followed by the name.
+    let expected = [
+        (".", fs::metadata("/calls/dir").unwrap().inode, 4),
+        ("..", fs::metadata("/calls").unwrap().inode, 4),
+        ("target", fs::metadata("/calls/dir/target").unwrap().inode, 8)
+    ];
+    for (index, (name, inode, kind)) in expected.into_iter().enumerate() {
+        let field = |start: usize, end: usize| records[start..end].iter().rev().fold(0, |value, &byte| (value << 8) | byte as u64);
+        let length = field(16, 18) as usize;
+        assert_eq!((field(0, 8), field(8, 16), records[18]), (inode, index as u64 + 1, kind));
+        assert_eq!(&records[19..19 + name.len()], name.as_bytes());
+        assert_eq!(records[19 + name.len()], 0);
+        records = &records[length..];
+    }
+    assert!(records.is_empty());
+    fs::unmount("/calls/dir").unwrap();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}
@@ -0,0 +1,113 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(halogen_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use halogen_os::{allocator, block, drivers, firmware, interrupt, pci, thread};
+use halogen_os::block::{BlockDevice, SECTOR_SIZE};
+use halogen_os::drivers::virtio::{self, LegacyTransport, ModernTransport, VirtioBlock};
+use halogen_os::memory::{self, BitmapFrameAllocator};
+use halogen_os::pci::{DeviceMatch, PciDevice};
+use halogen_os::syscall::Errno;
+use x86_64::VirtAddr;
+use common::pattern;
+
+entry_point!(virtio_blk_tests);
+
+fn virtio_blk_tests(boot_info: &'static mut BootInfo) -> ! {
+    halogen_os::init_headless();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_regions, physical_memory_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("Heap initialization failed!");
+    firmware::init(boot_info.rsdp_addr.into_option());
+    interrupt::init_apic();
+    thread::init();
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+// halogen-boot gives every test a blank 4 MiB disk.
+const SCRATCH_SECTORS: u64 = 4 * 1024 * 1024 / SECTOR_SIZE as u64;
+
+fn scratch_disk() -> PciDevice {
+    pci::find(DeviceMatch::vendor(virtio::VENDOR_ID)).into_iter().next().expect("No virtio device attached!")
+}
+
+// Has to run before the driver takes the device for itself.
+#[test_case]
+fn both_transports_work() {
+    let device = scratch_disk();
+    let legacy = LegacyTransport::new(&device).expect("Device has no legacy interface!");
+    let disk = VirtioBlock::new(&device, Box::new(legacy)).unwrap();
+    assert_eq!(disk.sector_count(), SCRATCH_SECTORS);
+    assert!(disk.uses_interrupts());
+    disk.write_sectors(10, &pattern(2 * SECTOR_SIZE, 0x5A)).unwrap();
+    disk.flush().unwrap();
+    drop(disk);
+
+    let modern = ModernTransport::new(&device).expect("Device has no modern interface!");
+    let disk = VirtioBlock::new(&device, Box::new(modern)).unwrap();
+    assert_eq!(disk.sector_count(), SCRATCH_SECTORS);
+    let mut buffer = vec![0; 2 * SECTOR_SIZE];
+    disk.read_sectors(10, &mut buffer).unwrap();
+    assert_eq!(buffer, pattern(2 * SECTOR_SIZE, 0x5A));
+}
+
+#[test_case]
+fn driver_registers_disk() {
+    drivers::init();
+    assert_eq!(pci::bound_driver(scratch_disk().address), Some("virtio-blk"));
+    // The boot disk shows up too, through the ATA driver.
+    let disks: Vec<String> = block::devices().into_iter().filter(|name| name.starts_with("vd")).collect();
+    assert_eq!(disks, ["vda"]);
+    let disk = block::device("vda").unwrap();
+    assert_eq!(disk.sector_count(), SCRATCH_SECTORS);
+    assert!(!disk.read_only());
+}
+
+#[test_case]
+fn sectors_round_trip() {
+    let disk = block::device("vda").unwrap();
+    // Big enough to be split into several requests.
+    let data = pattern(300 * SECTOR_SIZE, 0xA5);
+    disk.write_sectors(100, &data).unwrap();
+    let mut buffer = vec![0; data.len()];
+    disk.read_sectors(100, &mut buffer).unwrap();
+    assert_eq!(buffer, data);
+
+    let last = SCRATCH_SECTORS - 1;
+    disk.write_sectors(last, &pattern(SECTOR_SIZE, 1)).unwrap();
+    let mut sector = [0; SECTOR_SIZE];
+    disk.read_sectors(last, &mut sector).unwrap();
+    assert_eq!(&sector[..], &pattern(SECTOR_SIZE, 1)[..]);
+    disk.flush().unwrap();
+}
+
+#[test_case]
+fn bad_requests_are_rejected() {
+    let disk = block::device("vda").unwrap();
+    let mut buffer = [0; 2 * SECTOR_SIZE];
+    assert_eq!(disk.read_sectors(SCRATCH_SECTORS - 1, &mut buffer), Err(Errno::EINVAL));
+    assert_eq!(disk.read_sectors(0, &mut buffer[..100]), Err(Errno::EINVAL));
+    assert_eq!(disk.write_sectors(u64::MAX, &buffer), Err(Errno::EINVAL));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    halogen_os::test_panic_handler(info)
+}